@@ -0,0 +1,551 @@
+//! Python bindings for the pcap reader and the MAPI analyzer of mapiproxy.
+//!
+//! ```python
+//! import mapiproxy
+//!
+//! for frame in mapiproxy.read_frames("capture.pcap", "messages"):
+//!     print(frame.conn_id, frame.direction, frame.data)
+//! ```
+
+use std::{collections::HashMap, fs::File};
+
+use mapiproxy::{
+    mapi::Analyzer,
+    pcap::{self, Tracker},
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+    Level,
+};
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+};
+
+/// A single event observed in the capture, see `MapiEvent` on the Rust side.
+#[pyclass(frozen, module = "mapiproxy")]
+struct Event {
+    /// One of "bound", "bind_failed", "accept_paused", "accept_resumed",
+    /// "primary_down", "primary_up", "capture_interface",
+    /// "capture_statistics", "failed", "incoming", "connecting", "connected",
+    /// "joined", "mirroring", "redirected", "out_of_band", "round_trip",
+    /// "connect_failed", "end", "still_open", "aborted", "data", "rewritten",
+    /// "dropped", "retransmitted", "bad_checksum", "keep_alive",
+    /// "zero_window", "segment", "held", "shutdown_read", "shutdown_write",
+    /// "note" and "level".
+    #[pyo3(get)]
+    kind: &'static str,
+    /// The connection id, None for "bound", "bind_failed", "accept_paused",
+    /// "accept_resumed", "primary_down", "primary_up", "capture_interface",
+    /// "capture_statistics" and "failed".
+    #[pyo3(get)]
+    conn: Option<usize>,
+    /// The source name passed to `read_events`, for events that have a
+    /// connection id.
+    #[pyo3(get)]
+    source: Option<String>,
+    /// "upstream" (client to server) or "downstream" (server to client).
+    #[pyo3(get)]
+    direction: Option<&'static str>,
+    /// The local address for "bound", "bind_failed", "accept_paused",
+    /// "accept_resumed" and "incoming", the remote address for "connecting",
+    /// "connected" and "connect_failed", the redirect URL for "redirected",
+    /// the primary server for "primary_down" and "primary_up" and the
+    /// interface name, if known, for "capture_interface".
+    #[pyo3(get)]
+    local: Option<String>,
+    /// The client address for "incoming".
+    #[pyo3(get)]
+    peer: Option<String>,
+    /// The error message for "aborted", "connect_failed", "bind_failed",
+    /// "accept_paused", "primary_down", "failed" and "bad_checksum", and for
+    /// "out_of_band" if sending failed.
+    #[pyo3(get)]
+    error: Option<String>,
+    /// The number of bytes discarded for "shutdown_write" and "dropped".
+    #[pyo3(get)]
+    discard: Option<usize>,
+    /// The size of the message that was replaced for "rewritten".
+    #[pyo3(get)]
+    original: Option<usize>,
+    /// The round trip times to the client and to the server in seconds, for
+    /// "round_trip".
+    #[pyo3(get)]
+    rtt: Option<(f64, f64)>,
+    /// How long the window stayed closed in seconds, for "zero_window".
+    #[pyo3(get)]
+    duration: Option<f64>,
+    /// The position in the stream of the first retransmitted byte, for
+    /// "retransmitted", or of the first byte of the packet, for
+    /// "bad_checksum", or of the first byte of the segment, for "segment".
+    #[pyo3(get)]
+    offset: Option<u64>,
+    /// The text of the note for "note".
+    #[pyo3(get)]
+    note: Option<String>,
+    /// "raw", "blocks" or "messages" for "level".
+    #[pyo3(get)]
+    level: Option<&'static str>,
+    data: Option<Vec<u8>>,
+    previous: Option<Vec<u8>>,
+    messages: Option<Vec<Vec<u8>>>,
+}
+
+#[pymethods]
+impl Event {
+    /// The connection id as mapiproxy shows it, for example "fileA#10" if
+    /// the source name is "fileA", or "#10" if there is none.
+    #[getter]
+    fn conn_id(&self) -> Option<String> {
+        let id = ConnectionId::new(self.conn?);
+        Some(id.in_source(self.source.as_deref()).to_string())
+    }
+
+    /// The payload of "data" events, the sample of the left out data for
+    /// "dropped" events, the retransmitted bytes for "retransmitted" events
+    /// or the urgent byte for "out_of_band" events, as bytes.
+    #[getter]
+    fn data(&self) -> Option<&[u8]> {
+        self.data.as_deref()
+    }
+
+    /// The bytes as they were first seen for "retransmitted" events.
+    #[getter]
+    fn previous(&self) -> Option<&[u8]> {
+        self.previous.as_deref()
+    }
+
+    /// The messages forwarded instead for "rewritten" events, as a list of
+    /// bytes.
+    #[getter]
+    fn messages(&self) -> Option<Vec<&[u8]>> {
+        let messages = self.messages.as_ref()?;
+        Some(messages.iter().map(Vec::as_slice).collect())
+    }
+
+    fn __repr__(&self) -> String {
+        let mut repr = format!("<Event {}", self.kind);
+        if let Some(conn_id) = self.conn_id() {
+            repr += &format!(" {conn_id}");
+        }
+        if let Some(dir) = self.direction {
+            repr += &format!(" {dir}");
+        }
+        if let Some(data) = &self.data {
+            repr += &format!(" {} bytes", data.len());
+        }
+        repr + ">"
+    }
+}
+
+impl Event {
+    fn new(kind: &'static str, conn: Option<ConnectionId>) -> Self {
+        Event {
+            kind,
+            conn: conn.map(|id| id.as_usize()),
+            source: None,
+            direction: None,
+            local: None,
+            peer: None,
+            error: None,
+            discard: None,
+            original: None,
+            rtt: None,
+            duration: None,
+            offset: None,
+            note: None,
+            level: None,
+            data: None,
+            previous: None,
+            messages: None,
+        }
+    }
+
+    fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction_name(direction));
+        self
+    }
+
+    fn with_source(mut self, source: Option<&str>) -> Self {
+        if self.conn.is_some() {
+            self.source = source.map(str::to_string);
+        }
+        self
+    }
+}
+
+impl From<MapiEvent> for Event {
+    fn from(ev: MapiEvent) -> Self {
+        match ev {
+            MapiEvent::BoundPort(addr) => Event {
+                local: Some(addr.to_string()),
+                ..Event::new("bound", None)
+            },
+            MapiEvent::BindFailed { local, error } => Event {
+                local: Some(local.to_string()),
+                error: Some(error.to_string()),
+                ..Event::new("bind_failed", None)
+            },
+            MapiEvent::AcceptPaused { local, error } => Event {
+                local: Some(local.to_string()),
+                error: Some(error.to_string()),
+                ..Event::new("accept_paused", None)
+            },
+            MapiEvent::AcceptResumed { local } => Event {
+                local: Some(local.to_string()),
+                ..Event::new("accept_resumed", None)
+            },
+            MapiEvent::PrimaryDown { primary, error, .. } => Event {
+                local: Some(primary),
+                error: Some(error.to_string()),
+                ..Event::new("primary_down", None)
+            },
+            MapiEvent::PrimaryUp { primary } => Event {
+                local: Some(primary),
+                ..Event::new("primary_up", None)
+            },
+            MapiEvent::CaptureInterface { name, .. } => Event {
+                local: name,
+                ..Event::new("capture_interface", None)
+            },
+            MapiEvent::CaptureStatistics { .. } => Event::new("capture_statistics", None),
+            MapiEvent::Failed { error } => Event {
+                error: Some(error.to_string()),
+                ..Event::new("failed", None)
+            },
+            MapiEvent::Incoming { id, local, peer } => Event {
+                local: Some(local.to_string()),
+                peer: Some(peer.to_string()),
+                ..Event::new("incoming", Some(id))
+            },
+            MapiEvent::Connecting { id, remote } => Event {
+                local: Some(remote.to_string()),
+                ..Event::new("connecting", Some(id))
+            },
+            MapiEvent::Connected { id, peer } => Event {
+                local: Some(peer.to_string()),
+                ..Event::new("connected", Some(id))
+            },
+            MapiEvent::Joined { id } => Event::new("joined", Some(id)),
+            MapiEvent::Mirroring { id, .. } => Event::new("mirroring", Some(id)),
+            MapiEvent::Redirected { id, to } => Event {
+                local: Some(to),
+                ..Event::new("redirected", Some(id))
+            },
+            MapiEvent::OutOfBand { id, byte, error } => Event {
+                data: Some(vec![byte]),
+                error: error.map(|e| e.to_string()),
+                ..Event::new("out_of_band", Some(id)).with_direction(Direction::Upstream)
+            },
+            MapiEvent::RoundTrip { id, client, server } => Event {
+                rtt: Some((client.as_secs_f64(), server.as_secs_f64())),
+                ..Event::new("round_trip", Some(id))
+            },
+            MapiEvent::ConnectFailed {
+                id, remote, error, ..
+            } => Event {
+                local: Some(remote),
+                error: Some(error.to_string()),
+                ..Event::new("connect_failed", Some(id))
+            },
+            MapiEvent::End { id } => Event::new("end", Some(id)),
+            MapiEvent::StillOpen { id } => Event::new("still_open", Some(id)),
+            MapiEvent::Aborted { id, error } => Event {
+                error: Some(error.to_string()),
+                ..Event::new("aborted", Some(id))
+            },
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => Event {
+                data: Some(data.into_vec()),
+                ..Event::new("data", Some(id)).with_direction(direction)
+            },
+            MapiEvent::Rewritten {
+                id,
+                direction,
+                original,
+                messages,
+            } => Event {
+                original: Some(original),
+                messages: Some(messages),
+                ..Event::new("rewritten", Some(id)).with_direction(direction)
+            },
+            MapiEvent::Dropped {
+                id,
+                direction,
+                bytes,
+                sample,
+                ..
+            } => Event {
+                discard: Some(bytes),
+                data: Some(sample),
+                ..Event::new("dropped", Some(id)).with_direction(direction)
+            },
+            MapiEvent::Retransmitted {
+                id,
+                direction,
+                offset,
+                original,
+                retransmitted,
+            } => Event {
+                offset: Some(offset),
+                data: Some(retransmitted),
+                previous: Some(original),
+                ..Event::new("retransmitted", Some(id)).with_direction(direction)
+            },
+            MapiEvent::BadChecksum {
+                id,
+                direction,
+                offset,
+                layer,
+            } => Event {
+                offset: Some(offset),
+                error: Some(format!("bad {layer} checksum")),
+                ..Event::new("bad_checksum", Some(id)).with_direction(direction)
+            },
+            MapiEvent::KeepAlive { id, direction } => {
+                Event::new("keep_alive", Some(id)).with_direction(direction)
+            }
+            MapiEvent::ZeroWindow {
+                id,
+                direction,
+                duration,
+            } => Event {
+                duration: Some(duration.as_secs_f64()),
+                ..Event::new("zero_window", Some(id)).with_direction(direction)
+            },
+            MapiEvent::Segment {
+                id, direction, seq, ..
+            } => Event {
+                offset: Some(seq),
+                ..Event::new("segment", Some(id)).with_direction(direction)
+            },
+            MapiEvent::Held { id, direction } => {
+                Event::new("held", Some(id)).with_direction(direction)
+            }
+            MapiEvent::ShutdownRead { id, direction } => {
+                Event::new("shutdown_read", Some(id)).with_direction(direction)
+            }
+            MapiEvent::ShutdownWrite {
+                id,
+                direction,
+                discard,
+            } => Event {
+                discard: Some(discard),
+                ..Event::new("shutdown_write", Some(id)).with_direction(direction)
+            },
+            MapiEvent::Note {
+                id,
+                direction,
+                note,
+            } => {
+                let event = Event {
+                    note: Some(note),
+                    ..Event::new("note", Some(id))
+                };
+                match direction {
+                    Some(direction) => event.with_direction(direction),
+                    None => event,
+                }
+            }
+            MapiEvent::Level { id, level } => Event {
+                level: Some(level.name()),
+                ..Event::new("level", Some(id))
+            },
+        }
+    }
+}
+
+/// A complete MAPI block or message, or in raw mode, a chunk of data as it was
+/// received.
+#[pyclass(frozen, module = "mapiproxy")]
+struct Frame {
+    /// The connection id.
+    #[pyo3(get)]
+    conn: usize,
+    /// The source name passed to `read_frames`.
+    #[pyo3(get)]
+    source: Option<String>,
+    /// "upstream" (client to server) or "downstream" (server to client).
+    #[pyo3(get)]
+    direction: &'static str,
+    /// "raw", "block" or "message".
+    #[pyo3(get)]
+    kind: &'static str,
+    data: Vec<u8>,
+}
+
+#[pymethods]
+impl Frame {
+    /// The connection id as mapiproxy shows it, see `Event.conn_id`.
+    #[getter]
+    fn conn_id(&self) -> String {
+        let id = ConnectionId::new(self.conn);
+        id.in_source(self.source.as_deref()).to_string()
+    }
+
+    /// The content of the frame, without block headers unless kind is "raw".
+    #[getter]
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<Frame {} {} {}, {} bytes>",
+            self.conn_id(),
+            self.direction,
+            self.kind,
+            self.data.len()
+        )
+    }
+}
+
+/// Collects the blocks or messages of one direction of a connection.
+struct Framer {
+    analyzer: Analyzer,
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    fn new(unix_client: bool) -> Self {
+        Framer {
+            analyzer: Analyzer::new(unix_client),
+            buf: vec![],
+        }
+    }
+
+    /// Pass each frame completed by `data` to `frame`. Returns false if the
+    /// data violates the MAPI protocol.
+    fn feed(&mut self, level: Level, mut data: &[u8], mut frame: impl FnMut(Vec<u8>)) -> bool {
+        while let Some(chunk) = self.analyzer.split_chunk(&mut data) {
+            if self.analyzer.was_error() {
+                return false;
+            }
+            if !self.analyzer.was_body() {
+                continue;
+            }
+            self.buf.extend_from_slice(chunk);
+            let at_end = match level {
+                Level::Blocks => self.analyzer.was_block_boundary(),
+                _ => self.analyzer.was_message_boundary(),
+            };
+            if at_end {
+                frame(std::mem::take(&mut self.buf));
+            }
+        }
+        true
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Upstream => "upstream",
+        Direction::Downstream => "downstream",
+    }
+}
+
+fn parse_level(level: &str) -> PyResult<Level> {
+    match level.to_lowercase().as_str() {
+        "raw" => Ok(Level::Raw),
+        "blocks" => Ok(Level::Blocks),
+        "messages" => Ok(Level::Messages),
+        other => Err(PyValueError::new_err(format!(
+            "level {other:?}: must be 'raw', 'blocks' or 'messages'"
+        ))),
+    }
+}
+
+/// Read the pcap or pcap-ng file and pass the events to the handler.
+fn parse_file(path: &str, handler: impl FnMut(MapiEvent) -> std::io::Result<()>) -> PyResult<()> {
+    let file = File::open(path)?;
+    let mut tracker = Tracker::new(handler);
+    pcap::parse_pcap_file(file, &mut tracker)
+        .and_then(|()| tracker.finish())
+        .map_err(|e| PyRuntimeError::new_err(format!("{path}: {e}")))
+}
+
+/// Read a pcap or pcap-ng file and return the list of events observed in it.
+/// If a source name is given, the connection ids are shown after it, as
+/// with --source-name.
+#[pyfunction]
+#[pyo3(signature = (path, source = None))]
+fn read_events(path: &str, source: Option<&str>) -> PyResult<Vec<Event>> {
+    let mut events = vec![];
+    parse_file(path, |ev| {
+        events.push(Event::from(ev).with_source(source));
+        Ok(())
+    })?;
+    Ok(events)
+}
+
+/// Read a pcap or pcap-ng file and return the list of frames observed in it.
+/// Level can be "raw", "blocks" or "messages". Decoding of a stream stops at
+/// the first MAPI protocol error. The source name is as for `read_events`.
+#[pyfunction]
+#[pyo3(signature = (path, level = "messages", source = None))]
+fn read_frames(path: &str, level: &str, source: Option<&str>) -> PyResult<Vec<Frame>> {
+    let level = parse_level(level)?;
+    let kind = match level {
+        Level::Raw => "raw",
+        Level::Blocks => "block",
+        Level::Messages => "message",
+    };
+
+    let mut framers: HashMap<(ConnectionId, &str), Option<Framer>> = HashMap::new();
+    let mut frames = vec![];
+    parse_file(path, |ev| {
+        match ev {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let unix_client = peer.is_unix();
+                framers.insert((id, "upstream"), Some(Framer::new(unix_client)));
+                framers.insert((id, "downstream"), Some(Framer::new(false)));
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let direction = direction_name(direction);
+                let conn = id.as_usize();
+                let mut frame = |data| {
+                    frames.push(Frame {
+                        conn,
+                        source: source.map(str::to_string),
+                        direction,
+                        kind,
+                        data,
+                    })
+                };
+                if level == Level::Raw {
+                    frame(data.into_vec());
+                } else if let Some(slot) = framers.get_mut(&(id, direction)) {
+                    if let Some(framer) = slot {
+                        if !framer.feed(level, &data, frame) {
+                            *slot = None;
+                        }
+                    }
+                }
+            }
+            MapiEvent::End { id } | MapiEvent::Aborted { id, .. } => {
+                framers.remove(&(id, "upstream"));
+                framers.remove(&(id, "downstream"));
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(frames)
+}
+
+/// Analyze MonetDB network traffic captures.
+#[pymodule]
+#[pyo3(name = "mapiproxy")]
+fn mapiproxy_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_class::<Event>()?;
+    m.add_class::<Frame>()?;
+    m.add_function(wrap_pyfunction!(read_events, m)?)?;
+    m.add_function(wrap_pyfunction!(read_frames, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,442 @@
+//! What to do with the events of the proxy when rendering cannot keep up.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use anyhow::{bail, Result as AResult};
+use mapiproxy::{
+    mapi::{Analyzer, Handshake},
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Policy {
+    /// Wait until there is room, which stalls the forwarding
+    Block,
+    /// Leave out data events and report how much was left out
+    Drop,
+    /// Like Drop, but also keep the first few bytes of what was left out
+    Summarize,
+}
+
+pub fn parse_policy(setting: &str, value: &str) -> AResult<Policy> {
+    let policy = match value.to_lowercase().as_str() {
+        "block" => Policy::Block,
+        "drop" => Policy::Drop,
+        "summarize" => Policy::Summarize,
+        other => bail!("{setting}={other}: must be 'block', 'drop' or 'summarize'"),
+    };
+    Ok(policy)
+}
+
+/// The number of events the channel between the proxy and the rendering
+/// thread can hold.
+pub const CHANNEL_CAPACITY: usize = 500;
+
+/// Passes the events of the proxy to the rendering thread, applying the
+/// [Policy] when the channel is full.
+///
+/// Only [MapiEvent::Data] events are ever left out. To allow the renderer to
+/// pick up the MAPI framing after a gap, the sender tracks the framing of every
+/// connection itself and passes it along in the [MapiEvent::Dropped] event it
+/// sends before the next event of the same connection.
+pub struct EventSender {
+    channel: SyncSender<MapiEvent>,
+    policy: Policy,
+    connections: HashMap<ConnectionId, [Lane; 2]>,
+    /// Connections whose handshake may still change the framing
+    handshakes: HashMap<ConnectionId, Handshake>,
+    budget: Option<Arc<MemoryBudget>>,
+    backlog: Backlog,
+}
+
+struct Lane {
+    analyzer: Analyzer,
+    dropped: Option<Dropped>,
+}
+
+struct Dropped {
+    chunks: usize,
+    bytes: usize,
+    sample: Vec<u8>,
+}
+
+impl EventSender {
+    /// How many bytes of the left out data to keep when summarizing.
+    const SAMPLE_SIZE: usize = 64;
+
+    pub fn new(channel: SyncSender<MapiEvent>, policy: Policy) -> Self {
+        EventSender {
+            channel,
+            policy,
+            connections: HashMap::new(),
+            handshakes: HashMap::new(),
+            budget: None,
+            backlog: Backlog::default(),
+        }
+    }
+
+    /// The number of events sent that the renderer has not taken yet.
+    pub fn backlog(&self) -> Backlog {
+        self.backlog.clone()
+    }
+
+    /// Also treat the channel as full when the events in it and the data the
+    /// renderer is collecting would exceed the budget.
+    pub fn set_budget(&mut self, budget: Arc<MemoryBudget>) {
+        self.budget = Some(budget);
+    }
+
+    pub fn send(&mut self, event: MapiEvent) {
+        if self.policy == Policy::Block {
+            if let Some(budget) = &self.budget {
+                budget.wait_for_room(event_size(&event));
+            }
+            self.queue(event);
+            return;
+        }
+
+        match &event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let lanes = [Lane::new(peer.is_unix()), Lane::new(false)];
+                self.connections.insert(*id, lanes);
+                self.handshakes.insert(*id, Handshake::new(peer.is_unix()));
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let (id, direction) = (*id, *direction);
+                let size = data.len();
+                // report the gap before the framing moves past this data, and
+                // don't send the data at all while the gap is still unreported.
+                // If the data is over budget anyway, let the gap grow instead.
+                let charged = self.budget.as_ref().is_none_or(|b| b.try_charge(size));
+                let flushed = charged && self.flush(id, direction, false);
+                let Some(lanes) = self.connections.get_mut(&id) else {
+                    if let Some(budget) = self.budget.as_ref().filter(|_| !charged) {
+                        budget.charge(size);
+                    }
+                    self.queue(event);
+                    return;
+                };
+                let analyzer = &mut lanes[lane_index(direction)].analyzer;
+                let mut rest = &data[..];
+                while analyzer.split_chunk(&mut rest).is_some() {}
+                if let Some(handshake) = self.handshakes.get_mut(&id) {
+                    if let Some(protocol) = handshake.data(direction, data) {
+                        if protocol.wide_headers() {
+                            lanes.iter_mut().for_each(|l| l.analyzer.set_wide_headers());
+                        }
+                    }
+                    if handshake.is_done() {
+                        self.handshakes.remove(&id);
+                    }
+                }
+                if !flushed {
+                    if let Some(budget) = self.budget.as_ref().filter(|_| charged) {
+                        budget.release(size);
+                    }
+                    self.drop_data(event);
+                    return;
+                }
+                match self.try_queue(event) {
+                    Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+                    Err(TrySendError::Full(event)) => {
+                        if let Some(budget) = &self.budget {
+                            budget.release(size);
+                        }
+                        self.drop_data(event)
+                    }
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        if let Some(id) = event.connection_id() {
+            for direction in [Direction::Upstream, Direction::Downstream] {
+                self.flush(id, direction, true);
+            }
+            if matches!(event, MapiEvent::End { .. } | MapiEvent::Aborted { .. }) {
+                self.connections.remove(&id);
+                self.handshakes.remove(&id);
+            }
+        }
+        if let Some(budget) = &self.budget {
+            budget.charge(event_size(&event));
+        }
+        self.queue(event);
+    }
+
+    /// Send the event, counting it before the renderer can take it.
+    fn queue(&self, event: MapiEvent) {
+        self.backlog.0.fetch_add(1, Ordering::Relaxed);
+        if self.channel.send(event).is_err() {
+            self.backlog.taken();
+        }
+    }
+
+    fn try_queue(&self, event: MapiEvent) -> Result<(), TrySendError<MapiEvent>> {
+        self.backlog.0.fetch_add(1, Ordering::Relaxed);
+        let result = self.channel.try_send(event);
+        if result.is_err() {
+            self.backlog.taken();
+        }
+        result
+    }
+
+    /// Count the data event as left out.
+    fn drop_data(&mut self, event: MapiEvent) {
+        let MapiEvent::Data {
+            id,
+            direction,
+            data,
+        } = event
+        else {
+            unreachable!()
+        };
+        let Some(lanes) = self.connections.get_mut(&id) else {
+            return;
+        };
+        let dropped = lanes[lane_index(direction)]
+            .dropped
+            .get_or_insert_with(|| Dropped {
+                chunks: 0,
+                bytes: 0,
+                sample: vec![],
+            });
+        dropped.chunks += 1;
+        dropped.bytes += data.len();
+        if self.policy == Policy::Summarize && dropped.sample.len() < Self::SAMPLE_SIZE {
+            let n = data.len().min(Self::SAMPLE_SIZE - dropped.sample.len());
+            dropped.sample.extend_from_slice(&data[..n]);
+        }
+    }
+
+    /// Report the data that has been left out, if any. Returns false if that
+    /// was not possible without blocking.
+    fn flush(&mut self, id: ConnectionId, direction: Direction, block: bool) -> bool {
+        let Some(lanes) = self.connections.get_mut(&id) else {
+            return true;
+        };
+        let lane = &mut lanes[lane_index(direction)];
+        let Some(dropped) = lane.dropped.take() else {
+            return true;
+        };
+        let event = MapiEvent::Dropped {
+            id,
+            direction,
+            chunks: dropped.chunks,
+            bytes: dropped.bytes,
+            sample: dropped.sample,
+            analyzer: lane.analyzer.clone(),
+        };
+        // the sample is small, don't let it hold up the report
+        if let Some(budget) = &self.budget {
+            budget.charge(event_size(&event));
+        }
+        if block {
+            self.queue(event);
+            return true;
+        }
+        match self.try_queue(event) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => true,
+            Err(TrySendError::Full(MapiEvent::Dropped {
+                chunks,
+                bytes,
+                sample,
+                ..
+            })) => {
+                if let Some(budget) = &self.budget {
+                    budget.release(sample.len());
+                }
+                let lanes = self.connections.get_mut(&id).unwrap();
+                lanes[lane_index(direction)].dropped = Some(Dropped {
+                    chunks,
+                    bytes,
+                    sample,
+                });
+                false
+            }
+            Err(TrySendError::Full(_)) => unreachable!(),
+        }
+    }
+}
+
+/// Counts the events waiting to be rendered, to tell when rendering falls
+/// behind. The renderer calls [Backlog::taken] for every event it receives.
+#[derive(Debug, Clone, Default)]
+pub struct Backlog(Arc<AtomicUsize>);
+
+impl Backlog {
+    /// Condense raw dumps once the channel is half full.
+    const CONDENSE_AT: usize = CHANNEL_CAPACITY / 2;
+
+    /// Show everything in full again once no more than this many are left.
+    const RESTORE_AT: usize = CHANNEL_CAPACITY / 20;
+
+    pub fn taken(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether raw dumps should be condensed, given whether they are now, see
+    /// [mapi::State::set_condensed](mapiproxy::mapi::State::set_condensed).
+    /// The thresholds are far apart so it does not switch back and forth
+    /// all the time.
+    pub fn overloaded(&self, condensed: bool) -> bool {
+        let waiting = self.waiting();
+        if condensed {
+            waiting > Self::RESTORE_AT
+        } else {
+            waiting >= Self::CONDENSE_AT
+        }
+    }
+}
+
+/// Keeps track of the memory taken up by events waiting to be rendered and by
+/// the data the renderer has collected so far, see
+/// [mapi::State::buffered](mapiproxy::mapi::State::buffered).
+pub struct MemoryBudget {
+    cap: usize,
+    usage: Mutex<Usage>,
+    room: Condvar,
+}
+
+#[derive(Default)]
+struct Usage {
+    in_flight: usize,
+    buffered: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(cap: usize) -> Self {
+        MemoryBudget {
+            cap,
+            usage: Default::default(),
+            room: Condvar::new(),
+        }
+    }
+
+    /// Count `n` more bytes in flight, waiting until they fit. Anything fits if
+    /// nothing is in flight, otherwise a single large event would never be
+    /// sent.
+    fn wait_for_room(&self, n: usize) {
+        let mut usage = self.usage.lock().unwrap();
+        while n > 0 && usage.in_flight > 0 && usage.in_flight + usage.buffered + n > self.cap {
+            usage = self.room.wait(usage).unwrap();
+        }
+        usage.in_flight += n;
+    }
+
+    /// Like [MemoryBudget::wait_for_room] but return false instead of waiting.
+    fn try_charge(&self, n: usize) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.in_flight > 0 && usage.in_flight + usage.buffered + n > self.cap {
+            return false;
+        }
+        usage.in_flight += n;
+        true
+    }
+
+    /// Count `n` more bytes in flight whether they fit or not.
+    fn charge(&self, n: usize) {
+        self.usage.lock().unwrap().in_flight += n;
+    }
+
+    fn release(&self, n: usize) {
+        self.usage.lock().unwrap().in_flight -= n;
+        self.room.notify_all();
+    }
+
+    /// Count an event that is held back before being rendered, if it fits.
+    /// It is released again by [MemoryBudget::rendered].
+    pub fn try_hold(&self, event: &MapiEvent) -> bool {
+        self.try_charge(event_size(event))
+    }
+
+    /// Like [MemoryBudget::try_hold] but count the event whether it fits or
+    /// not.
+    pub fn hold(&self, event: &MapiEvent) {
+        self.charge(event_size(event))
+    }
+
+    /// To be called by the renderer after handling each event, with the number
+    /// of bytes it has collected at that point.
+    pub fn rendered(&self, event: &MapiEvent, buffered: usize) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.in_flight -= event_size(event);
+        usage.buffered = buffered;
+        drop(usage);
+        self.room.notify_all();
+    }
+}
+
+impl Lane {
+    fn new(unix_client: bool) -> Self {
+        Lane {
+            analyzer: Analyzer::new(unix_client),
+            dropped: None,
+        }
+    }
+}
+
+fn lane_index(direction: Direction) -> usize {
+    match direction {
+        Direction::Upstream => 0,
+        Direction::Downstream => 1,
+    }
+}
+
+/// The number of bytes of payload the event holds on to.
+fn event_size(event: &MapiEvent) -> usize {
+    match event {
+        MapiEvent::Data { data, .. } => data.len(),
+        MapiEvent::Rewritten { messages, .. } => messages.iter().map(Vec::len).sum(),
+        MapiEvent::Dropped { sample, .. } => sample.len(),
+        MapiEvent::Retransmitted {
+            original,
+            retransmitted,
+            ..
+        } => original.len() + retransmitted.len(),
+        _ => 0,
+    }
+}
+
+#[test]
+fn test_backlog_overloaded() {
+    let backlog = Backlog::default();
+    let set = |n| backlog.0.store(n, Ordering::Relaxed);
+
+    // not condensed yet: only switch when the channel is half full
+    set(Backlog::CONDENSE_AT - 1);
+    assert!(!backlog.overloaded(false));
+    set(Backlog::CONDENSE_AT);
+    assert!(backlog.overloaded(false));
+
+    // condensed: keep at it until the backlog has mostly drained
+    set(Backlog::CONDENSE_AT - 1);
+    assert!(backlog.overloaded(true));
+    set(Backlog::RESTORE_AT + 1);
+    assert!(backlog.overloaded(true));
+    set(Backlog::RESTORE_AT);
+    assert!(!backlog.overloaded(true));
+
+    set(1);
+    backlog.taken();
+    backlog.taken();
+    assert_eq!(backlog.waiting(), 0);
+}
@@ -0,0 +1,395 @@
+//! Capturing the packets of a network interface, for --live. On Linux an
+//! AF_PACKET socket hands over the Ethernet frames directly, so neither
+//! libpcap nor tcpdump is needed. The frames go straight into a [Tracker], as
+//! if they had been read from a pcap file.
+
+#[cfg(target_os = "linux")]
+use std::{
+    ffi::CString,
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use std::{
+    fmt, io,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result as AResult;
+#[cfg(target_os = "linux")]
+use anyhow::{bail, Context};
+#[cfg(target_os = "linux")]
+use etherparse::{LaxNetSlice, LaxSlicedPacket, TransportSlice};
+use mapiproxy::pcap::Tracker;
+#[cfg(target_os = "linux")]
+use mapiproxy::{pcap::PcapError, proxy::event::MapiEvent};
+
+/// How often to check whether Ctrl-C has been pressed while waiting for
+/// packets, in milliseconds.
+#[cfg(target_os = "linux")]
+const POLL_INTERVAL_MS: i32 = 200;
+
+/// Large enough for the 64 KiB packets segmentation offload produces.
+#[cfg(target_os = "linux")]
+const MAX_FRAME: usize = 256 * 1024;
+
+pub struct LiveCapture {
+    interface: String,
+    #[cfg(target_os = "linux")]
+    socket: OwnedFd,
+    filter: Option<CaptureFilter>,
+    stop: Arc<AtomicBool>,
+}
+
+/// A capture filter such as `port 50000 and host 10.1.2.3`. Only TCP packets
+/// that match every term are passed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureFilter(Vec<Term>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Term {
+    Port(u16),
+    Host(IpAddr),
+}
+
+impl LiveCapture {
+    /// Start capturing on the named interface. This needs root or the
+    /// CAP_NET_RAW capability.
+    #[cfg(target_os = "linux")]
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        // SAFETY: name is a valid NUL terminated string
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        // SAFETY: plain system call, the result is checked below
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol as i32,
+            )
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::PermissionDenied {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("{err}, this needs root or the CAP_NET_RAW capability"),
+                ));
+            }
+            return Err(err);
+        }
+        // SAFETY: fd is a socket we just created and nobody else owns
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: all zeroes is a valid sockaddr_ll
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = index as i32;
+        // SAFETY: addr is a sockaddr_ll of the size we pass
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(LiveCapture {
+            interface: interface.to_string(),
+            socket,
+            filter: None,
+            stop: Arc::default(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_interface: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "live capture is only supported on Linux",
+        ))
+    }
+
+    pub fn set_filter(&mut self, filter: Option<CaptureFilter>) {
+        self.filter = filter;
+    }
+
+    /// Returns a function that makes [Self::run] return, for the Ctrl-C
+    /// handler.
+    pub fn stopper(&self) -> Box<dyn Fn() + Send + Sync> {
+        let stop = self.stop.clone();
+        Box::new(move || stop.store(true, Ordering::Relaxed))
+    }
+
+    /// Hand the packets to the tracker until the capture is stopped. Emits a
+    /// [MapiEvent::CaptureInterface] before the first packet and a
+    /// [MapiEvent::CaptureStatistics] at the end, so dropped packets are
+    /// pointed out.
+    #[cfg(target_os = "linux")]
+    pub fn run(&mut self, tracker: &mut Tracker) -> AResult<()> {
+        tracker.emit(MapiEvent::CaptureInterface {
+            interface: 0,
+            name: Some(self.interface.clone()),
+            description: None,
+            os: None,
+            filter: self.filter.as_ref().map(CaptureFilter::to_string),
+        })?;
+
+        let mut buf = vec![0u8; MAX_FRAME];
+        while !self.stop.load(Ordering::Relaxed) {
+            if !self.wait_readable()? {
+                continue;
+            }
+            // SAFETY: all zeroes is a valid sockaddr_ll
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            // SAFETY: buf and addr are as large as we say they are
+            let n = unsafe {
+                libc::recvfrom(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_TRUNC,
+                    &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err)
+                    .with_context(|| format!("Could not capture on {}", self.interface));
+            }
+            let n = n as usize;
+            if n > buf.len() {
+                return Err(PcapError::TruncatedPacket.into());
+            }
+            match addr.sll_hatype {
+                // on loopback every packet is seen leaving and arriving
+                libc::ARPHRD_LOOPBACK if addr.sll_pkttype == libc::PACKET_OUTGOING => continue,
+                libc::ARPHRD_LOOPBACK | libc::ARPHRD_ETHER => {}
+                other => bail!(
+                    "{} has link type {other}, only Ethernet is supported",
+                    self.interface
+                ),
+            }
+            let frame = &buf[..n];
+            if !self.wanted(frame) {
+                continue;
+            }
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok();
+            tracker.set_time(now);
+            tracker.process_ethernet(frame)?;
+        }
+
+        let (received, dropped) = self.statistics()?;
+        tracker.emit(MapiEvent::CaptureStatistics {
+            interface: 0,
+            received: Some(received),
+            interface_dropped: None,
+            os_dropped: Some(dropped),
+        })?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn run(&mut self, _tracker: &mut Tracker) -> AResult<()> {
+        unreachable!("{}: rejected by open", self.interface)
+    }
+
+    /// Only unfragmented TCP packets that pass the filter are of interest.
+    /// Anything else is dropped here rather than in the tracker, which would
+    /// stop at the first packet it cannot handle.
+    #[cfg(target_os = "linux")]
+    fn wanted(&self, frame: &[u8]) -> bool {
+        let Ok(packet) = LaxSlicedPacket::from_ethernet(frame) else {
+            return false;
+        };
+        let Some(TransportSlice::Tcp(tcp)) = &packet.transport else {
+            return false;
+        };
+        let (src, dest) = match &packet.net {
+            Some(LaxNetSlice::Ipv4(ip)) if !ip.is_payload_fragmented() => {
+                let header = ip.header();
+                (
+                    header.source_addr().into(),
+                    header.destination_addr().into(),
+                )
+            }
+            Some(LaxNetSlice::Ipv6(ip)) if !ip.is_payload_fragmented() => {
+                let header = ip.header();
+                (
+                    header.source_addr().into(),
+                    header.destination_addr().into(),
+                )
+            }
+            _ => return false,
+        };
+        let ports = (tcp.source_port(), tcp.destination_port());
+        self.filter
+            .as_ref()
+            .is_none_or(|f| f.matches((src, dest), ports))
+    }
+
+    /// Wait at most [POLL_INTERVAL_MS] for a packet. Returns false if none
+    /// arrived.
+    #[cfg(target_os = "linux")]
+    fn wait_readable(&self) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: poll only writes to the pollfd we pass it
+        let ret = unsafe { libc::poll(&mut pollfd, 1, POLL_INTERVAL_MS) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(ret > 0)
+    }
+
+    /// The number of packets the kernel has received and dropped since the
+    /// capture started.
+    #[cfg(target_os = "linux")]
+    fn statistics(&self) -> io::Result<(u64, u64)> {
+        let mut stats = libc::tpacket_stats {
+            tp_packets: 0,
+            tp_drops: 0,
+        };
+        let mut len = mem::size_of::<libc::tpacket_stats>() as libc::socklen_t;
+        // SAFETY: stats is as large as we say it is
+        let ret = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((stats.tp_packets as u64, stats.tp_drops as u64))
+    }
+}
+
+impl CaptureFilter {
+    #[cfg(target_os = "linux")]
+    fn matches(&self, (src, dest): (IpAddr, IpAddr), (sport, dport): (u16, u16)) -> bool {
+        self.0.iter().all(|term| match *term {
+            Term::Port(port) => sport == port || dport == port,
+            Term::Host(host) => src == host || dest == host,
+        })
+    }
+}
+
+impl FromStr for CaptureFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut terms = vec![];
+        let mut words = s.split_whitespace();
+        loop {
+            let Some(keyword) = words.next() else {
+                let what = if terms.is_empty() {
+                    "empty filter"
+                } else {
+                    "nothing after 'and'"
+                };
+                return Err(what.to_string());
+            };
+            terms.push(parse_term(keyword, words.next())?);
+            match words.next() {
+                None => return Ok(CaptureFilter(terms)),
+                Some("and") => {}
+                Some(other) => return Err(format!("expected 'and', found '{other}'")),
+            }
+        }
+    }
+}
+
+fn parse_term(keyword: &str, value: Option<&str>) -> Result<Term, String> {
+    let Some(value) = value else {
+        return Err(format!("'{keyword}' needs a value"));
+    };
+    match keyword {
+        "port" => match value.parse() {
+            Ok(port) => Ok(Term::Port(port)),
+            Err(_) => Err(format!("invalid port '{value}'")),
+        },
+        "host" => match value.parse() {
+            Ok(host) => Ok(Term::Host(host)),
+            Err(_) => Err(format!("invalid IP address '{value}'")),
+        },
+        other => Err(format!("unknown term '{other}', must be 'port' or 'host'")),
+    }
+}
+
+impl fmt::Display for CaptureFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, term) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" and ")?;
+            }
+            match term {
+                Term::Port(port) => write!(f, "port {port}")?,
+                Term::Host(host) => write!(f, "host {host}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_capture_filter() {
+    let parse = |s: &str| s.parse::<CaptureFilter>().map(|f| f.to_string());
+
+    assert_eq!(parse("port 50000"), Ok("port 50000".to_string()));
+    assert_eq!(parse(" host  10.0.0.1 "), Ok("host 10.0.0.1".to_string()));
+    assert_eq!(
+        parse("port 50000 and host ::1"),
+        Ok("port 50000 and host ::1".to_string())
+    );
+
+    assert_eq!(parse(""), Err("empty filter".to_string()));
+    assert_eq!(
+        parse("port 50000 and"),
+        Err("nothing after 'and'".to_string())
+    );
+    assert_eq!(
+        parse("port 50000 or port 1"),
+        Err("expected 'and', found 'or'".to_string())
+    );
+    assert_eq!(parse("port"), Err("'port' needs a value".to_string()));
+    assert_eq!(parse("port 70000"), Err("invalid port '70000'".to_string()));
+    assert_eq!(
+        parse("host localhost"),
+        Err("invalid IP address 'localhost'".to_string())
+    );
+    assert_eq!(
+        parse("net 10.0.0.0/8"),
+        Err("unknown term 'net', must be 'port' or 'host'".to_string())
+    );
+}
@@ -0,0 +1,171 @@
+//! Where the renderer and the recorders get the time from. Normally that is
+//! the system clock, but when reading a pcap file it is the capture time of
+//! the packet being processed, see [Clock](crate::pcap::Clock), so that a
+//! capture is shown with the times at which it was recorded. Tests can use a
+//! [LogicalClock] which only moves when they say so.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// A source of the current time.
+pub trait TimeSource: Send {
+    /// The current time as time since the Unix epoch, or None if it is not
+    /// known, for example before the first packet of a capture.
+    fn now(&self) -> Option<Duration>;
+
+    /// Whether the time between two readings is the time that has passed.
+    /// Not so for the system clock, which can be set back or forward while
+    /// the program runs. Durations are then measured with [Instant] instead.
+    ///
+    /// [Instant]: std::time::Instant
+    fn is_steady(&self) -> bool {
+        true
+    }
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock;
+
+impl TimeSource for WallClock {
+    fn now(&self) -> Option<Duration> {
+        // SystemTime::now() panics on wasm32-unknown-unknown
+        if cfg!(all(target_family = "wasm", target_os = "unknown")) {
+            return None;
+        }
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+    }
+
+    fn is_steady(&self) -> bool {
+        false
+    }
+}
+
+/// A clock that stands still until it is moved. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct LogicalClock(Arc<Mutex<Duration>>);
+
+impl LogicalClock {
+    /// A clock showing `start`, as time since the Unix epoch.
+    pub fn new(start: Duration) -> Self {
+        LogicalClock(Arc::new(Mutex::new(start)))
+    }
+
+    pub fn set(&self, time: Duration) {
+        *self.0.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl TimeSource for LogicalClock {
+    fn now(&self) -> Option<Duration> {
+        Some(*self.0.lock().unwrap())
+    }
+}
+
+#[test]
+fn test_renderer_follows_clock() {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::render::{Renderer, TimeZone};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let out = Shared::default();
+    let clock = LogicalClock::new(Duration::from_secs(1_700_000_000));
+    let mut renderer = Renderer::new(false, out.clone());
+    renderer.set_clock(clock.clone());
+    renderer.set_time_zone(TimeZone::Utc);
+    renderer.message(None, None, "one").unwrap();
+    clock.advance(Duration::from_millis(100));
+    renderer.message(None, None, "two").unwrap();
+    clock.advance(Duration::from_secs(120));
+    renderer.message(None, None, "three").unwrap();
+    drop(renderer);
+
+    let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let expected = "\
+‣ TIME is 2023-11-14 22:13:20.000 UTC
+‣ one
+‣ two
+
+‣ TIME is 2023-11-14 22:15:20.100 UTC
+‣ three
+";
+    assert_eq!(text, expected);
+}
+
+#[test]
+fn test_unsteady_clock() {
+    use std::io;
+
+    use crate::render::{Renderer, TimeZone};
+
+    /// Like the system clock, it can be set forward
+    #[derive(Clone)]
+    struct Unsteady(LogicalClock);
+
+    impl TimeSource for Unsteady {
+        fn now(&self) -> Option<Duration> {
+            self.0.now()
+        }
+
+        fn is_steady(&self) -> bool {
+            false
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let out = Shared::default();
+    let clock = LogicalClock::new(Duration::from_secs(1_700_000_000));
+    let mut renderer = Renderer::new(false, out.clone());
+    renderer.set_clock(Unsteady(clock.clone()));
+    renderer.set_time_zone(TimeZone::Utc);
+    renderer.message(None, None, "one").unwrap();
+    // the clock is set forward but hardly any time passes
+    clock.advance(Duration::from_secs(120));
+    renderer.message(None, None, "two").unwrap();
+    drop(renderer);
+
+    let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let expected = "\
+‣ TIME is 2023-11-14 22:13:20.000 UTC
+‣ one
+‣ TIME is 2023-11-14 22:15:20.000 UTC
+‣ two
+";
+    assert_eq!(text, expected);
+}
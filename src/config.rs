@@ -0,0 +1,387 @@
+//! Default settings read from a TOML file and from `MAPIPROXY_*` environment
+//! variables. Settings given on the command line take precedence over the
+//! environment, which takes precedence over the file.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use serde::Deserialize;
+
+/// The contents of the configuration file, for example
+///
+/// ```toml
+/// listen = "50001"
+/// forward = "localhost:50000"
+/// level = "messages"
+/// color = "always"
+/// ```
+///
+/// Relative paths are taken relative to the directory holding the file.
+///
+/// Each setting can also be given as an environment variable, for example
+/// `MAPIPROXY_LISTEN`. The file itself can be chosen with `MAPIPROXY_CONFIG`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The file the settings were read from
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// The settings that were taken from the environment
+    #[serde(skip)]
+    from_env: Vec<&'static str>,
+    pub listen: Option<String>,
+    pub forward: Option<String>,
+    pub forward_standby: Option<String>,
+    pub mirror: Option<String>,
+    pub mirror_password: Option<String>,
+    pub resolve: Option<String>,
+    pub tos: Option<String>,
+    pub so_mark: Option<String>,
+    pub pcap: Option<PathBuf>,
+    pub live: Option<String>,
+    pub capture_filter: Option<String>,
+    pub pcap_strict: Option<bool>,
+    pub pcap_checksums: Option<bool>,
+    pub join_mid_stream: Option<bool>,
+    pub tcp_debug: Option<bool>,
+    pub per_connection: Option<bool>,
+    pub shift_time: Option<String>,
+    pub rebase_time: Option<String>,
+    pub level: Option<String>,
+    pub binary: Option<bool>,
+    pub events: Option<bool>,
+    pub deterministic: Option<bool>,
+    pub labels: Option<bool>,
+    pub label_regex: Option<String>,
+    pub source_name: Option<String>,
+    pub color: Option<String>,
+    pub color_theme: Option<String>,
+    pub color_depth: Option<String>,
+    pub time_zone: Option<String>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub brief: Option<String>,
+    pub hex_width: Option<String>,
+    pub glyphs: Option<String>,
+    pub time_separator: Option<String>,
+    pub time_announce: Option<String>,
+    pub frames: Option<String>,
+    pub prompts: Option<String>,
+    pub format: Option<String>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub connection: Option<String>,
+    pub direction: Option<String>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub port: Option<String>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub conn: Option<String>,
+    pub backend: Option<String>,
+    pub backlog: Option<u32>,
+    pub accept_rate: Option<String>,
+    pub status_interval: Option<u32>,
+    pub require_all_binds: Option<bool>,
+    pub force_bind: Option<bool>,
+    pub listen_all: Option<bool>,
+    pub unix_socket_dir: Option<PathBuf>,
+    pub unix_socket_template: Option<String>,
+    pub backpressure: Option<String>,
+    pub max_memory: Option<String>,
+    pub sample_bytes: Option<String>,
+    pub max_capture_bytes: Option<String>,
+    pub script: Option<PathBuf>,
+    pub har: Option<PathBuf>,
+    pub sqlite: Option<PathBuf>,
+    pub parquet: Option<PathBuf>,
+    pub tee: Option<PathBuf>,
+    pub verify: Option<PathBuf>,
+    pub write_pcap: Option<PathBuf>,
+    pub stats: Option<bool>,
+    pub annotate: Option<PathBuf>,
+    pub rewrite: Option<PathBuf>,
+    pub handshake_strip_hash: Option<String>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub handshake_reply_size: Option<String>,
+    pub handshake_language: Option<String>,
+    pub database: Option<String>,
+    pub control: Option<PathBuf>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub fragment: Option<String>,
+    pub notify: Option<String>,
+    pub webhook: Option<String>,
+}
+
+impl Config {
+    const KEYS: [&'static str; 72] = [
+        "listen",
+        "forward",
+        "forward_standby",
+        "mirror",
+        "mirror_password",
+        "resolve",
+        "tos",
+        "so_mark",
+        "pcap",
+        "live",
+        "capture_filter",
+        "pcap_strict",
+        "pcap_checksums",
+        "join_mid_stream",
+        "tcp_debug",
+        "per_connection",
+        "shift_time",
+        "rebase_time",
+        "level",
+        "binary",
+        "events",
+        "deterministic",
+        "labels",
+        "label_regex",
+        "source_name",
+        "color",
+        "color_theme",
+        "color_depth",
+        "time_zone",
+        "brief",
+        "hex_width",
+        "glyphs",
+        "time_separator",
+        "time_announce",
+        "frames",
+        "prompts",
+        "format",
+        "connection",
+        "direction",
+        "port",
+        "conn",
+        "backend",
+        "backlog",
+        "accept_rate",
+        "status_interval",
+        "require_all_binds",
+        "force_bind",
+        "listen_all",
+        "unix_socket_dir",
+        "unix_socket_template",
+        "backpressure",
+        "max_memory",
+        "sample_bytes",
+        "max_capture_bytes",
+        "script",
+        "har",
+        "sqlite",
+        "parquet",
+        "tee",
+        "verify",
+        "write_pcap",
+        "stats",
+        "annotate",
+        "rewrite",
+        "handshake_strip_hash",
+        "handshake_reply_size",
+        "handshake_language",
+        "database",
+        "control",
+        "fragment",
+        "notify",
+        "webhook",
+    ];
+
+    /// Read the given file, or if None, the file named by `MAPIPROXY_CONFIG`
+    /// or the default file if it exists. Then apply the environment variables.
+    pub fn load(path: Option<&Path>) -> AResult<Config> {
+        let env_path = env_setting("config")?.map(PathBuf::from);
+        let mut config = match path.or(env_path.as_deref()) {
+            Some(path) => Self::read(path)?,
+            None => match Self::default_path() {
+                Some(path) if path.exists() => Self::read(&path)?,
+                _ => Config::default(),
+            },
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Describe where the given setting came from, for use in error messages.
+    pub fn origin(&self, key: &str) -> String {
+        if self.from_env.contains(&key) {
+            format!("In environment variable {}", env_name(key))
+        } else {
+            format!("In config file {}", self.path.display())
+        }
+    }
+
+    fn apply_env(&mut self) -> AResult<()> {
+        for key in Self::KEYS {
+            let Some(value) = env_setting(key)? else {
+                continue;
+            };
+            match key {
+                "listen" => self.listen = Some(value),
+                "forward" => self.forward = Some(value),
+                "forward_standby" => self.forward_standby = Some(value),
+                "mirror" => self.mirror = Some(value),
+                "mirror_password" => self.mirror_password = Some(value),
+                "resolve" => self.resolve = Some(value),
+                "tos" => self.tos = Some(value),
+                "so_mark" => self.so_mark = Some(value),
+                "pcap" => self.pcap = Some(value.into()),
+                "live" => self.live = Some(value),
+                "capture_filter" => self.capture_filter = Some(value),
+                "pcap_strict" => self.pcap_strict = Some(parse_bool(key, &value)?),
+                "pcap_checksums" => self.pcap_checksums = Some(parse_bool(key, &value)?),
+                "join_mid_stream" => self.join_mid_stream = Some(parse_bool(key, &value)?),
+                "tcp_debug" => self.tcp_debug = Some(parse_bool(key, &value)?),
+                "per_connection" => self.per_connection = Some(parse_bool(key, &value)?),
+                "shift_time" => self.shift_time = Some(value),
+                "rebase_time" => self.rebase_time = Some(value),
+                "level" => self.level = Some(value),
+                "binary" => self.binary = Some(parse_bool(key, &value)?),
+                "events" => self.events = Some(parse_bool(key, &value)?),
+                "deterministic" => self.deterministic = Some(parse_bool(key, &value)?),
+                "labels" => self.labels = Some(parse_bool(key, &value)?),
+                "label_regex" => self.label_regex = Some(value),
+                "source_name" => self.source_name = Some(value),
+                "color" => self.color = Some(value),
+                "color_theme" => self.color_theme = Some(value),
+                "color_depth" => self.color_depth = Some(value),
+                "time_zone" => self.time_zone = Some(value),
+                "brief" => self.brief = Some(value),
+                "hex_width" => self.hex_width = Some(value),
+                "glyphs" => self.glyphs = Some(value),
+                "time_separator" => self.time_separator = Some(value),
+                "time_announce" => self.time_announce = Some(value),
+                "frames" => self.frames = Some(value),
+                "prompts" => self.prompts = Some(value),
+                "format" => self.format = Some(value),
+                "connection" => self.connection = Some(value),
+                "direction" => self.direction = Some(value),
+                "port" => self.port = Some(value),
+                "conn" => self.conn = Some(value),
+                "backend" => self.backend = Some(value),
+                "backlog" => self.backlog = Some(parse_number(key, &value)?),
+                "accept_rate" => self.accept_rate = Some(value),
+                "status_interval" => self.status_interval = Some(parse_number(key, &value)?),
+                "require_all_binds" => self.require_all_binds = Some(parse_bool(key, &value)?),
+                "force_bind" => self.force_bind = Some(parse_bool(key, &value)?),
+                "listen_all" => self.listen_all = Some(parse_bool(key, &value)?),
+                "unix_socket_dir" => self.unix_socket_dir = Some(value.into()),
+                "unix_socket_template" => self.unix_socket_template = Some(value),
+                "backpressure" => self.backpressure = Some(value),
+                "max_memory" => self.max_memory = Some(value),
+                "sample_bytes" => self.sample_bytes = Some(value),
+                "max_capture_bytes" => self.max_capture_bytes = Some(value),
+                "script" => self.script = Some(value.into()),
+                "har" => self.har = Some(value.into()),
+                "sqlite" => self.sqlite = Some(value.into()),
+                "parquet" => self.parquet = Some(value.into()),
+                "tee" => self.tee = Some(value.into()),
+                "verify" => self.verify = Some(value.into()),
+                "write_pcap" => self.write_pcap = Some(value.into()),
+                "stats" => self.stats = Some(parse_bool(key, &value)?),
+                "annotate" => self.annotate = Some(value.into()),
+                "rewrite" => self.rewrite = Some(value.into()),
+                "handshake_strip_hash" => self.handshake_strip_hash = Some(value),
+                "handshake_reply_size" => self.handshake_reply_size = Some(value),
+                "handshake_language" => self.handshake_language = Some(value),
+                "database" => self.database = Some(value),
+                "control" => self.control = Some(value.into()),
+                "fragment" => self.fragment = Some(value),
+                "notify" => self.notify = Some(value),
+                "webhook" => self.webhook = Some(value),
+                _ => unreachable!(),
+            }
+            self.from_env.push(key);
+        }
+        Ok(())
+    }
+
+    /// `$XDG_CONFIG_HOME/mapiproxy.toml`, or `~/.config/mapiproxy.toml`.
+    fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("mapiproxy.toml"))
+    }
+
+    fn read(path: &Path) -> AResult<Config> {
+        let context = || format!("Could not read config file {}", path.display());
+        let text = fs::read_to_string(path).with_context(context)?;
+        let mut config: Config = toml::from_str(&text).with_context(context)?;
+        config.path = path.to_owned();
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let paths = [
+            &mut config.pcap,
+            &mut config.script,
+            &mut config.rewrite,
+            &mut config.har,
+            &mut config.sqlite,
+            &mut config.parquet,
+            &mut config.tee,
+            &mut config.verify,
+            &mut config.write_pcap,
+            &mut config.annotate,
+            &mut config.control,
+            &mut config.unix_socket_dir,
+        ];
+        for p in paths.into_iter().flatten() {
+            if p.is_relative() && p.as_os_str() != "-" {
+                *p = dir.join(&*p);
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("MAPIPROXY_{}", key.to_uppercase())
+}
+
+/// The value of the environment variable for this setting, if set and not
+/// empty.
+fn env_setting(key: &str) -> AResult<Option<String>> {
+    let name = env_name(key);
+    match env::var(&name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => bail!("{name}: {e}"),
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> AResult<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => bail!("{}={value}: must be 'true' or 'false'", env_name(key)),
+    }
+}
+
+/// For settings that are usually a number but can also be a word, such as
+/// `brief = 5` and `brief = "full"`.
+fn number_or_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Number(i64),
+        Text(String),
+    }
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(n) => Some(n.to_string()),
+        Value::Text(s) => Some(s),
+    })
+}
+
+fn parse_number(key: &str, value: &str) -> AResult<u32> {
+    match value.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("{}={value}: must be a number", env_name(key)),
+    }
+}
@@ -0,0 +1,78 @@
+//! What it takes to behave on a Windows console. Elsewhere these are no-ops.
+
+use std::sync::Arc;
+
+/// Called when the console window is closed, see [on_close].
+pub type Trigger = Arc<dyn Fn() + Send + Sync>;
+
+/// Make the console stdout is connected to interpret the escape sequences
+/// used for colors. Returns false if stdout is a console that cannot do that,
+/// such as the console of Windows versions before Windows 10.
+#[cfg(windows)]
+pub fn enable_escape_sequences() -> bool {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+        STD_OUTPUT_HANDLE,
+    };
+
+    // SAFETY: these only take the handle and the mode we pass them
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        if GetConsoleMode(handle, &mut mode) == 0 {
+            // not a console, escape sequences pass through unchanged
+            return true;
+        }
+        mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0
+            || SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+#[cfg(not(windows))]
+pub fn enable_escape_sequences() -> bool {
+    true
+}
+
+/// Call `trigger` when the console window is closed, as the Ctrl-C handler
+/// would, and give it a few seconds to wrap up before Windows ends the
+/// process. Ctrl-C and Ctrl-Break are left to the Ctrl-C handler.
+#[cfg(windows)]
+pub fn on_close(trigger: Trigger) -> std::io::Result<()> {
+    use std::{sync::OnceLock, thread, time::Duration};
+    use windows_sys::Win32::{
+        Foundation::BOOL,
+        System::Console::{SetConsoleCtrlHandler, CTRL_CLOSE_EVENT},
+    };
+
+    static ON_CLOSE: OnceLock<Trigger> = OnceLock::new();
+
+    /// Windows ends the process this long after the window was closed anyway.
+    const GRACE: Duration = Duration::from_secs(5);
+
+    unsafe extern "system" fn handler(event: u32) -> BOOL {
+        if event != CTRL_CLOSE_EVENT {
+            return 0;
+        }
+        if let Some(trigger) = ON_CLOSE.get() {
+            trigger();
+        }
+        // Returning lets Windows end the process right away. The process
+        // exits by itself once the output files have been written.
+        thread::sleep(GRACE);
+        1
+    }
+
+    if ON_CLOSE.set(trigger).is_err() {
+        return Ok(());
+    }
+    // SAFETY: the handler only uses ON_CLOSE, which has been set
+    if unsafe { SetConsoleCtrlHandler(Some(handler), 1) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn on_close(_trigger: Trigger) -> std::io::Result<()> {
+    Ok(())
+}
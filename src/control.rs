@@ -0,0 +1,216 @@
+//! Implementation of --control and --step. The control socket accepts
+//! commands such as `interrupt 12`, which makes the proxy send an out-of-band
+//! byte to the server of connection #12. This makes it possible to test how
+//! the server handles interrupted queries without patching a client. With
+//! --step, the messages the clients send are held back until they are
+//! released from the keyboard or with the `step` command. The `note`
+//! command adds a note to the output, next to the traffic passing at that
+//! moment, and the `level` command changes how much detail is shown of a
+//! connection.
+
+use std::{io, path::Path, sync::mpsc, thread};
+
+use anyhow::Result as AResult;
+use mapiproxy::proxy::Control;
+#[cfg(unix)]
+use mapiproxy::{
+    proxy::event::{ConnectionId, Direction},
+    Level,
+};
+
+pub type Controller = Box<dyn Fn(Control) + Send + Sync>;
+
+/// Removes the control socket when dropped, see [listen].
+#[must_use]
+pub struct ControlSocket {
+    #[cfg(unix)]
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        mapiproxy::proxy::network::unbind_unix(&self.path);
+    }
+}
+
+#[cfg(unix)]
+type Reply = mpsc::Sender<io::Result<()>>;
+
+/// The byte `interrupt` sends if none is given.
+#[cfg(unix)]
+const DEFAULT_BYTE: u8 = 1;
+
+#[cfg(unix)]
+const USAGE: &str = "commands: 'interrupt ID [BYTE]', 'step [ID]', 'note ID [DIRECTION] TEXT', \
+                     'level ID LEVEL'";
+
+/// Start a thread that accepts connections on `path` and passes the commands
+/// it receives on to the proxy. The socket file is removed when the returned
+/// [ControlSocket] is dropped.
+#[cfg(unix)]
+pub fn listen(path: &Path, controller: Controller) -> AResult<ControlSocket> {
+    use std::{os::unix::net::UnixListener, sync::Arc};
+
+    use anyhow::Context;
+    use mapiproxy::proxy::network::bind_unix;
+
+    let listener = bind_unix(path, false, |p| UnixListener::bind(p))
+        .with_context(|| format!("Could not listen on control socket {}", path.display()))?;
+    let controller = Arc::new(controller);
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let Ok(conn) = conn else {
+                continue;
+            };
+            let controller = Arc::clone(&controller);
+            thread::spawn(move || serve(conn, &controller));
+        }
+    });
+    Ok(ControlSocket {
+        path: path.to_owned(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path, _controller: Controller) -> AResult<ControlSocket> {
+    anyhow::bail!("--control requires Unix domain sockets")
+}
+
+/// Handle the commands of one connection, one per line. Every command gets a
+/// one line reply saying whether it succeeded.
+#[cfg(unix)]
+fn serve(conn: std::os::unix::net::UnixStream, controller: &Controller) -> io::Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut out = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let (reply, outcome) = mpsc::channel();
+        let reply = match parse_command(&line?, reply) {
+            Ok(None) => continue,
+            Ok(Some(control)) => {
+                controller(control);
+                match outcome.recv() {
+                    Ok(Ok(())) => "ok".to_string(),
+                    Ok(Err(e)) => format!("error: {e}"),
+                    Err(_) => "error: the proxy has stopped".to_string(),
+                }
+            }
+            Err(e) => format!("error: {e}"),
+        };
+        writeln!(out, "{reply}")?;
+    }
+    Ok(())
+}
+
+/// Implementation of --step. Start a thread that releases the message that
+/// has been held longest each time Enter is pressed.
+pub fn step_on_enter(controller: Controller) {
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            if line.is_err() {
+                break;
+            }
+            let (reply, outcome) = mpsc::channel();
+            let reply = Some(reply);
+            controller(Control::Step { id: None, reply });
+            match outcome.recv() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("mapiproxy: --step: {e}"),
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Parse a line such as `interrupt #12 0x01`. Empty lines yield `None`.
+#[cfg(unix)]
+fn parse_command(line: &str, reply: Reply) -> Result<Option<Control>, String> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(None);
+    };
+    let reply = Some(reply);
+    let control = match command {
+        "interrupt" => {
+            let Some(id) = words.next() else {
+                return Err(format!("missing connection number, {USAGE}"));
+            };
+            let id = parse_id(id)?;
+            let byte = match words.next() {
+                Some(byte) => parse_byte(byte)?,
+                None => DEFAULT_BYTE,
+            };
+            Control::OutOfBand { id, byte, reply }
+        }
+        "step" => {
+            let id = words.next().map(parse_id).transpose()?;
+            Control::Step { id, reply }
+        }
+        "level" => {
+            let Some(id) = words.next() else {
+                return Err(format!("missing connection number, {USAGE}"));
+            };
+            let id = parse_id(id)?;
+            let level = match words.next() {
+                Some("raw") => Level::Raw,
+                Some("blocks") => Level::Blocks,
+                Some("messages") => Level::Messages,
+                Some(other) => return Err(format!("{other}: must be raw, blocks or messages")),
+                None => return Err(format!("missing level, {USAGE}")),
+            };
+            Control::SetLevel { id, level, reply }
+        }
+        "note" => {
+            let Some(id) = words.next() else {
+                return Err(format!("missing connection number, {USAGE}"));
+            };
+            let id = parse_id(id)?;
+            let mut words = words.peekable();
+            let direction = match words.peek() {
+                Some(&"upstream") => Some(Direction::Upstream),
+                Some(&"downstream") => Some(Direction::Downstream),
+                _ => None,
+            };
+            if direction.is_some() {
+                words.next();
+            }
+            let note = words.collect::<Vec<_>>().join(" ");
+            if note.is_empty() {
+                return Err(format!("missing note, {USAGE}"));
+            }
+            return Ok(Some(Control::Note {
+                id,
+                direction,
+                note,
+                reply,
+            }));
+        }
+        _ => return Err(format!("unknown command '{command}', {USAGE}")),
+    };
+    match words.next() {
+        None => Ok(Some(control)),
+        Some(extra) => Err(format!("unexpected '{extra}', {USAGE}")),
+    }
+}
+
+/// A connection number, with or without the `#` or the name given with
+/// --source-name.
+#[cfg(unix)]
+fn parse_id(word: &str) -> Result<ConnectionId, String> {
+    let number = word.rsplit_once('#').map_or(word, |(_, number)| number);
+    match number.parse() {
+        Ok(n) => Ok(ConnectionId::new(n)),
+        Err(_) => Err(format!("{word}: not a connection number")),
+    }
+}
+
+/// A byte value, decimal or hexadecimal with 0x.
+#[cfg(unix)]
+fn parse_byte(word: &str) -> Result<u8, String> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("{word}: must be a byte value such as 1 or 0x01"))
+}
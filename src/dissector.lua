@@ -0,0 +1,176 @@
+-- Wireshark dissector for the MonetDB MAPI protocol.
+--
+-- Generated by `mapiproxy export-dissector` from mapiproxy @VERSION@, which
+-- decodes MAPI the same way. Regenerate it rather than editing it.
+--
+-- To install, copy it to the personal Lua plugins folder shown under
+-- Help > About Wireshark > Folders, for example ~/.local/lib/wireshark/plugins/.
+
+local mapi = Proto("mapi", "MonetDB MAPI")
+
+-- Largest block payload with 2 byte headers and with the 8 byte headers of
+-- protocol 10. Anything larger means the traffic is not MAPI.
+local MAX_BLOCK = @MAX_BLOCK@
+local MAX_WIDE_BLOCK = @MAX_WIDE_BLOCK@
+
+local DEFAULT_PORT = @PORT@
+
+-- The kind of a message, by its first byte
+local REQUEST_KINDS = @REQUEST_KINDS@
+local DEFAULT_REQUEST_KIND = "@DEFAULT_REQUEST_KIND@"
+local RESPONSE_KINDS = @RESPONSE_KINDS@
+local DEFAULT_RESPONSE_KIND = "@DEFAULT_RESPONSE_KIND@"
+
+-- salt:server:version:hashes:..., for example
+-- abc:mserver:9:RIPEMD160,SHA512,PROT10:LIT:SHA512:
+local CHALLENGE = "^[^:]*:[^:]*:%d+:([^:]*):"
+-- byteorder:user:password:lang:database:PROT10:...
+local PROT10_LOGIN = "^[^:]*:[^:]*:[^:]*:[^:]*:[^:]*:PROT10:"
+
+local f_length = ProtoField.uint32("mapi.block.length", "Block length", base.DEC)
+local f_last = ProtoField.bool("mapi.block.last", "Last block of the message")
+local f_payload = ProtoField.bytes("mapi.block.payload", "Payload")
+local f_kind = ProtoField.string("mapi.kind", "Message kind")
+local f_text = ProtoField.string("mapi.text", "Text")
+mapi.fields = { f_length, f_last, f_payload, f_kind, f_text }
+
+local f_tcp_stream = Field.new("tcp.stream")
+
+-- What has been learned about each TCP stream on the first pass
+local streams = {}
+-- Kind of the message started by the block at frame:offset, or false if the
+-- block continues a message
+local block_kinds = {}
+
+local function stream_info(pinfo)
+    local id = f_tcp_stream().value
+    local info = streams[id]
+    if info == nil then
+        info = { server_port = nil, prot10_offered = false, wide_after = nil, in_message = {} }
+        streams[id] = info
+    end
+    return info
+end
+
+local function is_upstream(info, pinfo)
+    if info.server_port ~= nil then
+        return pinfo.dst_port == info.server_port
+    end
+    return pinfo.dst_port == DEFAULT_PORT
+end
+
+-- Work out the kind of the message starting with this text and follow the
+-- handshake, which may switch the stream to 8 byte headers.
+local function start_message(info, pinfo, text)
+    local hashes = text:match(CHALLENGE)
+    if hashes ~= nil and info.wide_after == nil then
+        info.server_port = pinfo.src_port
+        for hash in hashes:gmatch("[^,]+") do
+            if hash == "PROT10" then
+                info.prot10_offered = true
+            end
+        end
+        return "CHALLENGE"
+    end
+    if info.prot10_offered and info.wide_after == nil and text:match(PROT10_LOGIN) then
+        info.wide_after = pinfo.number
+    end
+    local first = text:byte(1)
+    if is_upstream(info, pinfo) then
+        return REQUEST_KINDS[first] or DEFAULT_REQUEST_KIND
+    end
+    return RESPONSE_KINDS[first] or DEFAULT_RESPONSE_KIND
+end
+
+local function add_block(tvb, pinfo, tree, info, offset, head, len, last)
+    local key = pinfo.number .. ":" .. offset
+    local payload = tvb(offset + head, len)
+    if not pinfo.visited then
+        local direction = pinfo.src_port .. ">" .. pinfo.dst_port
+        local kind = false
+        if not info.in_message[direction] then
+            kind = start_message(info, pinfo, payload:string())
+        end
+        info.in_message[direction] = not last
+        block_kinds[key] = kind
+    end
+    local kind = block_kinds[key]
+
+    local subtree = tree:add(mapi, tvb(offset, head + len))
+    subtree:add_le(f_length, tvb(offset, head), len)
+    subtree:add(f_last, last)
+    if len > 0 then
+        subtree:add(f_payload, payload)
+        subtree:add(f_text, payload)
+    end
+    if kind then
+        subtree:add(f_kind, kind)
+        local first_line = payload:string():match("^[^\n]*"):sub(1, 60)
+        pinfo.cols.info:append(" " .. kind .. " " .. first_line)
+        subtree:append_text(", " .. kind)
+    end
+    if not last then
+        subtree:append_text(", continued")
+    end
+end
+
+local function dissect(tvb, pinfo, tree)
+    local info = stream_info(pinfo)
+    local wide = info.wide_after ~= nil and pinfo.number > info.wide_after
+    local head = wide and 8 or 2
+    local max = wide and MAX_WIDE_BLOCK or MAX_BLOCK
+    local total = tvb:len()
+    local offset = 0
+
+    pinfo.cols.protocol = "MAPI"
+    pinfo.cols.info = ""
+    while offset < total do
+        if total - offset < head then
+            pinfo.desegment_offset = offset
+            pinfo.desegment_len = DESEGMENT_ONE_MORE_SEGMENT
+            return total
+        end
+        local header
+        if wide then
+            header = tvb(offset, 8):le_uint64():tonumber()
+        else
+            header = tvb(offset, 2):le_uint()
+        end
+        local len = math.floor(header / 2)
+        local last = header % 2 == 1
+        if len > max then
+            -- not MAPI, or we lost track
+            return offset
+        end
+        if total - offset < head + len then
+            pinfo.desegment_offset = offset
+            pinfo.desegment_len = head + len - (total - offset)
+            return total
+        end
+        add_block(tvb, pinfo, tree, info, offset, head, len, last)
+        offset = offset + head + len
+    end
+    return offset
+end
+
+mapi.dissector = dissect
+
+-- Recognize MAPI on other ports by the challenge the server sends first
+local function heuristic(tvb, pinfo, tree)
+    if tvb:len() < 2 then
+        return false
+    end
+    local len = math.floor(tvb(0, 2):le_uint() / 2)
+    if len == 0 or len > MAX_BLOCK or tvb:len() < 2 + len then
+        return false
+    end
+    if not tvb(2, len):string():match(CHALLENGE) then
+        return false
+    end
+    pinfo.conversation = mapi
+    dissect(tvb, pinfo, tree)
+    return true
+end
+
+mapi:register_heuristic("tcp", heuristic)
+DissectorTable.get("tcp.port"):add(DEFAULT_PORT, mapi)
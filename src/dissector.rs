@@ -0,0 +1,81 @@
+//! Implementation of `mapiproxy export-dissector`. It fills in the Lua
+//! template in dissector.lua with the limits and message kinds mapiproxy
+//! itself uses, so Wireshark decodes MAPI the same way.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use argsplitter::{ArgError, ArgSplitter};
+use mapiproxy::mapi::{Analyzer, MAX_BLOCK_SIZE};
+
+use crate::{
+    exchange::{Outcome, DEFAULT_REQUEST_KIND, REQUEST_KINDS},
+    USAGE, VERSION,
+};
+
+const TEMPLATE: &str = include_str!("dissector.lua");
+
+pub fn export_dissector(mut args: ArgSplitter) -> AResult<()> {
+    let mut output: Option<PathBuf> = None;
+    let mut port = 50000;
+
+    while let Some(flag) = args.flag()? {
+        match flag {
+            "-o" | "--output" => output = Some(args.param_os()?.into()),
+            "--port" => port = parse_port("--port", &args.param()?)?,
+            "--help" => {
+                println!("Mapiproxy version {VERSION}");
+                println!();
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => return Err(ArgError::unknown_flag(flag).into()),
+        }
+    }
+    args.no_more_stashed()?;
+
+    let lua = generate(port);
+    match output {
+        Some(path) => {
+            fs::write(&path, lua).with_context(|| format!("Could not write {}", path.display()))?
+        }
+        None => io::stdout().write_all(lua.as_bytes())?,
+    }
+    Ok(())
+}
+
+fn generate(port: u16) -> String {
+    let responses = Outcome::PREFIXES.map(|(b, outcome)| (b, outcome.name()));
+    TEMPLATE
+        .replace("@VERSION@", VERSION)
+        .replace("@PORT@", &port.to_string())
+        .replace("@MAX_BLOCK@", &MAX_BLOCK_SIZE.to_string())
+        .replace("@MAX_WIDE_BLOCK@", &Analyzer::MAX_WIDE_BLOCK.to_string())
+        .replace("@REQUEST_KINDS@", &lua_table(&REQUEST_KINDS))
+        .replace("@DEFAULT_REQUEST_KIND@", DEFAULT_REQUEST_KIND)
+        .replace("@RESPONSE_KINDS@", &lua_table(&responses))
+        .replace("@DEFAULT_RESPONSE_KIND@", Outcome::Ok.name())
+}
+
+/// A Lua table mapping bytes to names.
+fn lua_table(entries: &[(u8, &str)]) -> String {
+    let mut table = String::from("{");
+    for (byte, name) in entries {
+        let _ = write!(table, " [0x{byte:02x}] = \"{name}\",");
+    }
+    table.pop();
+    table.push_str(" }");
+    table
+}
+
+fn parse_port(setting: &str, value: &str) -> AResult<u16> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{setting}={value}: must be a port number"),
+    }
+}
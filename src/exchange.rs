@@ -0,0 +1,461 @@
+//! Splits the traffic on each connection into messages and pairs up the
+//! requests of the client with the responses of the server, for the logs
+//! written by --har and --sqlite.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    time::Duration,
+};
+
+use anyhow::Result as AResult;
+use mapiproxy::{
+    mapi::{Analyzer, Handshake},
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+};
+
+/// A log that is written while the events come in, such as [HarLog](crate::har::HarLog).
+pub trait Recorder {
+    /// Process an event that happened at `now`, the time since the Unix epoch.
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()>;
+
+    /// Show what has been collected so far, when asked for from the
+    /// keyboard. Returns false if there is nothing to show.
+    fn report(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Problems of the recorder itself that the user should hear about, such
+    /// as documents that could not be delivered, gathered since the last
+    /// call. They are shown between the traffic.
+    fn take_messages(&mut self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Wrap up, the connections that are still open are considered closed.
+    fn finish(self: Box<Self>, now: Duration) -> AResult<()>;
+}
+
+/// Something [Exchanges::process] has found out. Times are given as time
+/// since the Unix epoch.
+pub enum Observed {
+    /// A client has connected
+    Opened {
+        id: ConnectionId,
+        client: String,
+        time: Duration,
+    },
+    /// The connection to the server has been established
+    Connected { id: ConnectionId, server: String },
+    /// A new estimate of the round trip times, see [MapiEvent::RoundTrip]
+    RoundTrip { id: ConnectionId, rtt: RoundTrip },
+    /// A complete message has been seen
+    Message {
+        id: ConnectionId,
+        direction: Direction,
+        message: Message,
+    },
+    /// A request and its response, or None if the connection ended first.
+    /// `time` is when the response completed or the connection ended.
+    Exchange {
+        id: ConnectionId,
+        server: String,
+        request: Request,
+        response: Option<Message>,
+        time: Duration,
+    },
+    /// The connection has ended. `error` is set if it was aborted.
+    Closed {
+        id: ConnectionId,
+        time: Duration,
+        error: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// The start of the message, see [Exchanges::MAX_TEXT]
+    pub text: String,
+    pub size: usize,
+    /// When the first byte was seen
+    pub started: Duration,
+    /// When the last byte was seen
+    pub completed: Duration,
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub message: Message,
+    /// When the first byte of the response was seen
+    pub first_byte: Option<Duration>,
+}
+
+/// Round trip times between the point of capture and the client and the
+/// server.
+#[derive(Debug, Clone, Copy)]
+pub struct RoundTrip {
+    pub client: Duration,
+    pub server: Duration,
+}
+
+/// The kind of request a message from the client is, by its first byte.
+/// Anything else is [LOGIN](DEFAULT_REQUEST_KIND).
+pub const REQUEST_KINDS: [(u8, &str); 2] = [(b's', "QUERY"), (b'X', "COMMAND")];
+
+pub const DEFAULT_REQUEST_KIND: &str = "LOGIN";
+
+/// How a request turned out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Error,
+    Redirect,
+    NoResponse,
+}
+
+#[derive(Default)]
+pub struct Exchanges {
+    conns: HashMap<ConnectionId, Conversation>,
+}
+
+/// What is known about one connection.
+struct Conversation {
+    server: String,
+    upstream: Collector,
+    downstream: Collector,
+    /// Until the login is over, see [Handshake]
+    handshake: Option<Handshake>,
+    /// Requests that have been sent completely, oldest first
+    waiting: VecDeque<Request>,
+}
+
+/// Follows the MAPI framing of both directions of a connection, including a
+/// switch to the 8 byte headers of protocol 10, without keeping the data.
+pub struct Framing {
+    upstream: Analyzer,
+    downstream: Analyzer,
+    /// Until the login is over, see [Handshake]
+    handshake: Option<Handshake>,
+}
+
+/// Splits the data flowing in one direction into messages.
+struct Collector {
+    analyzer: Analyzer,
+    text: Vec<u8>,
+    size: usize,
+    started: Option<Duration>,
+}
+
+impl Exchanges {
+    /// Longer messages are cut off, the sizes are still correct.
+    pub const MAX_TEXT: usize = 64 * 1024;
+
+    /// Process an event that happened at `now`, appending what it reveals to
+    /// `observed`.
+    pub fn process(&mut self, event: &MapiEvent, now: Duration, observed: &mut Vec<Observed>) {
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let conv = Conversation {
+                    server: String::new(),
+                    upstream: Collector::new(peer.is_unix()),
+                    downstream: Collector::new(false),
+                    handshake: Some(Handshake::new(peer.is_unix())),
+                    waiting: VecDeque::new(),
+                };
+                self.conns.insert(*id, conv);
+                observed.push(Observed::Opened {
+                    id: *id,
+                    client: peer.to_string(),
+                    time: now,
+                });
+            }
+            MapiEvent::Connected { id, peer } => {
+                if let Some(conv) = self.conns.get_mut(id) {
+                    conv.server = peer.to_string();
+                    observed.push(Observed::Connected {
+                        id: *id,
+                        server: conv.server.clone(),
+                    });
+                }
+            }
+            MapiEvent::RoundTrip { id, client, server } => {
+                let rtt = RoundTrip {
+                    client: *client,
+                    server: *server,
+                };
+                observed.push(Observed::RoundTrip { id: *id, rtt });
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let Some(conv) = self.conns.get_mut(id) else {
+                    return;
+                };
+                let direction = *direction;
+                if direction == Direction::Downstream {
+                    if let Some(req) = conv.waiting.front_mut() {
+                        req.first_byte.get_or_insert(now);
+                    }
+                }
+                let collector = match direction {
+                    Direction::Upstream => &mut conv.upstream,
+                    Direction::Downstream => &mut conv.downstream,
+                };
+                let messages = collector.feed(data, now);
+                if let Some(handshake) = &mut conv.handshake {
+                    if let Some(protocol) = handshake.data(direction, data) {
+                        if protocol.wide_headers() {
+                            conv.upstream.analyzer.set_wide_headers();
+                            conv.downstream.analyzer.set_wide_headers();
+                        }
+                    }
+                    if handshake.is_done() {
+                        conv.handshake = None;
+                    }
+                }
+                for message in messages {
+                    observed.push(Observed::Message {
+                        id: *id,
+                        direction,
+                        message: message.clone(),
+                    });
+                    if direction == Direction::Upstream {
+                        conv.waiting.push_back(Request {
+                            message,
+                            first_byte: None,
+                        });
+                        continue;
+                    }
+                    // Messages the server sends on its own, such as the login
+                    // challenge, do not answer a request
+                    if let Some(request) = conv.waiting.pop_front() {
+                        observed.push(Observed::Exchange {
+                            id: *id,
+                            server: conv.server.clone(),
+                            request,
+                            response: Some(message),
+                            time: now,
+                        });
+                    }
+                }
+            }
+            MapiEvent::Dropped {
+                id,
+                direction,
+                analyzer,
+                ..
+            } => {
+                if let Some(conv) = self.conns.get_mut(id) {
+                    conv.handshake = None;
+                    let collector = match direction {
+                        Direction::Upstream => &mut conv.upstream,
+                        Direction::Downstream => &mut conv.downstream,
+                    };
+                    collector.skip(analyzer);
+                }
+            }
+            MapiEvent::End { id } | MapiEvent::StillOpen { id } => {
+                self.close(*id, now, None, observed)
+            }
+            MapiEvent::Aborted { id, error } => {
+                self.close(*id, now, Some(error.to_string()), observed)
+            }
+            _ => {}
+        }
+    }
+
+    /// Close the connections that are still open, for example because the
+    /// capture file ends.
+    pub fn finish(&mut self, now: Duration, observed: &mut Vec<Observed>) {
+        let mut ids: Vec<ConnectionId> = self.conns.keys().copied().collect();
+        ids.sort();
+        for id in ids {
+            self.close(id, now, None, observed);
+        }
+    }
+
+    fn close(
+        &mut self,
+        id: ConnectionId,
+        now: Duration,
+        error: Option<String>,
+        observed: &mut Vec<Observed>,
+    ) {
+        let Some(conv) = self.conns.remove(&id) else {
+            return;
+        };
+        for request in conv.waiting {
+            observed.push(Observed::Exchange {
+                id,
+                server: conv.server.clone(),
+                request,
+                response: None,
+                time: now,
+            });
+        }
+        observed.push(Observed::Closed {
+            id,
+            time: now,
+            error,
+        });
+    }
+}
+
+impl Message {
+    /// LOGIN, QUERY or COMMAND, assuming the client sent it.
+    pub fn request_kind(&self) -> &'static str {
+        let first = self.text.as_bytes().first();
+        REQUEST_KINDS
+            .iter()
+            .find(|(b, _)| Some(b) == first)
+            .map_or(DEFAULT_REQUEST_KIND, |(_, kind)| kind)
+    }
+}
+
+impl Request {
+    /// LOGIN, QUERY or COMMAND.
+    pub fn kind(&self) -> &'static str {
+        self.message.request_kind()
+    }
+}
+
+impl RoundTrip {
+    /// Split a latency seen at the point of capture into the time spent on
+    /// the network, from the client's point of view, and in the server.
+    pub fn split(&self, latency: Duration) -> (Duration, Duration) {
+        let server_side = self.server.min(latency);
+        (self.client + server_side, latency - server_side)
+    }
+}
+
+impl Outcome {
+    /// Responses starting with these bytes are not [Outcome::Ok].
+    pub const PREFIXES: [(u8, Outcome); 2] = [(b'!', Outcome::Error), (b'^', Outcome::Redirect)];
+
+    pub fn of(response: Option<&Message>) -> Outcome {
+        let Some(response) = response else {
+            return Outcome::NoResponse;
+        };
+        let first = response.text.as_bytes().first();
+        Outcome::PREFIXES
+            .iter()
+            .find(|(b, _)| Some(b) == first)
+            .map_or(Outcome::Ok, |(_, outcome)| *outcome)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Ok => "OK",
+            Outcome::Error => "ERROR",
+            Outcome::Redirect => "REDIRECT",
+            Outcome::NoResponse => "NO RESPONSE",
+        }
+    }
+}
+
+impl Framing {
+    pub fn new(unix_client: bool) -> Self {
+        Framing {
+            upstream: Analyzer::new(unix_client),
+            downstream: Analyzer::new(false),
+            handshake: Some(Handshake::new(unix_client)),
+        }
+    }
+
+    /// Returns the number of messages completed by this data, or None if the
+    /// data in this direction is not MAPI.
+    pub fn data(&mut self, direction: Direction, data: &[u8]) -> Option<usize> {
+        let analyzer = self.analyzer(direction);
+        let mut rest = data;
+        let mut messages = 0;
+        while analyzer.split_chunk(&mut rest).is_some() {
+            if analyzer.was_error() {
+                return None;
+            }
+            if analyzer.was_body() && analyzer.was_message_boundary() {
+                messages += 1;
+            }
+        }
+        if let Some(handshake) = &mut self.handshake {
+            if let Some(protocol) = handshake.data(direction, data) {
+                if protocol.wide_headers() {
+                    self.upstream.set_wide_headers();
+                    self.downstream.set_wide_headers();
+                }
+            }
+            if handshake.is_done() {
+                self.handshake = None;
+            }
+        }
+        Some(messages)
+    }
+
+    /// Data has been left out, continue from the framing state after it.
+    pub fn skip(&mut self, direction: Direction, analyzer: &Analyzer) {
+        *self.analyzer(direction) = analyzer.clone();
+        self.handshake = None;
+    }
+
+    /// Whether the data in this direction ended on a message boundary, see
+    /// [Analyzer::check_incomplete].
+    pub fn check_incomplete(&mut self, direction: Direction) -> Result<(), &'static str> {
+        self.analyzer(direction).check_incomplete()
+    }
+
+    fn analyzer(&mut self, direction: Direction) -> &mut Analyzer {
+        match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        }
+    }
+}
+
+impl Collector {
+    fn new(unix_client: bool) -> Self {
+        Collector {
+            analyzer: Analyzer::new(unix_client),
+            text: vec![],
+            size: 0,
+            started: None,
+        }
+    }
+
+    /// Returns the messages completed by this data.
+    fn feed(&mut self, mut data: &[u8], now: Duration) -> Vec<Message> {
+        let mut complete = vec![];
+        while let Some(chunk) = self.analyzer.split_chunk(&mut data) {
+            if self.analyzer.was_error() {
+                // not MAPI, give up on this direction
+                return complete;
+            }
+            self.started.get_or_insert(now);
+            if !self.analyzer.was_body() {
+                continue;
+            }
+            let room = Exchanges::MAX_TEXT.saturating_sub(self.text.len());
+            self.text.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            self.size += chunk.len();
+            if self.analyzer.was_message_boundary() {
+                complete.push(Message {
+                    text: String::from_utf8_lossy(&self.text).into_owned(),
+                    size: self.size,
+                    started: self.started.take().unwrap(),
+                    completed: now,
+                });
+                self.text.clear();
+                self.size = 0;
+            }
+        }
+        complete
+    }
+
+    /// Data has been left out, continue from the framing state after it.
+    fn skip(&mut self, analyzer: &Analyzer) {
+        self.analyzer = analyzer.clone();
+        self.text.clear();
+        self.size = 0;
+        self.started = None;
+    }
+}
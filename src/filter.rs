@@ -0,0 +1,120 @@
+//! Implementation of --connection, --direction and --port. In proxy mode the
+//! filter runs on the proxy thread, before the events are queued for the
+//! renderer, so connections that are left out take up neither room in the
+//! queue nor time to render.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result as AResult};
+use mapiproxy::proxy::{
+    event::{ConnectionId, Direction, MapiEvent},
+    network::Addr,
+};
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    connections: Option<HashSet<ConnectionId>>,
+    direction: Option<Direction>,
+    port: Option<u16>,
+    /// Connections that were let through when they came in
+    admitted: HashSet<ConnectionId>,
+}
+
+impl Filter {
+    /// Only let through the connections with these numbers.
+    pub fn set_connections(&mut self, ids: impl IntoIterator<Item = ConnectionId>) {
+        self.connections
+            .get_or_insert_with(HashSet::new)
+            .extend(ids);
+    }
+
+    /// Only let through the data flowing in this direction.
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = Some(direction);
+    }
+
+    /// Only let through connections from or to this TCP port.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = Some(port);
+    }
+
+    /// Whether the event should be passed on. Events that do not belong to a
+    /// connection, such as the proxy starting to listen, always are.
+    pub fn admit(&mut self, event: &MapiEvent) -> bool {
+        let (id, direction) = match event {
+            MapiEvent::Incoming { id, local, peer } => {
+                let wanted = self.connections.as_ref().is_none_or(|ids| ids.contains(id))
+                    && self
+                        .port
+                        .is_none_or(|port| has_port(local, port) || has_port(peer, port));
+                if wanted {
+                    self.admitted.insert(*id);
+                }
+                return wanted;
+            }
+            MapiEvent::End { id } | MapiEvent::StillOpen { id } | MapiEvent::Aborted { id, .. } => {
+                return self.admitted.remove(id);
+            }
+            MapiEvent::Connecting { id, .. }
+            | MapiEvent::Connected { id, .. }
+            | MapiEvent::Joined { id }
+            | MapiEvent::Redirected { id, .. }
+            | MapiEvent::OutOfBand { id, .. }
+            | MapiEvent::Note { id, .. }
+            | MapiEvent::Level { id, .. }
+            | MapiEvent::RoundTrip { id, .. }
+            | MapiEvent::ConnectFailed { id, .. } => (id, None),
+            MapiEvent::Data { id, direction, .. }
+            | MapiEvent::Rewritten { id, direction, .. }
+            | MapiEvent::Dropped { id, direction, .. }
+            | MapiEvent::Retransmitted { id, direction, .. }
+            | MapiEvent::Held { id, direction }
+            | MapiEvent::Segment { id, direction, .. }
+            | MapiEvent::BadChecksum { id, direction, .. }
+            | MapiEvent::KeepAlive { id, direction }
+            | MapiEvent::ZeroWindow { id, direction, .. }
+            | MapiEvent::ShutdownRead { id, direction }
+            | MapiEvent::ShutdownWrite { id, direction, .. } => (id, Some(*direction)),
+            _ => return true,
+        };
+        self.admitted.contains(id)
+            && match (self.direction, direction) {
+                (Some(wanted), Some(direction)) => wanted == direction,
+                _ => true,
+            }
+    }
+}
+
+fn has_port(addr: &Addr, port: u16) -> bool {
+    match addr {
+        Addr::Tcp(a) => a.port() == port,
+        Addr::Unix(_) => false,
+    }
+}
+
+/// A comma separated list of connection numbers, with or without the `#`.
+pub fn parse_connections(setting: &str, value: &str) -> AResult<Vec<ConnectionId>> {
+    let mut ids = vec![];
+    for part in value.split(',') {
+        match part.trim().trim_start_matches('#').parse() {
+            Ok(n) => ids.push(ConnectionId::new(n)),
+            Err(_) => bail!("{setting}={value}: must be a list of connection numbers"),
+        }
+    }
+    Ok(ids)
+}
+
+pub fn parse_direction(setting: &str, value: &str) -> AResult<Direction> {
+    match value {
+        "upstream" | "up" => Ok(Direction::Upstream),
+        "downstream" | "down" => Ok(Direction::Downstream),
+        _ => bail!("{setting}={value}: must be 'upstream' or 'downstream'"),
+    }
+}
+
+pub fn parse_port(setting: &str, value: &str) -> AResult<u16> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{setting}={value}: must be a port number"),
+    }
+}
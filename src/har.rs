@@ -0,0 +1,196 @@
+//! Implementation of --har. Writes the requests and responses on each
+//! connection as entries in the HTTP Archive format, so tools that understand
+//! HAR files can show database conversations on a timeline.
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result as AResult};
+use mapiproxy::{
+    proxy::event::{ConnectionId, MapiEvent},
+    render::civil_from_days,
+};
+
+use crate::{
+    exchange::{Exchanges, Message, Observed, Outcome, Recorder, Request},
+    VERSION,
+};
+
+pub struct HarLog {
+    out: BufWriter<File>,
+    entries: usize,
+    exchanges: Exchanges,
+    observed: Vec<Observed>,
+    /// See [HarLog::set_source_name]
+    source: Option<String>,
+}
+
+impl Recorder for HarLog {
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()> {
+        self.exchanges.process(event, now, &mut self.observed);
+        self.write_observed()
+    }
+
+    /// Log the requests that are still waiting and close the JSON document.
+    fn finish(mut self: Box<Self>, now: Duration) -> AResult<()> {
+        self.exchanges.finish(now, &mut self.observed);
+        self.write_observed()?;
+        self.out.write_all(b"\n]}}\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl HarLog {
+    pub fn create(path: &Path) -> AResult<HarLog> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create HAR file {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        write!(
+            out,
+            r#"{{"log":{{"version":"1.2","creator":{{"name":"mapiproxy","version":{}}},"entries":["#,
+            json(VERSION)
+        )?;
+        out.flush()?;
+        Ok(HarLog {
+            out,
+            entries: 0,
+            exchanges: Exchanges::default(),
+            observed: vec![],
+            source: None,
+        })
+    }
+
+    /// Write the connections as for example `fileA#10` rather than `#10`.
+    pub fn set_source_name(&mut self, name: Option<String>) {
+        self.source = name;
+    }
+
+    fn write_observed(&mut self) -> io::Result<()> {
+        let mut observed = std::mem::take(&mut self.observed);
+        for obs in observed.drain(..) {
+            // Messages the server sends on its own, such as the login
+            // challenge, are not logged
+            if let Observed::Exchange {
+                id,
+                server,
+                request,
+                response,
+                time,
+            } = obs
+            {
+                self.entry(id, &server, request, response, time)?;
+            }
+        }
+        self.observed = observed;
+        Ok(())
+    }
+
+    /// Write the entry for a request and its response, or for a request that
+    /// never got one.
+    fn entry(
+        &mut self,
+        id: ConnectionId,
+        server: &str,
+        req: Request,
+        resp: Option<Message>,
+        now: Duration,
+    ) -> io::Result<()> {
+        let method = req.kind();
+        let outcome = Outcome::of(resp.as_ref());
+        let status = match outcome {
+            Outcome::Ok => 200,
+            Outcome::Error => 500,
+            Outcome::Redirect => 302,
+            Outcome::NoResponse => 0,
+        };
+        let status_text = outcome.name();
+        let (resp_text, resp_size) = resp.map_or((String::new(), 0), |r| (r.text, r.size));
+        let redirect = match outcome {
+            Outcome::Redirect => resp_text[1..].trim_end(),
+            _ => "",
+        };
+        let sent = req.message.completed;
+        // saturating because capture times do not always go forward
+        let send = ms(sent.saturating_sub(req.message.started));
+        let wait = req.first_byte.map_or(0.0, |t| ms(t.saturating_sub(sent)));
+        let receive = req.first_byte.map_or(0.0, |t| ms(now.saturating_sub(t)));
+
+        let mut entry = String::new();
+        let _ = write!(
+            entry,
+            r#"{{"startedDateTime":{started},"time":{time},"connection":{id},"#,
+            id = json(&id.in_source(self.source.as_deref()).to_string()),
+            started = json(&iso8601(req.message.started)),
+            time = send + wait + receive,
+        );
+        let _ = write!(
+            entry,
+            r#""request":{{"method":"{method}","url":{url},"httpVersion":"MAPI","cookies":[],"headers":[],"queryString":[],"postData":{{"mimeType":"text/plain","text":{text}}},"headersSize":-1,"bodySize":{size}}},"#,
+            url = json(&format!("mapi://{server}/")),
+            text = json(&req.message.text),
+            size = req.message.size,
+        );
+        let _ = write!(
+            entry,
+            r#""response":{{"status":{status},"statusText":"{status_text}","httpVersion":"MAPI","cookies":[],"headers":[],"content":{{"size":{size},"mimeType":"text/plain","text":{text}}},"redirectURL":{redirect},"headersSize":-1,"bodySize":{size}}},"#,
+            text = json(&resp_text),
+            redirect = json(redirect),
+            size = resp_size,
+        );
+        let _ = write!(
+            entry,
+            r#""cache":{{}},"timings":{{"send":{send},"wait":{wait},"receive":{receive}}}}}"#
+        );
+
+        let sep = if self.entries == 0 { "\n" } else { ",\n" };
+        self.entries += 1;
+        self.out.write_all(sep.as_bytes())?;
+        self.out.write_all(entry.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    (d.as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0
+}
+
+/// Format the text as a JSON string literal.
+pub fn json(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format the time since the Unix epoch as an ISO 8601 UTC timestamp.
+pub fn iso8601(t: Duration) -> String {
+    let secs = t.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        t.subsec_millis()
+    )
+}
@@ -0,0 +1,675 @@
+//! Implementation of --format=json. Writes every event as a JSON object on a
+//! line of its own, for jq and other tools, instead of drawing it for people
+//! to read. The data is written in the frames of the mode: as it came in with
+//! -r, as blocks with -b and as whole messages with -m.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write as _},
+    io::{self, Write},
+    mem,
+};
+
+use mapiproxy::{
+    clock::{TimeSource, WallClock},
+    mapi::{Analyzer, Handshake},
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+    Level,
+};
+
+use crate::har::{iso8601, json};
+
+pub struct JsonLines {
+    out: Box<dyn Write + Send>,
+    level: Level,
+    events_only: bool,
+    deterministic: bool,
+    clock: Box<dyn TimeSource>,
+    conns: HashMap<ConnectionId, Framers>,
+    protocol_errors: usize,
+    /// Only these connections are written, see [JsonLines::set_shown_connections]
+    shown: Option<HashSet<ConnectionId>>,
+    /// Set while handling an event of a connection that is not written
+    muted: bool,
+    /// Written with every connection id, see [JsonLines::set_source_name]
+    source: Option<String>,
+}
+
+/// Splits the data of a connection into blocks or messages, following the
+/// handshake in case it switches to the 8 byte headers of protocol 10.
+struct Framers {
+    upstream: Option<Framer>,
+    downstream: Option<Framer>,
+    handshake: Option<Handshake>,
+}
+
+struct Framer {
+    analyzer: Analyzer,
+    buf: Vec<u8>,
+}
+
+/// One JSON object under construction.
+struct Line(String);
+
+impl JsonLines {
+    pub fn new(out: Box<dyn Write + Send>, level: Level) -> Self {
+        JsonLines {
+            out,
+            level,
+            events_only: false,
+            deterministic: false,
+            clock: Box::new(WallClock),
+            conns: HashMap::new(),
+            protocol_errors: 0,
+            shown: None,
+            muted: false,
+            source: None,
+        }
+    }
+
+    /// Only write the events, with the size of the data but not its contents.
+    pub fn set_events_only(&mut self, events_only: bool) {
+        self.events_only = events_only;
+    }
+
+    /// Leave out the times, as with the other outputs.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Take the time from `clock` instead of the system clock.
+    pub fn set_clock(&mut self, clock: impl TimeSource + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Add a `source` field with this name to every line that has a
+    /// connection id, as given with --source-name.
+    pub fn set_source_name(&mut self, name: Option<String>) {
+        self.source = name;
+    }
+
+    pub fn events_only(&self) -> bool {
+        self.events_only
+    }
+
+    /// Only write the lines of the connections with these numbers. The
+    /// others are still followed, like [State::set_shown_connections] does.
+    ///
+    /// [State::set_shown_connections]: mapiproxy::mapi::State::set_shown_connections
+    pub fn set_shown_connections(&mut self, ids: Option<HashSet<ConnectionId>>) {
+        self.shown = ids;
+    }
+
+    fn mute_unless_shown(&mut self, id: Option<ConnectionId>) {
+        self.muted = match (&self.shown, id) {
+            (Some(ids), Some(id)) => !ids.contains(&id),
+            _ => false,
+        };
+    }
+
+    /// Number of streams that turned out not to be MAPI.
+    pub fn protocol_errors(&self) -> usize {
+        self.protocol_errors
+    }
+
+    /// Start a line with the fields every line has.
+    fn line(&self, kind: &str, id: Option<ConnectionId>, direction: Option<Direction>) -> Line {
+        let mut line = Line(String::from("{"));
+        if !self.deterministic {
+            match self.clock.now() {
+                Some(t) => line.raw("time", json(&iso8601(t))),
+                None => line.raw("time", "null"),
+            }
+        }
+        line.str("kind", kind);
+        if let Some(id) = id {
+            line.raw("conn", id.as_usize());
+            line.opt_str("source", self.source.as_deref());
+        }
+        if let Some(direction) = direction {
+            line.str("direction", direction_name(direction));
+        }
+        line
+    }
+
+    fn write(&mut self, line: Line) -> io::Result<()> {
+        if self.muted {
+            return Ok(());
+        }
+        let mut text = line.0;
+        text.push_str("}\n");
+        self.out.write_all(text.as_bytes())?;
+        self.out.flush()
+    }
+
+    /// Write a message that is not about the traffic.
+    pub fn message(
+        &mut self,
+        id: Option<ConnectionId>,
+        direction: Option<Direction>,
+        message: impl Display,
+    ) -> io::Result<()> {
+        self.mute_unless_shown(id);
+        let mut line = self.line("info", id, direction);
+        line.str("text", &message.to_string());
+        self.write(line)
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> io::Result<()> {
+        self.mute_unless_shown(event.connection_id());
+        self.handle_event(event)
+    }
+
+    fn handle_event(&mut self, event: &MapiEvent) -> io::Result<()> {
+        use MapiEvent::*;
+        let line = match event {
+            BoundPort(addr) => {
+                let mut line = self.line("bound", None, None);
+                line.str("local", &addr.to_string());
+                line
+            }
+            BindFailed { local, error } => {
+                let mut line = self.line("bind_failed", None, None);
+                line.str("local", &local.to_string());
+                line.str("error", &error.to_string());
+                line
+            }
+            AcceptPaused { local, error } => {
+                let mut line = self.line("accept_paused", None, None);
+                line.str("local", &local.to_string());
+                line.str("error", &error.to_string());
+                line
+            }
+            AcceptResumed { local } => {
+                let mut line = self.line("accept_resumed", None, None);
+                line.str("local", &local.to_string());
+                line
+            }
+            PrimaryDown {
+                primary,
+                standby,
+                error,
+            } => {
+                let mut line = self.line("primary_down", None, None);
+                line.str("primary", primary);
+                line.str("standby", standby);
+                line.str("error", &error.to_string());
+                line
+            }
+            PrimaryUp { primary } => {
+                let mut line = self.line("primary_up", None, None);
+                line.str("primary", primary);
+                line
+            }
+            CaptureInterface {
+                interface,
+                name,
+                description,
+                os,
+                filter,
+            } => {
+                let mut line = self.line("capture_interface", None, None);
+                line.raw("interface", interface);
+                line.opt_str("name", name.as_deref());
+                line.opt_str("description", description.as_deref());
+                line.opt_str("os", os.as_deref());
+                line.opt_str("filter", filter.as_deref());
+                line
+            }
+            CaptureStatistics {
+                interface,
+                received,
+                interface_dropped,
+                os_dropped,
+            } => {
+                let mut line = self.line("capture_statistics", None, None);
+                line.raw("interface", interface);
+                line.opt_raw("received", *received);
+                line.opt_raw("interface_dropped", *interface_dropped);
+                line.opt_raw("os_dropped", *os_dropped);
+                line
+            }
+            Failed { error } => {
+                let mut line = self.line("failed", None, None);
+                line.str("error", &error.to_string());
+                line
+            }
+            Incoming { id, local, peer } => {
+                self.conns.insert(*id, Framers::new(peer.is_unix()));
+                let mut line = self.line("incoming", Some(*id), None);
+                line.str("local", &local.to_string());
+                line.str("peer", &peer.to_string());
+                line
+            }
+            Connecting { id, remote } => {
+                let mut line = self.line("connecting", Some(*id), None);
+                line.str("remote", &remote.to_string());
+                line
+            }
+            Connected { id, peer } => {
+                let mut line = self.line("connected", Some(*id), None);
+                line.str("peer", &peer.to_string());
+                line
+            }
+            Joined { id } => self.line("joined", Some(*id), None),
+            Mirroring { id, of } => {
+                let mut line = self.line("mirroring", Some(*id), None);
+                line.raw("of", of.as_usize());
+                line
+            }
+            Redirected { id, to } => {
+                let mut line = self.line("redirected", Some(*id), None);
+                line.str("to", to);
+                line
+            }
+            OutOfBand { id, byte, error } => {
+                let mut line = self.line("out_of_band", Some(*id), Some(Direction::Upstream));
+                line.raw("byte", byte);
+                if let Some(error) = error {
+                    line.str("error", &error.to_string());
+                }
+                line
+            }
+            Note {
+                id,
+                direction,
+                note,
+            } => {
+                let mut line = self.line("note", Some(*id), *direction);
+                line.str("note", note);
+                line
+            }
+            Level { id, level } => {
+                let mut line = self.line("level", Some(*id), None);
+                line.str("level", level.name());
+                line
+            }
+            RoundTrip { id, client, server } => {
+                let mut line = self.line("round_trip", Some(*id), None);
+                line.raw("client", client.as_secs_f64());
+                line.raw("server", server.as_secs_f64());
+                line
+            }
+            End { id } => {
+                self.conns.remove(id);
+                self.line("end", Some(*id), None)
+            }
+            StillOpen { id } => self.line("still_open", Some(*id), None),
+            Aborted { id, error } => {
+                self.conns.remove(id);
+                let mut line = self.line("aborted", Some(*id), None);
+                line.str("error", &error.to_string());
+                line
+            }
+            Data {
+                id,
+                direction,
+                data,
+            } => return self.data(*id, *direction, data),
+            Rewritten {
+                id,
+                direction,
+                original,
+                messages,
+            } => {
+                let mut line = self.line("rewritten", Some(*id), Some(*direction));
+                line.raw("original", original);
+                let payloads: Vec<String> = messages.iter().map(|m| payload(m)).collect();
+                line.raw("messages", format_args!("[{}]", payloads.join(",")));
+                line
+            }
+            Dropped {
+                id,
+                direction,
+                chunks,
+                bytes,
+                ..
+            } => {
+                // the blocks around the gap cannot be put together
+                if let Some(framers) = self.conns.get_mut(id) {
+                    *framers.get_mut(*direction) = None;
+                }
+                let mut line = self.line("dropped", Some(*id), Some(*direction));
+                line.raw("chunks", chunks);
+                line.raw("bytes", bytes);
+                line
+            }
+            Retransmitted {
+                id,
+                direction,
+                offset,
+                original,
+                retransmitted,
+            } => {
+                let mut line = self.line("retransmitted", Some(*id), Some(*direction));
+                line.raw("offset", offset);
+                line.raw("original", payload(original));
+                line.raw("retransmitted", payload(retransmitted));
+                line
+            }
+            BadChecksum {
+                id,
+                direction,
+                offset,
+                layer,
+            } => {
+                let mut line = self.line("bad_checksum", Some(*id), Some(*direction));
+                line.raw("offset", offset);
+                line.str("layer", layer);
+                line
+            }
+            KeepAlive { id, direction } => self.line("keep_alive", Some(*id), Some(*direction)),
+            ZeroWindow {
+                id,
+                direction,
+                duration,
+            } => {
+                let mut line = self.line("zero_window", Some(*id), Some(*direction));
+                line.raw("duration", duration.as_secs_f64());
+                line
+            }
+            Segment {
+                id,
+                direction,
+                flags,
+                seq,
+                ack,
+                window,
+                len,
+            } => {
+                let mut line = self.line("segment", Some(*id), Some(*direction));
+                line.str("flags", flags);
+                line.raw("seq", seq);
+                line.opt_raw("ack", *ack);
+                line.raw("window", window);
+                line.raw("len", len);
+                line
+            }
+            Held { id, direction } => self.line("held", Some(*id), Some(*direction)),
+            ShutdownRead { id, direction } => {
+                self.line("shutdown_read", Some(*id), Some(*direction))
+            }
+            ShutdownWrite {
+                id,
+                direction,
+                discard,
+            } => {
+                let mut line = self.line("shutdown_write", Some(*id), Some(*direction));
+                line.raw("discard", discard);
+                line
+            }
+            ConnectFailed {
+                id,
+                remote,
+                error,
+                immediately,
+            } => {
+                let mut line = self.line("connect_failed", Some(*id), None);
+                line.str("remote", remote);
+                line.str("error", &error.to_string());
+                line.raw("immediately", immediately);
+                line
+            }
+        };
+        self.write(line)
+    }
+
+    fn data(&mut self, id: ConnectionId, direction: Direction, data: &[u8]) -> io::Result<()> {
+        if self.events_only {
+            let mut line = self.line("data", Some(id), Some(direction));
+            line.raw("bytes", data.len());
+            return self.write(line);
+        }
+        let kind = match self.level {
+            Level::Raw => "data",
+            Level::Blocks => "block",
+            Level::Messages => "message",
+        };
+        // connections that were joined halfway have no framers, and streams
+        // that are not MAPI lose theirs
+        let level = self.level;
+        let framers = self.conns.get_mut(&id).filter(|_| level != Level::Raw);
+        let Some((framers, mut framer)) = framers.and_then(|f| {
+            let framer = f.get_mut(direction).take()?;
+            Some((f, framer))
+        }) else {
+            let mut line = self.line("data", Some(id), Some(direction));
+            line.raw("bytes", data.len());
+            line.raw("payload", payload(data));
+            return self.write(line);
+        };
+        let mut frames = vec![];
+        let broken = !framer.feed(level, data, |f| frames.push(f));
+        if !broken {
+            *framers.get_mut(direction) = Some(framer);
+        }
+        framers.follow_handshake(direction, data);
+        for frame in frames {
+            let mut line = self.line(kind, Some(id), Some(direction));
+            line.raw("bytes", frame.len());
+            line.raw("payload", payload(&frame));
+            self.write(line)?;
+        }
+        if broken {
+            self.protocol_errors += 1;
+            let mut line = self.line("protocol_error", Some(id), Some(direction));
+            line.raw("bytes", data.len());
+            line.raw("payload", payload(data));
+            self.write(line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Framers {
+    fn new(unix_client: bool) -> Self {
+        Framers {
+            upstream: Some(Framer::new(unix_client)),
+            downstream: Some(Framer::new(false)),
+            handshake: Some(Handshake::new(unix_client)),
+        }
+    }
+
+    fn get_mut(&mut self, direction: Direction) -> &mut Option<Framer> {
+        match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        }
+    }
+
+    fn follow_handshake(&mut self, direction: Direction, data: &[u8]) {
+        let Some(handshake) = &mut self.handshake else {
+            return;
+        };
+        if let Some(protocol) = handshake.data(direction, data) {
+            if protocol.wide_headers() {
+                for framer in [&mut self.upstream, &mut self.downstream]
+                    .into_iter()
+                    .flatten()
+                {
+                    framer.analyzer.set_wide_headers();
+                }
+            }
+        }
+        if handshake.is_done() {
+            self.handshake = None;
+        }
+    }
+}
+
+impl Framer {
+    fn new(unix_client: bool) -> Self {
+        Framer {
+            analyzer: Analyzer::new(unix_client),
+            buf: vec![],
+        }
+    }
+
+    /// Pass each frame completed by `data` to `frame`. Returns false if the
+    /// data violates the MAPI protocol.
+    fn feed(&mut self, level: Level, mut data: &[u8], mut frame: impl FnMut(Vec<u8>)) -> bool {
+        while let Some(chunk) = self.analyzer.split_chunk(&mut data) {
+            if self.analyzer.was_error() {
+                return false;
+            }
+            if !self.analyzer.was_body() {
+                continue;
+            }
+            self.buf.extend_from_slice(chunk);
+            let at_end = match level {
+                Level::Blocks => self.analyzer.was_block_boundary(),
+                _ => self.analyzer.was_message_boundary(),
+            };
+            if at_end {
+                frame(mem::take(&mut self.buf));
+            }
+        }
+        true
+    }
+}
+
+impl Line {
+    fn raw(&mut self, key: &str, value: impl Display) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        let _ = write!(self.0, "\"{key}\":{value}");
+    }
+
+    fn str(&mut self, key: &str, value: &str) {
+        self.raw(key, json(value));
+    }
+
+    fn opt_str(&mut self, key: &str, value: Option<&str>) {
+        if let Some(value) = value {
+            self.str(key, value);
+        }
+    }
+
+    fn opt_raw(&mut self, key: &str, value: Option<impl Display>) {
+        if let Some(value) = value {
+            self.raw(key, value);
+        }
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Upstream => "upstream",
+        Direction::Downstream => "downstream",
+    }
+}
+
+/// The bytes as `{"text":...}` if they are valid UTF-8, otherwise as
+/// `{"base64":...}`.
+fn payload(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) => format!("{{\"text\":{}}}", json(text)),
+        Err(_) => format!("{{\"base64\":\"{}\"}}", base64(data)),
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foob"), "Zm9vYg==");
+    assert_eq!(base64(&[0xff, 0xfe, 0xfd]), "//79");
+}
+
+#[test]
+fn test_json_lines() {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
+
+    use mapiproxy::proxy::network::Addr;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let run = |level| {
+        let out = Shared::default();
+        let mut json = JsonLines::new(Box::new(out.clone()), level);
+        json.set_deterministic(true);
+        let id = ConnectionId::new(10);
+        let addr = |port| Addr::from(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        let upstream = |data: &[u8]| MapiEvent::Data {
+            id,
+            direction: Direction::Upstream,
+            data: data.into(),
+        };
+        let events = [
+            MapiEvent::Incoming {
+                id,
+                local: addr(50000),
+                peer: addr(40000),
+            },
+            // one message in two blocks, the second one split across reads
+            upstream(b"\x06\x00say"),
+            upstream(b"\x0f\x00 \"hi\""),
+            upstream(b"\t\n"),
+            // a message that is not valid UTF-8
+            upstream(b"\x05\x00\xff\xfe"),
+        ];
+        for event in &events {
+            json.handle(event).unwrap();
+        }
+        drop(json);
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        text
+    };
+
+    let incoming =
+        r#"{"kind":"incoming","conn":10,"local":"127.0.0.1:50000","peer":"127.0.0.1:40000"}"#;
+    let expected = format!(
+        r#"{incoming}
+{{"kind":"message","conn":10,"direction":"upstream","bytes":10,"payload":{{"text":"say \"hi\"\t\n"}}}}
+{{"kind":"message","conn":10,"direction":"upstream","bytes":2,"payload":{{"base64":"//4="}}}}
+"#
+    );
+    assert_eq!(run(Level::Messages), expected);
+
+    let expected = format!(
+        r#"{incoming}
+{{"kind":"block","conn":10,"direction":"upstream","bytes":3,"payload":{{"text":"say"}}}}
+{{"kind":"block","conn":10,"direction":"upstream","bytes":7,"payload":{{"text":" \"hi\"\t\n"}}}}
+{{"kind":"block","conn":10,"direction":"upstream","bytes":2,"payload":{{"base64":"//4="}}}}
+"#
+    );
+    assert_eq!(run(Level::Blocks), expected);
+}
@@ -0,0 +1,151 @@
+//! Keyboard controls for when the proxy runs in a terminal. Single
+//! keystrokes, without Enter, pause the output, switch brief mode on and off,
+//! show the statistics or stop the proxy, so there is no need to restart it
+//! and drop all client connections just to change what is shown.
+
+use std::{
+    io::{self, Read},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::console::Trigger;
+
+/// What a keystroke asks the main loop to do. Quitting is handled by the
+/// thread reading the keyboard itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// `p`, stop or resume showing the traffic
+    Pause,
+    /// `b`, switch between brief mode and showing frames in full
+    Brief,
+    /// `s`, show the statistics collected so far
+    Stats,
+}
+
+pub const HELP: &str = "keys: p pause, b brief, s stats, q quit";
+
+pub struct Keyboard {
+    keys: mpsc::Receiver<Key>,
+}
+
+impl Keyboard {
+    /// How often the main loop should look for keystrokes while no events
+    /// come in.
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Start reading keystrokes if both stdin and stdout are terminals. `q`
+    /// calls `quit`, which should stop the proxy as Ctrl-C would. The
+    /// terminal settings are put back by [restore_terminal].
+    pub fn start(quit: Trigger) -> Option<Keyboard> {
+        if !is_terminal::is_terminal(io::stdin()) || !is_terminal::is_terminal(io::stdout()) {
+            return None;
+        }
+        single_keystrokes().ok()?;
+        let (send, keys) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    break;
+                };
+                let key = match byte.to_ascii_lowercase() {
+                    b'p' => Key::Pause,
+                    b'b' => Key::Brief,
+                    b's' => Key::Stats,
+                    b'q' => {
+                        quit();
+                        break;
+                    }
+                    _ => continue,
+                };
+                if send.send(key).is_err() {
+                    break;
+                }
+            }
+        });
+        Some(Keyboard { keys })
+    }
+
+    /// The keys pressed since the last call.
+    pub fn pressed(&self) -> impl Iterator<Item = Key> + '_ {
+        self.keys.try_iter()
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Terminal settings from before [single_keystrokes], to be put back.
+#[cfg(unix)]
+static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+#[cfg(windows)]
+static SAVED: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Make keystrokes available at once instead of after Enter, and stop
+/// echoing them. Ctrl-C keeps working.
+#[cfg(unix)]
+fn single_keystrokes() -> io::Result<()> {
+    let fd = libc::STDIN_FILENO;
+    // SAFETY: termios is plain data, which tcgetattr fills in
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let saved = termios;
+    termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+    termios.c_cc[libc::VMIN] = 1;
+    termios.c_cc[libc::VTIME] = 0;
+    // SAFETY: tcsetattr only reads the termios we pass it
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    *SAVED.lock().unwrap() = Some(saved);
+    Ok(())
+}
+
+#[cfg(windows)]
+fn single_keystrokes() -> io::Result<()> {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT,
+        STD_INPUT_HANDLE,
+    };
+
+    // SAFETY: these only take the handle and the mode we pass them
+    unsafe {
+        let handle = GetStdHandle(STD_INPUT_HANDLE);
+        let mut mode = 0;
+        if GetConsoleMode(handle, &mut mode) == 0
+            || SetConsoleMode(handle, mode & !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT)) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        *SAVED.lock().unwrap() = Some(mode);
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn single_keystrokes() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Put back the terminal settings [Keyboard::start] changed, if any. Also
+/// called before exiting in a hurry, for example on a second Ctrl-C.
+pub fn restore_terminal() {
+    #[cfg(unix)]
+    if let Some(saved) = SAVED.lock().unwrap().take() {
+        // SAFETY: tcsetattr only reads the termios we pass it
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+    }
+    #[cfg(windows)]
+    if let Some(mode) = SAVED.lock().unwrap().take() {
+        use windows_sys::Win32::System::Console::{GetStdHandle, SetConsoleMode, STD_INPUT_HANDLE};
+        // SAFETY: these only take the handle and the mode we pass them
+        unsafe { SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), mode) };
+    }
+}
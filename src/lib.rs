@@ -0,0 +1,42 @@
+//! The building blocks of mapiproxy: the [proxy] which forwards connections,
+//! the [pcap] reader which reconstructs them from captured network traffic,
+//! and the [mapi] module which analyzes the MAPI protocol and hands the result
+//! to the [render] module. The `mapiproxy` binary glues these together.
+//!
+//! Each of them has an error type of its own, re-exported here so callers can
+//! tell a bad capture file ([PcapError]) from a problem with the sockets
+//! ([ProxyError]) or with the MAPI traffic itself ([ProtocolError]).
+
+pub mod clock;
+pub mod mapi;
+pub mod pcap;
+pub mod proxy;
+pub mod render;
+#[cfg(feature = "script")]
+pub mod script;
+
+pub use mapi::ProtocolError;
+pub use pcap::PcapError;
+pub use proxy::Error as ProxyError;
+
+/// The layer of the MAPI protocol at which the traffic is rendered.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Level {
+    /// Bytes as they are read from the socket
+    Raw,
+    /// Individual MAPI blocks, without the block headers
+    Blocks,
+    /// Whole MAPI messages, without the block headers
+    Messages,
+}
+
+impl Level {
+    /// The name used on the command line and in the control socket.
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Raw => "raw",
+            Level::Blocks => "blocks",
+            Level::Messages => "messages",
+        }
+    }
+}
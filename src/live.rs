@@ -0,0 +1,105 @@
+//! Reading pcap data that is still being written, as in
+//! `tcpdump -w - | mapiproxy --pcap -`. Reads wait for more data for as long as
+//! the writer keeps the pipe or FIFO open, and Ctrl-C ends the input cleanly so
+//! the connections seen so far are still wrapped up.
+
+#[cfg(unix)]
+use std::os::{fd::AsRawFd, unix::fs::FileTypeExt};
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// How often to check whether Ctrl-C has been pressed while waiting for data,
+/// in milliseconds.
+#[cfg(unix)]
+const POLL_INTERVAL_MS: i32 = 200;
+
+pub struct LiveInput {
+    file: File,
+    stop: Arc<AtomicBool>,
+}
+
+impl LiveInput {
+    /// Wrap `file` if it is a pipe or FIFO. Anything else is handed back
+    /// because it can be read to the end straight away.
+    #[cfg(unix)]
+    pub fn new(file: File) -> Result<Self, File> {
+        match file.metadata() {
+            Ok(meta) if meta.file_type().is_fifo() => Ok(LiveInput {
+                file,
+                stop: Arc::default(),
+            }),
+            _ => Err(file),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(file: File) -> Result<Self, File> {
+        Err(file)
+    }
+
+    /// Returns a function that makes the input end at the next read, for the
+    /// Ctrl-C handler.
+    pub fn stopper(&self) -> Box<dyn Fn() + Send + Sync> {
+        let stop = self.stop.clone();
+        Box::new(move || stop.store(true, Ordering::Relaxed))
+    }
+
+    /// Returns a flag that is set once the input has been stopped.
+    pub fn stopped(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Wait at most [POLL_INTERVAL_MS] for the pipe to become readable.
+    /// Returns false if it did not.
+    #[cfg(unix)]
+    fn wait_readable(&self) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: poll only writes to the pollfd we pass it
+        let ret = unsafe { libc::poll(&mut pollfd, 1, POLL_INTERVAL_MS) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(ret > 0)
+    }
+
+    #[cfg(not(unix))]
+    fn wait_readable(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl Read for LiveInput {
+    /// Wait until there is data, the writer has closed the pipe or the input
+    /// has been stopped. A pipe that has nothing to read right now, or a read
+    /// interrupted by a signal, is not the end of the input.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+            if !self.wait_readable()? {
+                continue;
+            }
+            match self.file.read(buf) {
+                Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) => {
+                    continue
+                }
+                result => return result,
+            }
+        }
+    }
+}
@@ -1,72 +1,448 @@
 #![doc = include_str!("../README.md")]
 
-mod mapi;
-mod pcap;
-mod proxy;
-mod render;
+mod backpressure;
+mod capture;
+mod config;
+mod console;
+mod control;
+mod dissector;
+mod exchange;
+mod filter;
+mod har;
+mod jsonl;
+mod keys;
+mod live;
+mod notify;
+mod output;
+mod parquet;
+mod pcaplog;
+mod per_connection;
+mod selftest;
+mod serve;
+mod sqlite;
+mod stats;
+mod status;
+mod tee;
+mod verify;
+mod webhook;
 
-use std::fs::File;
-use std::panic::PanicInfo;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+#[cfg(unix)]
+use std::os::fd::AsFd;
+#[cfg(windows)]
+use std::os::windows::io::AsHandle;
+use std::panic::PanicHookInfo;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::{io, panic, process, thread};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, io, panic, process, thread};
 
 use anyhow::{bail, Context, Result as AResult};
 use argsplitter::{ArgError, ArgSplitter};
-use pcap::Tracker;
-use proxy::event::MapiEvent;
-use proxy::network::MonetAddr;
+use lazy_regex::Regex;
+use mapiproxy::{
+    clock::{TimeSource, WallClock},
+    mapi::{self, Annotation, Labeler, Prompts},
+    pcap::{self, Clock, PcapWriter, TimeShift, Tracker},
+    proxy::{
+        event::{ConnectionId, MapiEvent},
+        network::{
+            ListenOptions, MonetAddr, ResolveOverride, Resolver, TrafficMarking, DEFAULT_BACKLOG,
+        },
+        AcceptRate, AsyncProxy, Fragment, HandshakeRewriter, Negotiation, Proxy, Rewriter,
+    },
+    render::{Brief, ColorDepth, Frames, Glyphs, Renderer, Theme, TimeZone},
+    script::Script,
+    Level, PcapError, ProtocolError, ProxyError,
+};
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+use mapiproxy::proxy::UringProxy;
 
-use crate::{proxy::Proxy, render::Renderer};
+use crate::backpressure::{parse_policy, EventSender, MemoryBudget, Policy, CHANNEL_CAPACITY};
+use crate::capture::{CaptureFilter, LiveCapture};
+use crate::config::Config;
+use crate::exchange::Recorder;
+use crate::filter::{parse_connections, parse_direction, parse_port, Filter};
+use crate::har::HarLog;
+use crate::jsonl::JsonLines;
+use crate::keys::{Key, Keyboard};
+use crate::live::LiveInput;
+use crate::notify::{parse_notify, Notifier, Notify};
+use crate::output::{parse_output, Format, Output, OutputSpec, Outputs};
+use crate::parquet::ParquetLog;
+use crate::pcaplog::PcapLog;
+use crate::per_connection::PerConnection;
+use crate::sqlite::SqliteLog;
+use crate::stats::Stats;
+use crate::status::Status;
+use crate::tee::TeeLog;
+use crate::verify::Verifier;
+use crate::webhook::{parse_webhook, Webhook};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const USAGE: &str = include_str!("usage.txt");
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-enum Level {
-    Raw,
-    Blocks,
-    Messages,
+/// The subcommands. Without a subcommand, it is derived from the arguments.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Command {
+    Proxy,
+    Pcap,
+    /// Like `pcap --stats`, without showing the traffic itself
+    Summary,
+    Serve,
+    Selftest,
+    ExportDissector,
 }
 
+impl Command {
+    fn from_name(name: &str) -> Option<Command> {
+        match name {
+            "proxy" => Some(Command::Proxy),
+            "pcap" => Some(Command::Pcap),
+            "summary" => Some(Command::Summary),
+            "serve" | "replay" => Some(Command::Serve),
+            "selftest" => Some(Command::Selftest),
+            "export-dissector" => Some(Command::ExportDissector),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Backend {
+    Mio,
+    Tokio,
+    Uring,
+}
+
+// there is only ever one of these
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 enum Source {
     Proxy {
         listen_addr: MonetAddr,
         forward_addr: MonetAddr,
+        backend: Backend,
+        listen_options: ListenOptions,
+        marking: TrafficMarking,
+        backpressure: Policy,
+        rewrite_file: Option<PathBuf>,
+        negotiation: Negotiation,
+        database: Option<String>,
+        standby_addr: Option<MonetAddr>,
+        mirror_addr: Option<MonetAddr>,
+        mirror_password: Option<String>,
+        status_interval: Option<Duration>,
+        control: Option<PathBuf>,
+        fragment: Option<Fragment>,
+        step: bool,
+        accept_rate: Option<AcceptRate>,
+    },
+    Pcap {
+        input: PcapInput,
+        strict: bool,
+        checksums: bool,
+        join_mid_stream: bool,
+        tcp_debug: bool,
+        per_connection: bool,
+        time_shift: TimeShift,
+    },
+}
+
+/// Where --pcap mode gets its packets from.
+#[derive(Debug)]
+enum PcapInput {
+    File(PathBuf),
+    Live {
+        interface: String,
+        filter: Option<CaptureFilter>,
     },
-    Pcap(PathBuf),
 }
 
+/// Exit statuses that tell apart why mapiproxy stopped, see "Exit status" in
+/// the README. Other failures exit with 1.
+const EXIT_USAGE: u8 = 2;
+const EXIT_PCAP: u8 = 3;
+const EXIT_PROTOCOL: u8 = 4;
+const EXIT_PROXY: u8 = 5;
+
+/// The traffic contained MAPI protocol errors. They have already been shown,
+/// this only makes the exit status reflect them.
+#[derive(Debug, thiserror::Error)]
+#[error("found {0} MAPI protocol error(s) in the traffic")]
+struct ProtocolErrorsFound(usize);
+
 fn main() -> ExitCode {
-    argsplitter::main_support::report_errors(USAGE, mymain())
+    let result = mymain();
+    let code = result.as_ref().err().and_then(exit_code);
+    let reported = argsplitter::main_support::report_errors(USAGE, result);
+    code.map(ExitCode::from).unwrap_or(reported)
+}
+
+fn exit_code(err: &anyhow::Error) -> Option<u8> {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<ArgError>() {
+            return match e {
+                ArgError::ExitSuccessfully => None,
+                _ => Some(EXIT_USAGE),
+            };
+        } else if let Some(e) = cause.downcast_ref::<PcapError>() {
+            // the event handler failing is not the capture file's fault
+            if !matches!(e, PcapError::Handler(_)) {
+                return Some(EXIT_PCAP);
+            }
+        } else if cause.is::<ProxyError>() {
+            return Some(EXIT_PROXY);
+        } else if cause.is::<ProtocolError>() || cause.is::<ProtocolErrorsFound>() {
+            return Some(EXIT_PROTOCOL);
+        } else if let Some(e) = cause.downcast_ref::<io::Error>() {
+            if e.get_ref().is_some_and(|inner| inner.is::<ProtocolError>()) {
+                return Some(EXIT_PROTOCOL);
+            }
+        }
+    }
+    None
+}
+
+/// Fail if protocol errors were found in the traffic, see [ProtocolErrorsFound].
+fn check_protocol_errors(outputs: &Outputs) -> AResult<()> {
+    match outputs.protocol_errors() {
+        0 => Ok(()),
+        n => Err(ProtocolErrorsFound(n).into()),
+    }
 }
 
 fn mymain() -> AResult<()> {
     install_panic_hook();
 
+    let mut config_file: Option<PathBuf> = None;
     let mut pcap_file: Option<PathBuf> = None;
+    let mut live: Option<String> = None;
+    let mut capture_filter = None;
+    let mut pcap_strict = false;
+    let mut pcap_checksums = false;
+    let mut join_mid_stream = false;
+    let mut tcp_debug = false;
+    let mut per_connection = false;
+    let mut shift_time = None;
+    let mut rebase_time = None;
     let mut level = None;
     let mut force_binary = false;
+    let mut events_only = false;
+    let mut deterministic = false;
+    let mut labels = false;
+    let mut label_regex = None;
+    let mut source_name = None;
+    let mut har_file: Option<PathBuf> = None;
+    let mut sqlite_file: Option<PathBuf> = None;
+    let mut parquet_dir: Option<PathBuf> = None;
+    let mut tee_dir: Option<PathBuf> = None;
+    let mut verify_file: Option<PathBuf> = None;
+    let mut pcap_out: Option<PathBuf> = None;
+    let mut notify: Option<Notify> = None;
+    let mut webhook: Option<String> = None;
+    let mut outputs: Vec<OutputSpec> = vec![];
+    let mut stats = false;
+    let mut annotate_file: Option<PathBuf> = None;
     let mut colored = None;
+    let mut frames = None;
+    let mut prompts = None;
+    let mut format = None;
+    let mut theme = None;
+    let mut depth = None;
+    let mut time_zone = None;
+    let mut brief = None;
+    let mut hex_width = None;
+    let mut glyphs = None;
+    let mut time_separator = None;
+    let mut time_announce = None;
+    let mut backend = None;
+    let mut backlog = None;
+    let mut accept_rate = None;
+    let mut require_all_binds = false;
+    let mut force_bind = false;
+    let mut listen_all = false;
+    let mut unix_socket_dir: Option<PathBuf> = None;
+    let mut unix_socket_template = None;
+    let mut backpressure = None;
+    let mut max_memory = None;
+    let mut sample_bytes = None;
+    let mut max_capture_bytes = None;
+    let mut script_file: Option<PathBuf> = None;
+    let mut rewrite_file: Option<PathBuf> = None;
+    let mut strip_hashes = None;
+    let mut reply_size = None;
+    let mut language = None;
+    let mut database: Option<String> = None;
+    let mut standby_addr: Option<OsString> = None;
+    let mut mirror_addr: Option<OsString> = None;
+    let mut mirror_password: Option<String> = None;
+    let mut resolve = vec![];
+    let mut tos = None;
+    let mut so_mark = None;
+    let mut control: Option<PathBuf> = None;
+    let mut fragment = None;
+    let mut step = false;
+    let mut status_interval = None;
+    let mut connections: Option<Vec<ConnectionId>> = None;
+    let mut direction = None;
+    let mut port = None;
+    let mut shown_connections: Option<HashSet<ConnectionId>> = None;
+    let mut check = false;
+    let mut verbosity = 0;
 
-    let mut args = ArgSplitter::from_env();
+    let mut argv: Vec<_> = env::args_os().collect();
+    let command = argv
+        .get(1)
+        .and_then(|arg| arg.to_str())
+        .and_then(Command::from_name);
+    if command.is_some() {
+        argv.remove(1);
+    }
+    match command {
+        Some(Command::Serve) => return serve::serve(ArgSplitter::from(argv)),
+        Some(Command::Selftest) => return selftest::selftest(ArgSplitter::from(argv)),
+        Some(Command::ExportDissector) => {
+            return dissector::export_dissector(ArgSplitter::from(argv))
+        }
+        _ => {}
+    }
+    let proxy_flags = !matches!(command, Some(Command::Pcap | Command::Summary));
+
+    let mut args = ArgSplitter::from(argv);
     while let Some(flag) = args.flag()? {
         match flag {
-            "--pcap" => pcap_file = Some(args.param_os()?.into()),
+            "--pcap" if command.is_none() => pcap_file = Some(args.param_os()?.into()),
+            "--live" if command.is_none() => live = Some(args.param()?),
+            "--capture-filter" => {
+                capture_filter = Some(parse_capture_filter("--capture-filter", &args.param()?)?)
+            }
+            "--pcap-strict" => pcap_strict = true,
+            "--pcap-checksums" => pcap_checksums = true,
+            "--join-mid-stream" => join_mid_stream = true,
+            "--tcp-debug" => tcp_debug = true,
+            "--per-connection" => per_connection = true,
+            "--shift-time" => shift_time = Some(parse_shift("--shift-time", &args.param()?)?),
+            "--rebase-time" => rebase_time = Some(parse_rebase("--rebase-time", &args.param()?)?),
             "-m" | "--messages" => level = Some(Level::Messages),
             "-b" | "--blocks" => level = Some(Level::Blocks),
             "-r" | "--raw" => level = Some(Level::Raw),
             "-B" | "--binary" => force_binary = true,
-            "--color" => {
-                colored = match args.param()?.to_lowercase().as_str() {
-                    "always" => Some(true),
-                    "auto" => None,
-                    "never" => Some(false),
-                    other => bail!("--color={other}: must be 'always', 'auto' or 'never'"),
-                }
+            "-e" | "--events" => events_only = true,
+            "--deterministic" => deterministic = true,
+            "--labels" => labels = true,
+            "--label-regex" => label_regex = Some(parse_regex("--label-regex", &args.param()?)?),
+            "--source-name" => {
+                source_name = Some(parse_source_name("--source-name", &args.param()?)?)
+            }
+            "--color" => colored = Some(parse_color("--color", &args.param()?)?),
+            "--color-depth" => depth = Some(parse_depth("--color-depth", &args.param()?)?),
+            "--color-theme" => theme = Some(parse_theme("--color-theme", &args.param()?)?),
+            "--time-zone" => time_zone = Some(parse_time_zone("--time-zone", &args.param()?)?),
+            "--time-separator" => {
+                time_separator = Some(parse_pause("--time-separator", &args.param()?)?)
+            }
+            "--time-announce" => {
+                time_announce = Some(parse_pause("--time-announce", &args.param()?)?)
+            }
+            "--brief" => {
+                brief = Some(if args.has_param_attached() {
+                    parse_brief("--brief", &args.param()?)?
+                } else {
+                    Brief::all(Some(Brief::DEFAULT))
+                })
+            }
+            "--hex-width" => hex_width = Some(parse_hex_width("--hex-width", &args.param()?)?),
+            "--glyphs" => glyphs = Some(parse_glyphs("--glyphs", &args.param()?)?),
+            "--frames" => frames = Some(parse_frames("--frames", &args.param()?)?),
+            "--prompts" => prompts = Some(parse_prompts("--prompts", &args.param()?)?),
+            "--format" => format = Some(parse_format("--format", &args.param()?)?),
+            "--connection" => connections
+                .get_or_insert_with(Vec::new)
+                .extend(parse_connections("--connection", &args.param()?)?),
+            "--conn" => shown_connections
+                .get_or_insert_with(HashSet::new)
+                .extend(parse_connections("--conn", &args.param()?)?),
+            "--direction" => direction = Some(parse_direction("--direction", &args.param()?)?),
+            "--port" => port = Some(parse_port("--port", &args.param()?)?),
+            "--backend" if proxy_flags => {
+                backend = Some(parse_backend("--backend", &args.param()?)?)
+            }
+            "--backlog" if proxy_flags => {
+                backlog = Some(parse_backlog("--backlog", &args.param()?)?)
+            }
+            "--accept-rate" if proxy_flags => {
+                accept_rate = Some(parse_accept_rate("--accept-rate", &args.param()?)?)
+            }
+            "--require-all-binds" if proxy_flags => require_all_binds = true,
+            "--force-bind" if proxy_flags => force_bind = true,
+            "--listen-all" if proxy_flags => listen_all = true,
+            "--unix-socket-dir" if proxy_flags => unix_socket_dir = Some(args.param_os()?.into()),
+            "--unix-socket-template" if proxy_flags => {
+                unix_socket_template = Some(parse_socket_template(
+                    "--unix-socket-template",
+                    &args.param()?,
+                )?)
             }
+            "--backpressure" if proxy_flags => {
+                backpressure = Some(parse_policy("--backpressure", &args.param()?)?)
+            }
+            "--max-memory" => max_memory = Some(parse_size("--max-memory", &args.param()?)?),
+            "--sample-bytes" => sample_bytes = Some(parse_size("--sample-bytes", &args.param()?)?),
+            "--max-capture-bytes" => {
+                max_capture_bytes = Some(parse_size("--max-capture-bytes", &args.param()?)?)
+            }
+            "--config" => config_file = Some(args.param_os()?.into()),
+            "--har" => har_file = Some(args.param_os()?.into()),
+            "--sqlite" => sqlite_file = Some(args.param_os()?.into()),
+            "--parquet" => parquet_dir = Some(args.param_os()?.into()),
+            "--tee" => tee_dir = Some(args.param_os()?.into()),
+            "--verify" if proxy_flags => verify_file = Some(args.param_os()?.into()),
+            "--write-pcap" if proxy_flags => pcap_out = Some(args.param_os()?.into()),
+            "-o" | "--output" => outputs.push(parse_output("--output", &args.param()?)?),
+            "--stats" => stats = true,
+            "--annotate" => annotate_file = Some(args.param_os()?.into()),
+            "--script" => script_file = Some(args.param_os()?.into()),
+            "--rewrite" if proxy_flags => rewrite_file = Some(args.param_os()?.into()),
+            "--handshake-strip-hash" if proxy_flags => {
+                strip_hashes = Some(parse_hash_list("--handshake-strip-hash", &args.param()?)?)
+            }
+            "--handshake-reply-size" if proxy_flags => {
+                reply_size = Some(parse_reply_size("--handshake-reply-size", &args.param()?)?)
+            }
+            "--handshake-language" if proxy_flags => {
+                language = Some(parse_language("--handshake-language", &args.param()?)?)
+            }
+            "--database" if proxy_flags => database = Some(args.param()?),
+            "--forward-standby" if proxy_flags => standby_addr = Some(args.param_os()?),
+            "--mirror" if proxy_flags => mirror_addr = Some(args.param_os()?),
+            "--mirror-password" if proxy_flags => mirror_password = Some(args.param()?),
+            "--resolve" if proxy_flags => resolve.push(parse_resolve("--resolve", &args.param()?)?),
+            "--tos" if proxy_flags => tos = Some(parse_tos("--tos", &args.param()?)?),
+            "--so-mark" if proxy_flags => {
+                so_mark = Some(parse_so_mark("--so-mark", &args.param()?)?)
+            }
+            "--control" if proxy_flags => control = Some(args.param_os()?.into()),
+            "--step" if proxy_flags => step = true,
+            "--fragment" if proxy_flags => {
+                fragment = Some(parse_fragment("--fragment", &args.param()?)?)
+            }
+            "--notify" if proxy_flags => notify = Some(parse_notify("--notify", &args.param()?)?),
+            "--webhook" if proxy_flags => {
+                webhook = Some(parse_webhook("--webhook", &args.param()?)?)
+            }
+            "--status-interval" if proxy_flags => {
+                status_interval = Some(parse_interval("--status-interval", &args.param()?)?)
+            }
+            "--check" if proxy_flags => check = true,
+            "-v" | "--debug" if proxy_flags => verbosity += 1,
             "--help" => {
                 println!("Mapiproxy version {VERSION}");
                 println!();
@@ -80,94 +456,1749 @@ fn mymain() -> AResult<()> {
             _ => return Err(ArgError::unknown_flag(flag).into()),
         }
     }
-    let Some(level) = level else {
-        return Err(ArgError::message("Please set the mode using -r, -b or -m").into());
+
+    if verbosity > 0 {
+        install_tracing(verbosity);
+    }
+
+    // Settings from the command line take precedence over the environment and
+    // the config file
+    let config = Config::load(config_file.as_deref())?;
+    if level.is_none() {
+        if let Some(value) = &config.level {
+            level = Some(parse_level("level", value).with_context(|| config.origin("level"))?);
+        }
+    }
+    if colored.is_none() {
+        if let Some(value) = &config.color {
+            colored = Some(parse_color("color", value).with_context(|| config.origin("color"))?);
+        }
+    }
+    if glyphs.is_none() {
+        if let Some(value) = &config.glyphs {
+            glyphs = Some(parse_glyphs("glyphs", value).with_context(|| config.origin("glyphs"))?);
+        }
+    }
+    if hex_width.is_none() {
+        if let Some(value) = &config.hex_width {
+            hex_width = Some(
+                parse_hex_width("hex_width", value).with_context(|| config.origin("hex_width"))?,
+            );
+        }
+    }
+    if brief.is_none() {
+        if let Some(value) = &config.brief {
+            brief = Some(parse_brief("brief", value).with_context(|| config.origin("brief"))?);
+        }
+    }
+    if time_zone.is_none() {
+        if let Some(value) = &config.time_zone {
+            time_zone = Some(
+                parse_time_zone("time_zone", value).with_context(|| config.origin("time_zone"))?,
+            );
+        }
+    }
+    if time_separator.is_none() {
+        if let Some(value) = &config.time_separator {
+            time_separator = Some(
+                parse_pause("time_separator", value)
+                    .with_context(|| config.origin("time_separator"))?,
+            );
+        }
+    }
+    if time_announce.is_none() {
+        if let Some(value) = &config.time_announce {
+            time_announce = Some(
+                parse_pause("time_announce", value)
+                    .with_context(|| config.origin("time_announce"))?,
+            );
+        }
+    }
+    if depth.is_none() {
+        if let Some(value) = &config.color_depth {
+            depth = Some(
+                parse_depth("color_depth", value).with_context(|| config.origin("color_depth"))?,
+            );
+        }
+    }
+    if theme.is_none() {
+        if let Some(value) = &config.color_theme {
+            theme = Some(
+                parse_theme("color_theme", value).with_context(|| config.origin("color_theme"))?,
+            );
+        }
+    }
+    if frames.is_none() {
+        if let Some(value) = &config.frames {
+            frames = Some(parse_frames("frames", value).with_context(|| config.origin("frames"))?);
+        }
+    }
+    if prompts.is_none() {
+        if let Some(value) = &config.prompts {
+            prompts =
+                Some(parse_prompts("prompts", value).with_context(|| config.origin("prompts"))?);
+        }
+    }
+    if format.is_none() {
+        if let Some(value) = &config.format {
+            format = Some(parse_format("format", value).with_context(|| config.origin("format"))?);
+        }
+    }
+    if sample_bytes.is_none() {
+        if let Some(value) = &config.sample_bytes {
+            sample_bytes = Some(
+                parse_size("sample_bytes", value).with_context(|| config.origin("sample_bytes"))?,
+            );
+        }
+    }
+    if max_capture_bytes.is_none() {
+        if let Some(value) = &config.max_capture_bytes {
+            max_capture_bytes = Some(
+                parse_size("max_capture_bytes", value)
+                    .with_context(|| config.origin("max_capture_bytes"))?,
+            );
+        }
+    }
+    if max_memory.is_none() {
+        if let Some(value) = &config.max_memory {
+            max_memory =
+                Some(parse_size("max_memory", value).with_context(|| config.origin("max_memory"))?);
+        }
+    }
+    if connections.is_none() {
+        if let Some(value) = &config.connection {
+            connections = Some(
+                parse_connections("connection", value)
+                    .with_context(|| config.origin("connection"))?,
+            );
+        }
+    }
+    if direction.is_none() {
+        if let Some(value) = &config.direction {
+            direction = Some(
+                parse_direction("direction", value).with_context(|| config.origin("direction"))?,
+            );
+        }
+    }
+    if port.is_none() {
+        if let Some(value) = &config.port {
+            port = Some(parse_port("port", value).with_context(|| config.origin("port"))?);
+        }
+    }
+    if shown_connections.is_none() {
+        if let Some(value) = &config.conn {
+            let ids = parse_connections("conn", value).with_context(|| config.origin("conn"))?;
+            shown_connections = Some(ids.into_iter().collect());
+        }
+    }
+    let mut filter = Filter::default();
+    if let Some(ids) = connections {
+        filter.set_connections(ids);
+    }
+    if let Some(direction) = direction {
+        filter.set_direction(direction);
+    }
+    if let Some(port) = port {
+        filter.set_port(port);
+    }
+    force_binary |= config.binary.unwrap_or(false);
+    events_only |= config.events.unwrap_or(false);
+    deterministic |= config.deterministic.unwrap_or(false);
+    if label_regex.is_none() {
+        if let Some(value) = &config.label_regex {
+            label_regex = Some(
+                parse_regex("label_regex", value).with_context(|| config.origin("label_regex"))?,
+            );
+        }
+    }
+    labels |= config.labels.unwrap_or(false) || label_regex.is_some();
+    if source_name.is_none() {
+        if let Some(value) = &config.source_name {
+            source_name = Some(
+                parse_source_name("source_name", value)
+                    .with_context(|| config.origin("source_name"))?,
+            );
+        }
+    }
+    script_file = script_file.or_else(|| config.script.clone());
+    har_file = har_file.or_else(|| config.har.clone());
+    sqlite_file = sqlite_file.or_else(|| config.sqlite.clone());
+    parquet_dir = parquet_dir.or_else(|| config.parquet.clone());
+    tee_dir = tee_dir.or_else(|| config.tee.clone());
+    stats |= config.stats.unwrap_or(false);
+    // only for the proxy, but --pcap must reject the flags without them
+    let config_notify = match &config.notify {
+        Some(value) => {
+            Some(parse_notify("notify", value).with_context(|| config.origin("notify"))?)
+        }
+        None => None,
+    };
+    let config_webhook = match &config.webhook {
+        Some(value) => {
+            Some(parse_webhook("webhook", value).with_context(|| config.origin("webhook"))?)
+        }
+        None => None,
     };
 
-    let source = if let Some(path) = pcap_file {
-        Source::Pcap(path)
-    } else {
-        let listen_addr = args.stashed_os("LISTEN_ADDR")?.try_into()?;
-        let forward_addr = args.stashed_os("FORWARD_ADDR")?.try_into()?;
-        Source::Proxy {
-            listen_addr,
-            forward_addr,
+    let command = match command {
+        Some(command) => command,
+        None if pcap_file.is_some() || live.is_some() => Command::Pcap,
+        None if (config.pcap.is_some() || config.live.is_some())
+            && args.stashed_args_os(1, "").is_err() =>
+        {
+            Command::Pcap
+        }
+        None => Command::Proxy,
+    };
+
+    let source = match command {
+        Command::Pcap | Command::Summary => {
+            if rewrite_file.is_some() {
+                bail!("--rewrite cannot be combined with --pcap");
+            }
+            if strip_hashes.is_some() || reply_size.is_some() || language.is_some() {
+                bail!("the --handshake options cannot be combined with --pcap");
+            }
+            if database.is_some() {
+                bail!("--database cannot be combined with --pcap");
+            }
+            if standby_addr.is_some() {
+                bail!("--forward-standby cannot be combined with --pcap");
+            }
+            if mirror_addr.is_some() {
+                bail!("--mirror cannot be combined with --pcap");
+            }
+            if mirror_password.is_some() {
+                bail!("--mirror-password cannot be combined with --pcap");
+            }
+            if control.is_some() {
+                bail!("--control cannot be combined with --pcap");
+            }
+            if fragment.is_some() {
+                bail!("--fragment cannot be combined with --pcap");
+            }
+            if step {
+                bail!("--step cannot be combined with --pcap");
+            }
+            if verify_file.is_some() {
+                bail!("--verify cannot be combined with --pcap");
+            }
+            if pcap_out.is_some() {
+                bail!("--write-pcap cannot be combined with --pcap");
+            }
+            if notify.is_some() {
+                bail!("--notify cannot be combined with --pcap");
+            }
+            if webhook.is_some() {
+                bail!("--webhook cannot be combined with --pcap");
+            }
+            let proxy_only = [
+                ("--backend", backend.is_some()),
+                ("--backlog", backlog.is_some()),
+                ("--accept-rate", accept_rate.is_some()),
+                ("--require-all-binds", require_all_binds),
+                ("--force-bind", force_bind),
+                ("--listen-all", listen_all),
+                ("--unix-socket-dir", unix_socket_dir.is_some()),
+                ("--unix-socket-template", unix_socket_template.is_some()),
+                ("--backpressure", backpressure.is_some()),
+                ("--resolve", !resolve.is_empty()),
+                ("--tos", tos.is_some()),
+                ("--so-mark", so_mark.is_some()),
+                ("--status-interval", status_interval.is_some()),
+                ("--debug", verbosity > 0),
+            ];
+            if let Some((flag, _)) = proxy_only.iter().find(|(_, given)| *given) {
+                bail!("{flag} cannot be combined with --pcap");
+            }
+            let shift_millis = match (shift_time, &config.shift_time) {
+                (Some(millis), _) => millis,
+                (None, Some(value)) => {
+                    parse_shift("shift_time", value).with_context(|| config.origin("shift_time"))?
+                }
+                (None, None) => 0,
+            };
+            let rebase = match (rebase_time, &config.rebase_time) {
+                (Some(rebase), _) => Some(rebase),
+                (None, Some(value)) => Some(
+                    parse_rebase("rebase_time", value)
+                        .with_context(|| config.origin("rebase_time"))?,
+                ),
+                (None, None) => None,
+            };
+            if pcap_file.is_some() && live.is_some() {
+                bail!("--live cannot be combined with --pcap");
+            }
+            let live = match pcap_file {
+                Some(_) => None,
+                None => live.or(config.live.clone()),
+            };
+            if live.is_none() && capture_filter.is_some() {
+                bail!("--capture-filter can only be used with --live");
+            }
+            let input = match (pcap_file, live) {
+                (Some(path), _) => PcapInput::File(path),
+                (None, Some(interface)) => {
+                    let filter = match (capture_filter, &config.capture_filter) {
+                        (Some(filter), _) => Some(filter),
+                        (None, Some(value)) => Some(
+                            parse_capture_filter("capture_filter", value)
+                                .with_context(|| config.origin("capture_filter"))?,
+                        ),
+                        (None, None) => None,
+                    };
+                    PcapInput::Live { interface, filter }
+                }
+                (None, None) => {
+                    PcapInput::File(positional(&mut args, "PCAP_FILE", config.pcap)?.into())
+                }
+            };
+            let strict = pcap_strict || config.pcap_strict.unwrap_or(false);
+            let checksums = pcap_checksums || config.pcap_checksums.unwrap_or(false);
+            let join_mid_stream = join_mid_stream || config.join_mid_stream.unwrap_or(false);
+            let tcp_debug = tcp_debug || config.tcp_debug.unwrap_or(false);
+            let per_connection = per_connection || config.per_connection.unwrap_or(false);
+            Source::Pcap {
+                input,
+                strict,
+                checksums,
+                join_mid_stream,
+                tcp_debug,
+                per_connection,
+                time_shift: TimeShift {
+                    rebase,
+                    shift_millis,
+                },
+            }
+        }
+        Command::Serve | Command::Selftest | Command::ExportDissector => unreachable!(),
+        Command::Proxy => {
+            if pcap_strict {
+                bail!("--pcap-strict can only be used with --pcap");
+            }
+            if pcap_checksums {
+                bail!("--pcap-checksums can only be used with --pcap");
+            }
+            if capture_filter.is_some() {
+                bail!("--capture-filter can only be used with --live");
+            }
+            if join_mid_stream {
+                bail!("--join-mid-stream can only be used with --pcap");
+            }
+            if tcp_debug {
+                bail!("--tcp-debug can only be used with --pcap");
+            }
+            if per_connection {
+                bail!("--per-connection can only be used with --pcap");
+            }
+            if shift_time.is_some() {
+                bail!("--shift-time can only be used with --pcap");
+            }
+            if rebase_time.is_some() {
+                bail!("--rebase-time can only be used with --pcap");
+            }
+            let backend = match (backend, &config.backend) {
+                (Some(backend), _) => backend,
+                (None, Some(value)) => {
+                    parse_backend("backend", value).with_context(|| config.origin("backend"))?
+                }
+                (None, None) => Backend::Mio,
+            };
+            let backlog = match (backlog, config.backlog) {
+                (Some(backlog), _) => backlog,
+                (None, Some(n)) => parse_backlog("backlog", &n.to_string())
+                    .with_context(|| config.origin("backlog"))?,
+                (None, None) => DEFAULT_BACKLOG,
+            };
+            let status_interval = match (status_interval, config.status_interval) {
+                (Some(interval), _) => Some(interval),
+                (None, Some(n)) => Some(
+                    parse_interval("status_interval", &n.to_string())
+                        .with_context(|| config.origin("status_interval"))?,
+                ),
+                (None, None) => None,
+            };
+            let unix_socket_template = match (unix_socket_template, &config.unix_socket_template) {
+                (Some(template), _) => Some(template),
+                (None, Some(value)) => Some(
+                    parse_socket_template("unix_socket_template", value)
+                        .with_context(|| config.origin("unix_socket_template"))?,
+                ),
+                (None, None) => None,
+            };
+            if resolve.is_empty() {
+                if let Some(value) = &config.resolve {
+                    for entry in value.split_whitespace() {
+                        resolve.push(
+                            parse_resolve("resolve", entry)
+                                .with_context(|| config.origin("resolve"))?,
+                        );
+                    }
+                }
+            }
+            let tos = match (tos, &config.tos) {
+                (Some(tos), _) => Some(tos),
+                (None, Some(value)) => {
+                    Some(parse_tos("tos", value).with_context(|| config.origin("tos"))?)
+                }
+                (None, None) => None,
+            };
+            let so_mark = match (so_mark, &config.so_mark) {
+                (Some(mark), _) => Some(mark),
+                (None, Some(value)) => Some(
+                    parse_so_mark("so_mark", value).with_context(|| config.origin("so_mark"))?,
+                ),
+                (None, None) => None,
+            };
+            if so_mark.is_some() && !cfg!(target_os = "linux") {
+                bail!("--so-mark is only supported on Linux");
+            }
+            let marking = TrafficMarking { tos, mark: so_mark };
+            let mut resolver = Resolver {
+                overrides: resolve,
+                ..Resolver::default()
+            };
+            if let Some(dir) = unix_socket_dir.or_else(|| config.unix_socket_dir.clone()) {
+                resolver.unix_socket_dir = dir;
+            }
+            if let Some(template) = unix_socket_template {
+                resolver.unix_socket_template = template;
+            }
+            let listen_options = ListenOptions {
+                backlog,
+                require_all_binds: require_all_binds || config.require_all_binds.unwrap_or(false),
+                force_bind: force_bind || config.force_bind.unwrap_or(false),
+                resolver: Arc::new(resolver),
+            };
+            let backpressure = match (backpressure, &config.backpressure) {
+                (Some(policy), _) => policy,
+                (None, Some(value)) => parse_policy("backpressure", value)
+                    .with_context(|| config.origin("backpressure"))?,
+                (None, None) => Policy::Block,
+            };
+            let fragment = match (fragment, &config.fragment) {
+                (Some(fragment), _) => Some(fragment),
+                (None, Some(value)) => Some(
+                    parse_fragment("fragment", value).with_context(|| config.origin("fragment"))?,
+                ),
+                (None, None) => None,
+            };
+            if fragment.is_some() && backend != Backend::Mio {
+                bail!("--fragment is only supported with --backend=mio");
+            }
+            if step && backend != Backend::Mio {
+                bail!("--step is only supported with --backend=mio");
+            }
+            let accept_rate = match (accept_rate, &config.accept_rate) {
+                (Some(rate), _) => Some(rate),
+                (None, Some(value)) => Some(
+                    parse_accept_rate("accept_rate", value)
+                        .with_context(|| config.origin("accept_rate"))?,
+                ),
+                (None, None) => None,
+            };
+            if accept_rate.is_some() && backend != Backend::Mio {
+                bail!("--accept-rate is only supported with --backend=mio");
+            }
+            let strip_hashes = match (strip_hashes, &config.handshake_strip_hash) {
+                (Some(hashes), _) => hashes,
+                (None, Some(value)) => parse_hash_list("handshake_strip_hash", value)
+                    .with_context(|| config.origin("handshake_strip_hash"))?,
+                (None, None) => vec![],
+            };
+            let reply_size = match (reply_size, &config.handshake_reply_size) {
+                (Some(n), _) => Some(n),
+                (None, Some(value)) => Some(
+                    parse_reply_size("handshake_reply_size", value)
+                        .with_context(|| config.origin("handshake_reply_size"))?,
+                ),
+                (None, None) => None,
+            };
+            let language = match (language, &config.handshake_language) {
+                (Some(language), _) => Some(language),
+                (None, Some(value)) => Some(
+                    parse_language("handshake_language", value)
+                        .with_context(|| config.origin("handshake_language"))?,
+                ),
+                (None, None) => None,
+            };
+            let negotiation = Negotiation {
+                strip_hashes,
+                reply_size,
+                language,
+            };
+            let rewrite_file = rewrite_file.or(config.rewrite);
+            let database = database.or(config.database);
+            if database.is_some() && backend != Backend::Mio {
+                bail!("--database is only supported with --backend=mio");
+            }
+            let standby_addr = standby_addr.or(config.forward_standby.map(OsString::from));
+            if standby_addr.is_some() && backend != Backend::Mio {
+                bail!("--forward-standby is only supported with --backend=mio");
+            }
+            let mirror_addr = mirror_addr.or(config.mirror.map(OsString::from));
+            if mirror_addr.is_some() && backend != Backend::Mio {
+                bail!("--mirror is only supported with --backend=mio");
+            }
+            let mirror_password = mirror_password.or(config.mirror_password);
+            if mirror_password.is_some() && mirror_addr.is_none() {
+                bail!("--mirror-password can only be used with --mirror");
+            }
+            let control = control.or(config.control);
+            if control.is_some() && backend != Backend::Mio {
+                bail!("--control is only supported with --backend=mio");
+            }
+            let listen_addr = positional(&mut args, "LISTEN_ADDR", config.listen)?;
+            let forward_addr = positional(&mut args, "FORWARD_ADDR", config.forward)?;
+            let mut listen_addr = listen_addr.try_into()?;
+            let forward_addr: MonetAddr = forward_addr.try_into()?;
+            let standby_addr = standby_addr.map(MonetAddr::try_from).transpose()?;
+            let mirror_addr = mirror_addr.map(MonetAddr::try_from).transpose()?;
+            if listen_all || config.listen_all.unwrap_or(false) {
+                let MonetAddr::PortOnly(port) = listen_addr else {
+                    bail!("--listen-all needs LISTEN_ADDR to be a port number, not {listen_addr}");
+                };
+                listen_addr = MonetAddr::AllInterfaces(port);
+            }
+            for addr in [
+                Some(&forward_addr),
+                standby_addr.as_ref(),
+                mirror_addr.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if let MonetAddr::AllInterfaces(_) = addr {
+                    bail!("Cannot connect to {addr}, '*' is only for LISTEN_ADDR");
+                }
+            }
+            Source::Proxy {
+                listen_addr,
+                forward_addr,
+                backend,
+                listen_options,
+                marking,
+                backpressure,
+                rewrite_file,
+                negotiation,
+                database,
+                standby_addr,
+                mirror_addr,
+                mirror_password,
+                status_interval,
+                control,
+                fragment,
+                step,
+                accept_rate,
+            }
         }
     };
 
     args.no_more_stashed()?;
 
-    let out = io::stdout();
-    let colored = colored.unwrap_or_else(|| is_terminal::is_terminal(&out));
-    let mut renderer = Renderer::new(colored, out);
+    let summary = command == Command::Summary;
+    if summary {
+        if !outputs.is_empty() {
+            bail!("--output cannot be combined with summary");
+        }
+        stats = true;
+    }
+
+    if check {
+        let Source::Proxy {
+            listen_addr,
+            forward_addr,
+            listen_options,
+            ..
+        } = &source
+        else {
+            bail!("--check cannot be combined with --pcap");
+        };
+        return check_addrs(listen_addr, forward_addr, listen_options);
+    }
 
-    let mapi_state = mapi::State::new(level, force_binary);
+    // -e without a mode: the level is not used but needed anyway
+    let level = level.or(events_only.then_some(Level::Messages));
+    if outputs.is_empty() && !summary {
+        outputs.push(OutputSpec {
+            path: None,
+            level: None,
+            colored: None,
+            brief: None,
+            format: None,
+        });
+    }
+
+    let annotations = match annotate_file.or(config.annotate) {
+        Some(path) => read_annotations(&path)?,
+        None => vec![],
+    };
+
+    // every output would run the hooks of its own copy of the script
+    let drawn = outputs
+        .iter()
+        .filter(|spec| spec.format.or(format).unwrap_or_default() != Format::Json)
+        .count();
+    if script_file.is_some() && drawn > 1 {
+        bail!("--script can only be used with a single output that is not JSON");
+    }
+
+    let zone = time_zone.flatten().unwrap_or_else(local_time_zone);
+    let mut rendered = vec![];
+    for spec in &outputs {
+        let (level, events_only) = match spec.level {
+            Some(Some(level)) => (level, false),
+            Some(None) => (Level::Messages, true),
+            None => match level {
+                Some(level) => (level, events_only),
+                None => {
+                    let msg = "Please set the mode using -r, -b, -m or -e";
+                    return Err(ArgError::message(msg).into());
+                }
+            },
+        };
+        let (out, terminal) = spec.open()?;
+        if spec.format.or(format).unwrap_or_default() == Format::Json {
+            let mut json = JsonLines::new(out, level);
+            json.set_events_only(events_only);
+            json.set_deterministic(deterministic);
+            json.set_shown_connections(shown_connections.clone());
+            json.set_source_name(source_name.clone());
+            rendered.push(Output::Json(json));
+            continue;
+        }
+        let colored = spec.colored.or(colored).flatten().unwrap_or(terminal)
+            && console::enable_escape_sequences();
+        let columns = terminal.then(terminal_columns).flatten();
+        let hex_width = hex_width.flatten().unwrap_or_else(|| {
+            if columns.is_some_and(|n| n >= WIDE_HEX_COLUMNS) {
+                32
+            } else {
+                16
+            }
+        });
+        let mut renderer = Renderer::new(colored, out);
+        renderer.set_deterministic(deterministic);
+        renderer.set_frames(frames.unwrap_or(Frames::BOX));
+        renderer.set_theme(theme.unwrap_or_default());
+        renderer.set_brief(spec.brief.or(brief).unwrap_or_default());
+        renderer.set_hex_width(hex_width);
+        renderer.set_width(columns);
+        renderer.set_glyphs(glyphs.clone().unwrap_or_default());
+        renderer.set_time_zone(zone);
+        renderer.set_source_name(source_name.clone());
+        renderer.set_time_separator(time_separator.unwrap_or(Some(Renderer::DEFAULT_SEPARATOR)));
+        renderer.set_time_announce(time_announce.unwrap_or(Some(Renderer::DEFAULT_ANNOUNCE)));
+        renderer.set_color_depth(depth.flatten().unwrap_or_else(|| {
+            let colorterm = env::var("COLORTERM").ok();
+            let term = env::var("TERM").ok();
+            ColorDepth::detect(colorterm.as_deref(), term.as_deref())
+        }));
+
+        let mut state = mapi::State::new(level, force_binary);
+        state.set_events_only(events_only);
+        state.set_memory_cap(max_memory);
+        state.set_sample_bytes(sample_bytes);
+        state.set_max_capture_bytes(max_capture_bytes.map(|n| n as u64));
+        state.set_deterministic(deterministic);
+        state.set_prompts(prompts.unwrap_or_default());
+        state.set_annotations(annotations.clone());
+        state.set_shown_connections(shown_connections.clone());
+        if labels {
+            state.set_labels(Some(Labeler::new(label_regex.clone())));
+        }
+        if let Some(path) = &script_file {
+            state.set_hooks(Box::new(Script::load(path)?));
+        }
+        rendered.push(Output::Rendered { state, renderer });
+    }
+    let outputs = Outputs::new(rendered);
+    let mut recorders: Vec<Box<dyn Recorder>> = vec![];
+    if let Some(path) = har_file {
+        let mut har = HarLog::create(&path)?;
+        har.set_source_name(source_name.clone());
+        recorders.push(Box::new(har));
+    }
+    if let Some(path) = sqlite_file {
+        recorders.push(Box::new(SqliteLog::create(&path)?));
+    }
+    if let Some(path) = parquet_dir {
+        recorders.push(Box::new(ParquetLog::create(&path)?));
+    }
+    if let Some(path) = tee_dir {
+        recorders.push(Box::new(TeeLog::create(&path)?));
+    }
+    let pcap_out = pcap_out.or_else(|| config.write_pcap.clone());
+    if let (Some(path), Source::Proxy { .. }) = (pcap_out, &source) {
+        recorders.push(Box::new(PcapLog::create(&path)?));
+    }
+    if stats {
+        let mut stats = Stats::default();
+        // with summary, the histograms are all there is to show
+        stats.set_stdout(summary);
+        recorders.push(Box::new(stats));
+    }
+    let verify_file = verify_file.or_else(|| config.verify.clone());
+    if let (Some(path), Source::Proxy { .. }) = (verify_file, &source) {
+        recorders.push(Box::new(Verifier::load(&path)?));
+    }
+    if let (Some(how), Source::Proxy { .. }) = (notify.or(config_notify), &source) {
+        recorders.push(Box::new(Notifier::new(how)));
+    }
+    if let (Some(url), Source::Proxy { .. }) = (webhook.or(config_webhook), &source) {
+        let mut webhook = Webhook::new(url)?;
+        webhook.set_source_name(source_name.clone());
+        recorders.push(Box::new(webhook));
+    }
 
     match source {
         Source::Proxy {
             listen_addr,
             forward_addr,
-        } => run_proxy(listen_addr, forward_addr, mapi_state, &mut renderer),
-        Source::Pcap(path) => run_pcap(&path, mapi_state, &mut renderer),
+            backend,
+            listen_options,
+            marking,
+            backpressure,
+            rewrite_file,
+            negotiation,
+            database,
+            standby_addr,
+            mirror_addr,
+            mirror_password,
+            status_interval,
+            control,
+            fragment,
+            step,
+            accept_rate,
+        } => run_proxy(
+            listen_addr,
+            forward_addr,
+            backend,
+            listen_options,
+            marking,
+            backpressure,
+            rewrite_file,
+            negotiation,
+            database,
+            standby_addr,
+            mirror_addr,
+            mirror_password,
+            status_interval,
+            control,
+            fragment,
+            step,
+            accept_rate,
+            filter,
+            max_memory,
+            outputs,
+            recorders,
+            WallClock,
+        ),
+        Source::Pcap {
+            input,
+            strict,
+            checksums,
+            join_mid_stream,
+            tcp_debug,
+            per_connection,
+            time_shift,
+        } => run_pcap(
+            input,
+            strict,
+            checksums,
+            join_mid_stream,
+            tcp_debug,
+            time_shift,
+            per_connection.then(|| {
+                let budget = max_memory.map(|cap| Arc::new(MemoryBudget::new(cap)));
+                PerConnection::new((!deterministic).then_some(zone), budget)
+            }),
+            filter,
+            outputs,
+            recorders,
+        ),
+    }
+}
+
+/// Implementation of --annotate.
+fn read_annotations(path: &Path) -> AResult<Vec<Annotation>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Could not read annotations from {}", path.display()))?;
+    match Annotation::parse_all(&text) {
+        Ok(annotations) => Ok(annotations),
+        Err(e) => bail!("{}: {e}", path.display()),
     }
 }
 
+/// Implementation of --check. Prints the result of resolving, binding and
+/// connecting for every address, and fails if the proxy would not be able to
+/// start or could not reach any of the servers.
+fn check_addrs(
+    listen_addr: &MonetAddr,
+    forward_addr: &MonetAddr,
+    options: &ListenOptions,
+) -> AResult<()> {
+    fn outcome(result: io::Result<()>) -> String {
+        match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    let listen_addrs = listen_addr
+        .resolve_with(&options.resolver)
+        .with_context(|| format!("Could not resolve LISTEN_ADDR {listen_addr}"))?;
+    println!("LISTEN_ADDR {listen_addr}");
+    let mut bound = 0;
+    for addr in &listen_addrs {
+        let result = addr.check_listen();
+        bound += result.is_ok() as usize;
+        println!("    {addr}: {}", outcome(result));
+    }
+    let listen_ok = bound > 0 && (bound == listen_addrs.len() || !options.require_all_binds);
+
+    let forward_addrs = forward_addr
+        .resolve_with(&options.resolver)
+        .with_context(|| format!("Could not resolve FORWARD_ADDR {forward_addr}"))?;
+    println!("FORWARD_ADDR {forward_addr}");
+    let mut forward_ok = false;
+    for addr in &forward_addrs {
+        let result = addr.check_connect(Duration::from_secs(5));
+        forward_ok |= result.is_ok();
+        println!("    {addr}: {}", outcome(result));
+    }
+
+    // a ProxyError, so the exit status tells these apart from a bad config
+    let problem = match (listen_ok, forward_ok) {
+        (true, true) => return Ok(()),
+        (false, _) if bound > 0 => format!("Cannot listen on all of {listen_addr}"),
+        (false, _) => format!("Cannot listen on any of {listen_addr}"),
+        (true, false) => format!("Cannot connect to any of {forward_addr}"),
+    };
+    Err(ProxyError::Other(problem).into())
+}
+
+/// Take the next positional argument, or if there is none, the setting from the
+/// config file.
+fn positional(
+    args: &mut ArgSplitter,
+    desc: &str,
+    fallback: Option<impl Into<OsString>>,
+) -> AResult<OsString> {
+    if let Some(arg) = args.stashed_args_os(0, desc)?.next() {
+        return Ok(arg);
+    }
+    match fallback {
+        Some(value) => Ok(value.into()),
+        None => Ok(args.stashed_os(desc)?),
+    }
+}
+
+fn parse_level(setting: &str, value: &str) -> AResult<Level> {
+    let level = match value.to_lowercase().as_str() {
+        "raw" => Level::Raw,
+        "blocks" => Level::Blocks,
+        "messages" => Level::Messages,
+        other => bail!("{setting}={other}: must be 'raw', 'blocks' or 'messages'"),
+    };
+    Ok(level)
+}
+
+/// None means auto
+fn parse_color(setting: &str, value: &str) -> AResult<Option<bool>> {
+    let colored = match value.to_lowercase().as_str() {
+        "always" => Some(true),
+        "auto" => None,
+        "never" => Some(false),
+        other => bail!("{setting}={other}: must be 'always', 'auto' or 'never'"),
+    };
+    Ok(colored)
+}
+
+/// None means auto
+fn parse_depth(setting: &str, value: &str) -> AResult<Option<ColorDepth>> {
+    let value = value.to_lowercase();
+    if value == "auto" {
+        return Ok(None);
+    }
+    match ColorDepth::from_setting(&value) {
+        Some(depth) => Ok(Some(depth)),
+        None => bail!("{setting}={value}: must be 'auto', '8', '256' or 'truecolor'"),
+    }
+}
+
+/// None means local time
+fn parse_time_zone(setting: &str, value: &str) -> AResult<Option<TimeZone>> {
+    if value.eq_ignore_ascii_case("local") {
+        return Ok(None);
+    }
+    match TimeZone::from_setting(value) {
+        Some(zone) => Ok(Some(zone)),
+        None => bail!("{setting}={value}: must be 'utc', 'local' or an offset such as '+02:00'"),
+    }
+}
+
+/// The offset of local time from UTC as it is now.
+#[cfg(unix)]
+fn local_time_zone() -> TimeZone {
+    // SAFETY: localtime_r only writes to the tm we pass it
+    let offset = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return TimeZone::Utc;
+        }
+        tm.tm_gmtoff
+    };
+    TimeZone::Offset(offset as i32)
+}
+
+#[cfg(not(unix))]
+fn local_time_zone() -> TimeZone {
+    TimeZone::Utc
+}
+
+fn parse_theme(setting: &str, value: &str) -> AResult<Theme> {
+    match Theme::from_setting(&value.to_lowercase()) {
+        Some(theme) => Ok(theme),
+        None => bail!("{setting}={value}: must be 'default' or 'accessible'"),
+    }
+}
+
+fn parse_frames(setting: &str, value: &str) -> AResult<Frames> {
+    match Frames::from_setting(value) {
+        Some(frames) => Ok(frames),
+        None => bail!("{setting}={value}: must be 'box', 'ascii', 'arrows' or four characters"),
+    }
+}
+
+fn parse_prompts(setting: &str, value: &str) -> AResult<Prompts> {
+    let prompts = match value.to_lowercase().as_str() {
+        "frame" => Prompts::Frame,
+        "line" => Prompts::Line,
+        "collapse" => Prompts::Collapse,
+        "hide" => Prompts::Hide,
+        other => bail!("{setting}={other}: must be 'frame', 'line', 'collapse' or 'hide'"),
+    };
+    Ok(prompts)
+}
+
+fn parse_format(setting: &str, value: &str) -> AResult<Format> {
+    let format = match value.to_lowercase().as_str() {
+        "text" => Format::Text,
+        "json" => Format::Json,
+        other => bail!("{setting}={other}: must be 'text' or 'json'"),
+    };
+    Ok(format)
+}
+
+/// Implementation of --write-pcap of `selftest` and `serve`.
+fn create_pcap_writer(path: &Path) -> AResult<PcapWriter<io::BufWriter<File>>> {
+    let file = File::create(path)
+        .with_context(|| format!("Could not create pcap file {}", path.display()))?;
+    Ok(PcapWriter::new(io::BufWriter::new(file))?)
+}
+
+fn parse_hash_list(setting: &str, value: &str) -> AResult<Vec<String>> {
+    let hashes: Vec<String> = value.split(',').map(|h| h.trim().to_string()).collect();
+    if hashes
+        .iter()
+        .any(|h| h.is_empty() || !h.chars().all(|c| c.is_ascii_alphanumeric()))
+    {
+        bail!("{setting}={value}: must be a list of hash algorithms such as SHA1,PROT10");
+    }
+    Ok(hashes)
+}
+
+fn parse_reply_size(setting: &str, value: &str) -> AResult<i64> {
+    match value.parse() {
+        Ok(n @ -1..) => Ok(n),
+        _ => bail!("{setting}={value}: must be a number of rows, or -1 for all"),
+    }
+}
+
+fn parse_language(setting: &str, value: &str) -> AResult<String> {
+    if value.is_empty() || value.contains([':', '\n']) {
+        bail!("{setting}={value}: must be a language such as sql or mal");
+    }
+    Ok(value.to_string())
+}
+
+fn parse_capture_filter(setting: &str, value: &str) -> AResult<CaptureFilter> {
+    match value.parse() {
+        Ok(filter) => Ok(filter),
+        Err(e) => bail!("{setting}={value}: {e}"),
+    }
+}
+
+fn parse_backend(setting: &str, value: &str) -> AResult<Backend> {
+    let backend = match value.to_lowercase().as_str() {
+        "mio" => Backend::Mio,
+        "tokio" => Backend::Tokio,
+        "uring" if cfg!(all(feature = "uring", target_os = "linux")) => Backend::Uring,
+        "uring" => bail!("{setting}=uring: not supported by this build, see the README"),
+        other => bail!("{setting}={other}: must be 'mio', 'tokio' or 'uring'"),
+    };
+    Ok(backend)
+}
+
+fn parse_backlog(setting: &str, value: &str) -> AResult<i32> {
+    match value.parse() {
+        Ok(n @ 1..) => Ok(n),
+        _ => bail!("{setting}={value}: must be a positive number"),
+    }
+}
+
+/// A write size, optionally followed by a comma and the number of bytes each
+/// write may be smaller or larger.
+fn parse_fragment(setting: &str, value: &str) -> AResult<Fragment> {
+    let (size, jitter) = value.split_once(',').unwrap_or((value, "0"));
+    match (size.trim().parse(), jitter.trim().parse()) {
+        (Ok(size @ 1..), Ok(jitter)) => Ok(Fragment { size, jitter }),
+        _ => {
+            bail!("{setting}={value}: must be a number of bytes, optionally followed by ',JITTER'")
+        }
+    }
+}
+
+/// N/s or N/m, or just N for per second.
+fn parse_accept_rate(setting: &str, value: &str) -> AResult<AcceptRate> {
+    let (count, per) = value.split_once('/').unwrap_or((value, "s"));
+    let per = match per.trim() {
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        _ => Duration::ZERO,
+    };
+    match count.trim().parse() {
+        Ok(count @ 1..) if !per.is_zero() => Ok(AcceptRate { count, per }),
+        _ => bail!("{setting}={value}: must be a number of connections per second or minute, for example 5/s or 30/m"),
+    }
+}
+
+/// A TOS byte in decimal or hexadecimal, or the name of a DSCP class such as
+/// 'ef', 'af21' or 'cs1', which ends up in the upper six bits.
+fn parse_tos(setting: &str, value: &str) -> AResult<u8> {
+    let name = value.trim().to_ascii_lowercase();
+    let dscp = match name.as_bytes() {
+        b"ef" => Some(46),
+        [b'a', b'f', class @ b'1'..=b'4', drop @ b'1'..=b'3'] => {
+            Some(8 * (class - b'0') + 2 * (drop - b'0'))
+        }
+        [b'c', b's', class @ b'0'..=b'7'] => Some(8 * (class - b'0')),
+        _ => None,
+    };
+    match (dscp, parse_integer(&name).map(u8::try_from)) {
+        (Some(dscp), _) => Ok(dscp << 2),
+        (None, Some(Ok(tos))) => Ok(tos),
+        _ => bail!("{setting}={value}: must be a number from 0 to 255, or a DSCP class such as ef, af21 or cs1"),
+    }
+}
+
+/// A socket mark in decimal or hexadecimal.
+fn parse_so_mark(setting: &str, value: &str) -> AResult<u32> {
+    match parse_integer(value.trim()).map(u32::try_from) {
+        Some(Ok(mark)) => Ok(mark),
+        _ => bail!("{setting}={value}: must be a number, for example 42 or 0x2a"),
+    }
+}
+
+/// A non-negative number, hexadecimal if it starts with 0x.
+fn parse_integer(value: &str) -> Option<u64> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// NAME:PORT:ADDR as with curl, where PORT can be '*' and ADDR a comma
+/// separated list of IP addresses. IPv6 addresses may be put in brackets.
+fn parse_resolve(setting: &str, value: &str) -> AResult<ResolveOverride> {
+    let parse = || {
+        let (host, rest) = value.split_once(':')?;
+        let (port, addrs) = rest.split_once(':')?;
+        let port = match port {
+            "*" => None,
+            _ => Some(port.parse().ok()?),
+        };
+        let addrs = addrs
+            .split(',')
+            .map(|a| {
+                let a = a.trim();
+                let a = a
+                    .strip_prefix('[')
+                    .and_then(|a| a.strip_suffix(']'))
+                    .unwrap_or(a);
+                a.parse().ok()
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if host.is_empty() {
+            return None;
+        }
+        Some(ResolveOverride {
+            host: host.to_string(),
+            port,
+            addrs,
+        })
+    };
+    match parse() {
+        Some(entry) => Ok(entry),
+        None => bail!("{setting}={value}: must be NAME:PORT:ADDR, for example db1:50000:10.0.0.2"),
+    }
+}
+
+/// The name of the Unix Domain socket of a port, such as `.s.monetdb.{port}`.
+fn parse_socket_template(setting: &str, value: &str) -> AResult<String> {
+    if !value.contains("{port}") {
+        bail!("{setting}={value}: must contain '{{port}}'");
+    }
+    if value.contains('/') {
+        bail!("{setting}={value}: must be a file name, use --unix-socket-dir for the directory");
+    }
+    Ok(value.to_string())
+}
+
+fn parse_interval(setting: &str, value: &str) -> AResult<Duration> {
+    match value.parse() {
+        Ok(n @ 1..) => Ok(Duration::from_secs(n)),
+        _ => bail!("{setting}={value}: must be a positive number of seconds"),
+    }
+}
+
+/// A duration such as 500ms, 10s or 2m, or None for 'none'.
+fn parse_pause(setting: &str, value: &str) -> AResult<Option<Duration>> {
+    let lower = value.to_lowercase();
+    if lower == "none" {
+        return Ok(None);
+    }
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => lower.split_at(i),
+        None => (lower.as_str(), "s"),
+    };
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60_000,
+        _ => 0,
+    };
+    match digits.parse::<u64>() {
+        Ok(n @ 1..) if millis > 0 => Ok(Some(Duration::from_millis(n * millis))),
+        _ => bail!("{setting}={value}: must be 'none' or a duration such as 500ms, 10s or 2m"),
+    }
+}
+
+/// A number of milliseconds such as -1500ms, +2h or 90 (seconds), for
+/// --shift-time.
+fn parse_shift(setting: &str, value: &str) -> AResult<i64> {
+    let lower = value.to_lowercase();
+    let (negative, rest) = match lower.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, lower.strip_prefix('+').unwrap_or(&lower)),
+    };
+    let (digits, unit) = match rest.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => rest.split_at(i),
+        None => (rest, "s"),
+    };
+    let millis: i64 = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        _ => 0,
+    };
+    match digits
+        .parse::<i64>()
+        .ok()
+        .and_then(|n| n.checked_mul(millis))
+    {
+        Some(n) if millis > 0 => Ok(if negative { -n } else { n }),
+        _ => bail!("{setting}={value}: must be a duration such as -1500ms, +90s or +2h"),
+    }
+}
+
+/// The time the first packet is moved to, for --rebase-time.
+fn parse_rebase(setting: &str, value: &str) -> AResult<Duration> {
+    match value.to_lowercase().as_str() {
+        "zero" => Ok(Duration::ZERO),
+        "now" => Ok(wall_clock()),
+        _ => bail!("{setting}={value}: must be 'zero' or 'now'"),
+    }
+}
+
+/// A name to show before the connection ids, for --source-name.
+fn parse_source_name(setting: &str, value: &str) -> AResult<String> {
+    if value.is_empty() || value.contains(|c: char| c == '#' || c.is_whitespace()) {
+        bail!("{setting}={value}: must be a name without spaces or '#'");
+    }
+    Ok(value.to_string())
+}
+
+/// None means auto
+fn parse_hex_width(setting: &str, value: &str) -> AResult<Option<usize>> {
+    match value.to_lowercase().as_str() {
+        "auto" => Ok(None),
+        "16" => Ok(Some(16)),
+        "32" => Ok(Some(32)),
+        _ => bail!("{setting}={value}: must be '16', '32' or 'auto'"),
+    }
+}
+
+/// Terminal width needed for hex dumps with 32 bytes per line.
+const WIDE_HEX_COLUMNS: usize = 147;
+
+/// The width of the terminal stdout is connected to, or the COLUMNS
+/// environment variable.
+fn terminal_columns() -> Option<usize> {
+    #[cfg(unix)]
+    {
+        // SAFETY: TIOCGWINSZ only writes to the winsize we pass it
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if ret == 0 && size.ws_col > 0 {
+            return Some(size.ws_col as usize);
+        }
+    }
+    env::var("COLUMNS").ok()?.parse().ok()
+}
+
+fn parse_glyphs(setting: &str, value: &str) -> AResult<Glyphs> {
+    match Glyphs::from_setting(value) {
+        Ok(glyphs) => Ok(glyphs),
+        Err(e) => bail!("{setting}={value}: {e}"),
+    }
+}
+
+fn parse_brief(setting: &str, value: &str) -> AResult<Brief> {
+    match Brief::from_setting(value) {
+        Ok(brief) => Ok(brief),
+        Err(e) => bail!("{setting}={value}: {e}"),
+    }
+}
+
+fn parse_regex(setting: &str, value: &str) -> AResult<Regex> {
+    match Regex::new(value) {
+        Ok(regex) => Ok(regex),
+        Err(e) => bail!("{setting}={value}: {e}"),
+    }
+}
+
+/// A number of bytes, optionally followed by K, M or G for powers of 1024.
+fn parse_size(setting: &str, value: &str) -> AResult<usize> {
+    let lower = value.to_lowercase();
+    let (digits, unit) = match lower.strip_suffix(['k', 'm', 'g']) {
+        Some(digits) => (digits, &lower[digits.len()..]),
+        None => (lower.as_str(), ""),
+    };
+    let shift = match unit {
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        _ => 0,
+    };
+    match digits.parse::<usize>() {
+        Ok(n @ 1..) if n.leading_zeros() >= shift => Ok(n << shift),
+        _ => bail!("{setting}={value}: must be a positive number of bytes, for example 512M"),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run_proxy(
     listen_addr: MonetAddr,
     forward_addr: MonetAddr,
-    mut mapi_state: mapi::State,
-    renderer: &mut Renderer,
+    backend: Backend,
+    listen_options: ListenOptions,
+    marking: TrafficMarking,
+    backpressure: Policy,
+    rewrite_file: Option<PathBuf>,
+    negotiation: Negotiation,
+    database: Option<String>,
+    standby_addr: Option<MonetAddr>,
+    mirror_addr: Option<MonetAddr>,
+    mirror_password: Option<String>,
+    status_interval: Option<Duration>,
+    control: Option<PathBuf>,
+    fragment: Option<Fragment>,
+    step: bool,
+    accept_rate: Option<AcceptRate>,
+    mut filter: Filter,
+    max_memory: Option<usize>,
+    mut outputs: Outputs,
+    mut recorders: Vec<Box<dyn Recorder>>,
+    clock: impl TimeSource + Clone + 'static,
 ) -> AResult<()> {
-    let (send_events, receive_events) = std::sync::mpsc::sync_channel(500);
+    outputs.set_clock(clock.clone());
+    let rewriter = match rewrite_file {
+        Some(path) => {
+            let script = Script::load(&path)?;
+            if !script.can_rewrite() {
+                bail!("{}: no on_forward function defined", path.display());
+            }
+            Some(script)
+        }
+        None => None,
+    };
+
+    let (send_events, receive_events) = std::sync::mpsc::sync_channel(CHANNEL_CAPACITY);
+    let failures = send_events.clone();
+    let on_failure = move |event| {
+        let _ = failures.send(event);
+    };
+    let mut sender = EventSender::new(send_events, backpressure);
+    let backlog = sender.backlog();
+    let budget = max_memory.map(|cap| Arc::new(MemoryBudget::new(cap)));
+    if let Some(budget) = &budget {
+        sender.set_budget(budget.clone());
+    }
+    // runs on the proxy thread, so filter before the events are queued
     let handler = move |event| {
-        let _ = send_events.send(event);
+        if filter.admit(&event) {
+            sender.send(event)
+        }
     };
-    let mut proxy = Proxy::new(listen_addr, forward_addr, handler)?;
-    install_ctrl_c_handler(proxy.get_shutdown_trigger())?;
-    thread::spawn(move || proxy.run().unwrap());
+    let mut rewriter = rewriter.map(|r| Box::new(r) as Box<dyn Rewriter>);
+    if !negotiation.is_empty() {
+        rewriter = Some(Box::new(HandshakeRewriter::new(negotiation, rewriter)));
+    }
+    let (trigger, _) = start_proxy(
+        listen_addr,
+        forward_addr,
+        backend,
+        listen_options,
+        marking,
+        !outputs.events_only() || !recorders.is_empty() || status_interval.is_some(),
+        rewriter,
+        database,
+        standby_addr,
+        mirror_addr,
+        mirror_password,
+        control,
+        fragment,
+        step,
+        accept_rate,
+        handler,
+        on_failure,
+    )?;
+    let trigger: console::Trigger = Arc::from(trigger);
+    install_ctrl_c_handler(Box::new({
+        let trigger = trigger.clone();
+        move || trigger()
+    }))?;
+    // --step reads Enter from stdin itself
+    let keyboard = if step { None } else { Keyboard::start(trigger) };
+    if step {
+        outputs.message(
+            None,
+            None,
+            "STEPPING, press Enter to forward the message that has been held longest",
+        )?;
+    }
+    if keyboard.is_some() {
+        outputs.message(None, None, keys::HELP)?;
+    }
 
-    while let Ok(ev) = receive_events.recv() {
-        mapi_state.handle(&ev, renderer)?;
+    let mut status = status_interval.map(Status::new);
+    // the events that came in while the output was paused
+    let mut held: Option<Vec<MapiEvent>> = None;
+    let mut condensed = false;
+    let mut result = Ok(());
+    loop {
+        let timeout = match (&status, &keyboard) {
+            (Some(status), Some(_)) => Some(status.remaining().min(Keyboard::POLL_INTERVAL)),
+            (Some(status), None) => Some(status.remaining()),
+            (None, Some(_)) => Some(Keyboard::POLL_INTERVAL),
+            (None, None) => None,
+        };
+        let received = match timeout {
+            Some(timeout) => receive_events.recv_timeout(timeout),
+            None => receive_events.recv().map_err(RecvTimeoutError::from),
+        };
+        if let Some(status) = &mut status {
+            if let Ok(ev) = &received {
+                status.record(ev);
+            }
+            status.render_if_due(&mut outputs)?;
+        }
+        for key in keyboard.iter().flat_map(Keyboard::pressed) {
+            match key {
+                Key::Pause => match held.take() {
+                    None => {
+                        outputs.message(None, None, "PAUSED, press p to continue")?;
+                        held = Some(vec![]);
+                    }
+                    Some(events) => {
+                        let msg = format_args!("RESUMED after {} events", events.len());
+                        outputs.message(None, None, msg)?;
+                        render_events(&events, &mut outputs, budget.as_deref())?;
+                    }
+                },
+                Key::Brief => {
+                    let msg = match outputs.toggle_brief() {
+                        true => "BRIEF, only showing the first and last lines of each frame",
+                        false => "FULL, showing frames in full",
+                    };
+                    outputs.message(None, None, msg)?;
+                }
+                Key::Stats => {
+                    let mut shown = false;
+                    if let Some(status) = &mut status {
+                        status.render_now(&mut outputs)?;
+                        shown = true;
+                    }
+                    for recorder in &mut recorders {
+                        shown |= recorder.report()?;
+                    }
+                    if !shown {
+                        let msg = "NO STATS, start with --stats or --status-interval";
+                        outputs.message(None, None, msg)?;
+                    }
+                }
+            }
+        }
+        let ev = match received {
+            Ok(ev) => ev,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        backlog.taken();
+        if outputs.shows_raw() && backlog.overloaded(condensed) != condensed {
+            condensed = !condensed;
+            if condensed {
+                let msg = format_args!(
+                    "CONDENSING raw dumps, {} events waiting to be shown",
+                    backlog.waiting()
+                );
+                outputs.message(None, None, msg)?;
+                outputs.set_condensed(true)?;
+            } else {
+                outputs.set_condensed(false)?;
+                outputs.message(None, None, "CAUGHT UP, showing raw dumps in full again")?;
+            }
+        }
+        if matches!(ev, MapiEvent::Failed { .. }) {
+            if let Some(events) = held.take() {
+                render_events(&events, &mut outputs, budget.as_deref())?;
+            }
+        }
+        if held.is_none() {
+            render_events([&ev], &mut outputs, budget.as_deref())?;
+        }
+        let now = clock.now().unwrap_or_default();
+        for recorder in &mut recorders {
+            recorder.record(&ev, now)?;
+            for msg in recorder.take_messages() {
+                outputs.message(None, None, msg)?;
+            }
+        }
+        if let MapiEvent::Failed { error } = ev {
+            result = Err(error).context("The proxy stopped");
+            break;
+        }
+        if let Some(events) = &mut held {
+            events.push(ev);
+        }
+    }
+    if let Some(events) = held {
+        render_events(&events, &mut outputs, budget.as_deref())?;
+    }
+    outputs.set_condensed(false)?;
+    let now = clock.now().unwrap_or_default();
+    for recorder in recorders {
+        recorder.finish(now)?;
+    }
+    result?;
+    check_protocol_errors(&outputs)
+}
+
+/// Show the events on the outputs and tell the memory budget they have been
+/// dealt with.
+fn render_events<'a>(
+    events: impl IntoIterator<Item = &'a MapiEvent>,
+    outputs: &mut Outputs,
+    budget: Option<&MemoryBudget>,
+) -> io::Result<()> {
+    for ev in events {
+        outputs.handle(ev)?;
+        if let Some(budget) = budget {
+            budget.rendered(ev, outputs.buffered());
+        }
     }
     Ok(())
 }
 
-fn run_pcap(path: &Path, mut mapi_state: mapi::State, renderer: &mut Renderer) -> AResult<()> {
-    let mut owned_file;
-    let mut owned_stdin;
+/// The current time as time since the Unix epoch, for the [Recorder]s of
+/// `serve` and `selftest`, and for `--rebase-time=now`.
+fn wall_clock() -> Duration {
+    WallClock.now().unwrap_or_default()
+}
+
+/// Stops a proxy started by [start_proxy].
+type ShutdownTrigger = Box<dyn Fn() + Send + Sync>;
 
-    let reader: &mut dyn io::Read = if path == Path::new("-") {
-        owned_stdin = Some(io::stdin().lock());
-        owned_stdin.as_mut().unwrap()
-    } else {
-        let file = File::open(path)
-            .with_context(|| format!("Could not open pcap file {}", path.display()))?;
-        owned_file = Some(file);
-        owned_file.as_mut().unwrap()
+/// Bind the listen addresses and run the proxy on a separate thread. Returns a
+/// function that can be called to stop it. If the proxy stops because of an
+/// error, `on_failure` is called with a [MapiEvent::Failed] describing it.
+/// With `--backend=mio` it also returns a [control::Controller] for the proxy.
+#[allow(clippy::too_many_arguments)]
+fn start_proxy(
+    listen_addr: MonetAddr,
+    forward_addr: MonetAddr,
+    backend: Backend,
+    listen_options: ListenOptions,
+    marking: TrafficMarking,
+    report_data: bool,
+    rewriter: Option<Box<dyn Rewriter>>,
+    database: Option<String>,
+    standby_addr: Option<MonetAddr>,
+    mirror_addr: Option<MonetAddr>,
+    mirror_password: Option<String>,
+    control: Option<PathBuf>,
+    fragment: Option<Fragment>,
+    step: bool,
+    accept_rate: Option<AcceptRate>,
+    handler: impl FnMut(MapiEvent) + 'static + Send,
+    on_failure: impl FnOnce(MapiEvent) + 'static + Send,
+) -> AResult<(ShutdownTrigger, Option<control::Controller>)> {
+    let mut controller = None;
+    let trigger = match backend {
+        Backend::Mio => {
+            let mut proxy =
+                Proxy::with_options(listen_addr, forward_addr, listen_options, handler)?;
+            proxy.set_report_data(report_data);
+            proxy.set_traffic_marking(marking);
+            proxy.set_database(database);
+            proxy.set_standby(standby_addr);
+            proxy.set_mirror(mirror_addr);
+            proxy.set_mirror_password(mirror_password);
+            proxy.set_fragment(fragment);
+            proxy.set_step(step);
+            proxy.set_accept_rate(accept_rate);
+            if let Some(rewriter) = rewriter {
+                proxy.set_rewriter(rewriter);
+            }
+            let control_socket = match control {
+                Some(path) => Some(control::listen(&path, proxy.get_controller())?),
+                None => None,
+            };
+            if step {
+                control::step_on_enter(proxy.get_controller());
+            }
+            controller = Some(proxy.get_controller());
+            let trigger = proxy.get_shutdown_trigger();
+            thread::spawn(move || {
+                // removed before the proxy is, which ends the main loop
+                let _control_socket = control_socket;
+                if let Err(error) = proxy.run() {
+                    on_failure(MapiEvent::Failed { error });
+                }
+            });
+            trigger
+        }
+        Backend::Tokio => {
+            let mut proxy =
+                AsyncProxy::with_options(listen_addr, forward_addr, listen_options, handler)?;
+            proxy.set_report_data(report_data);
+            proxy.set_traffic_marking(marking);
+            if let Some(rewriter) = rewriter {
+                proxy.set_rewriter(rewriter);
+            }
+            let trigger = proxy.get_shutdown_trigger();
+            thread::spawn(move || {
+                if let Err(error) = proxy.run() {
+                    on_failure(MapiEvent::Failed { error });
+                }
+            });
+            trigger
+        }
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        Backend::Uring => {
+            let mut proxy =
+                UringProxy::with_options(listen_addr, forward_addr, listen_options, handler)?;
+            proxy.set_report_data(report_data);
+            proxy.set_traffic_marking(marking);
+            if let Some(rewriter) = rewriter {
+                proxy.set_rewriter(rewriter);
+            }
+            let trigger = proxy.get_shutdown_trigger();
+            thread::spawn(move || {
+                if let Err(error) = proxy.run() {
+                    on_failure(MapiEvent::Failed { error });
+                }
+            });
+            trigger
+        }
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        Backend::Uring => unreachable!("rejected by parse_backend"),
     };
+    Ok((trigger, controller))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_pcap(
+    input: PcapInput,
+    strict: bool,
+    checksums: bool,
+    join_mid_stream: bool,
+    tcp_debug: bool,
+    time_shift: TimeShift,
+    mut per_connection: Option<PerConnection>,
+    mut filter: Filter,
+    mut outputs: Outputs,
+    mut recorders: Vec<Box<dyn Recorder>>,
+) -> AResult<()> {
+    // A pipe, FIFO or network interface is followed until it ends or Ctrl-C
+    // is pressed
+    let mut stopped = None;
+    let mut reader: Option<Box<dyn io::Read>> = None;
+    let mut capture = None;
+    match input {
+        PcapInput::File(path) => {
+            let file = if path == Path::new("-") {
+                stdin_file().context("Could not read pcap data from stdin")?
+            } else {
+                File::open(&path)
+                    .with_context(|| format!("Could not open pcap file {}", path.display()))?
+            };
+            reader = Some(match LiveInput::new(file) {
+                Ok(live) => {
+                    install_ctrl_c_handler(live.stopper())?;
+                    stopped = Some(live.stopped());
+                    Box::new(live)
+                }
+                Err(file) => Box::new(file),
+            });
+        }
+        PcapInput::Live { interface, filter } => {
+            let mut live = LiveCapture::open(&interface)
+                .with_context(|| format!("Could not capture on interface {interface}"))?;
+            live.set_filter(filter);
+            install_ctrl_c_handler(live.stopper())?;
+            capture = Some(live);
+        }
+    }
 
-    let handler = |ev: MapiEvent| mapi_state.handle(&ev, renderer);
+    let clock = Clock::default();
+    // so pauses and the time of day are those of the capture
+    match &per_connection {
+        Some(per_connection) => outputs.set_clock(per_connection.clock()),
+        None => outputs.set_clock(clock.clone()),
+    }
+    let packet_time = clock.clone();
+    let handler = |ev: MapiEvent| {
+        if !filter.admit(&ev) {
+            return Ok(());
+        }
+        let now = packet_time.now().unwrap_or_default();
+        for recorder in &mut recorders {
+            recorder.record(&ev, now)?;
+        }
+        match &mut per_connection {
+            Some(per_connection) => per_connection.handle(ev, packet_time.now(), &mut outputs),
+            None => outputs.handle(&ev),
+        }
+    };
     let mut tracker = Tracker::new(handler);
-    pcap::parse_pcap_file(reader, &mut tracker)
+    tracker.set_clock(clock.clone());
+    tracker.set_time_shift(time_shift);
+    tracker.set_strict(strict);
+    tracker.set_check_checksums(checksums);
+    tracker.set_join_mid_stream(join_mid_stream);
+    tracker.set_segments(tcp_debug);
+    let result = match (reader, &mut capture) {
+        (Some(reader), _) => pcap::parse_pcap_file(reader, &mut tracker).map_err(Into::into),
+        (None, Some(capture)) => capture.run(&mut tracker),
+        (None, None) => unreachable!(),
+    };
+    // after Ctrl-C, the last packet may have been cut off halfway
+    let interrupted = stopped.is_some_and(|stopped| stopped.load(Ordering::Relaxed));
+    let result = match result {
+        Err(_) if interrupted => Ok(()),
+        result => result.and_then(|_| Ok(tracker.finish()?)),
+    };
+    let unknown_blocks = tracker.unknown_blocks().clone();
+    drop(tracker);
+    if let (Ok(()), Some(per_connection)) = (&result, &mut per_connection) {
+        per_connection.finish(&mut outputs)?;
+    }
+    // also when parsing failed, the tally tells what was in the file
+    if !unknown_blocks.is_empty() {
+        let summary: Vec<String> = unknown_blocks
+            .iter()
+            .map(|(block_type, tally)| {
+                format!(
+                    "{count} of type 0x{block_type:08X} ({bytes} bytes)",
+                    count = tally.count,
+                    bytes = tally.bytes
+                )
+            })
+            .collect();
+        outputs.message(
+            None,
+            None,
+            format_args!(
+                "{verdict} unknown pcapng blocks: {summary}",
+                verdict = if strict { "REJECTED" } else { "SKIPPED" },
+                summary = summary.join(", "),
+            ),
+        )?;
+    }
+    result?;
+    let now = clock.now().unwrap_or_default();
+    for recorder in recorders {
+        recorder.finish(now)?;
+    }
+    check_protocol_errors(&outputs)
+}
+
+/// Stdin as a [File], to read it without the buffering of [io::Stdin]. The
+/// pcap parser does its own buffering.
+fn stdin_file() -> io::Result<File> {
+    #[cfg(unix)]
+    let owned = io::stdin().as_fd().try_clone_to_owned()?;
+    #[cfg(windows)]
+    let owned = io::stdin().as_handle().try_clone_to_owned()?;
+    Ok(File::from(owned))
+}
+
+/// Log the proxy's internal decisions to stderr. Once for debug level, twice
+/// or more to also see every wakeup, read and write.
+fn install_tracing(verbosity: u32) {
+    let level = match verbosity {
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr)
+        .with_ansi(is_terminal::is_terminal(io::stderr()))
+        .init();
 }
 
 fn install_ctrl_c_handler(trigger: Box<dyn Fn() + Send + Sync>) -> AResult<()> {
+    let trigger: console::Trigger = Arc::from(trigger);
+    let on_ctrl_c = trigger.clone();
     let mut triggered = false;
     let handler = move || {
         if triggered {
+            keys::restore_terminal();
             std::process::exit(1);
         }
         triggered = true;
-        trigger()
+        on_ctrl_c()
     };
     ctrlc::set_handler(handler).with_context(|| "cannot set Ctrl-C handler")?;
+    // after the Ctrl-C handler, so Windows asks this one first
+    console::on_close(trigger).with_context(|| "cannot set console close handler")?;
     Ok(())
 }
 
 fn install_panic_hook() {
     let orig_hook = panic::take_hook();
-    let my_hook = Box::new(move |panic_info: &PanicInfo<'_>| {
+    let my_hook = Box::new(move |panic_info: &PanicHookInfo<'_>| {
         orig_hook(panic_info);
+        keys::restore_terminal();
         process::exit(1);
     });
     panic::set_hook(my_hook);
@@ -1,15 +1,27 @@
+use super::MAX_BLOCK_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analyzer {
+    state: State,
+    /// Block headers are 8 bytes rather than 2, as in protocol 10
+    wide: bool,
+    /// Number of bytes analyzed so far
+    offset: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Analyzer {
+enum State {
     Head {
         boundary: bool,
         was_body: bool,
     },
     PartialHead {
-        byte1: u8,
+        bytes: [u8; 8],
+        have: u8,
     },
     Body {
-        still_needed: u16,
-        len: u16,
+        still_needed: u32,
+        len: u32,
         last: bool,
     },
     Unix0,
@@ -17,23 +29,39 @@ pub enum Analyzer {
 }
 
 impl Analyzer {
+    /// Largest block accepted with 8 byte headers. The protocol allows more
+    /// but anything this large is more likely to be garbage.
+    pub const MAX_WIDE_BLOCK: u64 = 64 * 1024 * 1024;
+
     pub fn new(unix_client: bool) -> Self {
-        if unix_client {
-            Analyzer::Unix0
+        let state = if unix_client {
+            State::Unix0
         } else {
-            Analyzer::Head {
+            State::Head {
                 boundary: true,
                 was_body: false,
             }
+        };
+        Analyzer {
+            state,
+            wide: false,
+            offset: 0,
         }
     }
 
+    /// Switch to the 8 byte block headers of protocol 10. This takes effect
+    /// at the next block header.
+    pub fn set_wide_headers(&mut self) {
+        self.wide = true;
+    }
+
     pub fn split_chunk<'a>(&mut self, data: &mut &'a [u8]) -> Option<&'a [u8]> {
         // self.analyze(data).map(|n| data.split_at(n))
         match self.analyze(data) {
             Some(n) => {
                 let (head, tail) = data.split_at(n);
                 *data = tail;
+                self.offset += n as u64;
                 Some(head)
             }
             None => None,
@@ -41,14 +69,24 @@ impl Analyzer {
     }
 
     fn analyze(&mut self, data: &[u8]) -> Option<usize> {
-        use Analyzer::*;
-
-        let (taken, new_state) = match (&self, data) {
-            (Head { .. }, [byte1, byte2, ..]) => (2, Self::parse_header(byte1, byte2)),
-
-            (Head { .. }, [byte1]) => (1, Self::PartialHead { byte1: *byte1 }),
-
-            (PartialHead { byte1 }, [byte2, ..]) => (1, Self::parse_header(byte1, byte2)),
+        use State::*;
+
+        let (taken, new_state) = match (&self.state, data) {
+            (Head { .. } | PartialHead { .. }, [_, ..]) => {
+                let (mut bytes, have) = match self.state {
+                    PartialHead { bytes, have } => (bytes, have as usize),
+                    _ => ([0; 8], 0),
+                };
+                let size = if self.wide { 8 } else { 2 };
+                let n = (size - have).min(data.len());
+                bytes[have..have + n].copy_from_slice(&data[..n]);
+                if have + n < size {
+                    let have = (have + n) as u8;
+                    (n, PartialHead { bytes, have })
+                } else {
+                    (n, self.parse_header(bytes))
+                }
+            }
 
             (
                 Body {
@@ -56,7 +94,7 @@ impl Analyzer {
                 },
                 _,
             ) if *still_needed as usize <= data.len() => (
-                *still_needed,
+                *still_needed as usize,
                 Head {
                     was_body: true,
                     boundary: *last,
@@ -71,10 +109,10 @@ impl Analyzer {
                 },
                 [_byte1, ..],
             ) => {
-                let n =
-                    u16::try_from(data.len()).expect("large data slices handled in previous case");
+                // smaller than still_needed so it fits
+                let n = data.len() as u32;
                 (
-                    n,
+                    data.len(),
                     Body {
                         still_needed: still_needed - n,
                         len: *len,
@@ -85,46 +123,53 @@ impl Analyzer {
 
             (_, []) => return None,
 
-            (Error, _) => (u16::try_from(data.len()).unwrap_or(u16::MAX), Error),
+            (Error, _) => (data.len(), Error),
 
             (Unix0, [0x30, ..]) => (
                 1,
-                Self::Head {
+                Head {
                     was_body: false,
                     boundary: true,
                 },
             ),
 
-            (Unix0, [_, ..]) => (1, Self::Error),
+            (Unix0, [_, ..]) => (1, Error),
         };
-        *self = new_state;
-        Some(taken as usize)
+        self.state = new_state;
+        Some(taken)
     }
 
-    fn parse_header(byte1: &u8, byte2: &u8) -> Analyzer {
+    fn parse_header(&self, bytes: [u8; 8]) -> State {
         // little endian
-        let n = *byte1 as u16 + 256 * *byte2 as u16;
+        let (n, max) = if self.wide {
+            (u64::from_le_bytes(bytes), Self::MAX_WIDE_BLOCK)
+        } else {
+            (
+                u16::from_le_bytes([bytes[0], bytes[1]]) as u64,
+                MAX_BLOCK_SIZE as u64,
+            )
+        };
         let len = n / 2;
-        if len <= 8190 {
+        if len <= max {
             let last = n & 1 > 0;
-            Self::Body {
-                still_needed: len,
-                len,
+            State::Body {
+                still_needed: len as u32,
+                len: len as u32,
                 last,
             }
         } else {
-            Self::Error
+            State::Error
         }
     }
 
     pub fn was_error(&self) -> bool {
-        matches!(self, Self::Error)
+        matches!(self.state, State::Error)
     }
 
     pub fn was_head(&self) -> bool {
-        match self {
-            Self::PartialHead { .. } => true,
-            Self::Body {
+        match self.state {
+            State::PartialHead { .. } => true,
+            State::Body {
                 still_needed, len, ..
             } => still_needed == len,
             _ => false,
@@ -132,34 +177,79 @@ impl Analyzer {
     }
 
     pub fn was_body(&self) -> bool {
-        match self {
-            Self::Body {
+        match self.state {
+            State::Body {
                 still_needed, len, ..
             } => still_needed < len,
-            Self::Head { was_body, .. } => *was_body,
+            State::Head { was_body, .. } => was_body,
             _ => false,
         }
     }
 
     pub fn was_block_boundary(&self) -> bool {
-        matches!(self, Self::Head { .. })
+        matches!(self.state, State::Head { .. })
     }
 
     pub fn was_message_boundary(&self) -> bool {
-        matches!(self, Self::Head { boundary: true, .. })
+        matches!(self.state, State::Head { boundary: true, .. })
+    }
+
+    /// Number of bytes of the stream analyzed so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Where in the framing the next byte falls, for example "at the start of
+    /// a message" or "at byte 10 of a 8190 byte block".
+    pub fn describe_position(&self) -> String {
+        match &self.state {
+            State::Head { boundary: true, .. } => "at the start of a message".to_string(),
+            State::Head {
+                boundary: false, ..
+            } => "at the start of a block".to_string(),
+            State::PartialHead { .. } => "in the middle of a block header".to_string(),
+            State::Body {
+                still_needed,
+                len,
+                last,
+            } => {
+                let kind = if *last { "last block" } else { "block" };
+                format!("at byte {} of a {len} byte {kind}", len - still_needed)
+            }
+            State::Unix0 => "before the initial '0' byte".to_string(),
+            State::Error => "after a protocol error".to_string(),
+        }
     }
 
     pub fn check_incomplete(&self) -> Result<(), &'static str> {
-        let msg = match self {
-            Analyzer::Head { boundary: true, .. } => return Ok(()),
-            Analyzer::Head {
+        let msg = match self.state {
+            State::Head { boundary: true, .. } => return Ok(()),
+            State::Head {
                 boundary: false, ..
             } => "on a block boundary but not on a message boundary",
-            Analyzer::PartialHead { .. } => "in the middle of the header block",
-            Analyzer::Body { last: false, .. } => "in the middle of a block",
-            Analyzer::Body { last: true, .. } => "in the middle of the last block of the message",
-            Analyzer::Error | Analyzer::Unix0 => return Ok(()),
+            State::PartialHead { .. } => "in the middle of the header block",
+            State::Body { last: false, .. } => "in the middle of a block",
+            State::Body { last: true, .. } => "in the middle of the last block of the message",
+            State::Error | State::Unix0 => return Ok(()),
         };
         Err(msg)
     }
 }
+
+#[test]
+fn test_wide_headers() {
+    let mut analyzer = Analyzer::new(false);
+    let mut data: &[u8] = &[5, 0, b'h', b'i'];
+    analyzer.split_chunk(&mut data);
+    assert_eq!(analyzer.split_chunk(&mut data), Some(&b"hi"[..]));
+    assert!(analyzer.was_message_boundary());
+
+    analyzer.set_wide_headers();
+    let mut data: &[u8] = &[7, 0, 0];
+    assert_eq!(analyzer.split_chunk(&mut data).map(<[u8]>::len), Some(3));
+    assert!(analyzer.was_head());
+    let mut data: &[u8] = &[0, 0, 0, 0, 0, b'a', b'b', b'c'];
+    assert_eq!(analyzer.split_chunk(&mut data).map(<[u8]>::len), Some(5));
+    assert_eq!(analyzer.split_chunk(&mut data), Some(&b"abc"[..]));
+    assert!(analyzer.was_body() && analyzer.was_message_boundary());
+}
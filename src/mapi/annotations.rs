@@ -0,0 +1,103 @@
+//! Notes about positions in the traffic, see
+//! [State::set_annotations](super::State::set_annotations).
+
+use crate::proxy::event::{ConnectionId, Direction};
+
+/// A note about the byte at `offset` in one direction of a connection,
+/// counting from the first byte sent that way. These are the positions shown
+/// in `--raw` mode and in protocol errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    pub id: ConnectionId,
+    pub direction: Direction,
+    pub offset: u64,
+    pub note: String,
+}
+
+impl Annotation {
+    /// Parse one annotation per line, written as
+    /// `CONNECTION DIRECTION OFFSET NOTE`, for example
+    /// `#10 downstream 8192 the second block is late`. Empty lines and lines
+    /// starting with `--` are skipped.
+    pub fn parse_all(text: &str) -> Result<Vec<Annotation>, String> {
+        let mut annotations = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("--") {
+                continue;
+            }
+            let annotation = Self::parse(line).map_err(|e| format!("line {}: {e}", n + 1))?;
+            annotations.push(annotation);
+        }
+        Ok(annotations)
+    }
+
+    fn parse(line: &str) -> Result<Annotation, String> {
+        let mut rest = line;
+        let mut words = [""; 3];
+        for word in &mut words {
+            let Some((first, after)) = rest.split_once(char::is_whitespace) else {
+                return Err("expected CONNECTION DIRECTION OFFSET NOTE".to_string());
+            };
+            *word = first;
+            rest = after.trim_start();
+        }
+        let [id, direction, offset] = words;
+        // as copied from the output, possibly with a source name before the #
+        let number = id.rsplit_once('#').map_or(id, |(_, number)| number);
+        let Ok(id) = number.parse() else {
+            return Err(format!("{id}: not a connection number"));
+        };
+        let direction = match direction {
+            "upstream" | "up" => Direction::Upstream,
+            "downstream" | "down" => Direction::Downstream,
+            _ => return Err(format!("{direction}: must be 'upstream' or 'downstream'")),
+        };
+        let Ok(offset) = offset.parse() else {
+            return Err(format!("{offset}: not a byte offset"));
+        };
+        let note = rest.trim();
+        if note.is_empty() {
+            return Err("the note is missing".to_string());
+        }
+        Ok(Annotation {
+            id: ConnectionId::new(id),
+            direction,
+            offset,
+            note: note.to_string(),
+        })
+    }
+}
+
+#[test]
+fn test_parse_annotations() {
+    let text = "-- found while looking at issue 42\n\n#10 down 8192 the second block is late\n11 upstream 0  login\nfileA#12 up 3 copied from --source-name output\n";
+    let annotations = Annotation::parse_all(text).unwrap();
+    assert_eq!(
+        annotations,
+        vec![
+            Annotation {
+                id: ConnectionId::new(10),
+                direction: Direction::Downstream,
+                offset: 8192,
+                note: "the second block is late".to_string()
+            },
+            Annotation {
+                id: ConnectionId::new(11),
+                direction: Direction::Upstream,
+                offset: 0,
+                note: "login".to_string()
+            },
+            Annotation {
+                id: ConnectionId::new(12),
+                direction: Direction::Upstream,
+                offset: 3,
+                note: "copied from --source-name output".to_string()
+            },
+        ]
+    );
+
+    let err = Annotation::parse_all("#10 sideways 0 what").unwrap_err();
+    assert_eq!(err, "line 1: sideways: must be 'upstream' or 'downstream'");
+    assert!(Annotation::parse_all("#10 up 12").is_err());
+}
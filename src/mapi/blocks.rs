@@ -0,0 +1,63 @@
+//! Helpers for producing and consuming MAPI blocks directly, for example to
+//! rewrite or generate traffic.
+
+use std::io::{self, ErrorKind, Read};
+
+use super::ProtocolError;
+
+/// The maximum number of payload bytes in a single block.
+pub const MAX_BLOCK_SIZE: usize = 8190;
+
+/// Append the message to `out`, split into blocks with block headers.
+pub fn encode_message(message: &[u8], out: &mut Vec<u8>) {
+    let mut chunks = message.chunks(MAX_BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0]);
+        return;
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none() as usize;
+        let header = (chunk.len() * 2 + last) as u16;
+        out.extend_from_slice(&header.to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Read blocks from `rd` until a whole message has been received and return
+/// it without the block headers. Returns None if the stream ends before the
+/// message starts. If it ends in the middle of the message, the error holds
+/// a [ProtocolError] whose offset is counted from the start of the message.
+pub fn read_message(rd: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut message = vec![];
+    let mut offset = 0;
+    loop {
+        let mut header = [0u8; 2];
+        match rd.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if offset == 0 && e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(incomplete(e, offset, "in the middle of a block header")),
+        }
+        offset += header.len() as u64;
+        let header = u16::from_le_bytes(header) as usize;
+        let start = message.len();
+        message.resize(start + header / 2, 0);
+        rd.read_exact(&mut message[start..])
+            .map_err(|e| incomplete(e, offset, "in the middle of a block"))?;
+        offset += (header / 2) as u64;
+        if header & 1 == 1 {
+            return Ok(Some(message));
+        }
+    }
+}
+
+/// Wrap a [ProtocolError] in `err` if it is about the stream ending early.
+fn incomplete(err: io::Error, offset: u64, situation: &str) -> io::Error {
+    if err.kind() != ErrorKind::UnexpectedEof {
+        return err;
+    }
+    let situation = format!("stream ended {situation}");
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        ProtocolError { offset, situation },
+    )
+}
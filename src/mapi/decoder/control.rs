@@ -0,0 +1,28 @@
+//! The control language of monetdbd, used by `monetdbd` and `monetdb` to
+//! manage databases remotely. Responses are `OK`, data lines starting with
+//! `=` or errors starting with `!`, which are highlighted.
+
+use std::io;
+
+use crate::render::{Renderer, Style};
+
+use super::{dump_text, Decoder};
+
+#[derive(Debug)]
+pub struct Control;
+
+impl Decoder for Control {
+    fn language(&self) -> &'static str {
+        "control"
+    }
+
+    fn dump_line(&self, line: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+        if !line.starts_with(b"!") {
+            return dump_text(line, renderer);
+        }
+        let old_style = renderer.style(Style::Error)?;
+        dump_text(line, renderer)?;
+        renderer.style(old_style)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+//! MAL, the language of the MonetDB kernel, as spoken by `mclient -lmal`.
+//! Its results use the same rows as SQL.
+
+use std::io;
+
+use crate::render::Renderer;
+
+use super::{dump_text, sql::dump_row, Decoder};
+
+#[derive(Debug)]
+pub struct Mal;
+
+impl Decoder for Mal {
+    fn language(&self) -> &'static str {
+        "mal"
+    }
+
+    fn dump_line(&self, line: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+        if line.starts_with(b"[") {
+            dump_row(line, renderer.width(), renderer)
+        } else {
+            dump_text(line, renderer)
+        }
+    }
+}
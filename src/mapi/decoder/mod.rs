@@ -0,0 +1,75 @@
+//! Rendering of the text of messages, which depends on the language the
+//! client speaks. The language comes from the login, see
+//! [Protocol::language](super::Protocol::language). Each language has a
+//! module here with a [Decoder], listed in [DECODERS].
+
+mod control;
+mod mal;
+mod profiler;
+mod sql;
+
+use std::{fmt, io};
+
+use crate::render::{Glyph, Renderer};
+
+/// Renders the lines of text frames for one language.
+pub trait Decoder: fmt::Debug + Sync {
+    /// The name of the language in the login message, for example `sql`.
+    fn language(&self) -> &'static str;
+
+    /// Put one line of a text frame, including its newline if it has one.
+    /// By default the line is shown as it is.
+    fn dump_line(&self, line: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+        dump_text(line, renderer)
+    }
+
+    /// Look for inconsistencies in a complete message from the server, such
+    /// as a result set with fewer rows than its header announces. Returns a
+    /// description of the first one found.
+    fn check_response(&self, _message: &[u8]) -> Option<String> {
+        None
+    }
+}
+
+/// The decoders of all languages mapiproxy knows about.
+pub static DECODERS: [&dyn Decoder; 4] =
+    [&sql::Sql, &mal::Mal, &profiler::Profiler, &control::Control];
+
+/// The decoder for the given language. Until the login has been seen, and for
+/// languages without a decoder of their own, the text is rendered as SQL.
+pub fn decoder_for(language: &str) -> &'static dyn Decoder {
+    DECODERS
+        .iter()
+        .copied()
+        .find(|d| d.language() == language)
+        .unwrap_or(&sql::Sql)
+}
+
+/// Put text, showing newlines and tabs as glyphs.
+fn dump_text(mut data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+    while !data.is_empty() {
+        // pass everything up to the next special character in one go
+        let n = data
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\t')
+            .unwrap_or(data.len());
+        if n > 0 {
+            renderer.put(&data[..n])?;
+            renderer.count_bytes(n);
+        }
+        match data.get(n) {
+            Some(b'\n') => {
+                renderer.put_glyph(Glyph::Newline)?;
+                renderer.count_bytes(1);
+                renderer.nl()?;
+            }
+            Some(b'\t') => {
+                renderer.put_glyph(Glyph::Tab)?;
+                renderer.count_bytes(1);
+            }
+            _ => break,
+        }
+        data = &data[n + 1..];
+    }
+    Ok(())
+}
@@ -0,0 +1,14 @@
+//! The profiler stream, which sends an event in JSON for each instruction
+//! the server executes. JSON arrays are not result rows, so the lines are
+//! shown as they are.
+
+use super::Decoder;
+
+#[derive(Debug)]
+pub struct Profiler;
+
+impl Decoder for Profiler {
+    fn language(&self) -> &'static str {
+        "profiler"
+    }
+}
@@ -0,0 +1,197 @@
+//! SQL, the default. Result sets are sent as rows of the form
+//! `[ 1,\t"one"\t]`, which are folded between columns when they do not fit
+//! and have their special values highlighted.
+
+use std::io;
+
+use crate::render::{Glyph, Renderer, Style};
+
+use super::{dump_text, Decoder};
+
+#[derive(Debug)]
+pub struct Sql;
+
+impl Decoder for Sql {
+    fn language(&self) -> &'static str {
+        "sql"
+    }
+
+    fn dump_line(&self, line: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+        if line.starts_with(b"[") {
+            dump_row(line, renderer.width(), renderer)
+        } else {
+            dump_text(line, renderer)
+        }
+    }
+
+    fn check_response(&self, message: &[u8]) -> Option<String> {
+        check_row_counts(message)
+    }
+}
+
+/// Compare the number of rows following each result set header with the
+/// number the header announces. `&1 id rows columns tuples ...` starts a
+/// result set and `&6 id columns tuples offset` continues one after an
+/// Xexport, in both cases `tuples` rows follow in this message.
+fn check_row_counts(message: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(message);
+    // the header of the result set being counted, the rows it announces and
+    // the rows seen so far
+    let mut current: Option<(&str, u64, u64)> = None;
+    let check = |current: Option<(&str, u64, u64)>| match current {
+        Some((header, expected, seen)) if seen != expected => Some(format!(
+            "'{header}' announces {expected} rows but {seen} follow"
+        )),
+        _ => None,
+    };
+    for line in text.lines() {
+        if line.starts_with('[') || line.starts_with('=') {
+            if let Some((_, _, seen)) = &mut current {
+                *seen += 1;
+            }
+            continue;
+        }
+        if line.starts_with('%') {
+            continue;
+        }
+        if let Some(problem) = check(current.take()) {
+            return Some(problem);
+        }
+        let fields: Vec<&str> = line.split(' ').collect();
+        let tuples = match fields[0] {
+            "&1" => fields.get(4),
+            "&6" => fields.get(3),
+            _ => None,
+        };
+        if let Some(Ok(expected)) = tuples.map(|n| n.parse()) {
+            current = Some((line, expected, 0));
+        }
+    }
+    check(current)
+}
+
+/// Put a row of a result set. If it is wider than `width`, continue it on
+/// the next line, but only between two columns.
+pub(super) fn dump_row(
+    line: &[u8],
+    width: Option<usize>,
+    renderer: &mut Renderer,
+) -> io::Result<()> {
+    const INDENT: &str = "  ";
+    let tab = renderer.glyph_width(Glyph::Tab);
+    let newline = renderer.glyph_width(Glyph::Newline);
+    let measure = |text: &[u8]| -> usize {
+        text.iter()
+            .map(|&b| match b {
+                b'\t' => tab,
+                b'\n' => newline,
+                // UTF-8 continuation byte
+                _ if b & 0xC0 == 0x80 => 0,
+                _ => 1,
+            })
+            .sum()
+    };
+    // leave room for the frame
+    let fold_at = width
+        .map(|w| w.saturating_sub(1))
+        .filter(|&available| measure(line) > available);
+
+    let mut col = 0;
+    let mut rest = line;
+    while !rest.is_empty() {
+        // columns are separated by a comma and a tab
+        let n = rest
+            .windows(2)
+            .position(|w| w == b",\t")
+            .map_or(rest.len(), |i| i + 2);
+        let (column, tail) = rest.split_at(n);
+        let w = measure(column);
+        if fold_at.is_some_and(|available| col > 0 && col + w > available) {
+            renderer.fold(INDENT)?;
+            col = INDENT.len();
+        }
+        dump_column(column, renderer)?;
+        col += w;
+        rest = tail;
+    }
+    Ok(())
+}
+
+/// Put a column of a row, highlighting values that often point at
+/// problems with the data: NULL, empty strings, NaN, infinity and control
+/// characters.
+fn dump_column(column: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+    // the first column starts with "[ ", the value is followed by ",\t"
+    // or by "\t]" and a newline
+    let start = if column.starts_with(b"[ ") { 2 } else { 0 };
+    let end = [b",\t".as_slice(), b"\t]\n", b"\t]"]
+        .iter()
+        .find(|suffix| column.ends_with(suffix))
+        .map_or(column.len(), |suffix| column.len() - suffix.len());
+    if start > end {
+        return dump_text(column, renderer);
+    }
+    let value = &column[start..end];
+    dump_text(&column[..start], renderer)?;
+    let special = [b"NULL".as_slice(), b"\"\"", b"nan", b"inf", b"-inf"];
+    if special.contains(&value) {
+        let old_style = renderer.style(Style::Special)?;
+        dump_text(value, renderer)?;
+        renderer.style(old_style)?;
+    } else if value.starts_with(b"\"") {
+        dump_string(value, renderer)?;
+    } else {
+        dump_text(value, renderer)?;
+    }
+    dump_text(&column[end..], renderer)
+}
+
+/// Put a quoted string value, highlighting escaped control characters
+/// such as \n and \000.
+fn dump_string(mut value: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+    while let Some(i) = value.iter().position(|&b| b == b'\\') {
+        let escape_len = match value.get(i + 1) {
+            None => 1,
+            // not a control character
+            Some(b'\\' | b'"') => 2,
+            Some(b'0'..=b'7') => {
+                1 + value[i + 1..]
+                    .iter()
+                    .take(3)
+                    .take_while(|b| matches!(b, b'0'..=b'7'))
+                    .count()
+            }
+            Some(_) => 2,
+        };
+        let (before, rest) = value.split_at(i);
+        let (escape, rest) = rest.split_at(escape_len);
+        dump_text(before, renderer)?;
+        if matches!(escape, b"\\\\" | b"\\\"") {
+            dump_text(escape, renderer)?;
+        } else {
+            let old_style = renderer.style(Style::Special)?;
+            dump_text(escape, renderer)?;
+            renderer.style(old_style)?;
+        }
+        value = rest;
+    }
+    dump_text(value, renderer)
+}
+
+#[test]
+fn test_check_row_counts() {
+    let ok = b"&1 0 3 1 2 0 1 0 0\n% sys.t # table_name\n% x # name\n[ 1\t]\n[ 2\t]\n";
+    assert_eq!(check_row_counts(ok), None);
+    let short = b"&1 0 3 1 2 0 1 0 0\n% sys.t # table_name\n[ 1\t]\n";
+    assert_eq!(
+        check_row_counts(short).unwrap(),
+        "'&1 0 3 1 2 0 1 0 0' announces 2 rows but 1 follow"
+    );
+    let export = b"&6 0 1 1 2\n[ 3\t]\n[ 4\t]\n&3 1 1\n";
+    assert_eq!(
+        check_row_counts(export).unwrap(),
+        "'&6 0 1 1 2' announces 1 rows but 2 follow"
+    );
+    assert_eq!(check_row_counts(b"&2 1 -1\n"), None);
+    assert_eq!(check_row_counts(b"!42000!syntax error\n"), None);
+}
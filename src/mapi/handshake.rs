@@ -0,0 +1,210 @@
+//! Works out which version of the MAPI protocol a connection speaks, from the
+//! challenge of the server and the login of the client.
+
+use std::fmt;
+
+use crate::proxy::event::Direction;
+
+use super::Analyzer;
+
+/// What the handshake revealed about the protocol of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    /// The kind of server, usually mserver or merovingian
+    pub server: String,
+    /// The version in the challenge, 8 or 9
+    pub version: u32,
+    /// Set when the client switched to protocol 10, holds the compression
+    /// it asked for
+    pub prot10: Option<String>,
+    /// The language the client logged in with, such as sql or mal
+    pub language: String,
+}
+
+impl Protocol {
+    /// Whether the blocks after the login have 8 byte headers, see
+    /// [Analyzer::set_wide_headers].
+    pub fn wide_headers(&self) -> bool {
+        self.prot10.is_some()
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.prot10 {
+            None => write!(f, "{} ({})", self.version, self.server),
+            Some(compression) => write!(f, "10 ({}, {compression})", self.server),
+        }
+    }
+}
+
+/// Follows the handshake of one connection.
+pub struct Handshake {
+    upstream: Analyzer,
+    downstream: Analyzer,
+    upstream_message: Vec<u8>,
+    downstream_message: Vec<u8>,
+    stage: Stage,
+    /// Where the server sent the client, see [Handshake::take_redirect]
+    redirect: Option<String>,
+}
+
+#[derive(Debug)]
+enum Stage {
+    Challenge,
+    Login {
+        server: String,
+        version: u32,
+        prot10_offered: bool,
+    },
+    Response,
+    Done,
+}
+
+impl Handshake {
+    /// The handshake is not expected to contain larger messages.
+    const MAX_MESSAGE: usize = 8192;
+
+    pub fn new(unix_client: bool) -> Self {
+        Handshake {
+            upstream: Analyzer::new(unix_client),
+            downstream: Analyzer::new(false),
+            upstream_message: vec![],
+            downstream_message: vec![],
+            stage: Stage::Challenge,
+            redirect: None,
+        }
+    }
+
+    /// The URL the server redirected the client to, such as
+    /// `mapi:monetdb://localhost:50001/demo`, if it did since the last call.
+    /// Redirects that make monetdbd proxy the connection itself are not
+    /// included.
+    pub fn take_redirect(&mut self) -> Option<String> {
+        self.redirect.take()
+    }
+
+    /// True once there is nothing more to learn, for example because the
+    /// login has been answered.
+    pub fn is_done(&self) -> bool {
+        matches!(self.stage, Stage::Done)
+    }
+
+    /// Process data sent in the given direction. Returns the protocol when
+    /// the client has logged in. After a redirect by monetdbd in proxy mode,
+    /// the handshake with the server behind it is followed as well.
+    pub fn data(&mut self, direction: Direction, mut data: &[u8]) -> Option<Protocol> {
+        let mut protocol = None;
+        while !self.is_done() {
+            let (analyzer, message) = match direction {
+                Direction::Upstream => (&mut self.upstream, &mut self.upstream_message),
+                Direction::Downstream => (&mut self.downstream, &mut self.downstream_message),
+            };
+            let Some(chunk) = analyzer.split_chunk(&mut data) else {
+                break;
+            };
+            if analyzer.was_error() || message.len() + chunk.len() > Self::MAX_MESSAGE {
+                self.stage = Stage::Done;
+                break;
+            }
+            if !analyzer.was_body() {
+                continue;
+            }
+            message.extend_from_slice(chunk);
+            if !analyzer.was_message_boundary() {
+                continue;
+            }
+            let text = String::from_utf8_lossy(message).into_owned();
+            message.clear();
+            if let Some(found) = self.message_complete(direction, &text) {
+                protocol = Some(found);
+            }
+        }
+        protocol
+    }
+
+    fn message_complete(&mut self, direction: Direction, text: &str) -> Option<Protocol> {
+        let mut protocol = None;
+        let stage = std::mem::replace(&mut self.stage, Stage::Done);
+        self.stage = match (stage, direction) {
+            (Stage::Challenge, Direction::Downstream) => {
+                // salt:mserver:9:hashes,PROT10:LIT:SHA512:...
+                let fields: Vec<&str> = text.split(':').collect();
+                match fields.get(2).and_then(|v| v.parse().ok()) {
+                    Some(version) if fields.len() >= 4 => Stage::Login {
+                        server: fields[1].to_string(),
+                        version,
+                        prot10_offered: fields[3].split(',').any(|h| h == "PROT10"),
+                    },
+                    _ => Stage::Done,
+                }
+            }
+            (
+                Stage::Login {
+                    server,
+                    version,
+                    prot10_offered,
+                },
+                Direction::Upstream,
+            ) => {
+                // byteorder:user:password:lang:database:PROT10:compression:blocksize:
+                let fields: Vec<&str> = text.split(':').collect();
+                let prot10 = match fields.get(5) {
+                    Some(&"PROT10") if prot10_offered => {
+                        Some(fields.get(6).unwrap_or(&"").to_string())
+                    }
+                    _ => None,
+                };
+                let found = Protocol {
+                    server,
+                    version,
+                    prot10,
+                    language: fields.get(3).unwrap_or(&"").to_string(),
+                };
+                if found.wide_headers() {
+                    self.upstream.set_wide_headers();
+                    self.downstream.set_wide_headers();
+                }
+                protocol = Some(found);
+                Stage::Response
+            }
+            (Stage::Response, Direction::Downstream) if text.starts_with("^mapi:merovingian:") => {
+                Stage::Challenge
+            }
+            (Stage::Response, Direction::Downstream) if text.starts_with('^') => {
+                let url = text[1..].lines().next().unwrap_or_default();
+                self.redirect = Some(url.to_string());
+                Stage::Done
+            }
+            _ => Stage::Done,
+        };
+        protocol
+    }
+}
+
+#[test]
+fn test_handshake() {
+    use super::encode_message;
+
+    let mut handshake = Handshake::new(false);
+    let mut challenge = vec![];
+    encode_message(
+        b"salt:mserver:9:RIPEMD160,SHA512,PROT10:LIT:SHA512:",
+        &mut challenge,
+    );
+    assert_eq!(handshake.data(Direction::Downstream, &challenge), None);
+    let mut login = vec![];
+    encode_message(
+        b"LIT:monetdb:{SHA512}abc:sql:demo:PROT10:COMPRESSION_LZ4:1000000:",
+        &mut login,
+    );
+    let protocol = handshake.data(Direction::Upstream, &login).unwrap();
+    assert_eq!(protocol.to_string(), "10 (mserver, COMPRESSION_LZ4)");
+    assert_eq!(protocol.language, "sql");
+    assert!(protocol.wide_headers());
+
+    // the response already uses 8 byte headers
+    let response = [1, 0, 0, 0, 0, 0, 0, 0];
+    assert_eq!(handshake.data(Direction::Downstream, &response), None);
+    assert!(handshake.is_done());
+}
@@ -0,0 +1,39 @@
+use std::io;
+
+use crate::proxy::{
+    event::{ConnectionId, Direction},
+    network::Addr,
+};
+
+/// Callbacks that are consulted by [State](super::State) before it renders
+/// something. They can suppress, annotate or rewrite the rendering of frames.
+/// Returning an error aborts the analysis.
+pub trait Hooks {
+    /// Called when a new connection is observed. Setting [Verdict::drop]
+    /// suppresses the frames of the whole connection and [Verdict::tag] is
+    /// added to the header of all its frames.
+    fn on_connect(&mut self, id: ConnectionId, local: &Addr, peer: &Addr) -> io::Result<Verdict>;
+
+    /// Called for each frame about to be rendered. Depending on the level
+    /// this is a whole message, a block or a chunk of raw data.
+    fn on_message(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<Verdict>;
+}
+
+/// What a [Hooks] implementation wants done with a connection or frame.
+/// The default is to render it as usual.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// Do not render it at all
+    pub drop: bool,
+    /// Extra item to include in the frame header
+    pub tag: Option<String>,
+    /// Message to render after the connection announcement or the frame
+    pub note: Option<String>,
+    /// Render this instead of the actual data
+    pub replacement: Option<Vec<u8>>,
+}
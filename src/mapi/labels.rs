@@ -0,0 +1,168 @@
+//! Labels that make it easier to tell connections apart, see
+//! [State::set_labels](super::State::set_labels).
+
+use std::collections::HashMap;
+
+use lazy_regex::Regex;
+
+use crate::proxy::event::ConnectionId;
+
+use super::Analyzer;
+
+/// Derives labels such as `[user=monetdb, db=demo]` from the first messages
+/// the clients send.
+pub struct Labeler {
+    /// Applied to the first query of each connection
+    regex: Option<Regex>,
+    conns: HashMap<ConnectionId, Finder>,
+}
+
+/// The label of one connection, while it is being worked out.
+struct Finder {
+    analyzer: Analyzer,
+    message: Vec<u8>,
+    items: Vec<String>,
+    stage: Stage,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Stage {
+    Login,
+    Query,
+    Done,
+}
+
+impl Labeler {
+    /// Queries longer than this are not collected in full, the regex is
+    /// applied to the start.
+    const MAX_QUERY: usize = 64 * 1024;
+
+    pub fn new(regex: Option<Regex>) -> Self {
+        Labeler {
+            regex,
+            conns: Default::default(),
+        }
+    }
+
+    pub fn add_connection(&mut self, id: ConnectionId, unix_client: bool) {
+        let finder = Finder {
+            analyzer: Analyzer::new(unix_client),
+            message: vec![],
+            items: vec![],
+            stage: Stage::Login,
+        };
+        self.conns.insert(id, finder);
+    }
+
+    /// Stop looking for a label, for example because data has been dropped.
+    pub fn remove_connection(&mut self, id: ConnectionId) {
+        self.conns.remove(&id);
+    }
+
+    /// Process data sent by the client. Returns the label if it has changed.
+    pub fn upstream_data(&mut self, id: ConnectionId, mut data: &[u8]) -> Option<String> {
+        let finder = self.conns.get_mut(&id)?;
+        let mut changed = false;
+        while finder.stage != Stage::Done {
+            let Some(chunk) = finder.analyzer.split_chunk(&mut data) else {
+                break;
+            };
+            if finder.analyzer.was_error() {
+                finder.stage = Stage::Done;
+                break;
+            }
+            if !finder.analyzer.was_body() {
+                continue;
+            }
+            let room = Self::MAX_QUERY.saturating_sub(finder.message.len());
+            finder
+                .message
+                .extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if finder.analyzer.was_message_boundary() {
+                changed |= finder.message_complete(self.regex.as_ref());
+            }
+        }
+        if finder.stage == Stage::Done {
+            let finder = self.conns.remove(&id)?;
+            return changed.then(|| finder.label());
+        }
+        changed.then(|| finder.label())
+    }
+}
+
+impl Finder {
+    /// Returns true if items were added.
+    fn message_complete(&mut self, regex: Option<&Regex>) -> bool {
+        let message = String::from_utf8_lossy(&self.message).into_owned();
+        self.message.clear();
+        let before = self.items.len();
+        match self.stage {
+            Stage::Login => {
+                // BIG:user:{SHA512}hash:sql:database:...
+                let fields: Vec<&str> = message.split(':').collect();
+                if fields.len() >= 5 {
+                    self.items.push(format!("user={}", fields[1]));
+                    if !fields[4].is_empty() {
+                        self.items.push(format!("db={}", fields[4]));
+                    }
+                }
+                self.stage = if regex.is_some() {
+                    Stage::Query
+                } else {
+                    Stage::Done
+                };
+            }
+            Stage::Query => {
+                let Some(query) = message.strip_prefix('s') else {
+                    // not a query, for example an X command
+                    return false;
+                };
+                if let Some(caps) = regex.and_then(|re| re.captures(query)) {
+                    let regex = regex.unwrap();
+                    let mut named = false;
+                    for name in regex.capture_names().flatten() {
+                        named = true;
+                        if let Some(m) = caps.name(name) {
+                            self.items.push(format!("{name}={}", m.as_str()));
+                        }
+                    }
+                    if !named {
+                        let m = caps.get(1).or(caps.get(0)).unwrap();
+                        self.items.push(m.as_str().to_string());
+                    }
+                }
+                self.stage = Stage::Done;
+            }
+            Stage::Done => {}
+        }
+        self.items.len() > before
+    }
+
+    fn label(&self) -> String {
+        format!("[{}]", self.items.join(", "))
+    }
+}
+
+#[test]
+fn test_labeler() {
+    use super::encode_message;
+
+    let regex = Regex::new(r"/\* app=(?<app>\w+) \*/").unwrap();
+    let mut labeler = Labeler::new(Some(regex));
+    let id = ConnectionId::new(10);
+    labeler.add_connection(id, true);
+
+    let mut login = b"0".to_vec();
+    encode_message(b"BIG:monetdb:{SHA512}abc:sql:demo:\n", &mut login);
+    let (first, rest) = login.split_at(10);
+    assert_eq!(labeler.upstream_data(id, first), None);
+    let label = labeler.upstream_data(id, rest);
+    assert_eq!(label.as_deref(), Some("[user=monetdb, db=demo]"));
+
+    let mut queries = vec![];
+    encode_message(b"Xreply_size 100\n", &mut queries);
+    encode_message(b"s/* app=etl */ SELECT 42;\n", &mut queries);
+    let label = labeler.upstream_data(id, &queries);
+    assert_eq!(label.as_deref(), Some("[user=monetdb, db=demo, app=etl]"));
+    assert!(labeler.conns.is_empty());
+}
@@ -1,23 +1,91 @@
 mod analyzer;
+mod annotations;
+mod blocks;
+mod decoder;
+mod handshake;
+mod hooks;
+mod labels;
+mod redirects;
 
 use std::{
-    collections::HashMap,
-    io::{self, ErrorKind},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, io,
+    time::Duration,
 };
 
+use thiserror::Error as ThisError;
+
 use crate::{
-    proxy::event::{ConnectionId, Direction, MapiEvent},
-    render::{Renderer, Style},
+    proxy::{
+        event::{ConnectionId, Direction, MapiEvent},
+        network::Addr,
+    },
+    render::{Glyph, Renderer, Style},
     Level,
 };
 
-use self::analyzer::Analyzer;
+pub use self::analyzer::Analyzer;
+pub use self::annotations::Annotation;
+pub use self::blocks::{encode_message, read_message, MAX_BLOCK_SIZE};
+pub use self::decoder::{decoder_for, Decoder, DECODERS};
+pub use self::handshake::{Handshake, Protocol};
+pub use self::hooks::{Hooks, Verdict};
+pub use self::labels::Labeler;
+use self::redirects::Redirects;
+
+/// A violation of the MAPI protocol in what one side of a connection sent.
+/// [read_message] returns it wrapped in an [io::Error], from which it can be
+/// retrieved with [io::Error::get_ref].
+#[derive(Debug, Clone, PartialEq, Eq, ThisError)]
+#[error("{situation} at byte {offset}")]
+pub struct ProtocolError {
+    /// Position in the stream, counting from its first byte
+    pub offset: u64,
+    pub situation: String,
+}
+
+/// How to show the prompts the server sends when it is ready for the next
+/// query or wants more input, see [State::set_prompts].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Prompts {
+    /// As a frame, like any other message
+    #[default]
+    Frame,
+    /// As a single line saying PROMPT
+    Line,
+    /// As a single line for each run of prompts, with their number
+    Collapse,
+    /// Not at all
+    Hide,
+}
 
-#[derive(Debug)]
 pub struct State {
     level: Level,
     force_binary: bool,
+    events_only: bool,
     accs: HashMap<ConnectionId, (Accumulator, Accumulator)>,
+    hooks: Option<Box<dyn Hooks>>,
+    memory_cap: Option<usize>,
+    sample_bytes: Option<usize>,
+    max_capture_bytes: Option<u64>,
+    /// Total number of bytes collected by the accumulators
+    buffered: usize,
+    deterministic: bool,
+    labeler: Option<Labeler>,
+    /// Connections whose protocol version is still being worked out
+    handshakes: HashMap<ConnectionId, Handshake>,
+    /// Number of protocol errors found in the traffic so far
+    protocol_errors: usize,
+    /// Annotations of connections that have not started yet
+    annotations: HashMap<ConnectionId, Vec<Annotation>>,
+    /// Gather raw data into fewer dumps, see [State::set_condensed]
+    condensed: bool,
+    prompts: Prompts,
+    redirects: Redirects,
+    /// Only these connections are rendered, see [State::set_shown_connections]
+    shown: Option<HashSet<ConnectionId>>,
+    /// Renders the other connections into the void
+    hidden: Option<Box<Renderer>>,
 }
 
 impl State {
@@ -25,23 +93,330 @@ impl State {
         State {
             level,
             force_binary,
+            events_only: false,
             accs: Default::default(),
+            hooks: None,
+            memory_cap: None,
+            sample_bytes: None,
+            max_capture_bytes: None,
+            buffered: 0,
+            deterministic: false,
+            labeler: None,
+            handshakes: Default::default(),
+            protocol_errors: 0,
+            annotations: Default::default(),
+            condensed: false,
+            prompts: Prompts::default(),
+            redirects: Redirects::default(),
+            shown: None,
+            hidden: None,
+        }
+    }
+
+    /// Only render connections coming and going, not the data they exchange.
+    pub fn set_events_only(&mut self, events_only: bool) {
+        self.events_only = events_only;
+    }
+
+    /// Whether the data events are ignored, see [State::set_events_only].
+    pub fn events_only(&self) -> bool {
+        self.events_only
+    }
+
+    /// Consult the given [Hooks] before rendering connections and frames.
+    pub fn set_hooks(&mut self, hooks: Box<dyn Hooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Limit the number of bytes collected while waiting for the end of a
+    /// message or block. When the limit is exceeded, the largest partial
+    /// frames are rendered abbreviated instead of being collected further.
+    pub fn set_memory_cap(&mut self, cap: Option<usize>) {
+        self.memory_cap = cap;
+    }
+
+    pub fn memory_cap(&self) -> Option<usize> {
+        self.memory_cap
+    }
+
+    /// After rendering `n` bytes in one direction of a connection, only count
+    /// the data that follows and mention now and then how much was left out.
+    /// Rendering resumes, for both directions, when the data holds a protocol
+    /// error or an error response from the server.
+    pub fn set_sample_bytes(&mut self, n: Option<usize>) {
+        self.sample_bytes = n;
+    }
+
+    /// After rendering `n` bytes of a connection, counting both directions,
+    /// only count the data that follows, for the rest of the connection.
+    /// Unlike [State::set_sample_bytes], rendering never resumes.
+    pub fn set_max_capture_bytes(&mut self, n: Option<u64>) {
+        self.max_capture_bytes = n;
+    }
+
+    /// Show the notes after the frames holding the bytes they are about. In
+    /// `--raw` mode that is the chunk holding the byte. Notes about bytes
+    /// that never arrive are shown when the connection ends.
+    pub fn set_annotations(&mut self, annotations: Vec<Annotation>) {
+        self.annotations.clear();
+        for annotation in annotations {
+            self.annotations
+                .entry(annotation.id)
+                .or_default()
+                .push(annotation);
+        }
+    }
+
+    /// Gather the data of each direction of a connection shown `--raw` into
+    /// one dump that only shows the first bytes and counts the rest, for
+    /// when rendering cannot keep up. The dump is shown when enough data has
+    /// been gathered, when data flows the other way, on any other event of
+    /// the connection and when condensing is switched off again. Connections
+    /// that [Hooks] get to see are not condensed.
+    pub fn set_condensed(&mut self, condensed: bool, renderer: &mut Renderer) -> io::Result<()> {
+        self.condensed = condensed;
+        for (id, (upstream, downstream)) in self.accs.iter_mut() {
+            let renderer = match &mut self.hidden {
+                Some(hidden) if self.shown.as_ref().is_some_and(|ids| !ids.contains(id)) => hidden,
+                _ => &mut *renderer,
+            };
+            for acc in [upstream, downstream] {
+                acc.condensed = condensed;
+                if !condensed {
+                    acc.flush_gathered(renderer)?;
+                }
+            }
         }
+        Ok(())
+    }
+
+    /// Whether the data is shown as it comes in, which is what
+    /// [State::set_condensed] is about.
+    pub fn shows_raw(&self) -> bool {
+        self.level == Level::Raw && !self.events_only
+    }
+
+    /// Show the messages in which the server only prompts for the next query
+    /// or for more input differently from other messages. This only applies
+    /// to `--messages` mode. Prompts that are not shown as frames are not
+    /// passed to the [Hooks].
+    pub fn set_prompts(&mut self, prompts: Prompts) {
+        self.prompts = prompts;
+    }
+
+    /// Only render the connections with these numbers. The others are still
+    /// followed as usual, so their protocol errors are counted and their
+    /// data count toward the memory cap, but what would be rendered for
+    /// them is thrown away.
+    pub fn set_shown_connections(&mut self, ids: Option<HashSet<ConnectionId>>) {
+        self.hidden = ids
+            .as_ref()
+            .map(|_| Box::new(Renderer::new(false, io::sink())));
+        self.shown = ids;
+    }
+
+    fn is_shown(&self, id: ConnectionId) -> bool {
+        self.shown.as_ref().is_none_or(|ids| ids.contains(&id))
+    }
+
+    /// Number of bytes currently collected, see [State::set_memory_cap].
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Number of protocol errors found in the traffic so far. Each direction
+    /// of a connection counts at most once for garbled data, plus once if it
+    /// ends in the middle of a message.
+    pub fn protocol_errors(&self) -> usize {
+        self.protocol_errors
+    }
+
+    /// Leave out details that differ from run to run or between systems, such
+    /// as the port numbers of clients and operating system error codes. See
+    /// also [Renderer::set_deterministic].
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Label connections with the user and database from the login and
+    /// whatever the [Labeler]'s regex picks out of the first query. The labels
+    /// are shown after the connection id from then on.
+    pub fn set_labels(&mut self, labeler: Option<Labeler>) {
+        self.labeler = labeler;
     }
 
     pub fn handle(&mut self, event: &MapiEvent, renderer: &mut Renderer) -> io::Result<()> {
+        if event.connection_id().is_some_and(|id| !self.is_shown(id)) {
+            if let Some(mut hidden) = self.hidden.take() {
+                let result = self.handle_event(event, &mut hidden);
+                self.hidden = Some(hidden);
+                return result;
+            }
+        }
+        self.handle_event(event, renderer)
+    }
+
+    fn handle_event(&mut self, event: &MapiEvent, renderer: &mut Renderer) -> io::Result<()> {
+        if self.events_only
+            && matches!(
+                event,
+                MapiEvent::Data { .. }
+                    | MapiEvent::Rewritten { .. }
+                    | MapiEvent::Dropped { .. }
+                    | MapiEvent::Retransmitted { .. }
+                    | MapiEvent::Level { .. }
+            )
+        {
+            return Ok(());
+        }
+
+        if let (false, Some(id)) = (
+            matches!(event, MapiEvent::Data { .. }),
+            event.connection_id(),
+        ) {
+            if let Some((upstream, downstream)) = self.accs.get_mut(&id) {
+                upstream.flush_held(renderer)?;
+                downstream.flush_held(renderer)?;
+            }
+        }
+
         match event {
             MapiEvent::BoundPort(port) => {
                 renderer.message(None, None, format_args!("LISTEN on port {port}"))?;
             }
 
+            MapiEvent::BindFailed { local, error } => {
+                let error = self.stable_text(error);
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("LISTEN FAILED on {local}: {error}"),
+                )?;
+            }
+
+            MapiEvent::AcceptPaused { local, error } => {
+                let error = self.stable_text(error);
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("PAUSED accepting on {local}: {error}"),
+                )?;
+            }
+
+            MapiEvent::AcceptResumed { local } => {
+                renderer.message(None, None, format_args!("RESUMED accepting on {local}"))?;
+            }
+
+            MapiEvent::PrimaryDown {
+                primary,
+                standby,
+                error,
+            } => {
+                let error = self.stable_text(error);
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("PRIMARY DOWN: {primary}: {error}, switching to {standby}"),
+                )?;
+            }
+
+            MapiEvent::PrimaryUp { primary } => {
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("PRIMARY UP: switching back to {primary}"),
+                )?;
+            }
+
+            MapiEvent::CaptureInterface {
+                interface,
+                name,
+                description,
+                os,
+                filter,
+            } => {
+                let mut text = format!("CAPTURE INTERFACE {interface}");
+                if let Some(name) = name {
+                    text += &format!(": {name}");
+                }
+                if let Some(description) = description {
+                    text += &format!(" ({description})");
+                }
+                if let Some(os) = os {
+                    text += &format!(" on {os}");
+                }
+                if let Some(filter) = filter {
+                    text += &format!(", filter '{filter}'");
+                }
+                renderer.message(None, None, text)?;
+            }
+
+            MapiEvent::CaptureStatistics {
+                interface,
+                received,
+                interface_dropped,
+                os_dropped,
+            } => {
+                let mut parts = vec![];
+                if let Some(n) = received {
+                    parts.push(format!("{n} packets received"));
+                }
+                if let Some(n) = interface_dropped {
+                    parts.push(format!("{n} dropped by the interface"));
+                }
+                if let Some(n) = os_dropped {
+                    parts.push(format!("{n} dropped by the OS"));
+                }
+                if parts.is_empty() {
+                    parts.push("no packet counts".to_string());
+                }
+                let dropped = interface_dropped
+                    .unwrap_or(0)
+                    .saturating_add(os_dropped.unwrap_or(0));
+                if dropped > 0 {
+                    parts.push("expect protocol errors where packets are missing".to_string());
+                }
+                renderer.message(
+                    None,
+                    None,
+                    format_args!(
+                        "CAPTURE STATISTICS interface {interface}: {}",
+                        parts.join(", ")
+                    ),
+                )?;
+            }
+
+            MapiEvent::Failed { error } => {
+                let error = self.stable_text(error);
+                renderer.message(None, None, format_args!("FAILED: {error}"))?;
+            }
+
             MapiEvent::Incoming { id, local, peer } => {
+                let shown_peer = self.stable_peer(peer);
                 renderer.message(
                     Some(*id),
                     None,
-                    format_args!("INCOMING on {local} from {peer}"),
+                    format_args!("INCOMING on {local} from {shown_peer}"),
                 )?;
+                if let Some((first, url)) = self.redirects.incoming(local, peer) {
+                    let msg = format_args!("CONTINUES {first} after its redirect to {url}");
+                    renderer.message(Some(*id), None, msg)?;
+                }
+                self.redirects.add_connection(*id, peer);
                 self.add_connection(id, peer.is_unix());
+                self.handshakes.insert(*id, Handshake::new(peer.is_unix()));
+                if let Some(labeler) = &mut self.labeler {
+                    labeler.add_connection(*id, peer.is_unix());
+                }
+                if let Some(hooks) = &mut self.hooks {
+                    let verdict = hooks.on_connect(*id, local, peer)?;
+                    if let Some(note) = &verdict.note {
+                        renderer.message(Some(*id), None, note)?;
+                    }
+                    let (upstream, downstream) = self.accs.get_mut(id).unwrap();
+                    upstream.apply_connect_verdict(&verdict);
+                    downstream.apply_connect_verdict(&verdict);
+                }
             }
 
             MapiEvent::Connecting { id, remote } => {
@@ -52,6 +427,52 @@ impl State {
                 renderer.message(Some(*id), None, "CONNECTED")?;
             }
 
+            MapiEvent::Joined { id } => {
+                renderer.message(
+                    Some(*id),
+                    None,
+                    "JOINED mid-stream, the start of the connection was not captured",
+                )?;
+            }
+
+            MapiEvent::Mirroring { id, of } => {
+                let msg = format_args!("MIRROR of {of}, the responses of its server are discarded");
+                renderer.message(Some(*id), None, msg)?;
+            }
+
+            MapiEvent::Redirected { id, to } => {
+                renderer.message(Some(*id), None, format_args!("REDIRECTED to {to}"))?;
+            }
+
+            MapiEvent::OutOfBand { id, byte, error } => match error {
+                None => renderer.message(
+                    Some(*id),
+                    Some(Direction::Upstream),
+                    format_args!("OUT-OF-BAND byte 0x{byte:02x} sent to server"),
+                )?,
+                Some(e) => renderer.message(
+                    Some(*id),
+                    Some(Direction::Upstream),
+                    format_args!(
+                        "OUT-OF-BAND byte 0x{byte:02x} not sent: {}",
+                        self.stable_text(e)
+                    ),
+                )?,
+            },
+
+            MapiEvent::RoundTrip { id, client, server } => {
+                renderer.message(
+                    Some(*id),
+                    None,
+                    format_args!(
+                        "RTT {}: client side {}, server side {}",
+                        millis(*client + *server),
+                        millis(*client),
+                        millis(*server)
+                    ),
+                )?;
+            }
+
             MapiEvent::ConnectFailed {
                 id,
                 remote,
@@ -59,6 +480,7 @@ impl State {
                 error,
             } => {
                 let immediately = if *immediately { " immediately" } else { "" };
+                let error = self.stable_text(error);
                 renderer.message(
                     Some(*id),
                     None,
@@ -68,18 +490,101 @@ impl State {
 
             MapiEvent::End { id } => {
                 renderer.message(Some(*id), None, "ENDED")?;
-                self.remove_connection(id);
+                self.remove_connection(id, renderer)?;
+            }
+
+            MapiEvent::StillOpen { id } => {
+                renderer.message(Some(*id), None, "STILL OPEN at end of capture")?;
+                if let Some((upstream, downstream)) = self.accs.get_mut(id) {
+                    for acc in [upstream, downstream] {
+                        acc.note_unshown("", renderer)?;
+                        acc.flush_incomplete(renderer)?;
+                    }
+                }
+                self.remove_connection(id, renderer)?;
             }
 
             MapiEvent::Aborted { id, error } => {
+                let error = self.stable_text(error);
                 renderer.message(Some(*id), None, format_args!("ABORTED: {error}"))?;
-                self.remove_connection(id);
+                self.remove_connection(id, renderer)?;
             }
 
             MapiEvent::Data {
                 id,
                 direction,
                 data,
+            } => {
+                let Some((upstream, downstream)) = self.accs.get_mut(id) else {
+                    panic!("got data for conn {id} but don't have accumulators for it")
+                };
+                let (acc, other) = match direction {
+                    Direction::Upstream => (upstream, downstream),
+                    Direction::Downstream => (downstream, upstream),
+                };
+                other.flush_held(renderer)?;
+                if let (Some(labeler), Direction::Upstream) = (&mut self.labeler, direction) {
+                    if let Some(label) = labeler.upstream_data(*id, data) {
+                        renderer.set_label(*id, Some(label));
+                    }
+                }
+                let before = acc.buf.len();
+                let had_error = acc.analyzer.was_error();
+                if let Some(limit) = self.max_capture_bytes {
+                    if !acc.capped && acc.captured + other.captured >= limit {
+                        let size = human_bytes(limit);
+                        let msg = format_args!("CAPTURE LIMIT of {size} reached, only counting");
+                        renderer.message(Some(*id), None, msg)?;
+                        self.buffered -= other.buf.len();
+                        other.stop_capture(limit, renderer)?;
+                        acc.stop_capture(limit, renderer)?;
+                    }
+                    acc.captured += data.len() as u64;
+                }
+                let counted = if acc.capped {
+                    acc.count_unshown(data, renderer)?;
+                    true
+                } else {
+                    match self.sample_bytes {
+                        Some(limit) => acc.sample(data, limit, other, renderer)?,
+                        None => false,
+                    }
+                };
+                if !counted {
+                    acc.handle_data(data, renderer, &mut self.hooks)?;
+                }
+                acc.show_notes(false, renderer)?;
+                self.buffered = self.buffered - before + acc.buf.len();
+                if acc.analyzer.was_error() && !had_error {
+                    self.protocol_errors += 1;
+                }
+                self.enforce_memory_cap(self.is_shown(*id), renderer)?;
+                self.follow_handshake(*id, *direction, data, renderer)?;
+            }
+
+            MapiEvent::Rewritten {
+                id,
+                direction,
+                original,
+                messages,
+            } => {
+                let Some((upstream, downstream)) = self.accs.get(id) else {
+                    panic!("got data for conn {id} but don't have accumulators for it")
+                };
+                let acc = match direction {
+                    Direction::Upstream => upstream,
+                    Direction::Downstream => downstream,
+                };
+                acc.handle_rewritten(*original, messages, renderer)?;
+            }
+
+            MapiEvent::Dropped {
+                id,
+                direction,
+                chunks,
+                bytes,
+                sample,
+                analyzer,
             } => {
                 let Some((upstream, downstream)) = self.accs.get_mut(id) else {
                     panic!("got data for conn {id} but don't have accumulators for it")
@@ -88,10 +593,121 @@ impl State {
                     Direction::Upstream => upstream,
                     Direction::Downstream => downstream,
                 };
-                acc.handle_data(data, renderer)?;
+                self.buffered -= acc.buf.len();
+                if let (Some(labeler), Direction::Upstream) = (&mut self.labeler, direction) {
+                    labeler.remove_connection(*id);
+                }
+                self.handshakes.remove(id);
+                acc.handle_dropped(*chunks, *bytes, sample, analyzer, renderer)?;
+            }
+
+            MapiEvent::Retransmitted {
+                id,
+                direction,
+                offset,
+                original,
+                retransmitted,
+            } => {
+                let Some((upstream, downstream)) = self.accs.get(id) else {
+                    panic!("got data for conn {id} but don't have accumulators for it")
+                };
+                let acc = match direction {
+                    Direction::Upstream => upstream,
+                    Direction::Downstream => downstream,
+                };
+                acc.handle_retransmitted(*offset, original, retransmitted, renderer)?;
+            }
+
+            MapiEvent::Note {
+                id,
+                direction,
+                note,
+            } => {
+                renderer.message(Some(*id), *direction, format_args!("NOTE {note}"))?;
+            }
+
+            MapiEvent::Level { id, level } => {
+                let Some((upstream, downstream)) = self.accs.get_mut(id) else {
+                    return Ok(());
+                };
+                upstream.set_level(*level);
+                downstream.set_level(*level);
+                renderer.message(
+                    Some(*id),
+                    None,
+                    format_args!("LEVEL {name} from the next boundary", name = level.name()),
+                )?;
+            }
+
+            MapiEvent::Held { id, direction } => {
+                renderer.message(Some(*id), Some(*direction), "HELD until released")?;
+            }
+
+            MapiEvent::BadChecksum {
+                id,
+                direction,
+                offset,
+                layer,
+            } => {
+                renderer.message(
+                    Some(*id),
+                    Some(*direction),
+                    format_args!("BAD {layer} CHECKSUM in the packet at byte {offset}, the capture may be corrupted"),
+                )?;
+            }
+
+            MapiEvent::KeepAlive { id, direction } => {
+                renderer.message(Some(*id), Some(*direction), "KEEPALIVE probe")?;
+            }
+
+            MapiEvent::ZeroWindow {
+                id,
+                direction,
+                duration,
+            } => {
+                let duration = if *duration < Duration::from_secs(1) {
+                    millis(*duration)
+                } else {
+                    format!("{:.1}s", duration.as_secs_f64())
+                };
+                renderer.message(
+                    Some(*id),
+                    Some(*direction),
+                    format_args!(
+                        "ZERO WINDOW: {} advertised a zero window for {duration}",
+                        direction.receiver()
+                    ),
+                )?;
+            }
+
+            MapiEvent::Segment {
+                id,
+                direction,
+                flags,
+                seq,
+                ack,
+                window,
+                len,
+            } => {
+                let mut text = format!("TCP [{flags}] seq {seq}");
+                if let Some(ack) = ack {
+                    text += &format!(" ack {ack}");
+                }
+                text += &format!(" win {window} len {len}");
+                if let Some(position) = self.segment_position(*id, *direction, *seq, *len) {
+                    text += &format!(", {position}");
+                }
+                renderer.message(Some(*id), Some(*direction), text)?;
             }
 
             MapiEvent::ShutdownRead { id, direction } => {
+                if let Some((upstream, downstream)) = self.accs.get_mut(id) {
+                    let acc = match direction {
+                        Direction::Upstream => upstream,
+                        Direction::Downstream => downstream,
+                    };
+                    acc.note_unshown("", renderer)?;
+                }
                 self.check_incomplete(*id, *direction, renderer)?;
                 let sender = direction.sender();
                 renderer.message(
@@ -101,113 +717,725 @@ impl State {
                 )?;
             }
 
-            MapiEvent::ShutdownWrite {
-                id,
-                direction,
-                discard: n,
-            } => {
-                let receiver = direction.receiver();
-                renderer.message(
-                    Some(*id),
-                    Some(*direction),
-                    format_args!("{receiver} has stopped receiving data, discarding {n} bytes"),
-                )?;
+            MapiEvent::ShutdownWrite {
+                id,
+                direction,
+                discard: n,
+            } => {
+                let receiver = direction.receiver();
+                renderer.message(
+                    Some(*id),
+                    Some(*direction),
+                    format_args!("{receiver} has stopped receiving data, discarding {n} bytes"),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The text, without the operating system error codes if deterministic.
+    fn stable_text(&self, text: &dyn fmt::Display) -> String {
+        let text = text.to_string();
+        if !self.deterministic {
+            return text;
+        }
+        let mut stripped = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(pos) = rest.find(" (os error ") {
+            stripped.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            match rest.find(')') {
+                Some(end) => rest = &rest[end + 1..],
+                None => break,
+            }
+        }
+        stripped.push_str(rest);
+        stripped
+    }
+
+    /// The peer address, without the ephemeral port if deterministic.
+    fn stable_peer(&self, peer: &Addr) -> String {
+        let text = peer.to_string();
+        match text.rfind(':') {
+            Some(colon) if self.deterministic && peer.is_tcp() => format!("{}:*", &text[..colon]),
+            _ => text,
+        }
+    }
+
+    fn add_connection(&mut self, id: &ConnectionId, unix_client: bool) {
+        let level = self.level;
+        let mut upstream = Accumulator::new(
+            *id,
+            Direction::Upstream,
+            level,
+            self.force_binary,
+            unix_client,
+        );
+        let mut downstream =
+            Accumulator::new(*id, Direction::Downstream, level, self.force_binary, false);
+        for acc in [&mut upstream, &mut downstream] {
+            acc.condensed = self.condensed;
+            acc.prompts = self.prompts;
+        }
+        let mut annotations = self.annotations.remove(id).unwrap_or_default();
+        annotations.sort_by_key(|a| a.offset);
+        for annotation in annotations {
+            match annotation.direction {
+                Direction::Upstream => upstream.notes.push_back(annotation),
+                Direction::Downstream => downstream.notes.push_back(annotation),
+            }
+        }
+        let new = (upstream, downstream);
+        let prev = self.accs.insert(*id, new);
+        if prev.is_some() {
+            panic!("Already have state for incoming connection {id}");
+        }
+    }
+
+    fn remove_connection(&mut self, id: &ConnectionId, renderer: &mut Renderer) -> io::Result<()> {
+        let Some((mut upstream, mut downstream)) = self.accs.remove(id) else {
+            panic!("Found no state to remove for end event on connection {id}");
+        };
+        self.buffered -= upstream.buf.len() + downstream.buf.len();
+        upstream.show_notes(true, renderer)?;
+        downstream.show_notes(true, renderer)?;
+        self.handshakes.remove(id);
+        self.redirects.remove_connection(*id);
+        if let Some(labeler) = &mut self.labeler {
+            labeler.remove_connection(*id);
+        }
+        renderer.set_label(*id, None);
+        Ok(())
+    }
+
+    /// Where the payload of a [MapiEvent::Segment] lands in the MAPI framing,
+    /// judged by how far the data of the connection has been analyzed.
+    fn segment_position(
+        &self,
+        id: ConnectionId,
+        direction: Direction,
+        seq: u64,
+        len: usize,
+    ) -> Option<String> {
+        if len == 0 || self.events_only {
+            return None;
+        }
+        let (upstream, downstream) = self.accs.get(&id)?;
+        let analyzer = match direction {
+            Direction::Upstream => &upstream.analyzer,
+            Direction::Downstream => &downstream.analyzer,
+        };
+        let analyzed = analyzer.offset();
+        let position = if seq < analyzed {
+            "repeats data already seen".to_string()
+        } else if seq > analyzed {
+            format!("out of order, {} bytes early", seq - analyzed)
+        } else {
+            analyzer.describe_position()
+        };
+        Some(position)
+    }
+
+    /// Work out the protocol version from the handshake and show it. Protocol
+    /// 10 uses larger block headers after the login, so the framing of the
+    /// connection is switched over. The language picks the [Decoder].
+    fn follow_handshake(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        data: &[u8],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let Some(handshake) = self.handshakes.get_mut(&id) else {
+            return Ok(());
+        };
+        let protocol = handshake.data(direction, data);
+        if let Some(url) = handshake.take_redirect() {
+            self.redirects.redirected(id, &url);
+        }
+        if handshake.is_done() {
+            self.handshakes.remove(&id);
+            self.redirects.remove_connection(id);
+        }
+        let Some(protocol) = protocol else {
+            return Ok(());
+        };
+        if let Some((upstream, downstream)) = self.accs.get_mut(&id) {
+            for acc in [upstream, downstream] {
+                if protocol.wide_headers() {
+                    acc.analyzer.set_wide_headers();
+                }
+                acc.decoder = decoder_for(&protocol.language);
+            }
+        }
+        if protocol.language == "sql" {
+            renderer.message(Some(id), None, format_args!("MAPI PROTOCOL {protocol}"))
+        } else {
+            let language = &protocol.language;
+            let msg = format_args!("MAPI PROTOCOL {protocol}, language {language}");
+            renderer.message(Some(id), None, msg)
+        }
+    }
+
+    /// Abbreviate the largest partial frames until the total fits the cap.
+    /// Only the frames of connections that are `shown` or not, see
+    /// [State::set_shown_connections], can be rendered with `renderer`.
+    fn enforce_memory_cap(&mut self, shown: bool, renderer: &mut Renderer) -> io::Result<()> {
+        let Some(cap) = self.memory_cap else {
+            return Ok(());
+        };
+        while self.buffered > cap {
+            let largest = self
+                .accs
+                .iter_mut()
+                .filter(|(id, _)| self.shown.as_ref().is_none_or(|ids| ids.contains(id)) == shown)
+                .flat_map(|(_, (upstream, downstream))| [upstream, downstream])
+                .max_by_key(|acc| acc.buf.len());
+            let Some(acc) = largest else {
+                break;
+            };
+            self.buffered -= acc.buf.len();
+            acc.abbreviate(cap, renderer)?;
+        }
+        Ok(())
+    }
+
+    fn check_incomplete(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let Some((upstream, downstream)) = self.accs.get_mut(&id) else {
+            panic!("got data for conn {id} but don't have accumulators for it")
+        };
+        let acc = match direction {
+            Direction::Upstream => upstream,
+            Direction::Downstream => downstream,
+        };
+        if let Err(e) = acc.check_incomplete() {
+            self.protocol_errors += 1;
+            renderer.message(Some(id), Some(direction), e)?;
+        };
+        Ok(())
+    }
+}
+
+/// Format a duration in milliseconds with microsecond precision.
+/// What the server means by a message, if all it does is prompt: an empty
+/// message after the login, or a line starting with byte 1. The second
+/// prompt asks for more input, for example the data of a COPY INTO FROM
+/// STDIN.
+fn prompt_text(message: &[u8]) -> Option<&'static str> {
+    match message {
+        b"" | b"\x01\x01\n" => Some("PROMPT"),
+        b"\x01\x02\n" => Some("PROMPT for more input"),
+        _ => None,
+    }
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.3}ms", d.as_secs_f64() * 1000.0)
+}
+
+/// Format a number of bytes in KiB, MiB or GiB once it gets large.
+fn human_bytes(n: u64) -> String {
+    let mut value = n as f64;
+    let mut unit = "bytes";
+    for next in ["KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    match unit {
+        "bytes" => format!("{n} bytes"),
+        _ if value < 10.0 => format!("{value:.1} {unit}"),
+        _ => format!("{value:.0} {unit}"),
+    }
+}
+
+/// Raw data waiting to be shown in one dump, see [State::set_condensed].
+#[derive(Debug)]
+struct Gathered {
+    chunks: usize,
+    bytes: usize,
+    /// The framing before the first chunk, to show the first bytes in the
+    /// right colors
+    analyzer: Analyzer,
+    /// The bytes that will be shown
+    head: Vec<u8>,
+    /// Where the first protocol error is, as position in the stream
+    error_at: Option<u64>,
+}
+
+/// Data that was counted rather than rendered, see [State::set_sample_bytes].
+#[derive(Debug)]
+struct Unshown {
+    bytes: u64,
+    /// How many of those bytes have been mentioned
+    noted: u64,
+    /// Mention them again once this many have been left out
+    next_note: u64,
+}
+
+#[derive(Debug)]
+pub struct Accumulator {
+    id: ConnectionId,
+    direction: Direction,
+    level: Level,
+    force_binary: bool,
+    analyzer: Analyzer,
+    binary: Binary,
+    buf: Vec<u8>,
+    error_reported: bool,
+    muted: bool,
+    tag: Option<String>,
+    /// Set when the current frame is being abbreviated, holds the number of
+    /// bytes of it seen so far
+    abbreviated: Option<usize>,
+    /// Bytes rendered since sampling last stopped, see [State::set_sample_bytes]
+    sampled: usize,
+    unshown: Option<Unshown>,
+    /// Renders text frames, depending on the language of the connection
+    decoder: &'static dyn Decoder,
+    /// Bytes seen, see [State::set_max_capture_bytes]
+    captured: u64,
+    /// Set when the connection has used up its capture budget
+    capped: bool,
+    /// Annotations still to be shown, by offset, see [State::set_annotations]
+    notes: VecDeque<Annotation>,
+    /// Level to switch to as soon as that can be done cleanly, see
+    /// [Accumulator::set_level]
+    next_level: Option<Level>,
+    /// Gather raw data instead of showing each chunk, see
+    /// [State::set_condensed]
+    condensed: bool,
+    gathered: Option<Gathered>,
+    prompts: Prompts,
+    /// Prompts not shown yet because more may follow, see
+    /// [Prompts::Collapse]
+    prompt_run: Option<(&'static str, usize)>,
+}
+
+impl Accumulator {
+    /// How much of an abbreviated frame to show.
+    const ABBREVIATED_SIZE: usize = 1024;
+
+    /// Capacity to return to after collecting a large frame.
+    const INITIAL_CAPACITY: usize = 8192;
+
+    /// How much of the data gathered while condensing to show.
+    const GATHERED_SHOWN: usize = 256;
+
+    /// Show the gathered data once there is this much of it.
+    const GATHER_SIZE: usize = 256 * 1024;
+
+    fn new(
+        id: ConnectionId,
+        direction: Direction,
+        level: Level,
+        force_binary: bool,
+        unix_client: bool,
+    ) -> Self {
+        Accumulator {
+            id,
+            direction,
+            level,
+            force_binary,
+            analyzer: Analyzer::new(unix_client),
+            binary: Binary::new(),
+            buf: Vec::with_capacity(Self::INITIAL_CAPACITY),
+            error_reported: false,
+            muted: false,
+            tag: None,
+            abbreviated: None,
+            sampled: 0,
+            unshown: None,
+            decoder: decoder_for("sql"),
+            captured: 0,
+            capped: false,
+            notes: VecDeque::new(),
+            next_level: None,
+            condensed: false,
+            gathered: None,
+            prompts: Prompts::default(),
+            prompt_run: None,
+        }
+    }
+
+    fn apply_connect_verdict(&mut self, verdict: &Verdict) {
+        self.muted = verdict.drop;
+        self.tag = verdict.tag.clone();
+    }
+
+    fn handle_data(
+        &mut self,
+        data: &[u8],
+        renderer: &mut Renderer,
+        hooks: &mut Option<Box<dyn Hooks>>,
+    ) -> io::Result<()> {
+        if self.next_level.is_some() {
+            self.flush_gathered(renderer)?;
+        }
+        self.switch_level();
+        let mut data = data;
+        if let (Level::Raw, Some(level)) = (self.level, self.next_level) {
+            // show the bytes up to the boundary as raw, the rest as frames
+            let (now, rest) = data.split_at(self.bytes_before(level, data));
+            if !now.is_empty() {
+                self.handle_raw(renderer, now, hooks)?;
+            }
+            self.switch_level();
+            data = rest;
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+        match self.level {
+            Level::Raw if self.condensed && hooks.is_none() => {
+                self.gather(data);
+                if self.gathered.as_ref().unwrap().bytes >= Self::GATHER_SIZE {
+                    self.flush_gathered(renderer)?;
+                }
+                Ok(())
+            }
+            Level::Raw => {
+                self.flush_gathered(renderer)?;
+                self.handle_raw(renderer, data, hooks)
+            }
+            Level::Blocks | Level::Messages => self.handle_frame(renderer, data, hooks),
+        }
+    }
+
+    /// Add raw data to what is shown in the next condensed dump, keeping the
+    /// framing up to date.
+    fn gather(&mut self, mut data: &[u8]) {
+        let gathered = self.gathered.get_or_insert_with(|| Gathered {
+            chunks: 0,
+            bytes: 0,
+            analyzer: self.analyzer.clone(),
+            head: vec![],
+            error_at: None,
+        });
+        gathered.chunks += 1;
+        gathered.bytes += data.len();
+        let n = data.len().min(Self::GATHERED_SHOWN - gathered.head.len());
+        gathered.head.extend_from_slice(&data[..n]);
+        loop {
+            let at = self.analyzer.offset();
+            if self.analyzer.split_chunk(&mut data).is_none() {
+                break;
+            }
+            if self.analyzer.was_error() && !self.error_reported {
+                gathered.error_at = Some(at);
+                self.error_reported = true;
+            }
+        }
+    }
+
+    /// Show what is being held back to be shown in one go, because the
+    /// connection has moved on.
+    fn flush_held(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        self.flush_gathered(renderer)?;
+        self.flush_prompts(renderer)
+    }
+
+    /// Show the data gathered while condensing, if any.
+    fn flush_gathered(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        let Some(Gathered {
+            chunks,
+            bytes,
+            mut analyzer,
+            head,
+            error_at,
+        }) = self.gathered.take()
+        else {
+            return Ok(());
+        };
+        if self.muted {
+            return Ok(());
+        }
+        let start = analyzer.offset();
+        let end = start + bytes as u64;
+        let chunks = if chunks == 1 {
+            "1 chunk".to_string()
+        } else {
+            format!("{chunks} chunks")
+        };
+        let what = format!("CONDENSED {bytes} bytes in {chunks} at {start}..{end}");
+        let mut items: Vec<&dyn fmt::Display> = vec![&what];
+        if let Some(tag) = &self.tag {
+            items.push(tag);
+        }
+        renderer.header(self.id, self.direction, &items)?;
+        let mut rest = &head[..];
+        while let Some(part) = analyzer.split_chunk(&mut rest) {
+            let style = if analyzer.was_head() {
+                Style::Header
+            } else if analyzer.was_error() {
+                Style::Error
+            } else {
+                Style::Normal
+            };
+            for b in part {
+                self.binary.add(*b, style, renderer)?;
+            }
+        }
+        self.binary.finish(renderer)?;
+        let shown = format!("first {n} bytes shown", n = head.len());
+        let error = error_at.map(|pos| format!("encountered mapi protocol error at {pos}"));
+        let mut items: Vec<&dyn fmt::Display> = vec![];
+        if head.len() < bytes {
+            items.push(&shown);
+        }
+        if let Some(error) = &error {
+            items.push(error);
+        }
+        renderer.footer(&items)?;
+        self.show_notes(false, renderer)
+    }
+
+    /// Show the data at another level from the next block or message
+    /// boundary on, whichever `level` needs. Partially collected frames are
+    /// completed first. After a protocol error the data stays raw.
+    fn set_level(&mut self, level: Level) {
+        self.next_level = (level != self.level).then_some(level);
+    }
+
+    /// Switch to [Accumulator::next_level] if the data seen so far ends on
+    /// the right boundary and no frame is being collected. Returns true if
+    /// it did.
+    fn switch_level(&mut self) -> bool {
+        let Some(level) = self.next_level else {
+            return false;
+        };
+        if self.analyzer.was_error() {
+            self.next_level = None;
+            return false;
+        }
+        let at_boundary = match level {
+            Level::Raw => true,
+            Level::Blocks => self.analyzer.was_block_boundary(),
+            Level::Messages => self.analyzer.was_message_boundary(),
+        };
+        if !at_boundary || !self.buf.is_empty() || self.abbreviated.is_some() {
+            return false;
+        }
+        self.level = level;
+        self.next_level = None;
+        true
+    }
+
+    /// How many bytes of `data` come before the first boundary at which the
+    /// switch to `level` can be made.
+    fn bytes_before(&self, level: Level, data: &[u8]) -> usize {
+        let mut probe = self.analyzer.clone();
+        let mut rest = data;
+        while probe.split_chunk(&mut rest).is_some() {
+            let at_boundary = match level {
+                Level::Raw => true,
+                Level::Blocks => probe.was_block_boundary(),
+                Level::Messages => probe.was_message_boundary(),
+            };
+            if at_boundary || probe.was_error() {
+                break;
+            }
+        }
+        data.len() - rest.len()
+    }
+
+    /// Decide whether the data is only counted rather than rendered, see
+    /// [State::set_sample_bytes]. Counting starts at the next frame boundary
+    /// after `limit` bytes have been rendered. `other` is the accumulator
+    /// for the other direction, which resumes rendering along with this one.
+    fn sample(
+        &mut self,
+        data: &[u8],
+        limit: usize,
+        other: &mut Accumulator,
+        renderer: &mut Renderer,
+    ) -> io::Result<bool> {
+        if self.needs_attention(data) {
+            self.resume(renderer)?;
+            other.resume(renderer)?;
+            return Ok(false);
+        }
+        if self.unshown.is_none() {
+            if self.sampled < limit
+                || !self.buf.is_empty()
+                || self.abbreviated.is_some()
+                || self.gathered.is_some()
+            {
+                self.sampled += data.len();
+                return Ok(false);
             }
+            self.unshown = Some(Unshown {
+                bytes: 0,
+                noted: 0,
+                next_note: limit as u64,
+            });
         }
+        self.count_unshown(data, renderer)?;
+        Ok(true)
+    }
 
+    /// Count data instead of rendering it, mentioning the total each time it
+    /// doubles.
+    fn count_unshown(&mut self, data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+        // keep the framing up to date without rendering anything
+        let mut rest = data;
+        while self.analyzer.split_chunk(&mut rest).is_some() {}
+        let Some(unshown) = &mut self.unshown else {
+            unreachable!()
+        };
+        unshown.bytes += data.len() as u64;
+        if unshown.bytes >= unshown.next_note {
+            unshown.next_note *= 2;
+            self.note_unshown(" so far", renderer)?;
+        }
         Ok(())
     }
 
-    fn add_connection(&mut self, id: &ConnectionId, unix_client: bool) {
-        let level = self.level;
-        let upstream = Accumulator::new(
-            *id,
-            Direction::Upstream,
-            level,
-            self.force_binary,
-            unix_client,
-        );
-        let downstream =
-            Accumulator::new(*id, Direction::Downstream, level, self.force_binary, false);
-        let new = (upstream, downstream);
-        let prev = self.accs.insert(*id, new);
-        if prev.is_some() {
-            panic!("Already have state for incoming connection {id}");
+    /// The connection has used up its capture budget, see
+    /// [State::set_max_capture_bytes]. Show what has been collected of the
+    /// current frame and only count from now on.
+    fn stop_capture(&mut self, limit: u64, renderer: &mut Renderer) -> io::Result<()> {
+        self.flush_gathered(renderer)?;
+        self.capped = true;
+        if !self.buf.is_empty() && !self.muted {
+            let why = "incomplete, capture limit reached";
+            let mut items: Vec<&dyn fmt::Display> = vec![&why];
+            if let Some(tag) = &self.tag {
+                items.push(tag);
+            }
+            self.dump_frame_with_header(&self.buf, self.frame_kind(), &items, renderer)?;
         }
+        self.buf.clear();
+        self.buf.shrink_to(Self::INITIAL_CAPACITY);
+        self.abbreviated = None;
+        if self.unshown.is_none() {
+            self.unshown = Some(Unshown {
+                bytes: 0,
+                noted: 0,
+                next_note: limit.max(1),
+            });
+        }
+        Ok(())
     }
 
-    fn remove_connection(&mut self, id: &ConnectionId) {
-        let ended = self.accs.remove(id);
-        if ended.is_none() {
-            panic!("Found no state to remove for end event on connection {id}");
+    /// Whether the data holds a protocol error or the start of an error
+    /// response, which should be shown even while sampling.
+    fn needs_attention(&self, mut data: &[u8]) -> bool {
+        let mut probe = self.analyzer.clone();
+        let mut message_start = probe.was_message_boundary();
+        while let Some(chunk) = probe.split_chunk(&mut data) {
+            if probe.was_error() {
+                return true;
+            }
+            if probe.was_body() {
+                if message_start
+                    && self.direction == Direction::Downstream
+                    && chunk.first() == Some(&b'!')
+                {
+                    return true;
+                }
+                message_start = probe.was_message_boundary();
+            } else {
+                message_start |= probe.was_message_boundary();
+            }
         }
+        false
     }
 
-    fn check_incomplete(
-        &mut self,
-        id: ConnectionId,
-        direction: Direction,
-        renderer: &mut Renderer,
-    ) -> io::Result<()> {
-        let Some((upstream, downstream)) = self.accs.get_mut(&id) else {
-            panic!("got data for conn {id} but don't have accumulators for it")
-        };
-        let acc = match direction {
-            Direction::Upstream => upstream,
-            Direction::Downstream => downstream,
-        };
-        if let Err(e) = acc.check_incomplete() {
-            renderer.message(Some(id), Some(direction), e)?;
-        };
+    /// Stop sampling and render everything again.
+    fn resume(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        self.note_unshown(", resuming", renderer)?;
+        self.unshown = None;
+        self.sampled = 0;
         Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct Accumulator {
-    id: ConnectionId,
-    direction: Direction,
-    level: Level,
-    force_binary: bool,
-    analyzer: Analyzer,
-    binary: Binary,
-    buf: Vec<u8>,
-    error_reported: bool,
-}
+    /// Mention how much data has been left out by sampling, if anything
+    /// since the last time.
+    fn note_unshown(&mut self, suffix: &str, renderer: &mut Renderer) -> io::Result<()> {
+        let Some(unshown) = &mut self.unshown else {
+            return Ok(());
+        };
+        if unshown.bytes == unshown.noted || self.muted {
+            return Ok(());
+        }
+        unshown.noted = unshown.bytes;
+        let bytes = human_bytes(unshown.bytes);
+        renderer.message(
+            Some(self.id),
+            Some(self.direction),
+            format_args!("… {bytes} not shown{suffix}"),
+        )
+    }
 
-impl Accumulator {
-    fn new(
-        id: ConnectionId,
-        direction: Direction,
-        level: Level,
-        force_binary: bool,
-        unix_client: bool,
-    ) -> Self {
-        Accumulator {
-            id,
-            direction,
-            level,
-            force_binary,
-            analyzer: Analyzer::new(unix_client),
-            binary: Binary::new(),
-            buf: Vec::with_capacity(8192),
-            error_reported: false,
+    /// Ask the hooks what to do with this frame, taking the connection's own
+    /// verdict into account. Returns None if it should not be rendered.
+    fn consult_hooks(
+        &self,
+        data: &[u8],
+        hooks: &mut Option<Box<dyn Hooks>>,
+    ) -> io::Result<Option<Verdict>> {
+        if self.muted {
+            return Ok(None);
+        }
+        let verdict = match hooks {
+            Some(hooks) => hooks.on_message(self.id, self.direction, data)?,
+            None => Verdict::default(),
+        };
+        if verdict.drop {
+            return Ok(None);
         }
+        Ok(Some(verdict))
     }
 
-    fn handle_data(&mut self, data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
-        match self.level {
-            Level::Raw => self.handle_raw(renderer, data),
-            Level::Blocks | Level::Messages => self.handle_frame(renderer, data),
+    /// Header items derived from the verdict, to be appended to the regular ones.
+    fn verdict_items<'a>(&'a self, verdict: &'a Verdict) -> Vec<&'a dyn fmt::Display> {
+        let mut items: Vec<&dyn fmt::Display> = vec![];
+        if let Some(tag) = &self.tag {
+            items.push(tag);
+        }
+        if let Some(tag) = &verdict.tag {
+            items.push(tag);
+        }
+        if verdict.replacement.is_some() {
+            items.push(&"rewritten by script");
         }
+        items
     }
 
-    fn handle_raw(&mut self, renderer: &mut Renderer, mut data: &[u8]) -> Result<(), io::Error> {
-        renderer.header(
-            self.id,
-            self.direction,
-            &[&format_args!("{n} bytes", n = data.len())],
-        )?;
+    fn handle_raw(
+        &mut self,
+        renderer: &mut Renderer,
+        mut data: &[u8],
+        hooks: &mut Option<Box<dyn Hooks>>,
+    ) -> Result<(), io::Error> {
+        let Some(verdict) = self.consult_hooks(data, hooks)? else {
+            // keep the analyzer up to date but don't render anything
+            while self.analyzer.split_chunk(&mut data).is_some() {}
+            return Ok(());
+        };
+        // the position in the stream, to find the chunk back in a capture
+        let start = self.analyzer.offset();
+        let n = data.len();
+        let n = format_args!("{n} bytes at {start}..{end}", end = start + n as u64);
+        let mut items: Vec<&dyn fmt::Display> = vec![&n];
+        items.extend(self.verdict_items(&verdict));
+        renderer.header(self.id, self.direction, &items)?;
+
+        if let Some(replacement) = &verdict.replacement {
+            while self.analyzer.split_chunk(&mut data).is_some() {}
+            self.dump_frame_as_binary(replacement, renderer)?;
+            renderer.footer(&[])?;
+            return self.render_note(&verdict, renderer);
+        }
         let mut n = 0;
         let mut error_at = None;
         while let Some(head) = self.analyzer.split_chunk(&mut data) {
@@ -235,11 +1463,51 @@ impl Accumulator {
         } else {
             renderer.footer(&[])?;
         }
+        self.render_note(&verdict, renderer)
+    }
+
+    /// Show the annotations of the bytes that have been shown. While a frame
+    /// is being collected, wait until it has been shown. With `all`, show
+    /// the remaining ones too.
+    fn show_notes(&mut self, all: bool, renderer: &mut Renderer) -> io::Result<()> {
+        let upto = if all {
+            u64::MAX
+        } else if !self.buf.is_empty() || self.abbreviated.is_some() || self.gathered.is_some() {
+            return Ok(());
+        } else {
+            self.analyzer.offset()
+        };
+        while self.notes.front().is_some_and(|a| a.offset < upto) {
+            let Annotation { offset, note, .. } = self.notes.pop_front().unwrap();
+            renderer.message(
+                Some(self.id),
+                Some(self.direction),
+                format_args!("NOTE at {offset}: {note}"),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn render_note(&self, verdict: &Verdict, renderer: &mut Renderer) -> io::Result<()> {
+        if let Some(note) = &verdict.note {
+            renderer.message(Some(self.id), Some(self.direction), note)?;
+        }
         Ok(())
     }
 
-    fn handle_frame(&mut self, renderer: &mut Renderer, mut data: &[u8]) -> Result<(), io::Error> {
+    fn handle_frame(
+        &mut self,
+        renderer: &mut Renderer,
+        mut data: &[u8],
+        hooks: &mut Option<Box<dyn Hooks>>,
+    ) -> Result<(), io::Error> {
         loop {
+            if self.next_level.is_some() && self.switch_level() {
+                if data.is_empty() {
+                    return Ok(());
+                }
+                return self.handle_data(data, renderer, hooks);
+            }
             let whole = data;
             let Some(chunk) = self.analyzer.split_chunk(&mut data) else {
                 break;
@@ -261,7 +1529,8 @@ impl Accumulator {
                 renderer.message(Some(self.id), Some(self.direction), "mapi protocol error")?;
                 self.error_reported = true;
                 self.level = Level::Raw;
-                return self.handle_raw(renderer, whole);
+                self.abbreviated = None;
+                return self.handle_raw(renderer, whole, hooks);
             }
             if !self.analyzer.was_body() {
                 continue;
@@ -273,6 +1542,23 @@ impl Accumulator {
                 Level::Raw => unreachable!(),
             };
 
+            if let Some(seen) = &mut self.abbreviated {
+                *seen += chunk.len();
+                if at_end {
+                    let seen = *seen;
+                    self.abbreviated = None;
+                    if !self.muted {
+                        let kind = self.frame_kind();
+                        renderer.message(
+                            Some(self.id),
+                            Some(self.direction),
+                            format_args!("ABBREVIATED {kind} ended after {seen} bytes"),
+                        )?;
+                    }
+                }
+                continue;
+            }
+
             if !at_end {
                 self.buf.extend_from_slice(chunk);
                 continue;
@@ -285,29 +1571,273 @@ impl Accumulator {
                 self.buf.extend_from_slice(chunk);
                 None
             };
-            self.dump_frame(frame, renderer)?;
+            let prompt = if self.prompts != Prompts::Frame
+                && self.level == Level::Messages
+                && self.direction == Direction::Downstream
+            {
+                prompt_text(frame.unwrap_or(&self.buf))
+            } else {
+                None
+            };
+            if let Some(prompt) = prompt {
+                self.handle_prompt(prompt, renderer)?;
+            } else {
+                self.flush_prompts(renderer)?;
+                self.dump_frame(frame, renderer, hooks)?;
+                if self.level == Level::Messages && self.direction == Direction::Downstream {
+                    self.check_response(frame, renderer)?;
+                }
+            }
             self.buf.clear();
+            self.buf.shrink_to(Self::INITIAL_CAPACITY);
+            self.show_notes(false, renderer)?;
         }
         Ok(())
     }
 
-    fn dump_frame(&mut self, data: Option<&[u8]>, renderer: &mut Renderer) -> io::Result<()> {
-        let data = data.unwrap_or(&self.buf);
-        let len = data.len();
-        let is_binary =
-            self.force_binary || self.is_scary(data) || std::str::from_utf8(data).is_err();
+    /// Point out a response that contradicts itself, for example a result set
+    /// with fewer rows than its header says.
+    fn check_response(&self, frame: Option<&[u8]>, renderer: &mut Renderer) -> io::Result<()> {
+        if self.muted {
+            return Ok(());
+        }
+        let message = frame.unwrap_or(&self.buf);
+        match self.decoder.check_response(message) {
+            Some(problem) => renderer.message(
+                Some(self.id),
+                Some(self.direction),
+                format_args!("ROW COUNT mismatch: {problem}"),
+            ),
+            None => Ok(()),
+        }
+    }
 
-        let format = if is_binary { "binary" } else { "text" };
-        let kind = if self.level == Level::Messages {
+    fn handle_prompt(&mut self, prompt: &'static str, renderer: &mut Renderer) -> io::Result<()> {
+        if self.muted {
+            return Ok(());
+        }
+        match self.prompts {
+            Prompts::Frame | Prompts::Hide => Ok(()),
+            Prompts::Line => renderer.message(Some(self.id), Some(self.direction), prompt),
+            Prompts::Collapse => {
+                match &mut self.prompt_run {
+                    Some((text, n)) if *text == prompt => *n += 1,
+                    _ => {
+                        self.flush_prompts(renderer)?;
+                        self.prompt_run = Some((prompt, 1));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Show the run of prompts held back by [Prompts::Collapse], if any.
+    fn flush_prompts(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        let (id, direction) = (Some(self.id), Some(self.direction));
+        match self.prompt_run.take() {
+            None => Ok(()),
+            Some((prompt, 1)) => renderer.message(id, direction, prompt),
+            Some((prompt, n)) => {
+                renderer.message(id, direction, format_args!("{prompt}, {n} in a row"))
+            }
+        }
+    }
+
+    /// Show the partial frame collected so far, because the rest of it will
+    /// not arrive.
+    fn flush_incomplete(&self, renderer: &mut Renderer) -> io::Result<()> {
+        if self.buf.is_empty() || self.muted {
+            return Ok(());
+        }
+        let kind = self.frame_kind();
+        let why = "incomplete, the capture ended first";
+        let mut items: Vec<&dyn fmt::Display> = vec![&why];
+        if let Some(tag) = &self.tag {
+            items.push(tag);
+        }
+        self.dump_frame_with_header(&self.buf, kind, &items, renderer)
+    }
+
+    fn frame_kind(&self) -> &'static str {
+        if self.level == Level::Messages {
             "message"
         } else {
             "block"
+        }
+    }
+
+    /// The partial frame has grown too large to keep collecting. Show the
+    /// start of it, then only count the rest until the frame ends.
+    fn abbreviate(&mut self, cap: usize, renderer: &mut Renderer) -> io::Result<()> {
+        let seen = self.buf.len();
+        self.abbreviated = Some(seen);
+        if !self.muted {
+            let shown = &self.buf[..seen.min(Self::ABBREVIATED_SIZE)];
+            let why = format!("ABBREVIATED after {seen} bytes, memory cap of {cap} bytes reached");
+            let mut items: Vec<&dyn fmt::Display> = vec![&why];
+            if let Some(tag) = &self.tag {
+                items.push(tag);
+            }
+            self.dump_frame_with_header(shown, self.frame_kind(), &items, renderer)?;
+        }
+        self.buf.clear();
+        self.buf.shrink_to(Self::INITIAL_CAPACITY);
+        Ok(())
+    }
+
+    fn dump_frame(
+        &mut self,
+        data: Option<&[u8]>,
+        renderer: &mut Renderer,
+        hooks: &mut Option<Box<dyn Hooks>>,
+    ) -> io::Result<()> {
+        let data = data.unwrap_or(&self.buf);
+        let Some(verdict) = self.consult_hooks(data, hooks)? else {
+            return Ok(());
+        };
+        let data = verdict.replacement.as_deref().unwrap_or(data);
+        let kind = self.frame_kind();
+        let items = self.verdict_items(&verdict);
+        self.dump_frame_with_header(data, kind, &items, renderer)?;
+        self.render_note(&verdict, renderer)
+    }
+
+    /// Data has been left out because rendering could not keep up. Anything
+    /// partially collected is lost, continue with the framing as it is after
+    /// the missing data.
+    fn handle_dropped(
+        &mut self,
+        chunks: usize,
+        bytes: usize,
+        sample: &[u8],
+        analyzer: &Analyzer,
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        self.analyzer = analyzer.clone();
+        self.abbreviated = None;
+        let lost = self.buf.len();
+        self.buf.clear();
+        if self.muted {
+            return Ok(());
+        }
+        let chunks = if chunks == 1 {
+            "1 chunk".to_string()
+        } else {
+            format!("{chunks} chunks")
         };
+        let mut what = format!("{bytes} bytes in {chunks}");
+        if lost > 0 {
+            what += &format!(", discarding {lost} bytes already received");
+        }
+        if sample.is_empty() {
+            return renderer.message(
+                Some(self.id),
+                Some(self.direction),
+                format_args!("DROPPED {what}"),
+            );
+        }
+        let summarized = format!("SUMMARIZED {what}");
+        renderer.header(self.id, self.direction, &[&summarized])?;
+        self.dump_frame_as_binary(sample, renderer)?;
+        renderer.footer(&[&format_args!("first {n} bytes shown", n = sample.len())])
+    }
+
+    /// Render the rows of 16 bytes in which a retransmission differs from the
+    /// original, each as the original row followed by the retransmitted row,
+    /// with the differing bytes highlighted.
+    fn handle_retransmitted(
+        &self,
+        offset: u64,
+        original: &[u8],
+        retransmitted: &[u8],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        if self.muted {
+            return Ok(());
+        }
+        let differing = original
+            .iter()
+            .zip(retransmitted)
+            .filter(|(a, b)| a != b)
+            .count();
         renderer.header(
             self.id,
             self.direction,
-            &[&format, &kind, &format_args!("{len} bytes")],
+            &[
+                &"RETRANSMITTED DIFFERENTLY",
+                &format_args!("at byte {offset}"),
+            ],
         )?;
+        let mut bin = Binary::new();
+        let width = renderer.hex_width();
+        for (old, new) in original.chunks(width).zip(retransmitted.chunks(width)) {
+            if old == new {
+                continue;
+            }
+            for (sign, row, other) in [("-", old, new), ("+", new, old)] {
+                // start the line before switching away from the style of
+                // the previous row
+                renderer.put("")?;
+                renderer.style(Style::Normal)?;
+                renderer.put(sign)?;
+                for (b, o) in row.iter().zip(other) {
+                    let style = if b == o { Style::Normal } else { Style::Error };
+                    bin.add(*b, style, renderer)?;
+                }
+                bin.finish(renderer)?;
+            }
+        }
+        renderer.footer(&[&format_args!(
+            "{differing} of {n} bytes differ",
+            n = original.len()
+        )])
+    }
+
+    /// Render the messages the proxy forwarded in place of the one it received.
+    /// They are always rendered as whole messages, regardless of the level.
+    fn handle_rewritten(
+        &self,
+        original: usize,
+        messages: &[Vec<u8>],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        if self.muted {
+            return Ok(());
+        }
+        if messages.is_empty() {
+            return renderer.message(
+                Some(self.id),
+                Some(self.direction),
+                format_args!("REWRITTEN: dropped message of {original} bytes"),
+            );
+        }
+        let mut items: Vec<&dyn fmt::Display> = vec![&"REWRITTEN"];
+        if let Some(tag) = &self.tag {
+            items.push(tag);
+        }
+        for message in messages {
+            self.dump_frame_with_header(message, "message", &items, renderer)?;
+        }
+        Ok(())
+    }
+
+    fn dump_frame_with_header(
+        &self,
+        data: &[u8],
+        kind: &str,
+        extra_items: &[&dyn fmt::Display],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let is_binary =
+            self.force_binary || self.is_scary(data) || std::str::from_utf8(data).is_err();
+
+        let format = if is_binary { "binary" } else { "text" };
+        let len = data.len();
+        let len = format_args!("{len} bytes");
+        let mut items: Vec<&dyn fmt::Display> = vec![&format, &kind, &len];
+        items.extend(extra_items);
+        renderer.header(self.id, self.direction, &items)?;
 
         if is_binary {
             self.dump_frame_as_binary(data, renderer)?;
@@ -315,16 +1845,16 @@ impl Accumulator {
             self.dump_frame_as_text(data, renderer)?;
         }
 
-        renderer.footer(&[])?;
-        Ok(())
+        renderer.footer(&[])
     }
 
-    fn check_incomplete(&mut self) -> io::Result<()> {
+    fn check_incomplete(&mut self) -> Result<(), ProtocolError> {
         if let Err(situation) = self.analyzer.check_incomplete() {
             let side = self.direction.sender();
-            let message = format!("{side} closed the connection {situation}");
-            let kind = ErrorKind::UnexpectedEof;
-            return Err(io::Error::new(kind, message));
+            return Err(ProtocolError {
+                offset: self.analyzer.offset(),
+                situation: format!("{side} closed the connection {situation}"),
+            });
         }
         Ok(())
     }
@@ -339,17 +1869,8 @@ impl Accumulator {
     }
 
     fn dump_frame_as_text(&self, data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
-        for byte in data {
-            match *byte {
-                b'\n' => {
-                    renderer.put("↵")?;
-                    renderer.nl()?;
-                }
-                b'\t' => {
-                    renderer.put("→")?;
-                }
-                b => renderer.put([b])?,
-            }
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            self.decoder.dump_line(line, renderer)?;
         }
         renderer.clear_line()?;
         Ok(())
@@ -365,16 +1886,20 @@ impl Accumulator {
     }
 }
 
+/// Writes hex dumps with [Renderer::hex_width] bytes per line, in groups of
+/// 16 followed by a pane with the bytes as text.
 #[derive(Debug)]
 struct Binary {
-    row: [(u8, Style); 16],
+    row: [(u8, Style); Binary::MAX_WIDTH],
     col: usize,
 }
 
 impl Binary {
+    const MAX_WIDTH: usize = 32;
+
     fn new() -> Self {
         Binary {
-            row: [(0, Style::Normal); 16],
+            row: [(0, Style::Normal); Binary::MAX_WIDTH],
             col: 0,
         }
     }
@@ -392,7 +1917,7 @@ impl Binary {
         self.row[self.col] = (byte, style);
         self.col += 1;
 
-        if self.col == 16 {
+        if self.col == renderer.hex_width().min(Self::MAX_WIDTH) {
             self.write_out(renderer, false)
         } else {
             Ok(())
@@ -408,9 +1933,10 @@ impl Binary {
 
     fn write_out(&mut self, renderer: &mut Renderer, _keep_head_state: bool) -> io::Result<()> {
         const HEX_DIGITS: [u8; 16] = *b"0123456789abcdef";
+        let width = renderer.hex_width().min(Self::MAX_WIDTH);
         let mut cur_head = false;
         for (i, (byte, style)) in self.row[..self.col].iter().cloned().enumerate() {
-            self.put_sep(i, &mut cur_head, style, renderer)?;
+            self.put_sep(i, width, &mut cur_head, style, renderer)?;
 
             let hi = HEX_DIGITS[byte as usize / 16];
             let lo = HEX_DIGITS[byte as usize & 0xF];
@@ -420,19 +1946,27 @@ impl Binary {
             renderer.style(Style::Normal)?;
         }
 
-        for i in self.col..16 {
-            self.put_sep(i, &mut cur_head, Style::Frame, renderer)?;
+        for i in self.col..width {
+            self.put_sep(i, width, &mut cur_head, Style::Frame, renderer)?;
             renderer.put(b"__")?;
         }
 
         // if the sep includes a style change, this is its
         // chance to wrap it up
-        self.put_sep(16, &mut cur_head, Style::Normal, renderer)?;
+        self.put_sep(width, width, &mut cur_head, Style::Normal, renderer)?;
 
-        for (byte, style) in &self.row[..self.col] {
+        for (i, (byte, style)) in self.row[..self.col].iter().enumerate() {
+            if i > 0 && i % 16 == 0 {
+                renderer.style(Style::Normal)?;
+                renderer.put(" ")?;
+            }
             renderer.style(*style)?;
-            renderer.put(Self::readable(&[*byte]))?;
+            match Self::glyph(*byte) {
+                Some(glyph) => renderer.put_glyph(glyph)?,
+                None => renderer.put([*byte])?,
+            }
         }
+        renderer.count_bytes(self.col);
 
         renderer.nl()?;
 
@@ -440,22 +1974,30 @@ impl Binary {
         Ok(())
     }
 
+    /// Extra spaces before byte `i` of a line, or before the text pane if
+    /// `i` is the width of the line.
+    fn extra_space(i: usize, width: usize) -> usize {
+        if i == width {
+            return 4;
+        }
+        match i % 16 {
+            0 if i > 0 => 4,
+            4 | 12 => 1,
+            8 => 2,
+            _ => 0,
+        }
+    }
+
     fn put_sep(
         &self,
         i: usize,
+        width: usize,
         in_head: &mut bool,
         style: Style,
         renderer: &mut Renderer,
     ) -> Result<(), io::Error> {
-        let extra_space: [u8; 17] = [
-            0, 0, 0, 0, //
-            1, 0, 0, 0, //
-            2, 0, 0, 0, //
-            1, 0, 0, 0, //
-            4,
-        ];
         let spaces = "          ";
-        let extra = extra_space[i] as usize;
+        let extra = Self::extra_space(i, width);
         let (open, close) = ("⟨", "⟩");
         let is_head = style == Style::Header;
         // let (open, close) = ("«", "»");
@@ -478,16 +2020,133 @@ impl Binary {
         Ok(())
     }
 
-    fn readable(byte: &[u8; 1]) -> &[u8] {
+    /// The glyph shown for bytes that cannot be shown as they are.
+    fn glyph(byte: u8) -> Option<Glyph> {
         // note that the readable range does not include 0x7f (DEL)
-        let s = match byte[0] {
-            b' ' => "·",
-            0x21..=0x7e => return byte.as_ref(),
-            b'\n' => "↵",
-            b'\t' => "→",
-            0 => "░",
-            _ => "▒",
+        let glyph = match byte {
+            b' ' => Glyph::Space,
+            0x21..=0x7e => return None,
+            b'\n' => Glyph::Newline,
+            b'\t' => Glyph::Tab,
+            0 => Glyph::Nul,
+            _ => Glyph::Other,
         };
-        s.as_bytes()
+        Some(glyph)
+    }
+}
+
+/// Collects what a [Renderer] writes, for the tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct TestOutput(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl TestOutput {
+    fn renderer(&self) -> Renderer {
+        let mut renderer = Renderer::new(false, self.clone());
+        renderer.set_deterministic(true);
+        renderer
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl io::Write for TestOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
+
+#[cfg(test)]
+fn test_incoming(id: ConnectionId) -> MapiEvent {
+    use std::net::{Ipv4Addr, SocketAddr};
+    let addr = |port| Addr::from(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    MapiEvent::Incoming {
+        id,
+        local: addr(50000),
+        peer: addr(40000 + id.as_usize() as u16),
+    }
+}
+
+#[test]
+fn test_shown_connections() {
+    let out = TestOutput::default();
+    let mut renderer = out.renderer();
+    let mut state = State::new(Level::Messages, false);
+    state.set_deterministic(true);
+    state.set_shown_connections(Some([ConnectionId::new(10)].into()));
+
+    for (n, data) in [(10, &b"\x13\x00sselect 1"[..]), (11, b"\xff\xff")] {
+        let id = ConnectionId::new(n);
+        let events = [
+            test_incoming(id),
+            MapiEvent::Data {
+                id,
+                direction: Direction::Upstream,
+                data: data.into(),
+            },
+            MapiEvent::End { id },
+        ];
+        for event in &events {
+            state.handle(event, &mut renderer).unwrap();
+        }
+    }
+    drop(renderer);
+
+    let text = out.text();
+    assert!(text.contains("sselect 1"), "{text}");
+    assert!(!text.contains("#11"), "{text}");
+    assert_eq!(state.protocol_errors(), 1);
+}
+
+#[test]
+fn test_condensed() {
+    let out = TestOutput::default();
+    let mut renderer = out.renderer();
+    let mut state = State::new(Level::Raw, false);
+    state.set_deterministic(true);
+
+    let id = ConnectionId::new(10);
+    let data = |direction, data: &[u8]| MapiEvent::Data {
+        id,
+        direction,
+        data: data.into(),
+    };
+    state.handle(&test_incoming(id), &mut renderer).unwrap();
+    state
+        .handle(&data(Direction::Upstream, b"\x07\x00abc"), &mut renderer)
+        .unwrap();
+    state.set_condensed(true, &mut renderer).unwrap();
+    state
+        .handle(&data(Direction::Upstream, b"\x05\x00xy"), &mut renderer)
+        .unwrap();
+    state
+        .handle(&data(Direction::Upstream, b"\xff\xffzz"), &mut renderer)
+        .unwrap();
+    drop(renderer);
+    assert!(!out.text().contains("CONDENSED"), "{}", out.text());
+
+    // data flowing the other way shows what was gathered
+    let mut renderer = out.renderer();
+    state
+        .handle(&data(Direction::Downstream, b"\x01\x00"), &mut renderer)
+        .unwrap();
+    drop(renderer);
+    let text = out.text();
+    assert!(
+        text.contains("CONDENSED 8 bytes in 2 chunks at 5..13"),
+        "{text}"
+    );
+    assert!(
+        text.contains("encountered mapi protocol error at 9"),
+        "{text}"
+    );
+    assert_eq!(state.protocol_errors(), 1);
+}
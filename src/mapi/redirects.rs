@@ -0,0 +1,148 @@
+//! Recognizes the connection a client makes after monetdbd redirected it
+//! elsewhere, so it can be shown as the continuation of the connection that
+//! was redirected.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    path::PathBuf,
+};
+
+use crate::proxy::{event::ConnectionId, network::Addr};
+
+/// Keeps the redirects that have not been followed yet.
+#[derive(Debug, Default)]
+pub struct Redirects {
+    /// The clients of the connections that may still be redirected
+    clients: HashMap<ConnectionId, Addr>,
+    pending: VecDeque<Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    id: ConnectionId,
+    url: String,
+    target: Target,
+    /// The address of the client, if it connected over TCP
+    client: Option<IpAddr>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    /// Any address if the host is a name rather than an IP address
+    Tcp(Option<IpAddr>, u16),
+    Unix(PathBuf),
+}
+
+impl Redirects {
+    /// Forget the oldest redirects beyond this many, their clients are
+    /// probably not coming back.
+    const MAX_PENDING: usize = 64;
+
+    pub fn add_connection(&mut self, id: ConnectionId, peer: &Addr) {
+        self.clients.insert(id, peer.clone());
+    }
+
+    /// The connection can no longer be redirected, for example because the
+    /// client has logged in.
+    pub fn remove_connection(&mut self, id: ConnectionId) {
+        self.clients.remove(&id);
+    }
+
+    /// The server of connection `id` sent the client elsewhere, for example
+    /// with `mapi:monetdb://localhost:50001/demo`.
+    pub fn redirected(&mut self, id: ConnectionId, url: &str) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        let Some(target) = parse_target(url) else {
+            return;
+        };
+        let client = match client {
+            Addr::Tcp(addr) => Some(addr.ip()),
+            Addr::Unix(_) => None,
+        };
+        if self.pending.len() == Self::MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(Pending {
+            id,
+            url: url.to_string(),
+            target,
+            client,
+        });
+    }
+
+    /// A connection came in on `local` from `peer`. If it goes where an
+    /// earlier connection from the same client was redirected to, returns
+    /// that connection and the redirect.
+    pub fn incoming(&mut self, local: &Addr, peer: &Addr) -> Option<(ConnectionId, String)> {
+        let pos = self.pending.iter().rposition(|p| p.matches(local, peer))?;
+        let Pending { id, url, .. } = self.pending.remove(pos)?;
+        Some((id, url))
+    }
+}
+
+impl Pending {
+    fn matches(&self, local: &Addr, peer: &Addr) -> bool {
+        match (&self.target, local) {
+            (Target::Tcp(host, port), Addr::Tcp(local)) => {
+                let same_client = match (self.client, peer) {
+                    (Some(client), Addr::Tcp(peer)) => client == peer.ip(),
+                    _ => false,
+                };
+                *port == local.port() && host.is_none_or(|h| h == local.ip()) && same_client
+            }
+            (Target::Unix(path), Addr::Unix(local)) => path == local,
+            _ => false,
+        }
+    }
+}
+
+/// Where a redirect such as `mapi:monetdb://localhost:50001/demo` or
+/// `mapi:monetdb:///tmp/.s.monetdb.50001?database=demo` sends the client.
+fn parse_target(url: &str) -> Option<Target> {
+    let rest = url.strip_prefix("mapi:monetdb://")?;
+    let rest = rest.split('?').next()?;
+    if rest.starts_with('/') {
+        return Some(Target::Unix(PathBuf::from(rest)));
+    }
+    let host_port = rest.split('/').next()?;
+    let (host, port) = host_port.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some(Target::Tcp(host.parse().ok(), port.parse().ok()?))
+}
+
+#[test]
+fn test_redirects() {
+    let client: Addr = Addr::Tcp("10.0.0.1:40001".parse().unwrap());
+    let again: Addr = Addr::Tcp("10.0.0.1:40002".parse().unwrap());
+    let other: Addr = Addr::Tcp("10.0.0.9:40003".parse().unwrap());
+    let monetdbd: Addr = Addr::Tcp("10.0.0.2:50000".parse().unwrap());
+    let mserver: Addr = Addr::Tcp("10.0.0.2:50001".parse().unwrap());
+
+    let mut redirects = Redirects::default();
+    redirects.add_connection(ConnectionId::new(10), &client);
+    redirects.redirected(ConnectionId::new(10), "mapi:monetdb://dbhost:50001/demo");
+    assert_eq!(redirects.incoming(&monetdbd, &again), None);
+    assert_eq!(redirects.incoming(&mserver, &other), None);
+    let (id, url) = redirects.incoming(&mserver, &again).unwrap();
+    assert_eq!(id, ConnectionId::new(10));
+    assert_eq!(url, "mapi:monetdb://dbhost:50001/demo");
+    // it is only followed once
+    assert_eq!(redirects.incoming(&mserver, &again), None);
+
+    let socket = Addr::Unix("/tmp/.s.monetdb.50001".into());
+    redirects.add_connection(ConnectionId::new(11), &client);
+    redirects.redirected(
+        ConnectionId::new(11),
+        "mapi:monetdb:///tmp/.s.monetdb.50001?database=demo",
+    );
+    let (id, _) = redirects.incoming(&socket, &Addr::Unix("".into())).unwrap();
+    assert_eq!(id, ConnectionId::new(11));
+
+    assert_eq!(
+        parse_target("mapi:monetdb://[::1]:50001/demo"),
+        Some(Target::Tcp(Some("::1".parse().unwrap()), 50001))
+    );
+}
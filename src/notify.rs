@@ -0,0 +1,152 @@
+//! Implementation of --notify. Rings the terminal bell or runs a command when
+//! a client connects or something goes wrong, so the user notices even when
+//! the output has scrolled away or goes to a file.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result as AResult};
+use mapiproxy::proxy::event::{ConnectionId, MapiEvent};
+
+use crate::exchange::{Framing, Recorder};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notify {
+    Bell,
+    /// Run this shell command
+    Command(String),
+}
+
+pub fn parse_notify(setting: &str, value: &str) -> AResult<Notify> {
+    if value == "bell" {
+        return Ok(Notify::Bell);
+    }
+    match value.strip_prefix("command:") {
+        Some(command) if !command.trim().is_empty() => Ok(Notify::Command(command.to_string())),
+        _ => bail!("{setting}={value}: must be 'bell' or 'command:COMMAND'"),
+    }
+}
+
+pub struct Notifier {
+    how: Notify,
+    /// Follows the framing of each connection to spot protocol errors
+    conns: HashMap<ConnectionId, Framing>,
+    /// When the last notification of each kind was given
+    last: HashMap<&'static str, Instant>,
+}
+
+impl Notifier {
+    /// Notifications of the same kind closer together than this are left out.
+    const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(how: Notify) -> Self {
+        Notifier {
+            how,
+            conns: HashMap::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    /// What happened that deserves a notification, as `(kind, text)`.
+    fn check(&mut self, event: &MapiEvent) -> Option<(&'static str, String)> {
+        match event {
+            MapiEvent::Failed { error } => Some(("error", format!("proxy failed: {error}"))),
+            MapiEvent::Incoming { id, peer, .. } => {
+                self.conns.insert(*id, Framing::new(peer.is_unix()));
+                Some(("connection", format!("{id} new connection from {peer}")))
+            }
+            MapiEvent::Aborted { id, error } => {
+                self.conns.remove(id);
+                Some(("error", format!("{id} aborted: {error}")))
+            }
+            MapiEvent::End { id } => {
+                self.conns.remove(id);
+                None
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let framing = self.conns.get_mut(id)?;
+                if framing.data(*direction, data).is_some() {
+                    return None;
+                }
+                // the rest of the connection is not MAPI
+                self.conns.remove(id);
+                Some(("error", format!("{id} {direction}: mapi protocol error")))
+            }
+            MapiEvent::Dropped {
+                id,
+                direction,
+                analyzer,
+                ..
+            } => {
+                self.conns.get_mut(id)?.skip(*direction, analyzer);
+                None
+            }
+            MapiEvent::ShutdownRead { id, direction } => {
+                let framing = self.conns.get_mut(id)?;
+                let situation = framing.check_incomplete(*direction).err()?;
+                let sender = direction.sender();
+                Some((
+                    "error",
+                    format!("{id} {sender} closed the connection {situation}"),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn notify(&mut self, kind: &'static str, text: &str) -> io::Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.last.insert(kind, now) {
+            if now.duration_since(last) < Self::MIN_INTERVAL {
+                self.last.insert(kind, last);
+                return Ok(());
+            }
+        }
+        match &self.how {
+            Notify::Bell => {
+                // stderr, because stdout may well be redirected to a file
+                let mut err = io::stderr();
+                err.write_all(b"\x07")?;
+                err.flush()
+            }
+            Notify::Command(command) => {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("MAPIPROXY_EVENT", kind)
+                    .env("MAPIPROXY_MESSAGE", text)
+                    .stdin(Stdio::null())
+                    .spawn()?;
+                // do not hold up the proxy, but do not leave zombies either
+                thread::spawn(move || child.wait());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Recorder for Notifier {
+    fn record(&mut self, event: &MapiEvent, _now: Duration) -> io::Result<()> {
+        let Some((kind, text)) = self.check(event) else {
+            return Ok(());
+        };
+        if let Err(e) = self.notify(kind, &text) {
+            // a notification that cannot be delivered should not stop the proxy
+            eprintln!("mapiproxy: --notify: {e}");
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, _now: Duration) -> AResult<()> {
+        Ok(())
+    }
+}
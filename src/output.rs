@@ -0,0 +1,248 @@
+//! Implementation of -o/--output. Every output has a [mapi::State] and a
+//! [Renderer] of its own, so one output can show whole messages in color on
+//! the terminal while another writes raw bytes to a file. They are all fed
+//! the same events. An output with format=json writes them as JSON Lines
+//! instead, see [JsonLines].
+
+use std::{fmt::Display, fs::File, io, path::PathBuf};
+
+use anyhow::{bail, Context, Result as AResult};
+use mapiproxy::{
+    clock::TimeSource,
+    mapi,
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+    render::{Brief, Renderer},
+    Level,
+};
+
+use crate::{jsonl::JsonLines, parse_brief, parse_color, parse_format, parse_level};
+
+/// What is known about an output after parsing the command line. Settings
+/// that are None are taken from the global options.
+#[derive(Debug)]
+pub struct OutputSpec {
+    /// None means stdout
+    pub path: Option<PathBuf>,
+    /// Some(None) means only the events, as with --events
+    pub level: Option<Option<Level>>,
+    /// Some(None) means auto
+    pub colored: Option<Option<bool>>,
+    pub brief: Option<Brief>,
+    pub format: Option<Format>,
+}
+
+/// What an output looks like, see --format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Drawn by the [Renderer] for people to read
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Parse `FILE[,SETTING=VALUE]...`. A FILE of `-` means stdout.
+pub fn parse_output(setting: &str, value: &str) -> AResult<OutputSpec> {
+    let mut parts = value.split(',');
+    let path = match parts.next() {
+        Some("-") => None,
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => bail!("{setting}={value}: expected a file name or '-'"),
+    };
+    let mut spec = OutputSpec {
+        path,
+        level: None,
+        colored: None,
+        brief: None,
+        format: None,
+    };
+    for part in parts {
+        let Some((key, val)) = part.split_once('=') else {
+            bail!("{setting}={value}: expected SETTING=VALUE, not '{part}'");
+        };
+        match key {
+            "mode" if val.eq_ignore_ascii_case("events") => spec.level = Some(None),
+            "mode" => spec.level = Some(Some(parse_level(setting, val)?)),
+            "color" => spec.colored = Some(parse_color(setting, val)?),
+            "brief" => spec.brief = Some(parse_brief(setting, val)?),
+            "format" => spec.format = Some(parse_format(setting, val)?),
+            other => bail!(
+                "{setting}={value}: unknown setting '{other}', expected 'mode', 'color', 'brief' or 'format'"
+            ),
+        }
+    }
+    Ok(spec)
+}
+
+impl OutputSpec {
+    /// Where the output goes. The flag tells whether that is a terminal.
+    pub fn open(&self) -> AResult<(Box<dyn io::Write + Send>, bool)> {
+        match &self.path {
+            None => {
+                let out = io::stdout();
+                let terminal = is_terminal::is_terminal(&out);
+                Ok((Box::new(out), terminal))
+            }
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("Could not create output file {}", path.display()))?;
+                let terminal = is_terminal::is_terminal(&file);
+                Ok((Box::new(file), terminal))
+            }
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum Output {
+    Rendered {
+        state: mapi::State,
+        renderer: Renderer,
+    },
+    Json(JsonLines),
+}
+
+/// All outputs. There are none for `mapiproxy summary`, which only prints
+/// the histograms of `--stats`.
+pub struct Outputs {
+    outputs: Vec<Output>,
+    /// The brief settings of the outputs while [Outputs::toggle_brief] has
+    /// switched them
+    toggled: Option<Vec<Brief>>,
+}
+
+impl Outputs {
+    pub fn new(outputs: Vec<Output>) -> Outputs {
+        Outputs {
+            outputs,
+            toggled: None,
+        }
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> io::Result<()> {
+        for output in &mut self.outputs {
+            match output {
+                Output::Rendered { state, renderer } => state.handle(event, renderer)?,
+                Output::Json(json) => json.handle(event)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Show a message that is not about the traffic on every output.
+    pub fn message(
+        &mut self,
+        id: Option<ConnectionId>,
+        direction: Option<Direction>,
+        message: impl Display,
+    ) -> io::Result<()> {
+        for output in &mut self.outputs {
+            match output {
+                Output::Rendered { renderer, .. } => renderer.message(id, direction, &message)?,
+                Output::Json(json) => json.message(id, direction, &message)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch the outputs that show frames in full to [Brief::DEFAULT] lines
+    /// and the others to showing them in full, or back again. Returns true if
+    /// the first output now abbreviates frames. JSON outputs always show
+    /// them in full.
+    pub fn toggle_brief(&mut self) -> bool {
+        let mut renderers: Vec<&mut Renderer> = self
+            .outputs
+            .iter_mut()
+            .filter_map(|output| match output {
+                Output::Rendered { renderer, .. } => Some(renderer),
+                Output::Json(_) => None,
+            })
+            .collect();
+        match self.toggled.take() {
+            Some(saved) => {
+                for (renderer, brief) in renderers.iter_mut().zip(saved) {
+                    renderer.set_brief(brief);
+                }
+            }
+            None => {
+                let mut saved = vec![];
+                for renderer in &mut renderers {
+                    let brief = renderer.brief();
+                    saved.push(brief);
+                    renderer.set_brief(if brief == Brief::default() {
+                        Brief::all(Some(Brief::DEFAULT))
+                    } else {
+                        Brief::default()
+                    });
+                }
+                self.toggled = Some(saved);
+            }
+        }
+        renderers
+            .first()
+            .is_some_and(|renderer| renderer.brief() != Brief::default())
+    }
+
+    /// Make every output take the time from `clock`, see
+    /// [Renderer::set_clock].
+    pub fn set_clock(&mut self, clock: impl TimeSource + Clone + 'static) {
+        for output in &mut self.outputs {
+            match output {
+                Output::Rendered { renderer, .. } => renderer.set_clock(clock.clone()),
+                Output::Json(json) => json.set_clock(clock.clone()),
+            }
+        }
+    }
+
+    /// Gather raw dumps on the outputs that show them, see
+    /// [mapi::State::set_condensed].
+    pub fn set_condensed(&mut self, condensed: bool) -> io::Result<()> {
+        for output in &mut self.outputs {
+            if let Output::Rendered { state, renderer } = output {
+                state.set_condensed(condensed, renderer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// True if any output shows the data as it comes in.
+    pub fn shows_raw(&self) -> bool {
+        self.outputs.iter().any(|output| match output {
+            Output::Rendered { state, .. } => state.shows_raw(),
+            Output::Json(_) => false,
+        })
+    }
+
+    /// True if no output shows the data.
+    pub fn events_only(&self) -> bool {
+        self.outputs.iter().all(|output| match output {
+            Output::Rendered { state, .. } => state.events_only(),
+            Output::Json(json) => json.events_only(),
+        })
+    }
+
+    /// Bytes held back by all outputs together, see [mapi::State::buffered].
+    pub fn buffered(&self) -> usize {
+        self.outputs
+            .iter()
+            .map(|output| match output {
+                Output::Rendered { state, .. } => state.buffered(),
+                Output::Json(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Protocol errors found in the traffic. Outputs that do not parse the
+    /// messages, for example at `--events`, find none, so this takes the
+    /// largest count rather than adding them up.
+    pub fn protocol_errors(&self) -> usize {
+        self.outputs
+            .iter()
+            .map(|output| match output {
+                Output::Rendered { state, .. } => state.protocol_errors(),
+                Output::Json(json) => json.protocol_errors(),
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
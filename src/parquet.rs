@@ -0,0 +1,632 @@
+//! Implementation of --parquet. Writes one row per message to Parquet files
+//! in a directory, so large captures can be aggregated with tools like DuckDB.
+//!
+//! The files are written by hand rather than through the Arrow libraries:
+//! one row group per file, one uncompressed PLAIN encoded page per column.
+//! The metadata is encoded with the Thrift compact protocol by [Thrift].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use mapiproxy::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+use crate::{
+    exchange::{Exchanges, Observed, Outcome, Recorder, RoundTrip},
+    VERSION,
+};
+
+pub struct ParquetLog {
+    dir: PathBuf,
+    files: usize,
+    exchanges: Exchanges,
+    observed: Vec<Observed>,
+    conns: HashMap<ConnectionId, Conversation>,
+    rows: Rows,
+}
+
+#[derive(Default)]
+struct Conversation {
+    /// When the requests that have not been answered yet were sent
+    waiting: VecDeque<Duration>,
+    rtt: Option<RoundTrip>,
+}
+
+/// The rows that have not been written yet, column by column.
+#[derive(Default)]
+struct Rows {
+    conn: Vec<i64>,
+    direction: Vec<&'static str>,
+    time: Vec<i64>,
+    size: Vec<i64>,
+    kind: Vec<&'static str>,
+    latency: Vec<Option<f64>>,
+    network: Vec<Option<f64>>,
+    server: Vec<Option<f64>>,
+}
+
+/// The values of one column, see [Rows::columns].
+enum Values<'a> {
+    Int64(&'a [i64]),
+    Timestamp(&'a [i64]),
+    Text(&'a [&'static str]),
+    OptionalDouble(&'a [Option<f64>]),
+}
+
+impl ParquetLog {
+    /// A new file is started after this many rows.
+    const ROWS_PER_FILE: usize = 100_000;
+
+    pub fn create(dir: &Path) -> AResult<ParquetLog> {
+        let context = || format!("Could not prepare Parquet directory {}", dir.display());
+        fs::create_dir_all(dir).with_context(context)?;
+        // the files of an earlier run may hold its results, and would mix
+        // with the new ones
+        for entry in fs::read_dir(dir).with_context(context)? {
+            let path = entry.with_context(context)?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with("messages-") && name.ends_with(".parquet") {
+                bail!("--parquet: {} already exists", path.display());
+            }
+        }
+        Ok(ParquetLog {
+            dir: dir.to_owned(),
+            files: 0,
+            exchanges: Exchanges::default(),
+            observed: vec![],
+            conns: HashMap::new(),
+            rows: Rows::default(),
+        })
+    }
+
+    fn add_rows(&mut self) -> io::Result<()> {
+        for obs in self.observed.drain(..) {
+            match obs {
+                Observed::Opened { id, .. } => {
+                    self.conns.insert(id, Conversation::default());
+                }
+                Observed::RoundTrip { id, rtt } => {
+                    if let Some(conv) = self.conns.get_mut(&id) {
+                        conv.rtt = Some(rtt);
+                    }
+                }
+                Observed::Message {
+                    id,
+                    direction,
+                    message,
+                } => {
+                    let Some(conv) = self.conns.get_mut(&id) else {
+                        continue;
+                    };
+                    let (kind, latency) = match direction {
+                        Direction::Upstream => {
+                            conv.waiting.push_back(message.completed);
+                            (message.request_kind(), None)
+                        }
+                        Direction::Downstream => match conv.waiting.pop_front() {
+                            Some(sent) => {
+                                let latency = message.completed.saturating_sub(sent);
+                                let outcome = Outcome::of(Some(&message));
+                                (outcome.name(), Some(latency))
+                            }
+                            // Messages the server sends on its own
+                            None => ("CHALLENGE", None),
+                        },
+                    };
+                    let split = latency.zip(conv.rtt).map(|(l, rtt)| rtt.split(l));
+                    let rows = &mut self.rows;
+                    rows.conn.push(id.as_usize() as i64);
+                    rows.direction.push(match direction {
+                        Direction::Upstream => "upstream",
+                        Direction::Downstream => "downstream",
+                    });
+                    rows.time.push(message.started.as_micros() as i64);
+                    rows.size.push(message.size as i64);
+                    rows.kind.push(kind);
+                    rows.latency.push(latency.map(|l| l.as_secs_f64()));
+                    rows.network.push(split.map(|(n, _)| n.as_secs_f64()));
+                    rows.server.push(split.map(|(_, s)| s.as_secs_f64()));
+                }
+                Observed::Closed { id, .. } => {
+                    self.conns.remove(&id);
+                }
+                _ => {}
+            }
+        }
+        if self.rows.conn.len() >= Self::ROWS_PER_FILE {
+            self.write_file()?;
+        }
+        Ok(())
+    }
+
+    /// Write the collected rows to the next file. It is written under a
+    /// temporary name first so other programs never see half a file.
+    fn write_file(&mut self) -> io::Result<()> {
+        self.files += 1;
+        let name = format!("messages-{:04}.parquet", self.files);
+        let path = self.dir.join(&name);
+        let tmp = self.dir.join(format!(".{name}.tmp"));
+        fs::write(&tmp, self.rows.encode())?;
+        fs::rename(&tmp, &path)?;
+        self.rows = Rows::default();
+        Ok(())
+    }
+}
+
+impl Recorder for ParquetLog {
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()> {
+        self.exchanges.process(event, now, &mut self.observed);
+        self.add_rows()
+    }
+
+    /// Write the remaining rows. If no file has been written yet, an empty
+    /// one is written so the directory can always be queried.
+    fn finish(mut self: Box<Self>, now: Duration) -> AResult<()> {
+        self.exchanges.finish(now, &mut self.observed);
+        self.add_rows()?;
+        if !self.rows.conn.is_empty() || self.files == 0 {
+            self.write_file()
+                .with_context(|| format!("Could not write to {}", self.dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Rows {
+    fn columns(&self) -> [(&'static str, Values<'_>); 8] {
+        [
+            ("conn", Values::Int64(&self.conn)),
+            ("direction", Values::Text(&self.direction)),
+            ("time", Values::Timestamp(&self.time)),
+            ("size", Values::Int64(&self.size)),
+            ("kind", Values::Text(&self.kind)),
+            ("latency", Values::OptionalDouble(&self.latency)),
+            ("network", Values::OptionalDouble(&self.network)),
+            ("server", Values::OptionalDouble(&self.server)),
+        ]
+    }
+
+    /// The complete contents of a Parquet file holding these rows.
+    fn encode(&self) -> Vec<u8> {
+        let num_rows = self.conn.len();
+        let mut file = b"PAR1".to_vec();
+        let mut chunks = vec![];
+        for (name, values) in self.columns() {
+            let offset = file.len();
+            let data = values.encode();
+            let mut header = Thrift::default();
+            header.i32(1, 0); // DATA_PAGE
+            header.i32(2, data.len() as i32);
+            header.i32(3, data.len() as i32);
+            header.begin_struct(5);
+            header.i32(1, num_rows as i32);
+            header.i32(2, 0); // PLAIN
+            header.i32(3, 3); // RLE
+            header.i32(4, 3); // RLE
+            header.end_struct();
+            header.end_struct();
+            file.extend_from_slice(&header.buf);
+            file.extend_from_slice(&data);
+            chunks.push((name, values.physical_type(), offset, file.len() - offset));
+        }
+        let total_size: usize = chunks.iter().map(|c| c.3).sum();
+
+        let mut meta = Thrift::default();
+        meta.i32(1, 1);
+        meta.list(2, Thrift::STRUCT, chunks.len() + 1);
+        meta.string(4, "schema");
+        meta.i32(5, chunks.len() as i32);
+        meta.end_struct();
+        for (name, values) in self.columns() {
+            meta.i32(1, values.physical_type());
+            meta.i32(3, i32::from(values.is_optional()));
+            meta.string(4, name);
+            if let Some(converted) = values.converted_type() {
+                meta.i32(6, converted);
+            }
+            meta.end_struct();
+        }
+        meta.i64(3, num_rows as i64);
+        meta.list(4, Thrift::STRUCT, 1);
+        meta.list(1, Thrift::STRUCT, chunks.len());
+        for &(name, physical_type, offset, size) in &chunks {
+            meta.i64(2, offset as i64);
+            meta.begin_struct(3);
+            meta.i32(1, physical_type);
+            meta.list(2, Thrift::I32, 2);
+            meta.list_i32(0); // PLAIN
+            meta.list_i32(3); // RLE
+            meta.list(3, Thrift::BINARY, 1);
+            meta.list_string(name);
+            meta.i32(4, 0); // UNCOMPRESSED
+            meta.i64(5, num_rows as i64);
+            meta.i64(6, size as i64);
+            meta.i64(7, size as i64);
+            meta.i64(9, offset as i64);
+            meta.end_struct();
+            meta.end_struct();
+        }
+        meta.i64(2, total_size as i64);
+        meta.i64(3, num_rows as i64);
+        meta.end_struct();
+        meta.string(6, &format!("mapiproxy version {VERSION}"));
+        meta.end_struct();
+
+        file.extend_from_slice(&meta.buf);
+        file.extend_from_slice(&(meta.buf.len() as u32).to_le_bytes());
+        file.extend_from_slice(b"PAR1");
+        file
+    }
+}
+
+impl Values<'_> {
+    fn physical_type(&self) -> i32 {
+        match self {
+            Values::Int64(_) | Values::Timestamp(_) => 2,
+            Values::Text(_) => 6,
+            Values::OptionalDouble(_) => 5,
+        }
+    }
+
+    fn converted_type(&self) -> Option<i32> {
+        match self {
+            Values::Timestamp(_) => Some(10), // TIMESTAMP_MICROS
+            Values::Text(_) => Some(0),       // UTF8
+            _ => None,
+        }
+    }
+
+    fn is_optional(&self) -> bool {
+        matches!(self, Values::OptionalDouble(_))
+    }
+
+    /// The page data: definition levels for optional columns, followed by
+    /// the values that are present.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Values::Int64(values) | Values::Timestamp(values) => {
+                for v in *values {
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            Values::Text(values) => {
+                for v in *values {
+                    out.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                    out.extend_from_slice(v.as_bytes());
+                }
+            }
+            Values::OptionalDouble(values) => {
+                // RLE runs of bit width 1, prefixed with their total length
+                let mut levels = vec![];
+                for run in values.chunk_by(|a, b| a.is_some() == b.is_some()) {
+                    varint(&mut levels, (run.len() as u64) << 1);
+                    levels.push(u8::from(run[0].is_some()));
+                }
+                out.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+                out.extend_from_slice(&levels);
+                for v in values.iter().flatten() {
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Encoder for the Thrift compact protocol, just enough for the Parquet
+/// metadata. Structs are ended explicitly, list elements that are structs
+/// are written as a sequence of fields followed by [Thrift::end_struct].
+#[derive(Default)]
+struct Thrift {
+    buf: Vec<u8>,
+    /// The last field id written in each open struct
+    last: Vec<i16>,
+    current: i16,
+}
+
+impl Thrift {
+    const I32: u8 = 5;
+    const I64: u8 = 6;
+    const BINARY: u8 = 8;
+    const LIST: u8 = 9;
+    const STRUCT: u8 = 12;
+
+    fn field(&mut self, id: i16, ty: u8) {
+        let delta = id - self.current;
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | ty);
+        } else {
+            self.buf.push(ty);
+            varint(&mut self.buf, zigzag(id.into()));
+        }
+        self.current = id;
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, Self::I32);
+        varint(&mut self.buf, zigzag(value.into()));
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, Self::I64);
+        varint(&mut self.buf, zigzag(value));
+    }
+
+    fn string(&mut self, id: i16, value: &str) {
+        self.field(id, Self::BINARY);
+        self.list_string(value);
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, Self::STRUCT);
+        self.last.push(self.current);
+        self.current = 0;
+    }
+
+    fn end_struct(&mut self) {
+        self.buf.push(0);
+        self.current = self.last.pop().unwrap_or(0);
+    }
+
+    /// Start a list field of `len` elements. Struct elements must each be
+    /// closed with [Thrift::end_struct], the list itself needs no end.
+    fn list(&mut self, id: i16, elem: u8, len: usize) {
+        self.field(id, Self::LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | elem);
+        } else {
+            self.buf.push(0xF0 | elem);
+            varint(&mut self.buf, len as u64);
+        }
+        if elem == Self::STRUCT && len > 0 {
+            // ending an element starts the next one, ending the last one
+            // returns to the enclosing struct
+            self.last.push(id);
+            self.last.extend(std::iter::repeat_n(0, len - 1));
+            self.current = 0;
+        }
+    }
+
+    fn list_i32(&mut self, value: i32) {
+        varint(&mut self.buf, zigzag(value.into()));
+    }
+
+    fn list_string(&mut self, value: &str) {
+        varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+#[test]
+fn test_create_keeps_existing_files() {
+    let dir = std::env::temp_dir().join(format!("mapiproxy-test-{}", std::process::id()));
+    let earlier = dir.join("messages-0001.parquet");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(&earlier, "earlier results").unwrap();
+    let result = ParquetLog::create(&dir);
+    let contents = fs::read_to_string(&earlier);
+    let _ = fs::remove_dir_all(&dir);
+
+    let Err(err) = result else {
+        panic!("existing files were accepted");
+    };
+    assert!(err.to_string().ends_with("already exists"), "{err}");
+    assert_eq!(contents.unwrap(), "earlier results");
+}
+
+#[test]
+fn test_encode_rows() {
+    /// A value read back with the Thrift compact protocol
+    #[derive(Debug)]
+    enum Parsed {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Parsed>),
+        Struct(Vec<(i16, Parsed)>),
+    }
+
+    impl Parsed {
+        fn get(&self, id: i16) -> &Parsed {
+            let Parsed::Struct(fields) = self else {
+                panic!("not a struct: {self:?}");
+            };
+            let found = fields.iter().find(|(i, _)| *i == id);
+            &found.unwrap_or_else(|| panic!("no field {id}")).1
+        }
+
+        fn int(&self) -> i64 {
+            let Parsed::Int(n) = self else {
+                panic!("not an int: {self:?}");
+            };
+            *n
+        }
+
+        fn text(&self) -> &str {
+            let Parsed::Binary(b) = self else {
+                panic!("not a binary: {self:?}");
+            };
+            std::str::from_utf8(b).unwrap()
+        }
+
+        fn list(&self) -> &[Parsed] {
+            let Parsed::List(items) = self else {
+                panic!("not a list: {self:?}");
+            };
+            items
+        }
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn take(&mut self, n: usize) -> &[u8] {
+            let (taken, rest) = self.0.split_at(n);
+            self.0 = rest;
+            taken
+        }
+
+        fn varint(&mut self) -> u64 {
+            let mut n = 0;
+            for shift in (0..64).step_by(7) {
+                let b = self.take(1)[0];
+                n |= u64::from(b & 0x7F) << shift;
+                if b < 0x80 {
+                    break;
+                }
+            }
+            n
+        }
+
+        fn int(&mut self) -> i64 {
+            let n = self.varint();
+            (n >> 1) as i64 ^ -((n & 1) as i64)
+        }
+
+        fn value(&mut self, ty: u8) -> Parsed {
+            match ty {
+                Thrift::I32 | Thrift::I64 => Parsed::Int(self.int()),
+                Thrift::BINARY => {
+                    let len = self.varint() as usize;
+                    Parsed::Binary(self.take(len).to_vec())
+                }
+                Thrift::LIST => {
+                    let header = self.take(1)[0];
+                    let len = match header >> 4 {
+                        15 => self.varint() as usize,
+                        n => n as usize,
+                    };
+                    Parsed::List((0..len).map(|_| self.value(header & 15)).collect())
+                }
+                Thrift::STRUCT => {
+                    let mut fields = vec![];
+                    let mut id = 0;
+                    loop {
+                        let header = self.take(1)[0];
+                        if header == 0 {
+                            return Parsed::Struct(fields);
+                        }
+                        id = match header >> 4 {
+                            0 => self.int() as i16,
+                            delta => id + delta as i16,
+                        };
+                        fields.push((id, self.value(header & 15)));
+                    }
+                }
+                _ => panic!("unexpected type {ty}"),
+            }
+        }
+    }
+
+    let mut rows = Rows::default();
+    for (conn, direction, kind, latency, network) in [
+        (10, "downstream", "CHALLENGE", None, None),
+        (10, "upstream", "LOGIN", None, None),
+        (10, "downstream", "OK", Some(0.5), None),
+        (12, "downstream", "ERROR", Some(0.25), Some(0.125)),
+    ] {
+        rows.conn.push(conn);
+        rows.direction.push(direction);
+        rows.time.push(1_700_000_000_000_000 + conn);
+        rows.size.push(conn * 100);
+        rows.kind.push(kind);
+        rows.latency.push(latency);
+        rows.network.push(network);
+        rows.server.push(network.map(|n| 0.25 - n));
+    }
+    let file = rows.encode();
+
+    assert_eq!(&file[..4], b"PAR1");
+    assert_eq!(&file[file.len() - 4..], b"PAR1");
+    let footer = file.len() - 8;
+    let meta_len = u32::from_le_bytes(file[footer..footer + 4].try_into().unwrap()) as usize;
+    let mut reader = Reader(&file[footer - meta_len..footer]);
+    let meta = reader.value(Thrift::STRUCT);
+    assert!(
+        reader.0.is_empty(),
+        "metadata has {} bytes left",
+        reader.0.len()
+    );
+
+    assert_eq!(meta.get(3).int(), 4);
+    let schema = meta.get(2).list();
+    assert_eq!(schema[0].get(5).int(), 8);
+    let names: Vec<_> = schema[1..].iter().map(|s| s.get(4).text()).collect();
+    assert_eq!(
+        names,
+        [
+            "conn",
+            "direction",
+            "time",
+            "size",
+            "kind",
+            "latency",
+            "network",
+            "server"
+        ]
+    );
+
+    let row_group = &meta.get(4).list()[0];
+    assert_eq!(row_group.get(3).int(), 4);
+    let mut pages = vec![];
+    for chunk in row_group.get(1).list() {
+        let chunk_meta = chunk.get(3);
+        assert_eq!(chunk_meta.get(5).int(), 4);
+        let offset = chunk_meta.get(9).int() as usize;
+        let size = chunk_meta.get(7).int() as usize;
+        let mut reader = Reader(&file[offset..offset + size]);
+        let header = reader.value(Thrift::STRUCT);
+        assert_eq!(header.get(5).get(1).int(), 4);
+        assert_eq!(header.get(3).int() as usize, reader.0.len());
+        pages.push(reader.0);
+    }
+
+    let conns: Vec<_> = pages[0]
+        .chunks(8)
+        .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+        .collect();
+    assert_eq!(conns, [10, 10, 10, 12]);
+
+    let mut reader = Reader(pages[4]);
+    let kinds: Vec<_> = (0..4)
+        .map(|_| {
+            let len = u32::from_le_bytes(reader.take(4).try_into().unwrap()) as usize;
+            String::from_utf8(reader.take(len).to_vec()).unwrap()
+        })
+        .collect();
+    assert_eq!(kinds, ["CHALLENGE", "LOGIN", "OK", "ERROR"]);
+
+    // definition levels: a run of two absent values, then two present
+    let mut reader = Reader(pages[5]);
+    let levels_len = u32::from_le_bytes(reader.take(4).try_into().unwrap()) as usize;
+    let mut levels = Reader(reader.take(levels_len));
+    let mut defined = vec![];
+    while !levels.0.is_empty() {
+        let run = levels.varint() >> 1;
+        let value = levels.take(1)[0];
+        defined.extend(std::iter::repeat_n(value, run as usize));
+    }
+    assert_eq!(defined, [0, 0, 1, 1]);
+    let latencies: Vec<_> = reader
+        .0
+        .chunks(8)
+        .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        .collect();
+    assert_eq!(latencies, [0.5, 0.25]);
+}
@@ -0,0 +1,119 @@
+//! Validation of the IP and TCP checksums of captured packets, see
+//! [Tracker::set_check_checksums](super::Tracker::set_check_checksums).
+
+use std::net::IpAddr;
+
+use etherparse::{LaxNetSlice, TcpSlice};
+
+/// Which checksum of the packet is wrong, "IP" or "TCP", if any.
+///
+/// Packets sent by the capturing host itself often have not been given
+/// their checksums yet, because the network card fills them in later. Their
+/// checksum field is then zero or holds only the sum of the pseudo header,
+/// and on the loopback interface the checksums are never filled in at all.
+/// Such packets are not reported.
+pub(super) fn check(net: &LaxNetSlice, tcp: &TcpSlice) -> Option<&'static str> {
+    // the sum of the pseudo header the TCP checksum covers, except the length
+    let (src, dest, without_length) = match net {
+        LaxNetSlice::Ipv4(ipv4) => {
+            let header = ipv4.header();
+            if header.header_checksum() != 0 && fold(sum(header.slice(), 0)) != 0xFFFF {
+                return Some("IP");
+            }
+            let pseudo = sum(&header.source(), sum(&header.destination(), TCP));
+            (
+                IpAddr::from(header.source_addr()),
+                IpAddr::from(header.destination_addr()),
+                pseudo,
+            )
+        }
+        LaxNetSlice::Ipv6(ipv6) => {
+            let header = ipv6.header();
+            let pseudo = sum(&header.source(), sum(&header.destination(), TCP));
+            (
+                IpAddr::from(header.source_addr()),
+                IpAddr::from(header.destination_addr()),
+                pseudo,
+            )
+        }
+    };
+    if src.is_loopback() || dest.is_loopback() {
+        return None;
+    }
+
+    let segment = tcp.slice();
+    let with_length = without_length + segment.len() as u32;
+    if fold(sum(segment, with_length)) == 0xFFFF {
+        return None;
+    }
+    let field = tcp.checksum();
+    let offloaded = field == 0 || field == fold(with_length) || field == fold(without_length);
+    (!offloaded).then_some("TCP")
+}
+
+/// The protocol number in the pseudo header.
+const TCP: u32 = 6;
+
+/// Add the bytes to `acc` as big-endian 16-bit words, padding an odd length
+/// with a zero byte.
+fn sum(data: &[u8], acc: u32) -> u32 {
+    data.chunks(2).fold(acc, |acc, chunk| {
+        let word = u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]);
+        // fold early so it cannot overflow, whatever the length
+        let acc = acc + word as u32;
+        (acc & 0xFFFF) + (acc >> 16)
+    })
+}
+
+/// Fold the carries back in to get the ones' complement sum.
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xFFFF {
+        acc = (acc & 0xFFFF) + (acc >> 16);
+    }
+    acc as u16
+}
+
+#[test]
+fn test_checksums() {
+    use etherparse::{LaxSlicedPacket, PacketBuilder};
+
+    let build = |src: [u8; 4], dest: [u8; 4]| {
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(src, dest, 64)
+            .tcp(50000, 50001, 1000, 4096);
+        let payload = b"\x0b\x00hello";
+        let mut packet = Vec::new();
+        builder.write(&mut packet, payload).unwrap();
+        packet
+    };
+    let check_packet = |packet: &[u8]| {
+        let sliced = LaxSlicedPacket::from_ethernet(packet).unwrap();
+        let Some(etherparse::TransportSlice::Tcp(tcp)) = &sliced.transport else {
+            panic!("no tcp")
+        };
+        check(sliced.net.as_ref().unwrap(), tcp)
+    };
+
+    let good = build([10, 0, 0, 1], [10, 0, 0, 2]);
+    assert_eq!(check_packet(&good), None);
+
+    let mut bad_payload = good.clone();
+    *bad_payload.last_mut().unwrap() ^= 0x20;
+    assert_eq!(check_packet(&bad_payload), Some("TCP"));
+
+    // the TTL is at offset 8 of the IP header, after the Ethernet header
+    let mut bad_header = good.clone();
+    bad_header[14 + 8] -= 1;
+    assert_eq!(check_packet(&bad_header), Some("IP"));
+
+    // not filled in yet because the network card would do that
+    let mut offloaded = good.clone();
+    let tcp_checksum = 14 + 20 + 16;
+    offloaded[tcp_checksum..tcp_checksum + 2].copy_from_slice(&[0, 0]);
+    *offloaded.last_mut().unwrap() ^= 0x20;
+    assert_eq!(check_packet(&offloaded), None);
+
+    let mut loopback = build([127, 0, 0, 1], [127, 0, 0, 1]);
+    *loopback.last_mut().unwrap() ^= 0x20;
+    assert_eq!(check_packet(&loopback), None);
+}
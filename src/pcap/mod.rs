@@ -1,26 +1,88 @@
+mod checksum;
 mod mybufread;
 mod tcp;
 mod tracker;
+mod writer;
+mod zstd;
 
 use std::io;
 
-use anyhow::{bail, Result as AResult};
-
+use flate2::bufread::MultiGzDecoder;
 use pcap_file::{
     pcap::PcapReader,
-    pcapng::{Block, PcapNgReader},
+    pcapng::{
+        blocks::{
+            interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption},
+            interface_statistics::{InterfaceStatisticsBlock, InterfaceStatisticsOption},
+            section_header::SectionHeaderOption,
+        },
+        Block, PcapNgReader,
+    },
     DataLink,
 };
 
+use crate::proxy::event::MapiEvent;
+
 use self::mybufread::MyBufReader;
-pub use self::tracker::Tracker;
+pub use self::tracker::{Clock, TimeShift, Tracker, UnknownBlocks};
+pub use self::writer::PcapWriter;
+use self::zstd::ZstdReader;
+use thiserror::Error as ThisError;
+
+/// Errors that can occur while reading a capture file. Problems with the MAPI
+/// traffic in the file are not errors, they are rendered like any other
+/// traffic.
+#[derive(Debug, ThisError)]
+pub enum PcapError {
+    #[error("Could not read pcap file: {0}")]
+    Read(io::Error),
+
+    #[error("Could not write pcap file: {0}")]
+    Write(io::Error),
+
+    #[error("Unknown pcap file signature {:02X} {:02X} {:02X} {:02X}", .0[0], .0[1], .0[2], .0[3])]
+    Signature([u8; 4]),
+
+    #[error("{0}")]
+    Format(#[from] pcap_file::PcapError),
+
+    #[error("Could not decompress zstd data: {0}")]
+    Zstd(#[from] ruzstd::decoding::errors::FrameDecoderError),
+
+    #[error("truncated packet")]
+    TruncatedPacket,
+
+    #[error("pcap file contains packet of type {0:?}, this is not supported")]
+    LinkType(DataLink),
+
+    #[error("{0}")]
+    Packet(#[from] etherparse::err::packet::SliceError),
+
+    #[error("pcap file contains fragmented {0} packet, not supported")]
+    Fragmented(&'static str),
+
+    #[error("transport not found, expected this only with fragmented packets")]
+    NoTransport,
+
+    /// See [Tracker::set_strict]
+    #[error("pcap file contains block of unknown type 0x{block_type:08X}, {length} bytes")]
+    UnknownBlock { block_type: u32, length: u32 },
+
+    /// The event handler passed to [Tracker::new] failed
+    #[error(transparent)]
+    Handler(io::Error),
+}
+
+type Result<T> = std::result::Result<T, PcapError>;
 
 /// Parse PCAP records from the reader and hand the packets to the Tracker. This
-/// function works with both the old-style PCAP and with PCAP-NG file formats.
-pub fn parse_pcap_file(mut rd: impl io::Read, tracker: &mut Tracker) -> AResult<()> {
+/// function works with both the old-style PCAP and with PCAP-NG file formats,
+/// also when compressed with gzip or zstd. The reader can also be an
+/// in-memory `&[u8]`, for example in a wasm32 build.
+pub fn parse_pcap_file(mut rd: impl io::Read, tracker: &mut Tracker) -> Result<()> {
     // read ahead to inspect the file header
     let mut signature = [0u8; 4];
-    rd.read_exact(&mut signature)?;
+    rd.read_exact(&mut signature).map_err(PcapError::Read)?;
 
     // create a MyBufReader, which is basically a BufReader except
     // that we preload it with the bytes we read above
@@ -34,18 +96,14 @@ pub fn parse_pcap_file(mut rd: impl io::Read, tracker: &mut Tracker) -> AResult<
             parse_legacy_pcap(mybufreader, tracker)
         }
         [0x0A, 0x0D, 0x0D, 0x0A] => parse_pcap_ng(mybufreader, tracker),
-        _ => bail!(
-            "Unknown pcap file signature {:02X} {:02X} {:02X} {:02X}",
-            signature[0],
-            signature[1],
-            signature[2],
-            signature[3]
-        ),
+        [0x1F, 0x8B, _, _] => parse_pcap_file(MultiGzDecoder::new(mybufreader), tracker),
+        [0x28, 0xB5, 0x2F, 0xFD] => parse_pcap_file(ZstdReader::new(mybufreader)?, tracker),
+        _ => Err(PcapError::Signature(signature)),
     }
 }
 
 /// Parse the file as legacy PCAP and pass the packets to [process_packet]
-fn parse_legacy_pcap(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
+fn parse_legacy_pcap(rd: MyBufReader, tracker: &mut Tracker) -> Result<()> {
     let mut pcap_reader = PcapReader::new(rd)?;
 
     let header = pcap_reader.header();
@@ -53,9 +111,10 @@ fn parse_legacy_pcap(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
     while let Some(pkt) = pcap_reader.next_packet() {
         let pkt = pkt?;
         if pkt.data.len() == header.snaplen as usize {
-            bail!("truncated packet");
+            return Err(PcapError::TruncatedPacket);
         }
 
+        tracker.set_time(Some(pkt.timestamp));
         process_packet(header.datalink, &pkt.data, tracker)?;
     }
 
@@ -63,7 +122,7 @@ fn parse_legacy_pcap(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
 }
 
 /// Parse the file as PCAP-NG and pass the packets to [process_packet]
-fn parse_pcap_ng(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
+fn parse_pcap_ng(rd: MyBufReader, tracker: &mut Tracker) -> Result<()> {
     let mut pcapng_reader = PcapNgReader::new(rd)?;
 
     // With PCAP-NG the linktype is not a file-global setting but it is set and
@@ -71,21 +130,54 @@ fn parse_pcap_ng(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
     // This mutable holds the latest value we have seen.
     let mut linktype = None;
 
+    // Interfaces are numbered from 0 in each section. The operating system
+    // can be given for the whole section or per interface.
+    let mut interfaces = 0;
+    let mut section_os = None;
+
     while let Some(block) = pcapng_reader.next_block() {
-        let data = match block? {
+        let (data, time) = match block? {
+            Block::SectionHeader(section) => {
+                interfaces = 0;
+                section_os = section.options.into_iter().find_map(|opt| match opt {
+                    SectionHeaderOption::OS(os) => Some(os.into_owned()),
+                    _ => None,
+                });
+                continue;
+            }
             Block::InterfaceDescription(iface) => {
                 linktype = Some(iface.linktype);
+                tracker.emit(describe_interface(interfaces, iface, &section_os))?;
+                interfaces += 1;
+                continue;
+            }
+            Block::InterfaceStatistics(stats) => {
+                tracker.emit(interface_statistics(stats))?;
+                continue;
+            }
+            Block::Packet(packet) => {
+                check_complete(&packet.data, packet.original_len)?;
+                (packet.data, None)
+            }
+            Block::SimplePacket(packet) => {
+                check_complete(&packet.data, packet.original_len)?;
+                (packet.data, None)
+            }
+            Block::EnhancedPacket(packet) => {
+                check_complete(&packet.data, packet.original_len)?;
+                (packet.data, Some(packet.timestamp))
+            }
+            Block::Unknown(block) => {
+                tracker.unknown_block(block.type_, block.length)?;
                 continue;
             }
-            Block::Packet(packet) => packet.data,
-            Block::SimplePacket(packet) => packet.data,
-            Block::EnhancedPacket(packet) => packet.data,
             _ => continue,
         };
 
         // Broken files might contain packets before the first interface description block.
         // Ignore them.
         if let Some(lt) = linktype {
+            tracker.set_time(time);
             process_packet(lt, &data, tracker)?;
         }
     }
@@ -93,13 +185,76 @@ fn parse_pcap_ng(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
     Ok(())
 }
 
+/// The IP headers are not trusted to tell whether a packet was cut off by the
+/// snapshot length, see [Tracker::process_ethernet].
+fn check_complete(data: &[u8], original_len: u32) -> Result<()> {
+    if data.len() < original_len as usize {
+        return Err(PcapError::TruncatedPacket);
+    }
+    Ok(())
+}
+
+fn describe_interface(
+    interface: u32,
+    iface: InterfaceDescriptionBlock,
+    section_os: &Option<String>,
+) -> MapiEvent {
+    let mut name = None;
+    let mut description = None;
+    let mut os = None;
+    let mut filter = None;
+    for opt in iface.options {
+        match opt {
+            InterfaceDescriptionOption::IfName(s) => name = Some(s.into_owned()),
+            InterfaceDescriptionOption::IfDescription(s) => description = Some(s.into_owned()),
+            InterfaceDescriptionOption::IfOs(s) => os = Some(s.into_owned()),
+            // the first byte tells the kind of filter, 0 is a libpcap filter string
+            InterfaceDescriptionOption::IfFilter(f) => {
+                filter = match f.split_first() {
+                    Some((0, text)) => Some(String::from_utf8_lossy(text).into_owned()),
+                    Some(_) => Some("(compiled filter)".to_string()),
+                    None => None,
+                }
+            }
+            _ => {}
+        }
+    }
+    MapiEvent::CaptureInterface {
+        interface,
+        name,
+        description,
+        os: os.or_else(|| section_os.clone()),
+        filter,
+    }
+}
+
+fn interface_statistics(stats: InterfaceStatisticsBlock) -> MapiEvent {
+    let mut received = None;
+    let mut interface_dropped = None;
+    let mut os_dropped = None;
+    for opt in stats.options {
+        match opt {
+            InterfaceStatisticsOption::IsbIfRecv(n) => received = Some(n),
+            InterfaceStatisticsOption::IsbIfDrop(n) => interface_dropped = Some(n),
+            InterfaceStatisticsOption::IsbOsDrop(n) => os_dropped = Some(n),
+            _ => {}
+        }
+    }
+    MapiEvent::CaptureStatistics {
+        interface: stats.interface_id,
+        received,
+        interface_dropped,
+        os_dropped,
+    }
+}
+
 /// This function is called from both [parse_legacy_pcap] and [parse_pcap_ng]
 /// for each packet in the file.
-fn process_packet(linktype: DataLink, data: &[u8], tracker: &mut Tracker) -> AResult<()> {
+fn process_packet(linktype: DataLink, data: &[u8], tracker: &mut Tracker) -> Result<()> {
     // We expect to read ethernet frames but it's also possible for pcap files to
     // capture at the IP level. Right now we only support Ethernet.
     match linktype {
         DataLink::ETHERNET => tracker.process_ethernet(data),
-        _ => bail!("pcap file contains packet of type {linktype:?}, this is not supported"),
+        _ => Err(PcapError::LinkType(linktype)),
     }
 }
@@ -1,13 +1,20 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     io,
     net::{IpAddr, SocketAddr as TcpSocketAddr},
     ops::RangeFrom,
+    time::Duration,
 };
 
-use etherparse::TcpSlice;
+use etherparse::{TcpOptionElement, TcpSlice};
 
-use crate::proxy::event::{ConnectionId, Direction, MapiEvent};
+use crate::{
+    mapi::MAX_BLOCK_SIZE,
+    proxy::{
+        event::{ConnectionId, Direction, MapiEvent},
+        pool::BufferPool,
+    },
+};
 
 type Handler<'a> = dyn FnMut(MapiEvent) -> io::Result<()> + 'a;
 
@@ -38,6 +45,15 @@ pub struct TcpTracker {
     /// Container for the [StreamState]s. Once the connection is fully established,
     /// both its [Key] and its flipped ([Key::flip]) key will have an entry.
     streams: HashMap<Key, StreamState>,
+    /// Where the payload of the [MapiEvent::Data] events is copied into.
+    pool: BufferPool,
+    /// Whether to pick up connections whose handshake was not captured
+    join_mid_stream: bool,
+    /// Connections without a handshake, by the key of the first packet with
+    /// data that was seen, holding the key of the most recent one.
+    joining: HashMap<Key, Key>,
+    /// Whether to emit a [MapiEvent::Segment] for every packet
+    segments: bool,
 }
 
 impl TcpTracker {
@@ -46,15 +62,31 @@ impl TcpTracker {
         TcpTracker {
             conn_ids: 10..,
             streams: Default::default(),
+            pool: BufferPool::new(),
+            join_mid_stream: false,
+            joining: HashMap::new(),
+            segments: false,
         }
     }
 
-    /// Handle a TCP packet.
+    /// Emit a [MapiEvent::Segment] for every packet of a known connection.
+    pub fn set_segments(&mut self, segments: bool) {
+        self.segments = segments;
+    }
+
+    /// Pick up connections that were already open when the capture started,
+    /// see [Self::join].
+    pub fn set_join_mid_stream(&mut self, join: bool) {
+        self.join_mid_stream = join;
+    }
+
+    /// Handle a TCP packet captured at time `now`, if known.
     pub fn handle(
         &mut self,
         src_addr: IpAddr,
         dest_addr: IpAddr,
         tcp: &TcpSlice,
+        now: Option<Duration>,
         handler: &mut Handler,
     ) -> io::Result<()> {
         let key = Key {
@@ -63,10 +95,167 @@ impl TcpTracker {
         };
 
         match (tcp.syn(), tcp.ack()) {
-            (true, false) => self.handle_syn(key, tcp, handler),
-            (true, true) => self.handle_syn_ack(key, tcp, handler),
-            _ => self.handle_existing(key, tcp, handler),
+            (true, false) => {
+                self.handle_syn(key.clone(), tcp, handler)?;
+                self.emit_segment(&key, tcp, handler)?;
+            }
+            (true, true) => {
+                self.handle_syn_ack(key.clone(), tcp, handler)?;
+                self.emit_segment(&key, tcp, handler)?;
+            }
+            _ => {
+                // before the connection can disappear
+                if let Some(now) = now {
+                    self.handle_timestamps(&key, tcp, now, handler)?;
+                    self.handle_window(&key, tcp, now, handler)?;
+                }
+                return self.handle_existing(key, tcp, handler);
+            }
+        }
+        // after the stream has been created
+        if let Some(now) = now {
+            self.handle_timestamps(&key, tcp, now, handler)?;
+        }
+        Ok(())
+    }
+
+    /// Report a packet with a wrong checksum with a [MapiEvent::BadChecksum],
+    /// if it belongs to a connection being followed.
+    pub fn bad_checksum(
+        &mut self,
+        src_addr: IpAddr,
+        dest_addr: IpAddr,
+        tcp: &TcpSlice,
+        layer: &'static str,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        let key = Key {
+            src: (src_addr, tcp.source_port()).into(),
+            dest: (dest_addr, tcp.destination_port()).into(),
+        };
+        let Some(stream) = self.streams.get(&key) else {
+            return Ok(());
+        };
+        handler(MapiEvent::BadChecksum {
+            id: stream.id,
+            direction: stream.dir,
+            offset: stream.offset_of(tcp.sequence_number()),
+            layer,
+        })
+    }
+
+    /// Estimate the round trip times from the TCP timestamp options. When a
+    /// packet echoes a timestamp value, the time since that value was first
+    /// seen going the other way is the round trip time between the point of
+    /// capture and the side that echoes it. Emits a [MapiEvent::RoundTrip]
+    /// once both sides are known, and again when the estimate improves.
+    fn handle_timestamps(
+        &mut self,
+        key: &Key,
+        tcp: &TcpSlice,
+        now: Duration,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        let Some((value, echo)) = tcp.options_iterator().find_map(|opt| match opt {
+            Ok(TcpOptionElement::Timestamp(value, echo)) => Some((value, echo)),
+            _ => None,
+        }) else {
+            return Ok(());
+        };
+        if let Some(stream) = self.streams.get_mut(key) {
+            stream.timestamp_sent(value, now);
+        }
+        // the SYN carries no echo
+        if !tcp.ack() {
+            return Ok(());
+        }
+        let Some(other) = self.streams.get_mut(&key.flip()) else {
+            return Ok(());
+        };
+        if !other.timestamp_echoed(echo, now) {
+            return Ok(());
+        }
+
+        // the upstream stream holds the server side, downstream the client side
+        let upstream_key = match other.dir {
+            Direction::Upstream => key.flip(),
+            Direction::Downstream => key.clone(),
+        };
+        let client = self.streams.get(&upstream_key.flip()).and_then(|s| s.rtt);
+        let Some(upstream) = self.streams.get_mut(&upstream_key) else {
+            return Ok(());
+        };
+        let (Some(client), Some(server)) = (client, upstream.rtt) else {
+            return Ok(());
+        };
+        // only report when the estimate has improved by more than 10%
+        let total = client + server;
+        if upstream
+            .reported
+            .is_some_and(|reported| total * 10 > reported * 9)
+        {
+            return Ok(());
         }
+        upstream.reported = Some(total);
+        let ev = MapiEvent::RoundTrip {
+            id: upstream.id,
+            client,
+            server,
+        };
+        handler(ev)
+    }
+
+    /// Keep track of zero window advertisements. While a side advertises a
+    /// window of 0 bytes the other side cannot send anything, which shows up
+    /// as a pause at the MAPI level. Emits a [MapiEvent::ZeroWindow] when the
+    /// window opens again.
+    fn handle_window(
+        &mut self,
+        key: &Key,
+        tcp: &TcpSlice,
+        now: Duration,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        // a reset carries no meaningful window
+        if tcp.rst() {
+            return Ok(());
+        }
+        let Some(stream) = self.streams.get_mut(key) else {
+            return Ok(());
+        };
+        match (stream.zero_window, tcp.window_size()) {
+            (None, 0) => stream.zero_window = Some(now),
+            (Some(since), 1..) => {
+                stream.zero_window = None;
+                // the window limits the data flowing towards this side
+                let direction = match stream.dir {
+                    Direction::Upstream => Direction::Downstream,
+                    Direction::Downstream => Direction::Upstream,
+                };
+                let ev = MapiEvent::ZeroWindow {
+                    id: stream.id,
+                    direction,
+                    duration: now.saturating_sub(since),
+                };
+                handler(ev)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Emit a [MapiEvent::StillOpen] for each connection that has not ended,
+    /// in order of their ids, and forget about them.
+    pub fn finish(&mut self, handler: &mut Handler) -> io::Result<()> {
+        let mut ids: Vec<ConnectionId> = self.streams.values().map(|s| s.id).collect();
+        ids.sort();
+        ids.dedup();
+        self.streams.clear();
+        self.joining.clear();
+        for id in ids {
+            handler(MapiEvent::StillOpen { id })?;
+        }
+        Ok(())
     }
 
     fn handle_syn(&mut self, key: Key, tcp: &TcpSlice, handler: &mut Handler) -> io::Result<()> {
@@ -123,6 +312,12 @@ impl TcpTracker {
         tcp: &TcpSlice,
         handler: &mut Handler,
     ) -> io::Result<()> {
+        let known = self.streams.contains_key(&key)
+            || (self.join_mid_stream && self.join(&key, tcp, handler)?);
+        if !known {
+            return Ok(());
+        }
+        self.emit_segment(&key, tcp, handler)?;
         let Some(stream) = self.streams.get_mut(&key) else {
             return Ok(());
         };
@@ -132,6 +327,19 @@ impl TcpTracker {
 
         let seqno = tcp.sequence_number();
         let payload = tcp.payload();
+        if stream.is_keepalive(seqno, payload, tcp) {
+            return handler(MapiEvent::KeepAlive { id, direction });
+        }
+        if let Some((offset, original)) = stream.compare_retransmission(seqno, payload) {
+            let ev = MapiEvent::Retransmitted {
+                id,
+                direction,
+                offset,
+                retransmitted: payload[..original.len()].to_vec(),
+                original,
+            };
+            handler(ev)?;
+        }
         // Packets may arrive in the wrong order.
         // If this is exactly the packet we're waiting for, stream.reorder will
         // return it. If it's a future packet, it will store it.
@@ -139,13 +347,13 @@ impl TcpTracker {
         let Some(payload) = stream.reorder(seqno, tcp.fin(), payload) else {
             return Ok(());
         };
-        Self::emit_data(id, direction, payload, handler)?;
+        Self::emit_data(id, direction, payload, &self.pool, handler)?;
 
         // If stream.reorder above returned this packet, it means it was exactly
         // the packet we needed right now. Packets do not always arrive in-order
         // so it's possible that the next packet is already in our cache.
         while let Some(payload) = stream.next_ready() {
-            Self::emit_data(id, direction, &payload, handler)?;
+            Self::emit_data(id, direction, &payload, &self.pool, handler)?;
         }
 
         // Stream.finished is set by stream.reorder and stream.next_ready.
@@ -170,17 +378,114 @@ impl TcpTracker {
         Ok(())
     }
 
+    /// Try to pick up a connection whose handshake was not captured. A packet
+    /// is taken to start a MAPI message if the other side sent the packet
+    /// before it, so the conversation has just changed turns, and if its
+    /// payload looks like MAPI blocks. Whether it comes from the client or
+    /// the server follows from the first byte of the message. Returns true if
+    /// the connection has been set up, starting with this packet.
+    fn join(&mut self, key: &Key, tcp: &TcpSlice, handler: &mut Handler) -> io::Result<bool> {
+        let payload = tcp.payload();
+        if payload.is_empty() {
+            return Ok(false);
+        }
+        let flipped = key.flip();
+        let first = if self.joining.contains_key(key) {
+            key.clone()
+        } else {
+            flipped.clone()
+        };
+        let Some(previous) = self.joining.get_mut(&first) else {
+            self.joining.insert(key.clone(), key.clone());
+            return Ok(false);
+        };
+        let turned = *previous != *key;
+        *previous = key.clone();
+        if tcp.fin() {
+            self.joining.remove(&first);
+            return Ok(false);
+        }
+        let Some(direction) = message_direction(payload).filter(|_| turned) else {
+            return Ok(false);
+        };
+        self.joining.remove(&first);
+
+        let (client, server) = match direction {
+            Direction::Upstream => (key.src, key.dest),
+            Direction::Downstream => (key.dest, key.src),
+        };
+        let id = ConnectionId::new(self.conn_ids.next().unwrap());
+        handler(MapiEvent::Incoming {
+            id,
+            local: server.into(),
+            peer: client.into(),
+        })?;
+        handler(MapiEvent::Connected {
+            id,
+            peer: server.into(),
+        })?;
+        handler(MapiEvent::Joined { id })?;
+
+        // the acknowledgement tells where the other direction continues
+        let other_direction = match direction {
+            Direction::Upstream => Direction::Downstream,
+            Direction::Downstream => Direction::Upstream,
+        };
+        let this = StreamState::new(id, direction, tcp.sequence_number());
+        let other = StreamState::new(id, other_direction, tcp.acknowledgment_number());
+        self.streams.insert(key.clone(), this);
+        self.streams.insert(flipped, other);
+        Ok(true)
+    }
+
+    /// Describe the packet in a [MapiEvent::Segment], if asked to.
+    fn emit_segment(&self, key: &Key, tcp: &TcpSlice, handler: &mut Handler) -> io::Result<()> {
+        if !self.segments {
+            return Ok(());
+        }
+        let Some(stream) = self.streams.get(key) else {
+            return Ok(());
+        };
+        let ack = match self.streams.get(&key.flip()) {
+            Some(other) if tcp.ack() => Some(other.offset_of(tcp.acknowledgment_number())),
+            _ => None,
+        };
+        let flags = [
+            (tcp.syn(), 'S'),
+            (tcp.fin(), 'F'),
+            (tcp.psh(), 'P'),
+            (tcp.rst(), 'R'),
+            (tcp.urg(), 'U'),
+            (tcp.ack(), '.'),
+        ];
+        let ev = MapiEvent::Segment {
+            id: stream.id,
+            direction: stream.dir,
+            flags: flags
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, c)| c)
+                .collect(),
+            seq: stream.offset_of(tcp.sequence_number()),
+            ack,
+            window: tcp.window_size(),
+            len: tcp.payload().len(),
+        };
+        handler(ev)
+    }
+
     fn emit_data(
         id: ConnectionId,
         direction: Direction,
         payload: &[u8],
+        pool: &BufferPool,
         handler: &mut Handler,
     ) -> io::Result<()> {
         if !payload.is_empty() {
             let ev = MapiEvent::Data {
                 id,
                 direction,
-                data: payload.into(),
+                data: pool.copy_from_slice(payload),
             };
             handler(ev)?;
         }
@@ -188,6 +493,32 @@ impl TcpTracker {
     }
 }
 
+/// If `payload` looks like the start of a MAPI message, the direction it was
+/// sent in. The blocks must have plausible 2 byte headers up to the end of the
+/// packet, and the client starts its messages with `s` or `X` while the
+/// server never does. Connections that switched to the 8 byte headers of
+/// protocol 10 are not recognized.
+fn message_direction(payload: &[u8]) -> Option<Direction> {
+    let mut pos = 0;
+    while pos < payload.len() {
+        let header = payload.get(pos..pos + 2)?;
+        let header = u16::from_le_bytes([header[0], header[1]]);
+        let len = (header / 2) as usize;
+        let last = header & 1 == 1;
+        if len > MAX_BLOCK_SIZE || (len == 0 && !last) {
+            return None;
+        }
+        pos += 2 + len;
+    }
+    match payload.get(2) {
+        Some(b's' | b'X') => Some(Direction::Upstream),
+        Some(b'&' | b'%' | b'!' | b'^' | b'=' | b'[' | b'#') => Some(Direction::Downstream),
+        // an empty message, such as the prompt
+        None if payload == [1, 0] => Some(Direction::Downstream),
+        _ => None,
+    }
+}
+
 /// State stored for each half (client to server and server to client) of
 /// each TCP connection.
 #[derive(Debug)]
@@ -203,9 +534,29 @@ struct StreamState {
     waiting: HashMap<u32, (Vec<u8>, bool)>,
     /// If no more packets will arrive
     finished: bool,
+    /// Recent TCP timestamp values sent in this direction, with the time they
+    /// were first seen
+    timestamps: VecDeque<(u32, Duration)>,
+    /// Smallest round trip time between the point of capture and the
+    /// receiving side seen so far
+    rtt: Option<Duration>,
+    /// The last total round trip time reported, kept in the upstream half
+    reported: Option<Duration>,
+    /// The most recent bytes passed on, to compare retransmissions with
+    recent: VecDeque<u8>,
+    /// Number of bytes passed on so far
+    position: u64,
+    /// When this side started advertising a zero window, if it still does
+    zero_window: Option<Duration>,
 }
 
 impl StreamState {
+    /// How many timestamp values to remember.
+    const MAX_TIMESTAMPS: usize = 64;
+
+    /// How many bytes to remember for [Self::compare_retransmission].
+    const MAX_RECENT: usize = 16 * 1024;
+
     /// Create a new [StreamState]
     fn new(id: ConnectionId, dir: Direction, seqno: u32) -> Self {
         StreamState {
@@ -214,7 +565,78 @@ impl StreamState {
             waiting_for: seqno,
             waiting: Default::default(),
             finished: false,
+            timestamps: VecDeque::new(),
+            rtt: None,
+            reported: None,
+            recent: VecDeque::new(),
+            position: 0,
+            zero_window: None,
+        }
+    }
+
+    /// Position in the stream of the byte with this sequence number, counting
+    /// from the first byte after the SYN.
+    fn offset_of(&self, seqno: u32) -> u64 {
+        let delta = seqno.wrapping_sub(self.waiting_for) as i32;
+        self.position.saturating_add_signed(delta as i64)
+    }
+
+    /// Keep-alive probes carry the sequence number of the last byte already
+    /// sent, with no data or a single garbage byte, to make the other side
+    /// respond with an acknowledgement.
+    fn is_keepalive(&self, seqno: u32, payload: &[u8], tcp: &TcpSlice) -> bool {
+        payload.len() <= 1
+            && seqno.wrapping_add(1) == self.waiting_for
+            && !(tcp.syn() || tcp.fin() || tcp.rst())
+    }
+
+    /// Remember when a timestamp value was first seen.
+    fn timestamp_sent(&mut self, value: u32, now: Duration) {
+        if self.timestamps.iter().any(|&(v, _)| v == value) {
+            return;
         }
+        if self.timestamps.len() == Self::MAX_TIMESTAMPS {
+            self.timestamps.pop_front();
+        }
+        self.timestamps.push_back((value, now));
+    }
+
+    /// The other side has echoed a timestamp value. Returns true if this
+    /// gave a smaller round trip time.
+    fn timestamp_echoed(&mut self, echo: u32, now: Duration) -> bool {
+        let Some(pos) = self.timestamps.iter().position(|&(v, _)| v == echo) else {
+            return false;
+        };
+        // only the first echo of a value is a fair sample
+        let (_, sent) = self.timestamps[pos];
+        self.timestamps.drain(..=pos);
+        let sample = now.saturating_sub(sent);
+        if self.rtt.is_some_and(|rtt| rtt <= sample) {
+            return false;
+        }
+        self.rtt = Some(sample);
+        true
+    }
+
+    /// Compare a packet that repeats data already passed on with the original,
+    /// as far as it is still remembered. If they differ, returns the position
+    /// in the stream of the first repeated byte and the original bytes.
+    fn compare_retransmission(&self, seqno: u32, payload: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let back = self.waiting_for.wrapping_sub(seqno);
+        if (back as i32) <= 0 || back as usize > self.recent.len() {
+            return None;
+        }
+        // Keep-alive probes may repeat the last byte with garbage in it
+        if back == 1 && payload.len() == 1 {
+            return None;
+        }
+        let n = payload.len().min(back as usize);
+        let start = self.recent.len() - back as usize;
+        let original: Vec<u8> = self.recent.range(start..start + n).copied().collect();
+        if original == payload[..n] {
+            return None;
+        }
+        Some((self.position - back as u64, original))
     }
 
     /// Check for duplicate packets and packets that arrive in the wrong order
@@ -256,8 +678,32 @@ impl StreamState {
     /// Update the bookkeeping before returning the packet.
     fn yield_payload<T: AsRef<[u8]>>(&mut self, payload: T, fin: bool) -> Option<T> {
         self.finished |= fin;
-        let n = payload.as_ref().len() as u32;
+        let data = payload.as_ref();
+        let n = data.len() as u32;
         self.waiting_for = self.waiting_for.wrapping_add(n);
+        self.position += n as u64;
+        let keep = data.len().min(Self::MAX_RECENT);
+        self.recent.extend(&data[data.len() - keep..]);
+        let excess = self.recent.len().saturating_sub(Self::MAX_RECENT);
+        self.recent.drain(..excess);
         Some(payload)
     }
 }
+
+#[test]
+fn test_message_direction() {
+    assert_eq!(
+        message_direction(b"\x15\x00sSELECT 1;"),
+        Some(Direction::Upstream)
+    );
+    assert_eq!(message_direction(b"\x01\x00"), Some(Direction::Downstream));
+    // a large block that continues in the next packet
+    assert_eq!(
+        message_direction(b"\xfc\x3f&1 0 1000"),
+        Some(Direction::Downstream)
+    );
+    // the middle of a block
+    assert_eq!(message_direction(b"1\t]\n[ 2\t]\n"), None);
+    // no clue who sent it
+    assert_eq!(message_direction(b"\x07\x00hello"), None);
+}
@@ -1,16 +1,71 @@
-use std::{io, net::IpAddr};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::{bail, Result as AResult};
-use etherparse::{InternetSlice, Ipv4Slice, Ipv6Slice, SlicedPacket, TcpSlice, TransportSlice};
+use etherparse::{
+    err::packet::SliceError, LaxIpv4Slice, LaxIpv6Slice, LaxNetSlice, LaxSlicedPacket, TcpSlice,
+    TransportSlice,
+};
 
-use crate::proxy::event::MapiEvent;
+use crate::{clock::TimeSource, proxy::event::MapiEvent};
 
-use super::tcp::TcpTracker;
+use super::{checksum, tcp::TcpTracker, PcapError, Result};
 
 /// Struct Tracker holds the state necessary to process packets and emit MapiEvents.
 pub struct Tracker<'a> {
     handler: Box<dyn FnMut(MapiEvent) -> io::Result<()> + 'a>,
     tcp_tracker: TcpTracker,
+    clock: Clock,
+    time_shift: TimeShift,
+    /// Nanoseconds to add to the capture times, known from the first packet
+    time_offset: Option<i128>,
+    strict: bool,
+    check_checksums: bool,
+    /// Set by [Self::process_ethernet] for [Self::handle_tcp]
+    checksum_error: Option<&'static str>,
+    unknown_blocks: BTreeMap<u32, UnknownBlocks>,
+}
+
+/// How many pcapng blocks of a type the reader does not know have been
+/// skipped, and their total length.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnknownBlocks {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// The capture time of the packet being processed, as time since the Unix
+/// epoch. The event handler can keep a clone to find out when the events it
+/// receives happened, see [Tracker::set_clock].
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Arc<Mutex<Option<Duration>>>);
+
+impl Clock {
+    /// None if the capture file did not record the time of the packet.
+    pub fn now(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl TimeSource for Clock {
+    fn now(&self) -> Option<Duration> {
+        Clock::now(self)
+    }
+}
+
+/// How the capture times are adjusted before the event handler gets to see
+/// them, see [Tracker::set_time_shift].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeShift {
+    /// Move all times so the first packet was captured at this time since
+    /// the epoch.
+    pub rebase: Option<Duration>,
+    /// Then move them this many milliseconds later, or earlier if negative.
+    pub shift_millis: i64,
 }
 
 impl<'a> Tracker<'a> {
@@ -20,32 +75,173 @@ impl<'a> Tracker<'a> {
         Tracker {
             handler,
             tcp_tracker: TcpTracker::new(),
+            clock: Clock::default(),
+            time_shift: TimeShift::default(),
+            time_offset: None,
+            strict: false,
+            check_checksums: false,
+            checksum_error: None,
+            unknown_blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Pick up TCP connections that were already open when the capture
+    /// started, from the first packet that looks like the start of a MAPI
+    /// message. They are announced with [MapiEvent::Joined].
+    pub fn set_join_mid_stream(&mut self, join: bool) {
+        self.tcp_tracker.set_join_mid_stream(join);
+    }
+
+    /// Emit a [MapiEvent::Segment] for every TCP packet of the connections
+    /// being followed, to see the traffic below the MAPI level.
+    pub fn set_segments(&mut self, segments: bool) {
+        self.tcp_tracker.set_segments(segments);
+    }
+
+    /// Fail with [PcapError::UnknownBlock] on pcapng blocks of unknown or
+    /// vendor-specific types, instead of skipping them.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Emit a [MapiEvent::BadChecksum] before the contents of every packet
+    /// whose IP or TCP checksum is wrong, so a corrupted capture can be told
+    /// apart from a client or server that breaks the protocol. Checksums the
+    /// capturing host left to the network card to fill in are not reported.
+    pub fn set_check_checksums(&mut self, check: bool) {
+        self.check_checksums = check;
+    }
+
+    /// The blocks that have been skipped so far, by block type.
+    pub fn unknown_blocks(&self) -> &BTreeMap<u32, UnknownBlocks> {
+        &self.unknown_blocks
+    }
+
+    /// Report the connections that are still open with a
+    /// [MapiEvent::StillOpen], when there are no more packets to come. The
+    /// parser does not do this by itself because the capture may continue in
+    /// another file.
+    pub fn finish(&mut self) -> Result<()> {
+        self.tcp_tracker
+            .finish(&mut self.handler)
+            .map_err(PcapError::Handler)
+    }
+
+    /// Pass an event that is not about a connection to the event handler,
+    /// such as [MapiEvent::CaptureInterface].
+    pub fn emit(&mut self, event: MapiEvent) -> Result<()> {
+        (self.handler)(event).map_err(PcapError::Handler)
+    }
+
+    /// Called by the reader for each pcapng block of a type it does not know.
+    pub(super) fn unknown_block(&mut self, block_type: u32, length: u32) -> Result<()> {
+        let tally = self.unknown_blocks.entry(block_type).or_default();
+        tally.count += 1;
+        tally.bytes += length as u64;
+        if self.strict {
+            return Err(PcapError::UnknownBlock { block_type, length });
         }
+        Ok(())
+    }
+
+    /// Keep the given [Clock] up to date with the capture times of the
+    /// packets.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Adjust the capture times, for example to compare captures taken on
+    /// machines whose clocks disagree. Times that would end up before the
+    /// epoch become the epoch itself.
+    pub fn set_time_shift(&mut self, shift: TimeShift) {
+        self.time_shift = shift;
+        self.time_offset = None;
     }
 
+    /// Record the capture time of the packet about to be processed.
+    pub fn set_time(&mut self, time: Option<Duration>) {
+        let time = time.map(|t| {
+            let t = t.as_nanos() as i128;
+            let TimeShift {
+                rebase,
+                shift_millis,
+            } = self.time_shift;
+            let offset = *self.time_offset.get_or_insert_with(|| {
+                let rebase = rebase.map_or(0, |r| r.as_nanos() as i128 - t);
+                rebase + shift_millis as i128 * 1_000_000
+            });
+            let nanos = (t + offset).max(0) as u128;
+            Duration::new(
+                (nanos / 1_000_000_000) as u64,
+                (nanos % 1_000_000_000) as u32,
+            )
+        });
+        *self.clock.0.lock().unwrap() = time;
+    }
+
+    /// Ethernet frames up to this size may have been padded.
+    const MIN_FRAME: usize = 64;
+
+    /// Length of the checksum some captures keep at the end of the frame.
+    const FCS_LEN: usize = 4;
+
     /// Process the given packet as an Ethernet frame.
-    pub fn process_ethernet(&mut self, data: &[u8]) -> AResult<()> {
-        let ether_slice = SlicedPacket::from_ethernet(data)?;
-        let transport_slice = ether_slice.transport.as_ref();
+    ///
+    /// With segmentation offload (GSO/TSO), and always on the loopback
+    /// interface, the capture sees the large packets the TCP stack hands to
+    /// the driver rather than what goes over the wire. Their IP lengths can be
+    /// wrong, or zero above 64 KiB, so the length that was captured is trusted
+    /// over the IP headers. Truncated packets are caught by the reader, which
+    /// knows the original length.
+    pub fn process_ethernet(&mut self, data: &[u8]) -> Result<()> {
+        let ether_slice = LaxSlicedPacket::from_ethernet(data)
+            .map_err(|e| PcapError::from(SliceError::Len(e)))?;
+        if let Some((error, _)) = ether_slice.stop_err {
+            return Err(error.into());
+        }
+        let extended;
+        let mut transport_slice = ether_slice.transport.as_ref();
+        self.checksum_error = None;
+        if let Some(TransportSlice::Tcp(tcp)) = transport_slice {
+            if let Some(tcp) = Self::extend_to_frame(data, tcp) {
+                // the IP lengths are off, so the checksums will be as well
+                extended = TransportSlice::Tcp(tcp);
+                transport_slice = Some(&extended);
+            } else if let (true, Some(net)) = (self.check_checksums, &ether_slice.net) {
+                self.checksum_error = checksum::check(net, tcp);
+            }
+        }
         match &ether_slice.net {
-            Some(InternetSlice::Ipv4(inet4)) => self.handle_ipv4(inet4, transport_slice),
-            Some(InternetSlice::Ipv6(inet6)) => self.handle_ipv6(inet6, transport_slice),
+            Some(LaxNetSlice::Ipv4(inet4)) => self.handle_ipv4(inet4, transport_slice),
+            Some(LaxNetSlice::Ipv6(inet6)) => self.handle_ipv6(inet6, transport_slice),
             None => Ok(()),
         }
     }
 
+    /// If the IP header claims less than the frame holds, and the rest cannot
+    /// be Ethernet padding or a frame checksum, the TCP segment extending to
+    /// the end of the frame.
+    fn extend_to_frame<'d>(data: &'d [u8], tcp: &TcpSlice) -> Option<TcpSlice<'d>> {
+        let start = (tcp.slice().as_ptr() as usize).checked_sub(data.as_ptr() as usize)?;
+        let trailing = data.len().checked_sub(start + tcp.slice().len())?;
+        if data.len() <= Self::MIN_FRAME || trailing <= Self::FCS_LEN {
+            return None;
+        }
+        TcpSlice::from_slice(&data[start..]).ok()
+    }
+
     /// Examine IPv6 packet. If it's a TCP packet and not fragmented, hand it to [Self::handle_tcp]
     pub fn handle_ipv6(
         &mut self,
-        ipv6: &Ipv6Slice,
+        ipv6: &LaxIpv6Slice,
         transport: Option<&TransportSlice>,
-    ) -> AResult<()> {
+    ) -> Result<()> {
         if ipv6.is_payload_fragmented() {
-            bail!("pcap file contains fragmented ipv6 packet, not supported");
+            return Err(PcapError::Fragmented("ipv6"));
         }
 
         let tcp = match transport {
-            None => bail!("transport not found, expected this only with fragmented packets"),
+            None => return Err(PcapError::NoTransport),
             Some(TransportSlice::Tcp(tcp)) => tcp,
             _ => return Ok(()),
         };
@@ -59,15 +255,15 @@ impl<'a> Tracker<'a> {
     /// Examine IPv4 packet. If it's a TCP packet and not fragmented, hand it to [Self::handle_tcp]
     pub fn handle_ipv4(
         &mut self,
-        ipv4: &Ipv4Slice,
+        ipv4: &LaxIpv4Slice,
         transport: Option<&TransportSlice>,
-    ) -> AResult<()> {
+    ) -> Result<()> {
         if ipv4.is_payload_fragmented() {
-            bail!("pcap file contains fragmented ipv4 packet, not supported");
+            return Err(PcapError::Fragmented("ipv4"));
         }
 
         let tcp = match transport {
-            None => bail!("transport not found, expected this only with fragmented packets"),
+            None => return Err(PcapError::NoTransport),
             Some(TransportSlice::Tcp(tcp)) => tcp,
             _ => return Ok(()),
         };
@@ -79,10 +275,17 @@ impl<'a> Tracker<'a> {
     }
 
     /// Called by [Self::handle_ipv4] and [Self::handle_ipv6] when they encounter TCP traffic
-    pub fn handle_tcp(&mut self, src: IpAddr, dest: IpAddr, tcp: &TcpSlice) -> AResult<()> {
+    pub fn handle_tcp(&mut self, src: IpAddr, dest: IpAddr, tcp: &TcpSlice) -> Result<()> {
         // It's nice for handle_ipv4 and handle_ipv6 to simply call handle_tcp, but it turns
         // out that the actual handling is done by the [TcpTracker] subobject.
-        self.tcp_tracker.handle(src, dest, tcp, &mut self.handler)?;
-        Ok(())
+        let now = self.clock.now();
+        if let Some(layer) = self.checksum_error.take() {
+            self.tcp_tracker
+                .bad_checksum(src, dest, tcp, layer, &mut self.handler)
+                .map_err(PcapError::Handler)?;
+        }
+        self.tcp_tracker
+            .handle(src, dest, tcp, now, &mut self.handler)
+            .map_err(PcapError::Handler)
     }
 }
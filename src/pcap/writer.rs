@@ -0,0 +1,440 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use etherparse::PacketBuilder;
+use pcap_file::{
+    pcapng::{
+        blocks::{
+            enhanced_packet::EnhancedPacketBlock,
+            interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption},
+        },
+        PcapNgWriter,
+    },
+    DataLink,
+};
+
+use crate::proxy::{
+    event::{ConnectionId, Direction, MapiEvent},
+    network::Addr,
+};
+
+use super::{PcapError, Result};
+
+/// Writes the connections described by a stream of [MapiEvent]s to a pcap-ng
+/// file, as the TCP packets that could have carried them. Meant for traffic
+/// that was never on the wire, such as that of `mapiproxy selftest` or
+/// `mapiproxy serve`, so it can be looked at again with mapiproxy or
+/// Wireshark.
+///
+/// Only the side of the client is written. Unix domain socket connections are
+/// made to look like TCP connections on localhost, without the '0' byte their
+/// clients start with. The connections to a `--mirror` server are left out.
+/// Data that was forwarded but never reported, see [MapiEvent::Dropped], is
+/// left out too, leaving a gap in the sequence numbers like a capture that
+/// missed some packets.
+pub struct PcapWriter<W: io::Write> {
+    writer: PcapNgWriter<W>,
+    conns: HashMap<ConnectionId, Conn>,
+}
+
+const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// A connection being written.
+struct Conn {
+    client: Endpoint,
+    server: Endpoint,
+    /// Set until the '0' byte a Unix domain socket client starts with has
+    /// been left out
+    skip_zero: bool,
+}
+
+/// One end of a connection.
+struct Endpoint {
+    mac: [u8; 6],
+    addr: SocketAddr,
+    /// Sequence number of the next byte it sends
+    seq: u32,
+    fin_sent: bool,
+}
+
+/// The flags of a synthesized segment.
+#[derive(Debug, Clone, Copy, Default)]
+struct Flags {
+    syn: bool,
+    ack: bool,
+    fin: bool,
+    rst: bool,
+}
+
+impl<W: io::Write> PcapWriter<W> {
+    /// Largest amount of data to put in one packet.
+    const MSS: usize = 1460;
+
+    /// Start the file with a section header and the description of a single
+    /// Ethernet interface.
+    pub fn new(out: W) -> Result<Self> {
+        let mut writer = PcapNgWriter::new(out).map_err(write_error)?;
+        let interface = InterfaceDescriptionBlock {
+            linktype: DataLink::ETHERNET,
+            snaplen: 0,
+            options: vec![InterfaceDescriptionOption::IfDescription(Cow::Borrowed(
+                "synthesized by mapiproxy",
+            ))],
+        };
+        writer.write_pcapng_block(interface).map_err(write_error)?;
+        Ok(PcapWriter {
+            writer,
+            conns: HashMap::new(),
+        })
+    }
+
+    /// Write the packets for the event, which happened at `time` since the
+    /// epoch. Events that do not change what goes over the wire are skipped.
+    pub fn handle(&mut self, event: &MapiEvent, time: Duration) -> Result<()> {
+        match event {
+            MapiEvent::Incoming { id, local, peer } => {
+                let mut conn = Conn::new(*id, local, peer);
+                self.write_segment(&mut conn, Direction::Upstream, syn(false), b"", time)?;
+                self.write_segment(&mut conn, Direction::Downstream, syn(true), b"", time)?;
+                self.write_segment(&mut conn, Direction::Upstream, ack(), b"", time)?;
+                self.conns.insert(*id, conn);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let Some(mut conn) = self.conns.remove(id) else {
+                    return Ok(());
+                };
+                let mut data = &data[..];
+                if *direction == Direction::Upstream && conn.skip_zero && !data.is_empty() {
+                    data = &data[1..];
+                    conn.skip_zero = false;
+                }
+                let result = data.chunks(Self::MSS).try_for_each(|chunk| {
+                    self.write_segment(&mut conn, *direction, ack(), chunk, time)
+                });
+                self.conns.insert(*id, conn);
+                result?;
+            }
+            MapiEvent::Dropped {
+                id,
+                direction,
+                bytes,
+                ..
+            } => {
+                let Some(conn) = self.conns.get_mut(id) else {
+                    return Ok(());
+                };
+                let mut bytes = *bytes;
+                if *direction == Direction::Upstream && conn.skip_zero && bytes > 0 {
+                    bytes -= 1;
+                    conn.skip_zero = false;
+                }
+                let sender = conn.sender(*direction);
+                sender.seq = sender.seq.wrapping_add(bytes as u32);
+            }
+            MapiEvent::Mirroring { id, .. } => {
+                // not a connection of the client's
+                self.conns.remove(id);
+            }
+            MapiEvent::ShutdownRead { id, direction } => {
+                if let Some(mut conn) = self.conns.remove(id) {
+                    let result = self.write_fin(&mut conn, *direction, time);
+                    self.conns.insert(*id, conn);
+                    result?;
+                }
+            }
+            MapiEvent::End { id } => {
+                if let Some(mut conn) = self.conns.remove(id) {
+                    self.write_fin(&mut conn, Direction::Upstream, time)?;
+                    self.write_fin(&mut conn, Direction::Downstream, time)?;
+                }
+            }
+            MapiEvent::Aborted { id, .. } => {
+                if let Some(mut conn) = self.conns.remove(id) {
+                    let rst = Flags { rst: true, ..ack() };
+                    self.write_segment(&mut conn, Direction::Downstream, rst, b"", time)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.get_mut().flush().map_err(PcapError::Write)
+    }
+
+    /// Flush and return the underlying writer. Connections that have not
+    /// ended are left open.
+    pub fn finish(mut self) -> Result<W> {
+        self.flush()?;
+        Ok(self.writer.into_inner())
+    }
+
+    fn write_fin(&mut self, conn: &mut Conn, direction: Direction, time: Duration) -> Result<()> {
+        if conn.sender(direction).fin_sent {
+            return Ok(());
+        }
+        let fin = Flags { fin: true, ..ack() };
+        self.write_segment(conn, direction, fin, b"", time)?;
+        conn.sender(direction).fin_sent = true;
+        Ok(())
+    }
+
+    fn write_segment(
+        &mut self,
+        conn: &mut Conn,
+        direction: Direction,
+        flags: Flags,
+        payload: &[u8],
+        time: Duration,
+    ) -> Result<()> {
+        let (from, to) = match direction {
+            Direction::Upstream => (&conn.client, &conn.server),
+            Direction::Downstream => (&conn.server, &conn.client),
+        };
+        let ip = PacketBuilder::ethernet2(from.mac, to.mac);
+        let ip = match (from.addr.ip(), to.addr.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dest)) => ip.ipv4(src.octets(), dest.octets(), 64),
+            (src, dest) => ip.ipv6(ipv6_octets(src), ipv6_octets(dest), 64),
+        };
+        let mut tcp = ip.tcp(from.addr.port(), to.addr.port(), from.seq, 65535);
+        if flags.syn {
+            tcp = tcp.syn();
+        }
+        if flags.ack {
+            tcp = tcp.ack(to.seq);
+        }
+        if flags.fin {
+            tcp = tcp.fin();
+        }
+        if flags.rst {
+            tcp = tcp.rst();
+        }
+        if !payload.is_empty() {
+            tcp = tcp.psh();
+        }
+        let mut packet = Vec::with_capacity(tcp.size(payload.len()));
+        tcp.write(&mut packet, payload)
+            .expect("a segment of at most MSS bytes always fits");
+
+        let block = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp: time,
+            original_len: packet.len() as u32,
+            data: Cow::Owned(packet),
+            options: vec![],
+        };
+        self.writer.write_pcapng_block(block).map_err(write_error)?;
+
+        let advance = payload.len() as u32 + u32::from(flags.syn) + u32::from(flags.fin);
+        let sender = conn.sender(direction);
+        sender.seq = sender.seq.wrapping_add(advance);
+        Ok(())
+    }
+}
+
+impl Conn {
+    fn new(id: ConnectionId, local: &Addr, peer: &Addr) -> Self {
+        // Unix domain sockets become localhost, with a port per connection
+        let fake_port = 32768 + (id.as_usize() % 28000) as u16;
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let (client, skip_zero) = match peer {
+            Addr::Tcp(addr) => (*addr, false),
+            Addr::Unix(_) => (SocketAddr::new(localhost.ip(), fake_port), true),
+        };
+        let server = match local {
+            Addr::Tcp(addr) => *addr,
+            Addr::Unix(_) => SocketAddr::new(localhost.ip(), 50000),
+        };
+        Conn {
+            client: Endpoint {
+                mac: CLIENT_MAC,
+                addr: client,
+                seq: 1000,
+                fin_sent: false,
+            },
+            server: Endpoint {
+                mac: SERVER_MAC,
+                addr: server,
+                seq: 5000,
+                fin_sent: false,
+            },
+            skip_zero,
+        }
+    }
+
+    fn sender(&mut self, direction: Direction) -> &mut Endpoint {
+        match direction {
+            Direction::Upstream => &mut self.client,
+            Direction::Downstream => &mut self.server,
+        }
+    }
+}
+
+fn syn(ack: bool) -> Flags {
+    Flags {
+        syn: true,
+        ack,
+        ..Flags::default()
+    }
+}
+
+fn ack() -> Flags {
+    Flags {
+        ack: true,
+        ..Flags::default()
+    }
+}
+
+/// Mixed families only happen with IPv4-mapped addresses, so both are
+/// written as IPv6.
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn write_error(err: pcap_file::PcapError) -> PcapError {
+    match err {
+        pcap_file::PcapError::IoError(err) => PcapError::Write(err),
+        err => PcapError::Format(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::{parse_pcap_file, Tracker};
+
+    #[test]
+    fn test_read_back() {
+        let id = ConnectionId::new(10);
+        let local = Addr::Tcp("127.0.0.1:50000".parse().unwrap());
+        let peer = Addr::Tcp("127.0.0.1:40000".parse().unwrap());
+        let request = [0x11, 0x00, b's', b'e', b'l', b'e', b'c', b't', b' ', b'1'];
+        let long = vec![b'x'; 5000];
+        let events = [
+            MapiEvent::Incoming { id, local, peer },
+            MapiEvent::Data {
+                id,
+                direction: Direction::Upstream,
+                data: request.to_vec().into(),
+            },
+            MapiEvent::Data {
+                id,
+                direction: Direction::Downstream,
+                data: long.clone().into(),
+            },
+            MapiEvent::ShutdownRead {
+                id,
+                direction: Direction::Upstream,
+            },
+            MapiEvent::End { id },
+        ];
+
+        let mut writer = PcapWriter::new(vec![]).unwrap();
+        for (i, ev) in events.iter().enumerate() {
+            writer.handle(ev, Duration::from_secs(i as u64)).unwrap();
+        }
+        let file = writer.finish().unwrap();
+
+        let mut upstream = vec![];
+        let mut downstream = vec![];
+        let mut ended = false;
+        let handler = |ev: MapiEvent| {
+            match ev {
+                MapiEvent::Data {
+                    direction: Direction::Upstream,
+                    data,
+                    ..
+                } => upstream.extend_from_slice(&data),
+                MapiEvent::Data {
+                    direction: Direction::Downstream,
+                    data,
+                    ..
+                } => downstream.extend_from_slice(&data),
+                MapiEvent::End { .. } => ended = true,
+                _ => {}
+            }
+            Ok(())
+        };
+        let mut tracker = Tracker::new(handler);
+        parse_pcap_file(&file[..], &mut tracker).unwrap();
+        drop(tracker);
+        assert_eq!(upstream, request);
+        assert_eq!(downstream, long);
+        assert!(ended);
+    }
+
+    #[test]
+    fn test_dropped_and_mirrored() {
+        use crate::mapi::Analyzer;
+        use etherparse::{LaxSlicedPacket, TransportSlice};
+        use pcap_file::pcapng::{Block, PcapNgReader};
+
+        let id = ConnectionId::new(10);
+        let mirror = ConnectionId::new(11);
+        let local = Addr::Tcp("127.0.0.1:50000".parse().unwrap());
+        let peer = Addr::Tcp("127.0.0.1:40000".parse().unwrap());
+        let data = |id, direction, data: &[u8]| MapiEvent::Data {
+            id,
+            direction,
+            data: data.to_vec().into(),
+        };
+        let events = [
+            MapiEvent::Incoming { id, local, peer },
+            MapiEvent::Mirroring { id: mirror, of: id },
+            data(id, Direction::Downstream, b"abc"),
+            MapiEvent::Dropped {
+                id,
+                direction: Direction::Downstream,
+                chunks: 2,
+                bytes: 100,
+                sample: vec![],
+                analyzer: Analyzer::new(false),
+            },
+            data(mirror, Direction::Upstream, b"mirrored"),
+            data(id, Direction::Downstream, b"defg"),
+        ];
+
+        let mut writer = PcapWriter::new(vec![]).unwrap();
+        for ev in &events {
+            writer.handle(ev, Duration::ZERO).unwrap();
+        }
+        let file = writer.finish().unwrap();
+
+        // sequence number and size of the segments that carry data
+        let mut segments = vec![];
+        let mut reader = PcapNgReader::new(&file[..]).unwrap();
+        while let Some(block) = reader.next_block() {
+            let Block::EnhancedPacket(packet) = block.unwrap() else {
+                continue;
+            };
+            let sliced = LaxSlicedPacket::from_ethernet(&packet.data).unwrap();
+            let Some(TransportSlice::Tcp(tcp)) = sliced.transport else {
+                panic!("not a TCP packet");
+            };
+            if !tcp.payload().is_empty() {
+                segments.push((
+                    tcp.source_port(),
+                    tcp.sequence_number(),
+                    tcp.payload().len(),
+                ));
+            }
+        }
+        // the server starts at 5000, its SYN takes one
+        assert_eq!(segments, [(50000, 5001, 3), (50000, 5104, 4)]);
+    }
+}
@@ -0,0 +1,41 @@
+use std::io::{self, BufRead, Read};
+
+use ruzstd::decoding::{errors::FrameDecoderError, FrameDecoder, StreamingDecoder};
+
+/// Decompresses zstd data. Unlike the [StreamingDecoder] it wraps, it carries
+/// on with the next frame when a frame ends, so concatenated .zst files
+/// decompress to the concatenation of their contents, as with the zstd tool.
+pub struct ZstdReader<R: BufRead> {
+    /// None after a frame header turned out to be invalid
+    decoder: Option<StreamingDecoder<R, FrameDecoder>>,
+}
+
+impl<R: BufRead> ZstdReader<R> {
+    /// Start decompressing, this reads the header of the first frame.
+    pub fn new(reader: R) -> Result<Self, FrameDecoderError> {
+        let decoder = StreamingDecoder::new(reader)?;
+        Ok(ZstdReader {
+            decoder: Some(decoder),
+        })
+    }
+}
+
+impl<R: BufRead> Read for ZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(decoder) = &mut self.decoder {
+            let n = decoder.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            // this frame is done, is there another one?
+            if decoder.get_mut().fill_buf()?.is_empty() {
+                return Ok(0);
+            }
+            let (source, frame_decoder) = self.decoder.take().unwrap().into_parts();
+            let decoder = StreamingDecoder::new_with_decoder(source, frame_decoder)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.decoder = Some(decoder);
+        }
+        Ok(0)
+    }
+}
@@ -0,0 +1,42 @@
+//! Implementation of --write-pcap in proxy mode. Writes the forwarded traffic
+//! to a pcap-ng file as the TCP packets between the clients and the proxy, so
+//! a session can be looked at again with `mapiproxy --pcap` or Wireshark.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Result as AResult;
+use mapiproxy::{pcap::PcapWriter, proxy::event::MapiEvent};
+
+use crate::{create_pcap_writer, exchange::Recorder};
+
+pub struct PcapLog {
+    writer: PcapWriter<BufWriter<File>>,
+}
+
+impl PcapLog {
+    pub fn create(path: &Path) -> AResult<PcapLog> {
+        let writer = create_pcap_writer(path)?;
+        Ok(PcapLog { writer })
+    }
+}
+
+impl Recorder for PcapLog {
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()> {
+        self.writer.handle(event, now).map_err(io::Error::other)?;
+        // a file cut off by Ctrl-C should still hold the finished connections
+        if let MapiEvent::End { .. } | MapiEvent::Aborted { .. } = event {
+            self.writer.flush().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, _now: Duration) -> AResult<()> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! Implementation of --per-connection. Holds back the events of each
+//! connection until it is over and then shows them in one go, so every
+//! conversation in a capture can be read from top to bottom instead of being
+//! interleaved with the other connections.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use mapiproxy::{
+    clock::TimeSource,
+    proxy::event::{ConnectionId, MapiEvent},
+    render::TimeZone,
+};
+
+use crate::{backpressure::MemoryBudget, output::Outputs};
+
+pub struct PerConnection {
+    /// None in deterministic mode, which leaves out the times
+    zone: Option<TimeZone>,
+    held: BTreeMap<ConnectionId, Held>,
+    /// Connections that were shown before they ended, see `--max-memory`
+    cut_short: HashSet<ConnectionId>,
+    budget: Option<Arc<MemoryBudget>>,
+    clock: ReplayClock,
+}
+
+struct Held {
+    /// Packet time of the first event
+    started: Option<Duration>,
+    /// The events with the packet times they came from
+    events: Vec<(Option<Duration>, MapiEvent)>,
+}
+
+/// The time the outputs see. Held events are shown with the time of their own
+/// packet, not with that of the packet that ended the connection.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayClock(Arc<Mutex<Option<Duration>>>);
+
+impl ReplayClock {
+    fn set(&self, time: Option<Duration>) {
+        *self.0.lock().unwrap() = time;
+    }
+}
+
+impl TimeSource for ReplayClock {
+    fn now(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl PerConnection {
+    /// With a `budget`, the held events count towards `--max-memory`. When
+    /// it runs out, the connection held longest is shown before it has ended.
+    pub fn new(zone: Option<TimeZone>, budget: Option<Arc<MemoryBudget>>) -> Self {
+        PerConnection {
+            zone,
+            held: BTreeMap::new(),
+            cut_short: HashSet::new(),
+            budget,
+            clock: ReplayClock::default(),
+        }
+    }
+
+    /// The clock the outputs should take the time from.
+    pub fn clock(&self) -> ReplayClock {
+        self.clock.clone()
+    }
+
+    /// Hold the event back if it belongs to a connection, otherwise show it
+    /// right away. `now` is the time of the packet it came from.
+    pub fn handle(
+        &mut self,
+        event: MapiEvent,
+        now: Option<Duration>,
+        outputs: &mut Outputs,
+    ) -> io::Result<()> {
+        let Some(id) = event.connection_id() else {
+            self.clock.set(now);
+            return outputs.handle(&event);
+        };
+        if let Some(budget) = self.budget.clone() {
+            while !budget.try_hold(&event) {
+                let Some(&oldest) = self.held.keys().next() else {
+                    budget.hold(&event);
+                    break;
+                };
+                self.show(oldest, outputs, false)?;
+            }
+        }
+        let over = matches!(
+            event,
+            MapiEvent::End { .. } | MapiEvent::StillOpen { .. } | MapiEvent::Aborted { .. }
+        );
+        let held = self.held.entry(id).or_insert_with(|| Held {
+            started: now,
+            events: vec![],
+        });
+        held.events.push((now, event));
+        if over {
+            self.show(id, outputs, true)?;
+        }
+        self.clock.set(now);
+        Ok(())
+    }
+
+    /// Show the connections that have not ended yet, in the order they were
+    /// opened.
+    pub fn finish(&mut self, outputs: &mut Outputs) -> io::Result<()> {
+        let now = self.clock.now();
+        while let Some(&id) = self.held.keys().next() {
+            self.show(id, outputs, true)?;
+        }
+        self.clock.set(now);
+        Ok(())
+    }
+
+    fn show(&mut self, id: ConnectionId, outputs: &mut Outputs, over: bool) -> io::Result<()> {
+        let Some(held) = self.held.remove(&id) else {
+            return Ok(());
+        };
+        self.clock.set(held.started);
+        let continued = match over {
+            true => self.cut_short.remove(&id),
+            false => !self.cut_short.insert(id),
+        };
+        match (self.zone, held.started) {
+            _ if continued => outputs.message(Some(id), None, "CONVERSATION continued")?,
+            (Some(zone), Some(started)) => {
+                let time = zone.format(started);
+                outputs.message(Some(id), None, format_args!("CONVERSATION started {time}"))?;
+            }
+            _ => outputs.message(Some(id), None, "CONVERSATION")?,
+        }
+        for (time, event) in &held.events {
+            self.clock.set(*time);
+            outputs.handle(event)?;
+            if let Some(budget) = &self.budget {
+                budget.rendered(event, outputs.buffered());
+            }
+        }
+        if !over {
+            outputs.message(Some(id), None, "CONVERSATION cut short by --max-memory")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_replay_times() {
+    use std::io::Write;
+
+    use mapiproxy::{mapi, proxy::network::Addr, render::Renderer, Level};
+
+    use crate::output::Output;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let out = Shared::default();
+    let mut renderer = Renderer::new(false, out.clone());
+    renderer.set_time_zone(TimeZone::Utc);
+    let state = mapi::State::new(Level::Messages, false);
+    let mut outputs = Outputs::new(vec![Output::Rendered { state, renderer }]);
+    let mut per_connection = PerConnection::new(Some(TimeZone::Utc), None);
+    outputs.set_clock(per_connection.clock());
+
+    let id = ConnectionId::new(10);
+    let addr = |s: &str| Addr::Tcp(s.parse().unwrap());
+    let start = Duration::from_secs(1_700_000_000);
+    let events = [
+        MapiEvent::Incoming {
+            id,
+            local: addr("10.0.0.2:50000"),
+            peer: addr("10.0.0.1:40000"),
+        },
+        MapiEvent::End { id },
+    ];
+    for (i, ev) in events.into_iter().enumerate() {
+        let now = start + Duration::from_secs(300 * i as u64);
+        per_connection.handle(ev, Some(now), &mut outputs).unwrap();
+    }
+    drop(outputs);
+
+    let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let expected = "\
+‣ TIME is 2023-11-14 22:13:20.000 UTC
+‣ #10 CONVERSATION started 2023-11-14 22:13:20.000 UTC
+‣ #10 INCOMING on 10.0.0.2:50000 from 10.0.0.1:40000
+
+‣ TIME is 2023-11-14 22:18:20.000 UTC
+‣ #10 ENDED
+";
+    assert_eq!(text, expected);
+}
+
+#[test]
+fn test_max_memory() {
+    use mapiproxy::{mapi, proxy::network::Addr, render::Renderer, Level};
+
+    use crate::output::Output;
+
+    let renderer = Renderer::new(false, io::sink());
+    let state = mapi::State::new(Level::Messages, false);
+    let mut outputs = Outputs::new(vec![Output::Rendered { state, renderer }]);
+    let budget = Arc::new(MemoryBudget::new(100));
+    let mut per_connection = PerConnection::new(None, Some(budget.clone()));
+    let mut handle = |ev| per_connection.handle(ev, None, &mut outputs).unwrap();
+
+    let addr = |s: &str| Addr::Tcp(s.parse().unwrap());
+    let (conn10, conn11) = (ConnectionId::new(10), ConnectionId::new(11));
+    let data = |id, n| MapiEvent::Data {
+        id,
+        direction: mapiproxy::proxy::event::Direction::Upstream,
+        data: vec![b'x'; n].into(),
+    };
+    for id in [conn10, conn11] {
+        let (local, peer) = (addr("10.0.0.2:50000"), addr("10.0.0.1:40000"));
+        handle(MapiEvent::Incoming { id, local, peer });
+    }
+    handle(data(conn10, 60));
+    handle(data(conn11, 30));
+    // does not fit, so #10 is shown before it has ended
+    handle(data(conn11, 30));
+
+    let held: Vec<_> = per_connection.held.keys().copied().collect();
+    assert_eq!(held, [conn11]);
+    assert!(per_connection.cut_short.contains(&conn10));
+    assert!(!budget.try_hold(&data(conn10, 41)));
+    assert!(budget.try_hold(&data(conn10, 40)));
+}
@@ -0,0 +1,524 @@
+//! Alternative implementation of the [Proxy](super::Proxy) based on tokio.
+//!
+//! It runs all connections as tasks on a single threaded runtime so it can
+//! share the [EventSink], the address handling and the buffering in
+//! [Copying] with the mio based proxy.
+
+use std::{
+    cell::{Cell, RefCell},
+    io,
+    net::{Shutdown, SocketAddr as TcpSocketAddr},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    runtime::Runtime,
+    sync::Notify,
+    task::{JoinSet, LocalSet},
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tracing::debug;
+
+use super::{
+    bind_listeners,
+    event::{ConnectionId, ConnectionSink, Direction, EventSink, MapiEvent},
+    forward::{resolve_server, Copying, Dialer},
+    network::{
+        bind_tcp, is_out_of_fds, server_socket, Addr, ListenOptions, MonetAddr, TrafficMarking,
+        DEFAULT_BACKLOG,
+    },
+    rewrite::Rewriter,
+    Error, Result,
+};
+
+type Reader = Pin<Box<dyn AsyncRead>>;
+type Writer = Pin<Box<dyn AsyncWrite>>;
+
+type SharedSink = Rc<RefCell<EventSink>>;
+
+type SharedRewriter = Rc<RefCell<Option<Box<dyn Rewriter>>>>;
+
+/// Where and how to connect to the server.
+struct Server {
+    addr: MonetAddr,
+    dialer: Dialer,
+}
+
+/// The AsyncProxy listens on a number of sockets, forwards the connections to
+/// another server and reports on the traffic as a series of [MapiEvent]s,
+/// just like [Proxy](super::Proxy) but using a tokio runtime.
+pub struct AsyncProxy {
+    /// Configured address to forward to.
+    forward_addr: MonetAddr,
+    /// How to find and connect to it, see [ListenOptions::resolver] and
+    /// [AsyncProxy::set_traffic_marking].
+    dialer: Dialer,
+    /// The runtime the listeners have been registered with.
+    runtime: Runtime,
+    /// Notified to stop the proxy on Control-C.
+    shutdown: Arc<Notify>,
+    /// The bound listeners, moved into their accept loops by [AsyncProxy::run].
+    listeners: Vec<(Addr, AsyncListener)>,
+    /// This is where events are reported.
+    event_sink: EventSink,
+    /// If set, all messages are passed through this before being forwarded.
+    rewriter: Option<Box<dyn Rewriter>>,
+}
+
+impl AsyncProxy {
+    /// Create a new AsyncProxy which listens on the sockets denoted by
+    /// `listen_addr`. Returns an error if the listen sockets could not be
+    /// bound. Use [AsyncProxy::run] to start forwarding.
+    pub fn new(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<AsyncProxy> {
+        Self::with_backlog(listen_addr, forward_addr, DEFAULT_BACKLOG, event_handler)
+    }
+
+    /// Like [AsyncProxy::new] but with the given listen backlog.
+    pub fn with_backlog(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        backlog: i32,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<AsyncProxy> {
+        let options = ListenOptions {
+            backlog,
+            ..ListenOptions::default()
+        };
+        Self::with_options(listen_addr, forward_addr, options, event_handler)
+    }
+
+    /// Like [AsyncProxy::new] but with the given [ListenOptions].
+    pub fn with_options(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        options: ListenOptions,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<AsyncProxy> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .map_err(Error::CreateRuntime)?;
+        let mut proxy = AsyncProxy {
+            forward_addr,
+            dialer: Dialer {
+                resolver: Arc::clone(&options.resolver),
+                marking: TrafficMarking::default(),
+            },
+            runtime,
+            shutdown: Default::default(),
+            listeners: vec![],
+            event_sink: EventSink::new(event_handler),
+            rewriter: None,
+        };
+
+        let runtime = &proxy.runtime;
+        proxy.listeners = bind_listeners(&listen_addr, &options, &mut proxy.event_sink, |addr| {
+            let _guard = runtime.enter();
+            AsyncListener::bind(addr, &options)
+        })?;
+
+        Ok(proxy)
+    }
+
+    /// Pass all messages through the given [Rewriter] before forwarding them.
+    pub fn set_rewriter(&mut self, rewriter: Box<dyn Rewriter>) {
+        self.rewriter = Some(rewriter);
+    }
+
+    /// Tag the TCP connections to the server with `marking`.
+    pub fn set_traffic_marking(&mut self, marking: TrafficMarking) {
+        self.dialer.marking = marking;
+    }
+
+    /// Whether to emit [MapiEvent::Data] events. Unlike the mio based
+    /// [Proxy](super::Proxy), this proxy still copies the data when they are
+    /// not needed.
+    pub fn set_report_data(&mut self, report_data: bool) {
+        self.event_sink.set_report_data(report_data);
+    }
+
+    /// Obtain a shutdown trigger that when called, will end [AsyncProxy::run].
+    pub fn get_shutdown_trigger(&mut self) -> Box<dyn Fn() + Send + Sync + 'static> {
+        let shutdown = Arc::clone(&self.shutdown);
+        Box::new(move || shutdown.notify_one())
+    }
+
+    /// Run the proxy until the shutdown trigger fires or one of the listeners
+    /// fails.
+    pub fn run(self) -> Result<()> {
+        let AsyncProxy {
+            forward_addr,
+            dialer,
+            runtime,
+            shutdown,
+            listeners,
+            event_sink,
+            rewriter,
+        } = self;
+
+        let sink = Rc::new(RefCell::new(event_sink));
+        let rewriter = Rc::new(RefCell::new(rewriter));
+        let server = Rc::new(Server {
+            addr: forward_addr,
+            dialer,
+        });
+        let ids = Rc::new(Cell::new(10));
+        let closed = Rc::new(Notify::new());
+
+        debug!(nlisteners = listeners.len(), "starting runtime");
+        let local = LocalSet::new();
+        local.block_on(&runtime, async move {
+            let mut accept_loops = JoinSet::new();
+            for (addr, listener) in listeners {
+                let accept_loop = accept_loop(
+                    addr,
+                    listener,
+                    Rc::clone(&server),
+                    Rc::clone(&sink),
+                    Rc::clone(&rewriter),
+                    Rc::clone(&ids),
+                    Rc::clone(&closed),
+                );
+                accept_loops.spawn_local(accept_loop);
+            }
+            tokio::select! {
+                _ = shutdown.notified() => {
+                    debug!("shutdown triggered");
+                    Ok(())
+                }
+                Some(finished) = accept_loops.join_next() => match finished {
+                    Ok(result) => result,
+                    Err(e) => Err(Error::Other(e.to_string())),
+                },
+            }
+        })
+    }
+}
+
+/// How long to wait before accepting again after running out of file
+/// descriptors, if no connection has been closed in the mean time.
+const RETRY_ACCEPT_INTERVAL: Duration = Duration::from_secs(1);
+
+async fn accept_loop(
+    local: Addr,
+    listener: AsyncListener,
+    server: Rc<Server>,
+    sink: SharedSink,
+    rewriter: SharedRewriter,
+    ids: Rc<Cell<usize>>,
+    closed: Rc<Notify>,
+) -> Result<()> {
+    let mut paused = false;
+    loop {
+        let (conn, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) if is_out_of_fds(&e) => {
+                debug!(%local, "out of file descriptors, pausing listener");
+                if !paused {
+                    paused = true;
+                    sink.borrow_mut().emit_accept_paused(local.clone(), e);
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_ACCEPT_INTERVAL) => {}
+                    _ = closed.notified() => {}
+                }
+                continue;
+            }
+            Err(e) => return Err(Error::Accept(local.clone(), e)),
+        };
+        if paused {
+            paused = false;
+            debug!(%local, "resuming listener");
+            sink.borrow_mut().emit_accept_resumed(local.clone());
+        }
+
+        let id = ConnectionId::new(ids.replace(ids.get() + 1));
+        debug!(%id, %local, %peer, "accepted, spawning task");
+        emit(&sink, id, |s| s.emit_incoming(local.clone(), peer.clone()));
+
+        let server = Rc::clone(&server);
+        let sink = Rc::clone(&sink);
+        let rewriter = Rc::clone(&rewriter);
+        let closed = Rc::clone(&closed);
+        tokio::task::spawn_local(async move {
+            let result = forward(id, conn, &server, &sink, &rewriter).await;
+            debug!(%id, ok = result.is_ok(), "task finished");
+            emit(&sink, id, |s| match result {
+                Ok(()) => s.emit_end(),
+                Err(e) => s.emit_aborted(e),
+            });
+            closed.notify_waiters();
+        });
+    }
+}
+
+/// Connect to the server and copy data in both directions until both sides
+/// have finished.
+async fn forward(
+    id: ConnectionId,
+    client: AsyncStream,
+    server: &Server,
+    sink: &SharedSink,
+    rewriter: &SharedRewriter,
+) -> Result<()> {
+    let server = connect(id, server, sink).await?;
+
+    for (side, sock) in [("client", &client), ("server", &server)] {
+        sock.set_nodelay().map_err(|err| Error::Forward {
+            doing: "setting nodelay",
+            side,
+            err,
+        })?;
+    }
+
+    let rewriting = rewriter.borrow().is_some();
+    let upstream = Copying::new(client.is_unix(), server.is_unix(), rewriting);
+    let downstream = Copying::new(false, false, rewriting);
+    let (client_rd, client_wr) = client.into_split();
+    let (server_rd, server_wr) = server.into_split();
+
+    tokio::try_join!(
+        copy(
+            id,
+            Direction::Upstream,
+            upstream,
+            client_rd,
+            server_wr,
+            sink,
+            rewriter
+        ),
+        copy(
+            id,
+            Direction::Downstream,
+            downstream,
+            server_rd,
+            client_wr,
+            sink,
+            rewriter
+        ),
+    )?;
+    Ok(())
+}
+
+/// Try each of the addresses the server resolves to in turn.
+async fn connect(id: ConnectionId, server: &Server, sink: &SharedSink) -> Result<AsyncStream> {
+    let addrs = emit(sink, id, |s| {
+        resolve_server(s, &server.addr, &server.dialer.resolver)
+    })?;
+
+    for addr in addrs {
+        debug!(%id, %addr, "connecting");
+        emit(sink, id, |s| s.emit_connecting(addr.clone()));
+        let err = match AsyncStream::connect(&addr, server.dialer.marking).await {
+            Ok(stream) => match stream.peer_addr() {
+                Ok(peer) => {
+                    emit(sink, id, |s| s.emit_connected(peer));
+                    return Ok(stream);
+                }
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        emit(sink, id, |s| {
+            s.emit_connect_failed(addr.to_string(), false, err)
+        });
+    }
+
+    Err(Error::Connect)
+}
+
+/// Emit events about a connection. The sink is only borrowed for the duration
+/// of the call so this must not be held across an await.
+fn emit<T>(sink: &SharedSink, id: ConnectionId, f: impl FnOnce(&mut ConnectionSink) -> T) -> T {
+    f(&mut sink.borrow_mut().connection_sink(id))
+}
+
+/// Copy one direction of a connection until it has been shut down. The
+/// buffering, rewriting and Unix Domain socket adjustments are left to the
+/// same [Copying] the mio based proxy uses, this only does the reading and
+/// writing.
+async fn copy(
+    id: ConnectionId,
+    direction: Direction,
+    mut copying: Copying,
+    mut rd: Reader,
+    mut wr: Writer,
+    sink: &SharedSink,
+    rewriter: &SharedRewriter,
+) -> Result<()> {
+    let forward_error = |doing, err| {
+        let side = match doing {
+            "reading" => direction.sender(),
+            _ => direction.receiver(),
+        };
+        Error::Forward { doing, side, err }
+    };
+
+    loop {
+        let pending = copying.pending();
+        if !pending.is_empty() {
+            let n = wr
+                .write(pending)
+                .await
+                .map_err(|e| forward_error("writing", e))?;
+            emit(sink, id, |s| copying.sent(n, direction, s));
+            if n == 0 {
+                let _ = wr.shutdown().await;
+            }
+            continue;
+        }
+
+        // Tokio's read halves cannot be shut down, we simply stop reading
+        if let Some(Shutdown::Write) = emit(sink, id, |s| copying.wind_down(direction, s)) {
+            let _ = wr.shutdown().await;
+        }
+
+        let Some(space) = copying.read_space() else {
+            debug_assert!(copying.finished());
+            return Ok(());
+        };
+        let n = rd
+            .read(space)
+            .await
+            .map_err(|e| forward_error("reading", e))?;
+        let mut rewriter = rewriter.borrow_mut();
+        emit(sink, id, |s| {
+            copying.received(n, direction, s, &mut rewriter)
+        })?;
+        copying.strip_unix_zero()?;
+    }
+}
+
+enum AsyncListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl AsyncListener {
+    /// Must be called within the context of a runtime.
+    fn bind(addr: &Addr, options: &ListenOptions) -> io::Result<Self> {
+        let backlog = options.backlog;
+        let listener = match addr {
+            Addr::Tcp(a) => AsyncListener::Tcp(TcpListener::from_std(bind_tcp(a, backlog)?)?),
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                use super::network::{bind_unix, bind_unix_std};
+                let listener = bind_unix(path, options.force_bind, |p| bind_unix_std(p, backlog))?;
+                AsyncListener::Unix(UnixListener::from_std(listener)?, path.clone())
+            }
+            #[cfg(not(unix))]
+            Addr::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix Domain sockets are not supported on this system",
+                ))
+            }
+        };
+        Ok(listener)
+    }
+
+    async fn accept(&self) -> io::Result<(AsyncStream, Addr)> {
+        match self {
+            AsyncListener::Tcp(lis) => {
+                let (conn, peer) = lis.accept().await?;
+                Ok((AsyncStream::Tcp(conn), Addr::Tcp(peer)))
+            }
+            #[cfg(unix)]
+            AsyncListener::Unix(lis, _) => {
+                let (conn, peer) = lis.accept().await?;
+                Ok((AsyncStream::Unix(conn), unix_addr(peer)))
+            }
+        }
+    }
+}
+
+impl Drop for AsyncListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let AsyncListener::Unix(_, path) = self {
+            super::network::unbind_unix(path);
+        }
+    }
+}
+
+enum AsyncStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncStream {
+    async fn connect(addr: &Addr, marking: TrafficMarking) -> io::Result<Self> {
+        let conn = match addr {
+            Addr::Tcp(a) => {
+                let socket = TcpSocket::from_std_stream(server_socket(a, marking)?.into());
+                AsyncStream::Tcp(socket.connect(*a).await?)
+            }
+            #[cfg(unix)]
+            Addr::Unix(path) => AsyncStream::Unix(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            Addr::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix Domain sockets are not supported on this system",
+                ))
+            }
+        };
+        Ok(conn)
+    }
+
+    fn is_unix(&self) -> bool {
+        !matches!(self, AsyncStream::Tcp(_))
+    }
+
+    fn peer_addr(&self) -> io::Result<Addr> {
+        match self {
+            AsyncStream::Tcp(s) => s.peer_addr().map(|a: TcpSocketAddr| a.into()),
+            #[cfg(unix)]
+            AsyncStream::Unix(s) => s.peer_addr().map(unix_addr),
+        }
+    }
+
+    fn set_nodelay(&self) -> io::Result<()> {
+        match self {
+            AsyncStream::Tcp(s) => s.set_nodelay(true),
+            #[cfg(unix)]
+            AsyncStream::Unix(_) => Ok(()),
+        }
+    }
+
+    fn into_split(self) -> (Reader, Writer) {
+        match self {
+            AsyncStream::Tcp(s) => {
+                let (rd, wr) = s.into_split();
+                (Box::pin(rd), Box::pin(wr))
+            }
+            #[cfg(unix)]
+            AsyncStream::Unix(s) => {
+                let (rd, wr) = s.into_split();
+                (Box::pin(rd), Box::pin(wr))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unix_addr(addr: tokio::net::unix::SocketAddr) -> Addr {
+    let path = addr.as_pathname().unwrap_or("<UNNAMED>".as_ref());
+    Addr::Unix(path.to_path_buf())
+}
@@ -1,8 +1,12 @@
-use std::{fmt, io};
+use std::{fmt, io, time::Duration};
 
-use smallvec::SmallVec;
+use crate::{mapi::Analyzer, Level};
 
-use super::{network::Addr, Error};
+use super::{
+    network::Addr,
+    pool::{BufferPool, PooledBuf},
+    Error,
+};
 
 /// Connection id for display to the user.
 /// Displayed with a leading #, e.g., #10.
@@ -19,6 +23,34 @@ impl ConnectionId {
     pub fn new(n: usize) -> Self {
         ConnectionId(n)
     }
+
+    /// The number without the leading #.
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+
+    /// Display the id after the name of the capture or proxy it comes from,
+    /// as in `fileA#3`, or as usual if there is no name.
+    pub fn in_source<'a>(&self, source: Option<&'a str>) -> SourcedId<'a> {
+        SourcedId { source, id: *self }
+    }
+}
+
+/// A [ConnectionId] that tells where it comes from, see
+/// [ConnectionId::in_source].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcedId<'a> {
+    source: Option<&'a str>,
+    id: ConnectionId,
+}
+
+impl fmt::Display for SourcedId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(source) = self.source {
+            f.write_str(source)?;
+        }
+        self.id.fmt(f)
+    }
 }
 
 /// Enum to indicate client->server versus server->client
@@ -68,6 +100,56 @@ pub enum MapiEvent {
     /// Proxy has succesfully bound listen port
     BoundPort(Addr),
 
+    /// One of the addresses LISTEN_ADDR resolves to could not be bound. The
+    /// proxy continues with the others.
+    BindFailed { local: Addr, error: io::Error },
+
+    /// Accepting connections on this listen port failed because the proxy ran
+    /// out of file descriptors. It will try again when connections close or
+    /// after a while.
+    AcceptPaused { local: Addr, error: io::Error },
+
+    /// The listen port has accepted a connection again after a
+    /// [MapiEvent::AcceptPaused].
+    AcceptResumed { local: Addr },
+
+    /// The health check found the server at FORWARD_ADDR down. New connections
+    /// are forwarded to the standby server until it is back.
+    PrimaryDown {
+        primary: String,
+        standby: String,
+        error: io::Error,
+    },
+
+    /// The health check found the server at FORWARD_ADDR up again after a
+    /// [MapiEvent::PrimaryDown]. New connections are forwarded to it again.
+    PrimaryUp { primary: String },
+
+    /// A pcap-ng file describes the network interface the packets with this
+    /// interface number were captured on. Not emitted by the proxy.
+    CaptureInterface {
+        interface: u32,
+        name: Option<String>,
+        description: Option<String>,
+        os: Option<String>,
+        filter: Option<String>,
+    },
+
+    /// A pcap-ng file gives the packet counts of a capture interface, usually
+    /// at the end of the capture. Packets the kernel dropped explain gaps in
+    /// the traffic. Not emitted by the proxy.
+    CaptureStatistics {
+        interface: u32,
+        received: Option<u64>,
+        interface_dropped: Option<u64>,
+        os_dropped: Option<u64>,
+    },
+
+    /// The proxy has stopped because of an error it could not recover from.
+    /// It is not emitted by the proxy itself but by whoever called its `run`
+    /// method on a separate thread. No more events will follow.
+    Failed { error: Error },
+
     /// A new client connection has been detected. Introduces a newly allocated
     /// [ConnectionId].
     Incoming {
@@ -82,10 +164,64 @@ pub enum MapiEvent {
     /// Server has accepted the new connection
     Connected { id: ConnectionId, peer: Addr },
 
+    /// The connection was already open when the capture started, so the
+    /// login was not seen. Follows [MapiEvent::Connected], only emitted when
+    /// reading pcap files.
+    Joined { id: ConnectionId },
+
+    /// Connection `id` is a copy of connection `of` made for
+    /// [Proxy::set_mirror](super::Proxy::set_mirror). It carries the same
+    /// data from the client, the responses of its server are discarded.
+    /// Follows the [MapiEvent::Incoming] of `id`.
+    Mirroring { id: ConnectionId, of: ConnectionId },
+
+    /// While looking for the database set with
+    /// [Proxy::set_database](super::Proxy::set_database), monetdbd has sent
+    /// the proxy elsewhere. Field `to` holds the redirect URL.
+    Redirected { id: ConnectionId, to: String },
+
+    /// A byte was sent to the server as TCP urgent data on request of a
+    /// [Control::OutOfBand](super::Control::OutOfBand). MonetDB takes this as
+    /// a request to interrupt the running query. Field `error` is set if the
+    /// byte could not be sent.
+    OutOfBand {
+        id: ConnectionId,
+        byte: u8,
+        error: Option<io::Error>,
+    },
+
+    /// A note added with [Control::Note](super::Control::Note) or by the
+    /// proxy itself, shown where it falls in the traffic.
+    Note {
+        id: ConnectionId,
+        direction: Option<Direction>,
+        note: String,
+    },
+
+    /// Show the data of connection `id` at another [Level] from the next
+    /// block or message boundary on, asked for with
+    /// [Control::SetLevel](super::Control::SetLevel).
+    Level { id: ConnectionId, level: Level },
+
+    /// The round trip times between the point of capture and the client and
+    /// the server, estimated from TCP timestamp options. Only emitted when
+    /// reading pcap files, when the estimate is first known and when it
+    /// improves.
+    RoundTrip {
+        id: ConnectionId,
+        client: Duration,
+        server: Duration,
+    },
+
     /// The connection has ended peacefully, no more events on this
     /// [ConnectionId] will be reported.
     End { id: ConnectionId },
 
+    /// The capture ended while the connection was still open, see
+    /// [Tracker::finish](crate::pcap::Tracker::finish). No more events on this
+    /// [ConnectionId] will be reported.
+    StillOpen { id: ConnectionId },
+
     /// Something went wrong in Mapiproxy (not in the client or the server), no
     /// more events on this [ConnectionId] will be reported.
     Aborted { id: ConnectionId, error: Error },
@@ -96,7 +232,94 @@ pub enum MapiEvent {
     Data {
         id: ConnectionId,
         direction: Direction,
-        data: SmallVec<[u8; 8]>,
+        data: PooledBuf,
+    },
+
+    /// A message was replaced by the [Rewriter](super::Rewriter) before being
+    /// forwarded. Field `original` holds the size of the message as it was
+    /// received in the preceding [MapiEvent::Data] events, `messages` holds
+    /// what was forwarded instead. Both exclude the block headers.
+    Rewritten {
+        id: ConnectionId,
+        direction: Direction,
+        original: usize,
+        messages: Vec<Vec<u8>>,
+    },
+
+    /// Data events were left out because the consumer of the events could not
+    /// keep up, see `--backpressure`. `sample` holds the first few bytes of the
+    /// data that was left out, if requested. `analyzer` is the state of the MAPI
+    /// framing after the left out data so rendering can continue from there.
+    Dropped {
+        id: ConnectionId,
+        direction: Direction,
+        chunks: usize,
+        bytes: usize,
+        sample: Vec<u8>,
+        analyzer: Analyzer,
+    },
+
+    /// A TCP segment was retransmitted with different contents. `offset` is
+    /// the position in the stream of the first byte of `original`, which holds
+    /// the bytes as they were first seen. `retransmitted` holds what the
+    /// retransmission has in their place. Only emitted when reading pcap
+    /// files, the retransmission itself is not passed on.
+    Retransmitted {
+        id: ConnectionId,
+        direction: Direction,
+        offset: u64,
+        original: Vec<u8>,
+        retransmitted: Vec<u8>,
+    },
+
+    /// The IP or TCP checksum of a packet in this direction is wrong, as
+    /// `layer` tells, so its contents may not be what was sent. `offset` is
+    /// the position in the stream of its first byte. Comes before the data of
+    /// the packet. Only emitted when reading pcap files, see
+    /// [Tracker::set_check_checksums](crate::pcap::Tracker::set_check_checksums).
+    BadChecksum {
+        id: ConnectionId,
+        direction: Direction,
+        offset: u64,
+        layer: &'static str,
+    },
+
+    /// A TCP keep-alive probe was sent in this direction. Only emitted when
+    /// reading pcap files.
+    KeepAlive {
+        id: ConnectionId,
+        direction: Direction,
+    },
+
+    /// The receiving side of this direction advertised a zero window for
+    /// `duration`, so nothing could be sent in the mean time. Emitted when the
+    /// window opens again. Only emitted when reading pcap files.
+    ZeroWindow {
+        id: ConnectionId,
+        direction: Direction,
+        duration: Duration,
+    },
+
+    /// A TCP segment as captured, see [Tracker::set_segments](crate::pcap::Tracker::set_segments).
+    /// `seq` and `ack` are positions in the streams of the two directions,
+    /// counting from their first byte, `flags` as tcpdump shows them. Comes
+    /// before the [MapiEvent::Data] with its payload, if any.
+    Segment {
+        id: ConnectionId,
+        direction: Direction,
+        flags: String,
+        seq: u64,
+        ack: Option<u64>,
+        window: u16,
+        len: usize,
+    },
+
+    /// A complete message is being held back until it is released with
+    /// [Control::Step](super::Control::Step), see
+    /// [Proxy::set_step](super::Proxy::set_step).
+    Held {
+        id: ConnectionId,
+        direction: Direction,
     },
 
     /// Client or server has shut down the write-half of its socket. No more data will
@@ -128,19 +351,72 @@ pub enum MapiEvent {
     },
 }
 
+impl MapiEvent {
+    /// The connection the event is about, if any.
+    pub fn connection_id(&self) -> Option<ConnectionId> {
+        use MapiEvent::*;
+        match self {
+            BoundPort(_)
+            | BindFailed { .. }
+            | AcceptPaused { .. }
+            | AcceptResumed { .. }
+            | PrimaryDown { .. }
+            | PrimaryUp { .. }
+            | CaptureInterface { .. }
+            | CaptureStatistics { .. }
+            | Failed { .. } => None,
+            Incoming { id, .. }
+            | Connecting { id, .. }
+            | Connected { id, .. }
+            | Joined { id }
+            | Mirroring { id, .. }
+            | Redirected { id, .. }
+            | OutOfBand { id, .. }
+            | Note { id, .. }
+            | Level { id, .. }
+            | Held { id, .. }
+            | RoundTrip { id, .. }
+            | End { id }
+            | StillOpen { id }
+            | Aborted { id, .. }
+            | Data { id, .. }
+            | Rewritten { id, .. }
+            | Dropped { id, .. }
+            | Retransmitted { id, .. }
+            | Segment { id, .. }
+            | BadChecksum { id, .. }
+            | KeepAlive { id, .. }
+            | ZeroWindow { id, .. }
+            | ShutdownRead { id, .. }
+            | ShutdownWrite { id, .. }
+            | ConnectFailed { id, .. } => Some(*id),
+        }
+    }
+}
+
 /// Struct [EventSink] knows what to do with new [MapiEvent]s and
 /// provides helper functions to generate such events.
 ///
 /// Method [connection_sink] returns a derived struct that also holds
 /// a connection id and is used to emit events specific to a single
 /// connection.
-pub struct EventSink(Box<dyn FnMut(MapiEvent) + 'static + Send>);
+pub struct EventSink {
+    handler: Box<dyn FnMut(MapiEvent) + 'static + Send>,
+    /// Where the payload of [MapiEvent::Data] events is copied into
+    pool: BufferPool,
+    /// If false, no [MapiEvent::Data] events are emitted
+    report_data: bool,
+}
 
 impl EventSink {
     /// Create a new EventSink, wrapping a function that will deliver the events
     /// somehow.
     pub fn new(f: impl FnMut(MapiEvent) + 'static + Send) -> Self {
-        EventSink(Box::new(f))
+        EventSink {
+            handler: Box::new(f),
+            pool: BufferPool::new(),
+            report_data: true,
+        }
     }
 
     /// Create a [ConnectionSink] that will deliver messages about a specific
@@ -149,15 +425,50 @@ impl EventSink {
         ConnectionSink::new(&mut *self, id)
     }
 
+    /// Whether to emit [MapiEvent::Data] events. When not needed, the proxy may
+    /// be able to forward the data more efficiently.
+    pub fn set_report_data(&mut self, report_data: bool) {
+        self.report_data = report_data;
+    }
+
     /// Emit the given event.
     fn emit_event(&mut self, event: MapiEvent) {
-        (self.0)(event)
+        (self.handler)(event)
     }
 
     /// Emit a [MapiEvent::BoundPort] event.
     pub fn emit_bound(&mut self, port: Addr) {
         self.emit_event(MapiEvent::BoundPort(port))
     }
+
+    /// Emit a [MapiEvent::BindFailed] event.
+    pub fn emit_bind_failed(&mut self, local: Addr, error: io::Error) {
+        self.emit_event(MapiEvent::BindFailed { local, error })
+    }
+
+    /// Emit a [MapiEvent::AcceptPaused] event.
+    pub fn emit_accept_paused(&mut self, local: Addr, error: io::Error) {
+        self.emit_event(MapiEvent::AcceptPaused { local, error })
+    }
+
+    /// Emit a [MapiEvent::AcceptResumed] event.
+    pub fn emit_accept_resumed(&mut self, local: Addr) {
+        self.emit_event(MapiEvent::AcceptResumed { local })
+    }
+
+    /// Emit a [MapiEvent::PrimaryDown] event.
+    pub fn emit_primary_down(&mut self, primary: String, standby: String, error: io::Error) {
+        self.emit_event(MapiEvent::PrimaryDown {
+            primary,
+            standby,
+            error,
+        })
+    }
+
+    /// Emit a [MapiEvent::PrimaryUp] event.
+    pub fn emit_primary_up(&mut self, primary: String) {
+        self.emit_event(MapiEvent::PrimaryUp { primary })
+    }
 }
 
 /// Helper struct to emit [MapiEvent]s about a specific connection.
@@ -208,6 +519,44 @@ impl<'a> ConnectionSink<'a> {
         });
     }
 
+    /// Emit a [MapiEvent::Mirroring] event.
+    pub fn emit_mirroring(&mut self, of: ConnectionId) {
+        self.0
+            .emit_event(MapiEvent::Mirroring { id: self.id(), of });
+    }
+
+    /// Emit a [MapiEvent::Redirected] event.
+    pub fn emit_redirected(&mut self, to: String) {
+        self.0
+            .emit_event(MapiEvent::Redirected { id: self.id(), to });
+    }
+
+    /// Emit a [MapiEvent::OutOfBand] event.
+    pub fn emit_out_of_band(&mut self, byte: u8, error: Option<io::Error>) {
+        self.0.emit_event(MapiEvent::OutOfBand {
+            id: self.id(),
+            byte,
+            error,
+        });
+    }
+
+    /// Emit a [MapiEvent::Note] event.
+    pub fn emit_note(&mut self, direction: Option<Direction>, note: String) {
+        self.0.emit_event(MapiEvent::Note {
+            id: self.id(),
+            direction,
+            note,
+        });
+    }
+
+    /// Emit a [MapiEvent::Level] event.
+    pub fn emit_level(&mut self, level: Level) {
+        self.0.emit_event(MapiEvent::Level {
+            id: self.id(),
+            level,
+        });
+    }
+
     /// Emit a [MapiEvent::End] event.
     pub fn emit_end(&mut self) {
         self.0.emit_event(MapiEvent::End { id: self.id() });
@@ -221,15 +570,47 @@ impl<'a> ConnectionSink<'a> {
         });
     }
 
-    /// Emit a [MapiEvent::Data] event.
+    /// Whether [ConnectionSink::emit_data] does anything, see
+    /// [EventSink::set_report_data].
+    pub fn reports_data(&self) -> bool {
+        self.0.report_data
+    }
+
+    /// Emit a [MapiEvent::Data] event, unless data is not being reported.
     pub fn emit_data(&mut self, direction: Direction, data: &[u8]) {
+        if !self.0.report_data {
+            return;
+        }
         self.0.emit_event(MapiEvent::Data {
             id: self.id(),
             direction,
-            data: SmallVec::from_slice(data),
+            data: self.0.pool.copy_from_slice(data),
+        })
+    }
+
+    /// Emit a [MapiEvent::Rewritten] event.
+    pub fn emit_rewritten(
+        &mut self,
+        direction: Direction,
+        original: usize,
+        messages: Vec<Vec<u8>>,
+    ) {
+        self.0.emit_event(MapiEvent::Rewritten {
+            id: self.id(),
+            direction,
+            original,
+            messages,
         })
     }
 
+    /// Emit a [MapiEvent::Held] event.
+    pub fn emit_held(&mut self, direction: Direction) {
+        self.0.emit_event(MapiEvent::Held {
+            id: self.id(),
+            direction,
+        });
+    }
+
     /// Emit a [MapiEvent::ShutdownRead] event.
     pub fn emit_shutdown_read(&mut self, direction: Direction) {
         self.0.emit_event(MapiEvent::ShutdownRead {
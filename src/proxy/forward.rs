@@ -1,17 +1,23 @@
 use std::{
     io::{self, ErrorKind, Read, Write},
     ops::ControlFlow::{self, Break, Continue},
+    sync::Arc,
+    time::Instant,
     vec,
 };
 
-use mio::{
-    event::{Event, Source},
-    Interest, Registry, Token,
-};
+use mio::{event::Source, Interest, Registry, Token};
+use tracing::{debug, trace};
+
+#[cfg(target_os = "linux")]
+use super::splice::Pipe;
+use crate::mapi::Analyzer;
 
 use super::{
     event::{ConnectionId, ConnectionSink, Direction},
-    network::{Addr, MioStream, MonetAddr},
+    network::{Addr, MioStream, MonetAddr, Resolver, TrafficMarking},
+    rewrite::{MessageRewriter, Rewriter},
+    route::{Route, Step},
     would_block, Error, Result,
 };
 
@@ -20,10 +26,12 @@ pub struct Forwarder(Option<Forwarding>, ConnectionId);
 #[derive(Debug)]
 enum Forwarding {
     Connecting(Connecting),
+    Routing(Routing),
     Running(Running),
 }
 
 impl Forwarder {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         registry: &Registry,
         event_sink: &mut ConnectionSink,
@@ -32,7 +40,11 @@ impl Forwarder {
         client_token: Token,
         forward_addr: &MonetAddr,
         server_token: Token,
+        database: Option<&str>,
+        shaping: Shaping,
+        dialer: Dialer,
     ) -> Result<Self> {
+        let route = database.map(|db| Box::new(Route::new(db)));
         let connecting = Connecting::new(
             event_sink,
             forward_addr,
@@ -41,6 +53,9 @@ impl Forwarder {
             conn,
             server_token,
             registry,
+            route,
+            shaping,
+            dialer,
         )?;
         let forwarding = Forwarding::Connecting(connecting);
         let forwarder = Forwarder(Some(forwarding), event_sink.id());
@@ -51,9 +66,71 @@ impl Forwarder {
         self.1
     }
 
+    /// Send `byte` to the server as TCP urgent data. Fails if the connection
+    /// to the server has not been set up yet.
+    pub fn send_out_of_band(&self, byte: u8) -> io::Result<()> {
+        match &self.0 {
+            Some(Forwarding::Running(r)) => r.server.source.send_out_of_band(byte),
+            _ => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "not connected to the server yet",
+            )),
+        }
+    }
+
+    /// Whether the last call to [Forwarder::handle_event] has started holding
+    /// back data to pause between the writes of a [Fragment]. If so, call
+    /// [Forwarder::resume] and then [Forwarder::handle_event] a little later.
+    pub fn take_resume_wanted(&mut self) -> bool {
+        match &mut self.0 {
+            Some(Forwarding::Running(r)) => std::mem::take(&mut r.resume_wanted),
+            _ => false,
+        }
+    }
+
+    /// When the message held back for [Shaping::step] started waiting, if any.
+    pub fn held_since(&self) -> Option<Instant> {
+        match &self.0 {
+            Some(Forwarding::Running(r)) => r.upstream.stepper.as_ref()?.waiting,
+            _ => None,
+        }
+    }
+
+    /// Let the message held back for [Shaping::step] go. Returns false if
+    /// there was none. Call [Forwarder::handle_event] to forward it.
+    pub fn release(&mut self) -> bool {
+        match &mut self.0 {
+            Some(Forwarding::Running(r)) => {
+                r.upstream.stepper.as_mut().is_some_and(|s| s.release())
+            }
+            _ => false,
+        }
+    }
+
+    /// The data the client has sent since the last call, for the mirror, and
+    /// whether the client has stopped sending. See [Shaping::mirror].
+    pub fn take_mirrored(&mut self) -> (Vec<u8>, bool) {
+        match &mut self.0 {
+            Some(Forwarding::Running(r)) => {
+                let data = r.upstream.tap.as_mut().map(std::mem::take);
+                (data.unwrap_or_default(), !r.upstream.can_read)
+            }
+            _ => (vec![], false),
+        }
+    }
+
+    /// Stop holding back data, see [Forwarder::take_resume_wanted].
+    pub fn resume(&mut self) {
+        if let Some(Forwarding::Running(r)) = &mut self.0 {
+            r.upstream.resume();
+            r.downstream.resume();
+        }
+    }
+
     pub fn deregister(&mut self, registry: &Registry) {
         match &mut self.0 {
             Some(Forwarding::Connecting(c)) => c.deregister(registry),
+            Some(Forwarding::Routing(r)) => r.deregister(registry),
             Some(Forwarding::Running(r)) => r.deregister(registry),
             None => {}
         }
@@ -63,12 +140,13 @@ impl Forwarder {
         &mut self,
         sink: &mut ConnectionSink,
         registry: &Registry,
-        _ev: &Event,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
     ) -> Result<ControlFlow<()>> {
         let old_state = self.0.take().unwrap();
         let handled: ControlFlow<(), Forwarding> = match old_state {
-            Forwarding::Connecting(c) => c.process(sink, registry)?,
-            Forwarding::Running(r) => r.process(sink, registry)?,
+            Forwarding::Connecting(c) => c.process(sink, registry, rewriter)?,
+            Forwarding::Routing(r) => r.process(sink, registry, rewriter)?,
+            Forwarding::Running(r) => r.process(sink, registry, rewriter)?,
         };
         match handled {
             Continue(forwarding) => {
@@ -85,9 +163,14 @@ struct Connecting {
     client: Registered<MioStream>,
     server: Registered<MioStream>,
     addrs: vec::IntoIter<Addr>,
+    /// Set if we are looking for a database, see [Routing]
+    route: Option<Box<Route>>,
+    shaping: Shaping,
+    dialer: Dialer,
 }
 
 impl Connecting {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         event_sink: &mut ConnectionSink,
         server_addr: &MonetAddr,
@@ -96,26 +179,17 @@ impl Connecting {
         client: MioStream,
         server_token: Token,
         registry: &Registry,
+        route: Option<Box<Route>>,
+        shaping: Shaping,
+        dialer: Dialer,
     ) -> Result<Connecting> {
-        let addrs = match server_addr.resolve() {
-            Ok(addrs) => addrs,
-            Err(e) => {
-                event_sink.emit_connect_failed(server_addr.to_string(), true, e);
-                return Err(Error::Connect);
-            }
-        };
-
-        if addrs.is_empty() {
-            let msg = "name does not resolve to any addresses";
-            let e = io::Error::new(ErrorKind::NotFound, msg);
-            event_sink.emit_connect_failed(server_addr.to_string(), true, e);
-            return Err(Error::Connect);
-        }
+        let addrs = resolve_server(event_sink, server_addr, &dialer.resolver)?;
 
         let client = Registered::new(client_addr.to_string(), client_token, client);
 
         let mut addrs = addrs.into_iter();
-        let Some(server) = Self::connect_addrs(event_sink, server_token, registry, &mut addrs)
+        let marking = dialer.marking;
+        let Some(server) = connect_addrs(event_sink, server_token, registry, &mut addrs, marking)
         else {
             return Err(Error::Connect);
         };
@@ -124,38 +198,13 @@ impl Connecting {
             client,
             server,
             addrs,
+            route,
+            shaping,
+            dialer,
         };
         Ok(connecting)
     }
 
-    /// Try to connect to each of the addrs in turn, returning when one succeeds.
-    ///
-    /// If all fail, return the last error.
-    /// If there were no addrs left, return Ok(Some).
-    fn connect_addrs(
-        event_sink: &mut ConnectionSink,
-        token: Token,
-        registry: &Registry,
-        addrs: impl Iterator<Item = Addr>,
-    ) -> Option<Registered<MioStream>> {
-        for addr in addrs {
-            event_sink.emit_connecting(addr.clone());
-            let err = match addr.connect() {
-                Ok(stream) => {
-                    let mut server = Registered::new(addr.to_string(), token, stream);
-                    server.need(Some(Interest::WRITABLE));
-                    match server.update_registration(registry) {
-                        Ok(()) => return Some(server),
-                        Err(e) => e,
-                    }
-                }
-                Err(e) => e,
-            };
-            event_sink.emit_connect_failed(addr.to_string(), true, err);
-        }
-        None
-    }
-
     fn deregister(&mut self, registry: &Registry) {
         let _ = self.client.deregister(registry);
         let _ = self.server.deregister(registry);
@@ -165,11 +214,15 @@ impl Connecting {
         self,
         sink: &mut ConnectionSink,
         registry: &Registry,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
     ) -> Result<ControlFlow<(), Forwarding>> {
         let Connecting {
             client,
             mut server,
             mut addrs,
+            route,
+            shaping,
+            dialer,
         } = self;
 
         let established = server.attempt(Interest::WRITABLE, |conn| conn.established());
@@ -179,15 +232,34 @@ impl Connecting {
         let error = match established {
             Ok(Some(peer)) => {
                 sink.emit_connected(peer);
-                let running = Running::from(client, server)?;
+                if let Some(mut route) = route {
+                    debug!(id = %sink.id(), "state Connecting -> Routing");
+                    route.connected(server.source.is_unix());
+                    let routing = Routing {
+                        client,
+                        server,
+                        route,
+                        shaping,
+                        dialer,
+                    };
+                    return routing.process(sink, registry, rewriter);
+                }
+                debug!(id = %sink.id(), "state Connecting -> Running");
+                let rewriting = rewriter.is_some();
+                let splicing = !rewriting && !sink.reports_data() && shaping.allows_splice();
+                let mut running = Running::from(client, server, rewriting, splicing)?;
+                running.shape(shaping, sink.id());
                 // kickstart it by running its process method too
-                return running.process(sink, registry);
+                return running.process(sink, registry, rewriter);
             }
             Ok(None) => {
                 let connecting = Connecting {
                     client,
                     server,
                     addrs,
+                    route,
+                    shaping,
+                    dialer,
                 };
                 let forwarding = Forwarding::Connecting(connecting);
                 return Ok(Continue(forwarding));
@@ -200,11 +272,14 @@ impl Connecting {
         let token = server.token;
         drop(server);
 
-        if let Some(server) = Self::connect_addrs(sink, token, registry, &mut addrs) {
+        if let Some(server) = connect_addrs(sink, token, registry, &mut addrs, dialer.marking) {
             let connecting = Connecting {
                 client,
                 server,
                 addrs,
+                route,
+                shaping,
+                dialer,
             };
             let forwarding = Forwarding::Connecting(connecting);
             Ok(Continue(forwarding))
@@ -214,21 +289,231 @@ impl Connecting {
     }
 }
 
+/// Resolve the address to forward to, reporting it if that yields nothing.
+pub(super) fn resolve_server(
+    event_sink: &mut ConnectionSink,
+    server_addr: &MonetAddr,
+    resolver: &Resolver,
+) -> Result<Vec<Addr>> {
+    let addrs = match server_addr.resolve_with(resolver) {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            event_sink.emit_connect_failed(server_addr.to_string(), true, e);
+            return Err(Error::Connect);
+        }
+    };
+
+    if addrs.is_empty() {
+        let msg = "name does not resolve to any addresses";
+        let e = io::Error::new(ErrorKind::NotFound, msg);
+        event_sink.emit_connect_failed(server_addr.to_string(), true, e);
+        return Err(Error::Connect);
+    }
+
+    Ok(addrs)
+}
+
+/// Try to connect to each of the addrs in turn, returning when one succeeds.
+/// The ones that fail are reported. Returns None if none were left.
+pub(super) fn connect_addrs(
+    event_sink: &mut ConnectionSink,
+    token: Token,
+    registry: &Registry,
+    addrs: impl Iterator<Item = Addr>,
+    marking: TrafficMarking,
+) -> Option<Registered<MioStream>> {
+    for addr in addrs {
+        debug!(id = %event_sink.id(), %addr, "connecting");
+        event_sink.emit_connecting(addr.clone());
+        let err = match addr.connect(marking) {
+            Ok(stream) => {
+                let mut server = Registered::new(addr.to_string(), token, stream);
+                server.need(Some(Interest::WRITABLE));
+                match server.update_registration(registry) {
+                    Ok(()) => return Some(server),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        event_sink.emit_connect_failed(addr.to_string(), true, err);
+    }
+    None
+}
+
+/// Logging in to monetdbd on behalf of the client until it leads us to the
+/// server of the database, see [Route]. The client is left waiting.
+#[derive(Debug)]
+struct Routing {
+    client: Registered<MioStream>,
+    server: Registered<MioStream>,
+    route: Box<Route>,
+    shaping: Shaping,
+    dialer: Dialer,
+}
+
+impl Routing {
+    fn deregister(&mut self, registry: &Registry) {
+        let _ = self.client.deregister(registry);
+        let _ = self.server.deregister(registry);
+    }
+
+    fn process(
+        self,
+        sink: &mut ConnectionSink,
+        registry: &Registry,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
+    ) -> Result<ControlFlow<(), Forwarding>> {
+        let Routing {
+            client,
+            mut server,
+            mut route,
+            shaping,
+            dialer,
+        } = self;
+
+        server.clear();
+        loop {
+            let pending = route.pending();
+            if !pending.is_empty() {
+                match server.attempt(Interest::WRITABLE, |conn| conn.write(pending)) {
+                    Ok(n) => route.sent(n),
+                    Err(e) if would_block(&e) => {}
+                    Err(err) => {
+                        let side = "server";
+                        return Err(Error::Forward {
+                            doing: "writing",
+                            side,
+                            err,
+                        });
+                    }
+                }
+            }
+
+            let mut buf = [0u8; 8192];
+            let n = match server.attempt(Interest::READABLE, |conn| conn.read(&mut buf)) {
+                Ok(0) => {
+                    let db = route.database();
+                    let msg = format!("server closed the connection while looking for {db}");
+                    return Err(Error::Other(msg));
+                }
+                Ok(n) => n,
+                Err(e) if would_block(&e) => break,
+                Err(err) => {
+                    let side = "server";
+                    return Err(Error::Forward {
+                        doing: "reading",
+                        side,
+                        err,
+                    });
+                }
+            };
+
+            match route.received(&buf[..n], sink)? {
+                Step::Wait => {}
+                Step::Redirect(addr) => {
+                    let token = server.token;
+                    let _ = server.deregister(registry);
+                    drop(server);
+                    let mut addrs = resolve_server(sink, &addr, &dialer.resolver)?.into_iter();
+                    let marking = dialer.marking;
+                    let Some(server) = connect_addrs(sink, token, registry, &mut addrs, marking)
+                    else {
+                        return Err(Error::Connect);
+                    };
+                    debug!(id = %sink.id(), "state Routing -> Connecting");
+                    let connecting = Connecting {
+                        client,
+                        server,
+                        addrs,
+                        route: Some(route),
+                        shaping,
+                        dialer,
+                    };
+                    return Ok(Continue(Forwarding::Connecting(connecting)));
+                }
+                Step::Arrived(challenge) => {
+                    debug!(id = %sink.id(), "state Routing -> Running");
+                    let mut running =
+                        Running::routed(client, server, &route, &challenge, sink, rewriter)?;
+                    running.shape(shaping, sink.id());
+                    return running.process(sink, registry, rewriter);
+                }
+            }
+        }
+
+        server
+            .update_registration(registry)
+            .map_err(|err| Error::Forward {
+                doing: "registering",
+                side: "server",
+                err,
+            })?;
+        let routing = Routing {
+            client,
+            server,
+            route,
+            shaping,
+            dialer,
+        };
+        Ok(Continue(Forwarding::Routing(routing)))
+    }
+}
+
 #[derive(Debug)]
 struct Running {
     client: Registered<MioStream>,
     server: Registered<MioStream>,
     upstream: Copying,
     downstream: Copying,
+    /// Set when a direction has started holding back data, see
+    /// [Forwarder::take_resume_wanted].
+    resume_wanted: bool,
 }
 
 impl Running {
-    fn from(client: Registered<MioStream>, server: Registered<MioStream>) -> Result<Running> {
+    fn from(
+        client: Registered<MioStream>,
+        server: Registered<MioStream>,
+        rewriting: bool,
+        splicing: bool,
+    ) -> Result<Running> {
         let client_is_unix = client.source.is_unix();
         let server_is_unix = server.source.is_unix();
-        let upstream = Copying::new(client_is_unix, server_is_unix);
-        let downstream = Copying::new(false, false);
+        let mut upstream = Copying::new(client_is_unix, server_is_unix, rewriting);
+        let mut downstream = Copying::new(false, false, rewriting);
+        if splicing {
+            upstream.try_splice();
+            downstream.try_splice();
+        }
+        Self::new(client, server, upstream, downstream)
+    }
+
+    /// Start forwarding after [Routing] has found the server. Its challenge
+    /// has already been read, it goes out to the client first.
+    fn routed(
+        client: Registered<MioStream>,
+        server: Registered<MioStream>,
+        route: &Route,
+        challenge: &[u8],
+        sink: &mut ConnectionSink,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
+    ) -> Result<Running> {
+        let client_is_unix = client.source.is_unix();
+        // Routing has already sent the '0' byte if the server needs one
+        let mut upstream = Copying::new(client_is_unix, false, true);
+        upstream.set_login_database(route.database());
+        let mut downstream = Copying::new(false, false, rewriter.is_some());
+        downstream.preload(challenge, Direction::Downstream, sink, rewriter)?;
+        Self::new(client, server, upstream, downstream)
+    }
 
+    fn new(
+        client: Registered<MioStream>,
+        server: Registered<MioStream>,
+        upstream: Copying,
+        downstream: Copying,
+    ) -> Result<Running> {
         for (side, sock) in [("client", &client), ("server", &server)] {
             sock.source.set_nodelay(true).map_err(|e| Error::Forward {
                 doing: "setting nodelay",
@@ -242,10 +527,27 @@ impl Running {
             server,
             upstream,
             downstream,
+            resume_wanted: false,
         };
         Ok(running)
     }
 
+    /// Apply the [Shaping] to both directions. Splicing must not have been
+    /// enabled unless [Shaping::allows_splice].
+    fn shape(&mut self, shaping: Shaping, id: ConnectionId) {
+        if let Some(fragment) = shaping.fragment {
+            let seed = 2 * id.as_usize() as u64;
+            self.upstream.fragmenter = Some(Fragmenter::new(fragment, seed));
+            self.downstream.fragmenter = Some(Fragmenter::new(fragment, seed + 1));
+        }
+        if shaping.step {
+            self.upstream.step();
+        }
+        if shaping.mirror {
+            self.upstream.tap = Some(vec![]);
+        }
+    }
+
     fn deregister(&mut self, registry: &Registry) {
         let _ = self.client.deregister(registry);
         let _ = self.server.deregister(registry);
@@ -255,22 +557,30 @@ impl Running {
         mut self,
         sink: &mut ConnectionSink,
         registry: &Registry,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
     ) -> Result<ControlFlow<(), Forwarding>> {
         let Running {
             client,
             server,
             upstream,
             downstream,
+            resume_wanted,
         } = &mut self;
 
+        // one resume takes care of both directions
+        let was_held = upstream.held() || downstream.held();
         let mut progress = true;
         while progress {
             progress = false;
             client.clear();
             server.clear();
 
-            progress |= downstream.handle_one(Direction::Downstream, sink, server, client)?;
-            progress |= upstream.handle_one(Direction::Upstream, sink, client, server)?;
+            progress |=
+                downstream.handle_one(Direction::Downstream, sink, rewriter, server, client)?;
+            progress |= upstream.handle_one(Direction::Upstream, sink, rewriter, client, server)?;
+        }
+        if !was_held && (upstream.held() || downstream.held()) {
+            *resume_wanted = true;
         }
 
         client
@@ -288,7 +598,14 @@ impl Running {
                 err,
             })?;
 
+        trace!(
+            id = %sink.id(),
+            upstream_pending = upstream.pending_len(),
+            downstream_pending = downstream.pending_len(),
+            "buffers"
+        );
         if upstream.finished() && downstream.finished() {
+            debug!(id = %sink.id(), "state Running -> finished");
             Ok(Break(()))
         } else {
             Ok(Continue(Forwarding::Running(self)))
@@ -304,18 +621,38 @@ pub struct Copying {
     unsent_data: usize,
     free_space: usize,
     fix_unix_read: bool,
+    /// When rewriting, the data read into [Self::buffer] is immediately passed
+    /// on to this and the data to write is taken from there instead.
+    rewriting: Option<Box<MessageRewriter>>,
+    /// When splicing, the data goes through this pipe instead of
+    /// [Self::buffer], see [Copying::try_splice].
+    #[cfg(target_os = "linux")]
+    pipe: Option<Pipe>,
+    /// If set, the data is written in small pieces, see [Fragment].
+    fragmenter: Option<Fragmenter>,
+    /// If set, each message is held back until it is released, see
+    /// [Shaping::step].
+    stepper: Option<Box<Stepper>>,
+    /// If set, everything received is also collected here, see
+    /// [Shaping::mirror].
+    tap: Option<Vec<u8>>,
 }
 
 impl Copying {
     const BUFSIZE: usize = 8192;
 
-    fn new(fix_unix_read: bool, fix_unix_write: bool) -> Self {
+    pub(super) fn new(fix_unix_read: bool, fix_unix_write: bool, rewriting: bool) -> Self {
         let mut free_space = 0;
         let mut buffer = Box::new([0; Self::BUFSIZE]);
+        let mut rewriting = rewriting.then(|| Box::new(MessageRewriter::new()));
 
         if fix_unix_write {
-            buffer[0] = b'0';
-            free_space = 1;
+            if let Some(rw) = &mut rewriting {
+                rw.prepend(b"0");
+            } else {
+                buffer[0] = b'0';
+                free_space = 1;
+            }
         }
 
         Copying {
@@ -325,23 +662,119 @@ impl Copying {
             unsent_data: 0,
             free_space,
             fix_unix_read,
+            rewriting,
+            #[cfg(target_os = "linux")]
+            pipe: None,
+            fragmenter: None,
+            stepper: None,
+            tap: None,
         }
     }
 
-    fn handle_one(
+    /// Forward using splice(2) if possible. This requires that the data does
+    /// not need to be inspected or modified on the way, so it's only possible
+    /// if it is not reported, not rewritten and doesn't need the '0' byte
+    /// that starts Unix socket connections to be added or removed.
+    fn try_splice(&mut self) {
+        #[cfg(target_os = "linux")]
+        {
+            if self.rewriting.is_some() || self.fix_unix_read || self.free_space > 0 {
+                return;
+            }
+            match Pipe::new() {
+                Ok(pipe) => self.pipe = Some(pipe),
+                Err(e) => debug!("cannot splice, creating pipe failed: {e}"),
+            }
+        }
+    }
+
+    /// The number of bytes received but not yet sent
+    fn pending_len(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        if let Some(pipe) = &self.pipe {
+            return pipe.pending();
+        }
+        self.pending().len()
+    }
+
+    /// The data that has been received but not yet sent
+    pub(super) fn pending(&self) -> &[u8] {
+        match &self.rewriting {
+            Some(rw) => rw.pending(),
+            None => &self.buffer[self.unsent_data..self.free_space],
+        }
+    }
+
+    /// Mark the first `n` bytes of [Self::pending] as sent
+    fn consume(&mut self, n: usize) {
+        match &mut self.rewriting {
+            Some(rw) => rw.consume(n),
+            None => self.unsent_data += n,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        match &self.rewriting {
+            Some(rw) => rw.has_room(),
+            None => self.free_space < Self::BUFSIZE,
+        }
+    }
+
+    /// Pass the data that has just been read to the [MessageRewriter], if any.
+    fn rewrite(
         &mut self,
         direction: Direction,
         sink: &mut ConnectionSink,
-        rd: &mut Registered<MioStream>,
-        wr: &mut Registered<MioStream>,
-    ) -> Result<bool> {
-        assert!(self.unsent_data <= self.free_space);
-        assert!(self.free_space <= Self::BUFSIZE);
-        assert!(self.unsent_data == self.free_space || self.can_write);
+        rewriter: &mut Option<Box<dyn Rewriter>>,
+    ) -> Result<()> {
+        let Some(rw) = &mut self.rewriting else {
+            return Ok(());
+        };
+        let mut data = &self.buffer[..self.free_space];
+        if self.fix_unix_read && !data.is_empty() {
+            if data[0] != b'0' {
+                return Err(Error::Other(
+                    "client did not start with a '0' (0x30) byte".to_string(),
+                ));
+            }
+            data = &data[1..];
+            self.fix_unix_read = false;
+        }
+        let rewriter = rewriter.as_mut().map(|r| r.as_mut() as &mut dyn Rewriter);
+        rw.feed(data, direction, rewriter, sink)?;
+        self.free_space = 0;
+        Ok(())
+    }
 
-        let mut progress = false;
+    /// Replace the database name in the client's login response, see
+    /// [MessageRewriter::set_login_database]. Only works when rewriting.
+    fn set_login_database(&mut self, database: &str) {
+        if let Some(rw) = &mut self.rewriting {
+            rw.set_login_database(database);
+        }
+    }
 
-        if self.fix_unix_read && self.free_space > 0 {
+    /// Process data that was received before this [Copying] existed as if it
+    /// had been read just now.
+    fn preload(
+        &mut self,
+        data: &[u8],
+        direction: Direction,
+        sink: &mut ConnectionSink,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
+    ) -> Result<()> {
+        let Some(space) = self.read_space().filter(|space| space.len() >= data.len()) else {
+            return Err(Error::Other("server message too large".to_string()));
+        };
+        space[..data.len()].copy_from_slice(data);
+        self.received(data.len(), direction, sink, rewriter)
+    }
+
+    /// Skip the '0' byte a Unix Domain socket client starts with, once it
+    /// has been received. The rewriting code path does this in
+    /// [Copying::rewrite].
+    pub(super) fn strip_unix_zero(&mut self) -> Result<()> {
+        if self.fix_unix_read && self.rewriting.is_none() && self.free_space > 0 {
             assert_eq!(self.unsent_data, 0);
             if self.buffer[0] == b'0' {
                 // skip it
@@ -353,23 +786,221 @@ impl Copying {
                 ));
             }
         }
+        Ok(())
+    }
+
+    /// Where to read the next data into, or None if we should not read right
+    /// now.
+    pub(super) fn read_space(&mut self) -> Option<&mut [u8]> {
+        if self.can_read && self.can_write && self.has_room() {
+            Some(&mut self.buffer[self.free_space..])
+        } else {
+            None
+        }
+    }
+
+    /// Process `n` bytes that have been read into [Copying::read_space].
+    /// Zero means end of file, the caller must shut down the read side.
+    pub(super) fn received(
+        &mut self,
+        n: usize,
+        direction: Direction,
+        sink: &mut ConnectionSink,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
+    ) -> Result<()> {
+        if n == 0 {
+            sink.emit_shutdown_read(direction);
+            if let Some(rw) = &mut self.rewriting {
+                rw.finish();
+            }
+            self.can_read = false;
+            return Ok(());
+        }
+        trace!(id = %sink.id(), ?direction, n, "read");
+        let data = &self.buffer[self.free_space..self.free_space + n];
+        sink.emit_data(direction, data);
+        if let Some(tap) = &mut self.tap {
+            tap.extend_from_slice(data);
+        }
+        self.free_space += n;
+        self.rewrite(direction, sink, rewriter)
+    }
+
+    /// Process the result of writing `n` bytes of [Copying::pending]. Zero
+    /// means the receiver has gone away, the caller must shut down the write
+    /// side.
+    pub(super) fn sent(&mut self, n: usize, direction: Direction, sink: &mut ConnectionSink) {
+        if n > 0 {
+            trace!(id = %sink.id(), ?direction, n, "wrote");
+            self.consume(n);
+            return;
+        }
+        let n = self.pending().len();
+        sink.emit_shutdown_write(direction, n);
+        self.consume(n);
+        self.can_write = false;
+    }
+
+    /// Once everything has been sent, decide whether one of the sockets should
+    /// be shut down because the other side is done. The caller must carry out
+    /// the returned [Shutdown](std::net::Shutdown) on the receiving socket if
+    /// it's a Write, on the sending socket if it's a Read.
+    pub(super) fn wind_down(
+        &mut self,
+        direction: Direction,
+        sink: &mut ConnectionSink,
+    ) -> Option<std::net::Shutdown> {
+        if !self.pending().is_empty() {
+            return None;
+        }
+        self.unsent_data = 0;
+        self.free_space = 0;
+        if self.can_write && !self.can_read {
+            // No data in the buffer and no option to get more
+            debug!(id = %sink.id(), ?direction, "shutting down write side");
+            self.can_write = false;
+            return Some(std::net::Shutdown::Write);
+        }
+        if self.can_read && !self.can_write {
+            debug!(id = %sink.id(), ?direction, "shutting down read side");
+            sink.emit_shutdown_read(direction);
+            self.can_read = false;
+            return Some(std::net::Shutdown::Read);
+        }
+        None
+    }
+
+    fn handle_one(
+        &mut self,
+        direction: Direction,
+        sink: &mut ConnectionSink,
+        rewriter: &mut Option<Box<dyn Rewriter>>,
+        rd: &mut Registered<MioStream>,
+        wr: &mut Registered<MioStream>,
+    ) -> Result<bool> {
+        #[cfg(target_os = "linux")]
+        if self.pipe.is_some() {
+            return self.splice_one(direction, sink, rd, wr);
+        }
+
+        assert!(self.unsent_data <= self.free_space);
+        assert!(self.free_space <= Self::BUFSIZE);
+        assert!(self.pending().is_empty() || self.can_write);
+
+        let mut progress = false;
+
+        self.strip_unix_zero()?;
 
-        let to_write = &self.buffer[self.unsent_data..self.free_space];
+        let mut limit = match &mut self.fragmenter {
+            Some(fragmenter) if fragmenter.held => 0,
+            Some(fragmenter) => fragmenter.next_size(),
+            None => usize::MAX,
+        };
+        if let Some(mut stepper) = self.stepper.take() {
+            let (allowed, newly_waiting) = stepper.limit(self.pending(), !self.has_room());
+            if newly_waiting {
+                sink.emit_held(direction);
+            }
+            limit = limit.min(allowed);
+            self.stepper = Some(stepper);
+        }
+        let to_write = self.pending();
+        let to_write = &to_write[..to_write.len().min(limit)];
         if !to_write.is_empty() {
             assert!(self.can_write);
             match wr.attempt(Interest::WRITABLE, |w| w.write(to_write)) {
+                Ok(n) => {
+                    progress = true;
+                    self.sent(n, direction, sink);
+                    if n == 0 {
+                        let _ = wr.source.shutdown(std::net::Shutdown::Write);
+                    }
+                    if let Some(stepper) = &mut self.stepper {
+                        stepper.sent(n);
+                    }
+                    let more = !self.pending().is_empty();
+                    if let Some(fragmenter) = &mut self.fragmenter {
+                        // give the peer a chance to read this piece on its own
+                        fragmenter.held = more;
+                    }
+                }
+                Err(e) if would_block(&e) => {
+                    // don't touch progress
+                }
+                Err(err) => {
+                    return Err(Error::Forward {
+                        doing: "writing",
+                        side: direction.receiver(),
+                        err,
+                    })
+                }
+            }
+        }
+
+        match self.wind_down(direction, sink) {
+            Some(how @ std::net::Shutdown::Write) => {
+                let _ = wr.source.shutdown(how);
+            }
+            Some(how) => {
+                let _ = rd.source.shutdown(how);
+            }
+            None => {}
+        }
+
+        if let Some(dest) = self.read_space() {
+            match rd.attempt(Interest::READABLE, |r| r.read(dest)) {
+                Ok(n) => {
+                    progress = true;
+                    self.received(n, direction, sink, rewriter)?;
+                    if n == 0 {
+                        let _ = rd.source.shutdown(std::net::Shutdown::Read);
+                    }
+                }
+                Err(e) if would_block(&e) => {
+                    // don't touch progress
+                }
+                Err(err) => {
+                    return Err(Error::Forward {
+                        doing: "reading",
+                        side: direction.sender(),
+                        err,
+                    })
+                }
+            }
+        }
+
+        Ok(progress)
+    }
+
+    /// Like [Copying::handle_one] but moving the data through the pipe.
+    #[cfg(target_os = "linux")]
+    fn splice_one(
+        &mut self,
+        direction: Direction,
+        sink: &mut ConnectionSink,
+        rd: &mut Registered<MioStream>,
+        wr: &mut Registered<MioStream>,
+    ) -> Result<bool> {
+        let pipe = self.pipe.as_mut().unwrap();
+        assert!(pipe.pending() == 0 || self.can_write);
+
+        let mut progress = false;
+
+        if pipe.pending() > 0 {
+            match wr.attempt(Interest::WRITABLE, |w| pipe.drain(w)) {
                 Ok(n @ 1..) => {
+                    trace!(id = %sink.id(), ?direction, n, "spliced out");
                     progress = true;
-                    self.unsent_data += n;
                 }
                 Ok(0) => {
                     // eof
                     progress = true;
-                    let n = self.free_space - self.unsent_data;
-                    sink.emit_shutdown_write(direction, n);
-                    self.unsent_data = self.free_space;
+                    sink.emit_shutdown_write(direction, pipe.pending());
+                    // discard what's left, the regular code path takes it from here
+                    self.pipe = None;
                     self.can_write = false;
                     let _ = wr.source.shutdown(std::net::Shutdown::Write);
+                    return Ok(progress);
                 }
                 Err(e) if would_block(&e) => {
                     // don't touch progress
@@ -384,29 +1015,25 @@ impl Copying {
             }
         }
 
-        if self.unsent_data == self.free_space {
-            self.unsent_data = 0;
-            self.free_space = 0;
+        if pipe.pending() == 0 {
             if self.can_write && !self.can_read {
-                // No data in the buffer and no option to get more
+                debug!(id = %sink.id(), ?direction, "shutting down write side");
                 self.can_write = false;
                 let _ = wr.source.shutdown(std::net::Shutdown::Write);
             }
             if self.can_read && !self.can_write {
+                debug!(id = %sink.id(), ?direction, "shutting down read side");
                 sink.emit_shutdown_read(direction);
                 self.can_read = false;
                 let _ = rd.source.shutdown(std::net::Shutdown::Read);
             }
         }
 
-        if self.can_read && self.can_write && self.free_space < Self::BUFSIZE {
-            let dest = &mut self.buffer[self.free_space..];
-            match rd.attempt(Interest::READABLE, |r| r.read(dest)) {
+        if self.can_read && self.can_write && pipe.has_room() {
+            match rd.attempt(Interest::READABLE, |r| pipe.fill(r)) {
                 Ok(n @ 1..) => {
-                    let data = &dest[..n];
-                    sink.emit_data(direction, data);
+                    trace!(id = %sink.id(), ?direction, n, "spliced in");
                     progress = true;
-                    self.free_space += n;
                 }
                 Ok(0) => {
                     // eof
@@ -431,18 +1058,37 @@ impl Copying {
         Ok(progress)
     }
 
-    fn finished(&self) -> bool {
+    pub(super) fn finished(&self) -> bool {
         !self.can_read && !self.can_write
     }
+
+    /// Hold back every message until it is released, see [Shaping::step].
+    /// Must be called before any data has been received.
+    fn step(&mut self) {
+        // the '0' that starts a Unix domain socket connection to the server
+        let unix_server = self.pending() == b"0";
+        self.stepper = Some(Box::new(Stepper::new(unix_server)));
+    }
+
+    /// Whether data is being held back between the writes of a [Fragment].
+    fn held(&self) -> bool {
+        self.fragmenter.as_ref().is_some_and(|f| f.held)
+    }
+
+    fn resume(&mut self) {
+        if let Some(fragmenter) = &mut self.fragmenter {
+            fragmenter.held = false;
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Registered<S: Source> {
-    name: String,
+    pub(super) name: String,
 
-    source: S,
+    pub(super) source: S,
 
-    token: Token,
+    pub(super) token: Token,
 
     /// Interests we would like to be ready. Will be added
     /// to our Poll Registry if not already in ready.
@@ -453,7 +1099,7 @@ pub struct Registered<S: Source> {
 }
 
 impl<S: Source> Registered<S> {
-    fn new(name: String, token: Token, source: S) -> Self {
+    pub(super) fn new(name: String, token: Token, source: S) -> Self {
         Registered {
             name,
             source,
@@ -463,7 +1109,7 @@ impl<S: Source> Registered<S> {
         }
     }
 
-    fn clear(&mut self) {
+    pub(super) fn clear(&mut self) {
         self.needed = None;
     }
 
@@ -471,7 +1117,7 @@ impl<S: Source> Registered<S> {
         self.needed = combine_interests(self.needed, interests);
     }
 
-    fn attempt<T>(
+    pub(super) fn attempt<T>(
         &mut self,
         interests: Interest,
         f: impl FnOnce(&mut S) -> io::Result<T>,
@@ -485,7 +1131,7 @@ impl<S: Source> Registered<S> {
         result
     }
 
-    fn update_registration(&mut self, registry: &Registry) -> io::Result<()> {
+    pub(super) fn update_registration(&mut self, registry: &Registry) -> io::Result<()> {
         match (self.registered, self.needed) {
             (None, None) => {}
             (Some(_), None) => registry.deregister(&mut self.source)?,
@@ -498,11 +1144,20 @@ impl<S: Source> Registered<S> {
                 }
             }
         }
+        if self.registered != self.needed {
+            debug!(
+                name = self.name,
+                token = ?self.token,
+                old = ?self.registered,
+                new = ?self.needed,
+                "registration changed"
+            );
+        }
         self.registered = self.needed;
         Ok(())
     }
 
-    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+    pub(super) fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
         self.clear();
         self.update_registration(registry)
     }
@@ -516,6 +1171,167 @@ fn combine_interests(left: Option<Interest>, right: Option<Interest>) -> Option<
     }
 }
 
+/// Settings that change the way the data of a connection is forwarded.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Shaping {
+    /// Write the data in small pieces, see
+    /// [Proxy::set_fragment](super::Proxy::set_fragment)
+    pub fragment: Option<Fragment>,
+    /// Hold back each message sent by the client until it is released, see
+    /// [Proxy::set_step](super::Proxy::set_step)
+    pub step: bool,
+    /// Collect the data sent by the client for a second server, see
+    /// [Proxy::set_mirror](super::Proxy::set_mirror)
+    pub mirror: bool,
+}
+
+impl Shaping {
+    /// They all need to see the data pass by.
+    fn allows_splice(&self) -> bool {
+        self.fragment.is_none() && !self.step && !self.mirror
+    }
+}
+
+/// How to find and connect to the servers, see
+/// [ListenOptions::resolver](super::network::ListenOptions::resolver) and
+/// [Proxy::set_traffic_marking](super::Proxy::set_traffic_marking).
+#[derive(Debug, Clone, Default)]
+pub(super) struct Dialer {
+    pub resolver: Arc<Resolver>,
+    pub marking: TrafficMarking,
+}
+
+/// Finds the message boundaries in the data to forward and only lets the
+/// data through one message at a time, each when it has been released.
+#[derive(Debug)]
+struct Stepper {
+    analyzer: Analyzer,
+    /// How many bytes of the pending data the analyzer has looked at
+    scanned: usize,
+    /// Set when a complete message has been scanned, to when it was
+    waiting: Option<Instant>,
+    /// How many bytes may be forwarded before holding again
+    released: usize,
+    /// Set if the data turned out not to be MAPI, it is no longer held back
+    passthrough: bool,
+}
+
+impl Stepper {
+    fn new(unix_server: bool) -> Self {
+        Stepper {
+            analyzer: Analyzer::new(unix_server),
+            scanned: 0,
+            waiting: None,
+            released: 0,
+            passthrough: false,
+        }
+    }
+
+    /// How many bytes of `pending` may be forwarded, and whether a message
+    /// has just started waiting to be released. A message that does not fit
+    /// in the buffer, as indicated by `full`, cannot be held in one piece.
+    /// Its blocks are let through as they are scanned, only its end is held.
+    fn limit(&mut self, pending: &[u8], full: bool) -> (usize, bool) {
+        if self.passthrough {
+            return (usize::MAX, false);
+        }
+        if self.released > 0 || self.waiting.is_some() {
+            return (self.released, false);
+        }
+        let mut data = &pending[self.scanned..];
+        while let Some(chunk) = self.analyzer.split_chunk(&mut data) {
+            self.scanned += chunk.len();
+            if self.analyzer.was_error() {
+                self.passthrough = true;
+                return (usize::MAX, false);
+            }
+            if self.analyzer.was_message_boundary() {
+                self.waiting = Some(Instant::now());
+                return (0, true);
+            }
+        }
+        if full {
+            (self.scanned, false)
+        } else {
+            (0, false)
+        }
+    }
+
+    fn sent(&mut self, n: usize) {
+        self.scanned = self.scanned.saturating_sub(n);
+        self.released = self.released.saturating_sub(n);
+    }
+
+    fn release(&mut self) -> bool {
+        if self.waiting.take().is_none() {
+            return false;
+        }
+        self.released = self.scanned;
+        true
+    }
+}
+
+/// Forward the data in writes of `size` bytes, give or take up to `jitter`
+/// bytes, instead of writing as much as possible at once. This gives the peer
+/// awkward TCP segmentation, with block headers straddling the reads, which is
+/// good at bringing out bugs in the code that reassembles the blocks. See
+/// [Proxy::set_fragment](super::Proxy::set_fragment).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    pub size: usize,
+    pub jitter: usize,
+}
+
+/// Picks the sizes of the writes for a [Fragment]. The sizes are
+/// pseudo-random but depend only on the seed, so a connection that triggers a
+/// bug is likely to trigger it again when it is repeated.
+#[derive(Debug)]
+struct Fragmenter {
+    fragment: Fragment,
+    state: u64,
+    /// Set after a write until it is time for the next one
+    held: bool,
+}
+
+impl Fragmenter {
+    fn new(fragment: Fragment, seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        let state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        Fragmenter {
+            fragment,
+            state,
+            held: false,
+        }
+    }
+
+    fn next_size(&mut self) -> usize {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        let Fragment { size, jitter } = self.fragment;
+        let smallest = size.saturating_sub(jitter).max(1);
+        let spread = size + jitter - smallest + 1;
+        smallest + (x % spread as u64) as usize
+    }
+}
+
+#[test]
+fn test_fragmenter() {
+    let mut fixed = Fragmenter::new(Fragment { size: 3, jitter: 0 }, 10);
+    assert!((0..100).all(|_| fixed.next_size() == 3));
+
+    let fragment = Fragment { size: 4, jitter: 6 };
+    let mut jittery = Fragmenter::new(fragment, 11);
+    let sizes: Vec<_> = (0..1000).map(|_| jittery.next_size()).collect();
+    assert!(sizes.iter().all(|&n| (1..=10).contains(&n)));
+    assert!(sizes.contains(&1) && sizes.contains(&10));
+
+    let mut again = Fragmenter::new(fragment, 11);
+    assert!(sizes.iter().all(|&n| again.next_size() == n));
+}
+
 #[test]
 fn test_combine_interests() {
     let r = Some(Interest::READABLE);
@@ -528,3 +1344,54 @@ fn test_combine_interests() {
     assert_eq!(combine_interests(r, r), r);
     assert_eq!(combine_interests(r, w), rw);
 }
+
+#[test]
+fn test_stepper_large_message() {
+    use crate::mapi::encode_message;
+
+    let mut input = vec![];
+    encode_message(&[b'x'; 20_000], &mut input);
+    let first_len = input.len();
+    encode_message(b"sselect 1", &mut input);
+
+    // mimic Copying: read into a buffer of BUFSIZE, write what is allowed
+    let mut stepper = Stepper::new(false);
+    let mut input = &input[..];
+    let mut buffer = vec![];
+    let mut forwarded = 0;
+    let mut step = |stepper: &mut Stepper, buffer: &mut Vec<u8>, input: &mut &[u8]| {
+        let n = (Copying::BUFSIZE - buffer.len()).min(input.len());
+        buffer.extend_from_slice(&input[..n]);
+        *input = &input[n..];
+        let (allowed, newly_waiting) = stepper.limit(buffer, buffer.len() == Copying::BUFSIZE);
+        let n = allowed.min(buffer.len());
+        buffer.drain(..n);
+        stepper.sent(n);
+        forwarded += n;
+        (forwarded, newly_waiting)
+    };
+
+    let mut rounds = 0;
+    let (sent, _) = loop {
+        rounds += 1;
+        assert!(rounds < 10, "stuck with {} bytes buffered", buffer.len());
+        let (sent, newly_waiting) = step(&mut stepper, &mut buffer, &mut input);
+        if newly_waiting {
+            break (sent, newly_waiting);
+        }
+    };
+    // everything but the end of the first message went through
+    assert!(sent > Copying::BUFSIZE && sent < first_len, "{sent}");
+    assert!(stepper.waiting.is_some());
+    assert_eq!(step(&mut stepper, &mut buffer, &mut input).0, sent);
+
+    assert!(stepper.release());
+    let (sent, newly_waiting) = step(&mut stepper, &mut buffer, &mut input);
+    assert_eq!(sent, first_len);
+    assert!(!newly_waiting);
+
+    // the next message is held in one piece again
+    let (sent, newly_waiting) = step(&mut stepper, &mut buffer, &mut input);
+    assert_eq!(sent, first_len);
+    assert!(newly_waiting);
+}
@@ -0,0 +1,218 @@
+//! Health checks of the server at FORWARD_ADDR, used to decide whether new
+//! connections should go to the standby server instead, see
+//! [Proxy::set_standby](super::Proxy::set_standby).
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    time::Duration,
+    vec,
+};
+
+use mio::{Interest, Registry, Token};
+use tracing::debug;
+
+use super::{
+    event::EventSink,
+    forward::Dialer,
+    network::{Addr, MioStream, MonetAddr, TrafficMarking},
+    would_block,
+};
+
+#[derive(Debug)]
+pub(super) struct Health {
+    standby: MonetAddr,
+    /// Set while new connections go to the standby
+    primary_down: bool,
+    /// The check in progress, if any
+    probe: Option<Probe>,
+}
+
+impl Health {
+    /// How long to wait between the end of one check and the start of the next
+    pub const INTERVAL: Duration = Duration::from_secs(2);
+    /// How long the server gets to send the first bytes of its challenge
+    pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub fn new(standby: MonetAddr) -> Self {
+        Health {
+            standby,
+            primary_down: false,
+            probe: None,
+        }
+    }
+
+    /// The address new connections should be forwarded to.
+    pub fn target<'a>(&'a self, primary: &'a MonetAddr) -> &'a MonetAddr {
+        if self.primary_down {
+            &self.standby
+        } else {
+            primary
+        }
+    }
+
+    /// Start checking the primary. Returns false if the check has already
+    /// concluded, otherwise [Health::ready] must be called when `token` becomes
+    /// ready.
+    pub fn start(
+        &mut self,
+        primary: &MonetAddr,
+        dialer: &Dialer,
+        registry: &Registry,
+        token: Token,
+        sink: &mut EventSink,
+    ) -> bool {
+        match Probe::start(primary, dialer, registry, token) {
+            Ok(probe) => {
+                self.probe = Some(probe);
+                true
+            }
+            Err(e) => {
+                self.conclude(primary, Err(e), sink);
+                false
+            }
+        }
+    }
+
+    /// Continue the check in progress. Returns true if it has concluded.
+    pub fn ready(
+        &mut self,
+        primary: &MonetAddr,
+        registry: &Registry,
+        token: Token,
+        sink: &mut EventSink,
+    ) -> bool {
+        let Some(probe) = &mut self.probe else {
+            return false;
+        };
+        let Some(result) = probe.process(registry, token) else {
+            return false;
+        };
+        self.finish(registry);
+        self.conclude(primary, result, sink);
+        true
+    }
+
+    /// The check in progress took too long, count it as a failure.
+    pub fn timed_out(&mut self, primary: &MonetAddr, registry: &Registry, sink: &mut EventSink) {
+        if self.probe.is_none() {
+            return;
+        }
+        self.finish(registry);
+        let e = io::Error::new(ErrorKind::TimedOut, "no challenge received in time");
+        self.conclude(primary, Err(e), sink);
+    }
+
+    fn finish(&mut self, registry: &Registry) {
+        if let Some(mut probe) = self.probe.take() {
+            let _ = registry.deregister(&mut probe.stream);
+        }
+    }
+
+    fn conclude(&mut self, primary: &MonetAddr, result: io::Result<()>, sink: &mut EventSink) {
+        debug!(%primary, ?result, "health check");
+        match result {
+            Ok(()) if self.primary_down => {
+                self.primary_down = false;
+                sink.emit_primary_up(primary.to_string());
+            }
+            Err(e) if !self.primary_down => {
+                self.primary_down = true;
+                sink.emit_primary_down(primary.to_string(), self.standby.to_string(), e);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A lightweight check whether a server is up: connect and wait for the first
+/// bytes of its login challenge, then hang up.
+#[derive(Debug)]
+struct Probe {
+    stream: MioStream,
+    /// The addresses to try if this one fails
+    addrs: vec::IntoIter<Addr>,
+    marking: TrafficMarking,
+    connected: bool,
+}
+
+impl Probe {
+    fn start(
+        server: &MonetAddr,
+        dialer: &Dialer,
+        registry: &Registry,
+        token: Token,
+    ) -> io::Result<Probe> {
+        let mut addrs = server.resolve_with(&dialer.resolver)?.into_iter();
+        let marking = dialer.marking;
+        let stream = Self::connect(&mut addrs, marking, registry, token)?;
+        Ok(Probe {
+            stream,
+            addrs,
+            marking,
+            connected: false,
+        })
+    }
+
+    /// Connect to the first of `addrs` that does not fail immediately.
+    fn connect(
+        addrs: &mut vec::IntoIter<Addr>,
+        marking: TrafficMarking,
+        registry: &Registry,
+        token: Token,
+    ) -> io::Result<MioStream> {
+        let mut error = io::Error::new(ErrorKind::NotFound, "no addresses left to try");
+        for addr in addrs {
+            let attempt = addr.connect(marking).and_then(|mut stream| {
+                let interests = Interest::READABLE | Interest::WRITABLE;
+                registry.register(&mut stream, token, interests)?;
+                Ok(stream)
+            });
+            match attempt {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Returns None while the outcome is not known yet.
+    fn process(&mut self, registry: &Registry, token: Token) -> Option<io::Result<()>> {
+        let error = match self.attempt() {
+            Ok(false) => return None,
+            Ok(true) => return Some(Ok(())),
+            Err(e) => e,
+        };
+        let _ = registry.deregister(&mut self.stream);
+        match Self::connect(&mut self.addrs, self.marking, registry, token) {
+            Ok(stream) => {
+                self.stream = stream;
+                self.connected = false;
+                None
+            }
+            Err(_) => Some(Err(error)),
+        }
+    }
+
+    /// Returns true once the server has sent something.
+    fn attempt(&mut self) -> io::Result<bool> {
+        if !self.connected {
+            if self.stream.established()?.is_none() {
+                return Ok(false);
+            }
+            self.connected = true;
+            if self.stream.is_unix() {
+                self.stream.write_all(b"0")?;
+            }
+        }
+        let mut buf = [0u8; 2];
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "server closed the connection without sending a challenge",
+            )),
+            Ok(_) => Ok(true),
+            Err(e) if would_block(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! Sending a copy of what the clients send to a second server, see
+//! [Proxy::set_mirror](super::Proxy::set_mirror).
+//!
+//! Each mirror connection belongs to one forwarded connection and gets the
+//! data its client sends, as the [Forwarder](super::forward::Forwarder)
+//! collects it. The responses of the second server are reported and then
+//! thrown away. Whatever happens to the mirror, the forwarded connection
+//! carries on as if it wasn't there.
+//!
+//! The login response of the client is salted with the challenge of the real
+//! server, so the mirror would refuse it. Given the password, the mirror
+//! answers the challenges of the second server itself, see [Relogin].
+
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    net::Shutdown,
+    ops::ControlFlow::{self, Break, Continue},
+    str, vec,
+};
+
+use mio::{Interest, Registry, Token};
+use sha2::Digest;
+use tracing::debug;
+
+use crate::mapi::{encode_message, read_message};
+
+use super::{
+    event::{ConnectionId, ConnectionSink, Direction},
+    forward::{connect_addrs, resolve_server, Dialer, Registered},
+    network::{Addr, MioStream, MonetAddr, TrafficMarking},
+    would_block, Error, Result,
+};
+
+#[derive(Debug)]
+pub(super) struct Mirror {
+    id: ConnectionId,
+    conn: Registered<MioStream>,
+    /// The addresses to try if connecting to the current one fails
+    addrs: vec::IntoIter<Addr>,
+    marking: TrafficMarking,
+    connected: bool,
+    /// Data from the client that has yet to be sent to the mirror
+    outgoing: Vec<u8>,
+    /// Set until the '0' byte a Unix domain socket client starts with has
+    /// been left out
+    skip_zero: bool,
+    /// Set while the logins of the client are being rewritten
+    relogin: Option<Relogin>,
+    /// Set when the client has stopped sending
+    input_done: bool,
+    write_done: bool,
+    read_done: bool,
+}
+
+impl Mirror {
+    /// Give up on a mirror that has fallen this far behind the client.
+    const MAX_OUTGOING: usize = 16 * 1024 * 1024;
+
+    /// Start connecting to `addr`. The events go out as connection `sink.id()`.
+    /// With a `password`, the logins of the client are rewritten to match the
+    /// challenges of the mirror.
+    pub fn start(
+        sink: &mut ConnectionSink,
+        addr: &MonetAddr,
+        dialer: &Dialer,
+        password: Option<&str>,
+        token: Token,
+        registry: &Registry,
+        unix_client: bool,
+    ) -> Result<Mirror> {
+        let mut addrs = resolve_server(sink, addr, &dialer.resolver)?.into_iter();
+        let marking = dialer.marking;
+        let Some(conn) = connect_addrs(sink, token, registry, &mut addrs, marking) else {
+            return Err(Error::Connect);
+        };
+        let outgoing = if conn.source.is_unix() {
+            b"0".to_vec()
+        } else {
+            vec![]
+        };
+        Ok(Mirror {
+            id: sink.id(),
+            conn,
+            addrs,
+            marking,
+            connected: false,
+            outgoing,
+            skip_zero: unix_client,
+            relogin: password.map(Relogin::new),
+            input_done: false,
+            write_done: false,
+            read_done: false,
+        })
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Queue data the client has sent. `done` means it will send no more.
+    pub fn feed(&mut self, mut data: &[u8], done: bool, sink: &mut ConnectionSink) -> Result<()> {
+        if !data.is_empty() {
+            sink.emit_data(Direction::Upstream, data);
+            if self.skip_zero {
+                data = &data[1..];
+                self.skip_zero = false;
+            }
+        }
+        if self.write_done {
+            self.relogin = None;
+        } else if let Some(relogin) = &mut self.relogin {
+            if relogin.client_sent(data, &mut self.outgoing).is_break() {
+                self.relogin = None;
+            }
+        } else {
+            self.outgoing.extend_from_slice(data);
+        }
+        let held = self.relogin.as_ref().map_or(0, Relogin::held);
+        if self.outgoing.len() + held > Self::MAX_OUTGOING {
+            return Err(Error::Other(
+                "mirror server fell too far behind".to_string(),
+            ));
+        }
+        if done && !self.input_done {
+            sink.emit_shutdown_read(Direction::Upstream);
+            self.input_done = true;
+        }
+        Ok(())
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) {
+        let _ = self.conn.deregister(registry);
+    }
+
+    pub fn process(
+        &mut self,
+        sink: &mut ConnectionSink,
+        registry: &Registry,
+    ) -> Result<ControlFlow<()>> {
+        self.conn.clear();
+        if !self.connected {
+            match self.conn.attempt(Interest::WRITABLE, |c| c.established()) {
+                Ok(Some(peer)) => {
+                    sink.emit_connected(peer);
+                    self.connected = true;
+                    let _ = self.conn.source.set_nodelay(true);
+                }
+                Ok(None) => {
+                    self.update_registration(registry)?;
+                    return Ok(Continue(()));
+                }
+                Err(e) => {
+                    sink.emit_connect_failed(self.conn.name.clone(), false, e);
+                    let token = self.conn.token;
+                    let _ = self.conn.deregister(registry);
+                    let addrs = &mut self.addrs;
+                    let Some(conn) = connect_addrs(sink, token, registry, addrs, self.marking)
+                    else {
+                        return Err(Error::Connect);
+                    };
+                    self.conn = conn;
+                    return Ok(Continue(()));
+                }
+            }
+        }
+
+        let mut progress = true;
+        while progress {
+            progress = false;
+
+            if !self.outgoing.is_empty() {
+                let outgoing = &self.outgoing;
+                match self.conn.attempt(Interest::WRITABLE, |c| c.write(outgoing)) {
+                    Ok(0) => {
+                        progress = true;
+                        sink.emit_shutdown_write(Direction::Upstream, self.outgoing.len());
+                        self.outgoing.clear();
+                        self.write_done = true;
+                    }
+                    Ok(n) => {
+                        progress = true;
+                        self.outgoing.drain(..n);
+                    }
+                    Err(e) if would_block(&e) => {}
+                    Err(err) => {
+                        return Err(Error::Forward {
+                            doing: "writing",
+                            side: "mirror",
+                            err,
+                        })
+                    }
+                }
+            }
+            let holding = self.relogin.as_ref().is_some_and(|r| r.held() > 0);
+            if self.outgoing.is_empty() && self.input_done && !holding && !self.write_done {
+                debug!(id = %self.id, "shutting down write side of mirror");
+                let _ = self.conn.source.shutdown(Shutdown::Write);
+                self.write_done = true;
+            }
+
+            if !self.read_done {
+                let mut buf = [0u8; 8192];
+                match self.conn.attempt(Interest::READABLE, |c| c.read(&mut buf)) {
+                    Ok(0) => {
+                        progress = true;
+                        sink.emit_shutdown_read(Direction::Downstream);
+                        self.read_done = true;
+                        // no more challenges will come to answer
+                        if let Some(mut relogin) = self.relogin.take() {
+                            relogin.give_up(&mut self.outgoing);
+                        }
+                    }
+                    Ok(n) => {
+                        progress = true;
+                        sink.emit_data(Direction::Downstream, &buf[..n]);
+                        if let Some(relogin) = &mut self.relogin {
+                            if relogin
+                                .mirror_sent(&buf[..n], &mut self.outgoing)
+                                .is_break()
+                            {
+                                self.relogin = None;
+                            }
+                        }
+                    }
+                    Err(e) if would_block(&e) => {}
+                    Err(err) => {
+                        return Err(Error::Forward {
+                            doing: "reading",
+                            side: "mirror",
+                            err,
+                        })
+                    }
+                }
+            }
+        }
+
+        if self.write_done && self.read_done {
+            return Ok(Break(()));
+        }
+        self.update_registration(registry)?;
+        Ok(Continue(()))
+    }
+
+    fn update_registration(&mut self, registry: &Registry) -> Result<()> {
+        self.conn
+            .update_registration(registry)
+            .map_err(|err| Error::Forward {
+                doing: "registering",
+                side: "mirror",
+                err,
+            })
+    }
+}
+
+/// Answers the challenges of the mirror server on behalf of the client.
+///
+/// The client logs in with a hash of its password salted with the challenge
+/// of the real server. The logins of the client are held until the mirror
+/// has sent a challenge, and then sent with a hash computed from the password
+/// and that challenge. Like [Negotiation](super::negotiate::Negotiation), the
+/// challenges and logins are recognized by their shape, so the second login
+/// after monetdbd has passed the connection on to mserver5 is rewritten too.
+/// The first message of the client that is not a login ends the handshake,
+/// from then on its data is passed on as is.
+#[derive(Debug)]
+struct Relogin {
+    password: String,
+    /// Data from the client that does not form a whole message yet, or a
+    /// login that waits for a challenge
+    upstream: Vec<u8>,
+    /// Data from the mirror that does not form a whole message yet
+    downstream: Vec<u8>,
+    /// The challenges of the mirror that have not been answered yet
+    challenges: VecDeque<Challenge>,
+}
+
+impl Relogin {
+    fn new(password: &str) -> Self {
+        Relogin {
+            password: password.to_string(),
+            upstream: vec![],
+            downstream: vec![],
+            challenges: VecDeque::new(),
+        }
+    }
+
+    /// The number of bytes from the client that are being held back.
+    fn held(&self) -> usize {
+        self.upstream.len()
+    }
+
+    /// Process data from the client, appending what can be sent to the
+    /// mirror to `out`. Breaks when the handshake is over.
+    fn client_sent(&mut self, data: &[u8], out: &mut Vec<u8>) -> ControlFlow<()> {
+        self.upstream.extend_from_slice(data);
+        self.advance(out)
+    }
+
+    /// Process data from the mirror, appending the logins that can now be
+    /// sent to `out`. Breaks when the handshake is over.
+    fn mirror_sent(&mut self, data: &[u8], out: &mut Vec<u8>) -> ControlFlow<()> {
+        self.downstream.extend_from_slice(data);
+        while let Some(message) = take_message(&mut self.downstream) {
+            if let Some(challenge) = Challenge::parse(&message) {
+                self.challenges.push_back(challenge);
+            }
+        }
+        self.advance(out)
+    }
+
+    /// Send whatever is being held as is.
+    fn give_up(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.upstream);
+    }
+
+    fn advance(&mut self, out: &mut Vec<u8>) -> ControlFlow<()> {
+        loop {
+            let mut rd = &self.upstream[..];
+            let Ok(Some(message)) = read_message(&mut rd) else {
+                return Continue(());
+            };
+            if !is_login(&message) {
+                self.give_up(out);
+                return Break(());
+            }
+            let Some(challenge) = self.challenges.pop_front() else {
+                return Continue(());
+            };
+            let consumed = self.upstream.len() - rd.len();
+            self.upstream.drain(..consumed);
+            match challenge.answer(&message, &self.password) {
+                Some(login) => encode_message(&login, out),
+                None => encode_message(&message, out),
+            }
+        }
+    }
+}
+
+/// The parts of a challenge such as
+/// `salt:mserver:9:RIPEMD160,SHA512,SHA384:LIT:SHA512:` needed to log in.
+#[derive(Debug)]
+struct Challenge {
+    salt: String,
+    /// The hash algorithms the server accepts for the login
+    hashes: Vec<String>,
+    /// The hash algorithm the server stores the passwords with
+    password_hash: String,
+}
+
+impl Challenge {
+    /// The hash algorithms that can be used to log in, best first.
+    const ALGORITHMS: [&'static str; 6] =
+        ["SHA512", "SHA384", "SHA256", "SHA224", "SHA1", "RIPEMD160"];
+
+    fn parse(message: &[u8]) -> Option<Challenge> {
+        let text = str::from_utf8(message).ok()?;
+        let fields: Vec<&str> = text.split(':').collect();
+        let is_challenge = fields.len() >= 6
+            && fields[2].parse::<u32>().is_ok()
+            && !text.trim_end().contains('\n');
+        if !is_challenge {
+            return None;
+        }
+        Some(Challenge {
+            salt: fields[0].to_string(),
+            hashes: fields[3].split(',').map(str::to_string).collect(),
+            password_hash: fields[5].to_string(),
+        })
+    }
+
+    /// The `login` with its password hash replaced by one computed from
+    /// `password` for this challenge. The hash algorithm of the client is
+    /// kept if the mirror accepts it. Returns None if there is no algorithm
+    /// both sides support.
+    fn answer(&self, login: &[u8], password: &str) -> Option<Vec<u8>> {
+        let text = str::from_utf8(login).ok()?;
+        let mut fields: Vec<&str> = text.split(':').collect();
+        let client_algo = fields[2].strip_prefix('{')?.split('}').next()?;
+        let algo = Some(client_algo)
+            .filter(|a| self.accepts(a))
+            .or_else(|| Self::ALGORITHMS.into_iter().find(|a| self.accepts(a)))?;
+        let password = hex_digest(&self.password_hash, password.as_bytes())?;
+        let salted = format!("{password}{}", self.salt);
+        let hash = format!("{{{algo}}}{}", hex_digest(algo, salted.as_bytes())?);
+        fields[2] = &hash;
+        Some(fields.join(":").into_bytes())
+    }
+
+    fn accepts(&self, algo: &str) -> bool {
+        Self::ALGORITHMS.contains(&algo) && self.hashes.iter().any(|h| h == algo)
+    }
+}
+
+/// Whether `message` looks like the login of a client, such as
+/// `LIT:monetdb:{SHA512}...:sql:demo:`.
+fn is_login(message: &[u8]) -> bool {
+    let Ok(text) = str::from_utf8(message) else {
+        return false;
+    };
+    let fields: Vec<&str> = text.split(':').collect();
+    fields.len() >= 5 && matches!(fields[0], "BIG" | "LIT") && fields[2].starts_with('{')
+}
+
+fn take_message(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let mut rd = &buffer[..];
+    let message = read_message(&mut rd).ok()??;
+    let consumed = buffer.len() - rd.len();
+    buffer.drain(..consumed);
+    Some(message)
+}
+
+fn hex_digest(algo: &str, data: &[u8]) -> Option<String> {
+    let digest = match algo {
+        "SHA512" => sha2::Sha512::digest(data).to_vec(),
+        "SHA384" => sha2::Sha384::digest(data).to_vec(),
+        "SHA256" => sha2::Sha256::digest(data).to_vec(),
+        "SHA224" => sha2::Sha224::digest(data).to_vec(),
+        "SHA1" => sha1::Sha1::digest(data).to_vec(),
+        "RIPEMD160" => ripemd::Ripemd160::digest(data).to_vec(),
+        _ => return None,
+    };
+    Some(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: &str = "X9tZtrW2:mserver:9:RIPEMD160,SHA512,SHA256:LIT:SHA512:";
+    // sha512(sha512("monetdb").hexdigest() + "X9tZtrW2")
+    const SHA512_HASH: &str = "14fc9a81c74ad248bc373f0f77bfe325837860711c1fcabdf60ca51952143219\
+                               bfd532e54bd908a9cf13c70ccaf6489b7003def715956190e76340493b2990a1";
+    // sha256(sha512("monetdb").hexdigest() + "X9tZtrW2")
+    const SHA256_HASH: &str = "abade6259f57d6bee2a1f26f78abb03d343d3c9433f2c71e27c22a6c9fa5bcaa";
+
+    fn message(text: &str) -> Vec<u8> {
+        let mut out = vec![];
+        encode_message(text.as_bytes(), &mut out);
+        out
+    }
+
+    #[test]
+    fn test_answer() {
+        let challenge = Challenge::parse(CHALLENGE.as_bytes()).unwrap();
+        let login = b"LIT:monetdb:{SHA512}0123:sql:demo:FILETRANS:\n";
+        let answer = challenge.answer(login, "monetdb").unwrap();
+        let expected = format!("LIT:monetdb:{{SHA512}}{SHA512_HASH}:sql:demo:FILETRANS:\n");
+        assert_eq!(String::from_utf8(answer).unwrap(), expected);
+
+        // the mirror does not take SHA384, pick the best one it does take
+        let login = b"LIT:monetdb:{SHA384}0123:sql:demo:";
+        let answer = challenge.answer(login, "monetdb").unwrap();
+        assert_eq!(
+            answer,
+            format!("LIT:monetdb:{{SHA512}}{SHA512_HASH}:sql:demo:").as_bytes()
+        );
+
+        let login = b"BIG:monetdb:{SHA256}0123:sql:demo:";
+        let answer = challenge.answer(login, "monetdb").unwrap();
+        assert_eq!(
+            answer,
+            format!("BIG:monetdb:{{SHA256}}{SHA256_HASH}:sql:demo:").as_bytes()
+        );
+
+        let odd = Challenge::parse(b"salt:mserver:9:MD5:LIT:SHA512:").unwrap();
+        assert_eq!(odd.answer(login, "monetdb"), None);
+    }
+
+    #[test]
+    fn test_relogin() {
+        let mut relogin = Relogin::new("monetdb");
+        let mut out = vec![];
+
+        // the client logs in before the challenge of the mirror has arrived
+        let login = message("LIT:monetdb:{SHA512}0123:sql:demo:");
+        let query = message("sselect 1;");
+        assert_eq!(relogin.client_sent(&login[..5], &mut out), Continue(()));
+        assert_eq!(relogin.client_sent(&login[5..], &mut out), Continue(()));
+        assert!(out.is_empty());
+        assert_eq!(relogin.held(), login.len());
+
+        let challenge = message(CHALLENGE);
+        assert_eq!(relogin.mirror_sent(&challenge, &mut out), Continue(()));
+        let expected = format!("LIT:monetdb:{{SHA512}}{SHA512_HASH}:sql:demo:");
+        assert_eq!(out, message(&expected));
+        assert_eq!(relogin.held(), 0);
+
+        // the login response and a query end the handshake
+        out.clear();
+        assert_eq!(relogin.mirror_sent(&message(""), &mut out), Continue(()));
+        assert_eq!(relogin.client_sent(&query, &mut out), Break(()));
+        assert_eq!(out, query);
+    }
+
+    #[test]
+    fn test_relogin_through_monetdbd() {
+        let mut relogin = Relogin::new("monetdb");
+        let mut out = vec![];
+
+        let first = "merosalt:merovingian:9:RIPEMD160,SHA512:LIT:SHA512:";
+        assert_eq!(relogin.mirror_sent(&message(first), &mut out), Continue(()));
+        let login = message("LIT:monetdb:{SHA512}0123:sql:demo:");
+        assert_eq!(relogin.client_sent(&login, &mut out), Continue(()));
+        assert!(!out.is_empty());
+        assert_eq!(relogin.held(), 0);
+
+        // monetdbd passes the connection on, mserver5 starts a new login
+        out.clear();
+        let mut mirror = message("^mapi:merovingian://proxy?database=demo\n");
+        mirror.extend(message(CHALLENGE));
+        assert_eq!(relogin.mirror_sent(&mirror, &mut out), Continue(()));
+        assert_eq!(relogin.client_sent(&login, &mut out), Continue(()));
+        let expected = format!("LIT:monetdb:{{SHA512}}{SHA512_HASH}:sql:demo:");
+        assert_eq!(out, message(&expected));
+    }
+}
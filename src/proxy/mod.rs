@@ -1,23 +1,80 @@
+#[cfg(feature = "proxy")]
+mod async_proxy;
 pub mod event;
+#[cfg(feature = "proxy")]
 mod forward;
+#[cfg(feature = "proxy")]
+mod health;
+#[cfg(feature = "proxy")]
+mod mirror;
+#[cfg(feature = "proxy")]
+mod negotiate;
 pub mod network;
-
+pub mod pool;
+#[cfg(feature = "proxy")]
+mod rewrite;
+#[cfg(feature = "proxy")]
+mod route;
+#[cfg(all(feature = "proxy", target_os = "linux"))]
+mod splice;
+#[cfg(feature = "proxy")]
+mod throttle;
+#[cfg(feature = "proxy")]
+pub mod timer;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+
+use std::io;
+#[cfg(feature = "proxy")]
 use std::{
-    io::{self, ErrorKind},
+    collections::HashMap,
+    io::ErrorKind,
     ops::{ControlFlow, RangeFrom},
-    sync::Arc,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
 };
 
-use forward::Forwarder;
+#[cfg(feature = "proxy")]
+pub use async_proxy::AsyncProxy;
+#[cfg(feature = "proxy")]
+pub use forward::Fragment;
+#[cfg(feature = "proxy")]
+use forward::{Dialer, Forwarder, Shaping};
+#[cfg(feature = "proxy")]
+use health::Health;
+#[cfg(feature = "proxy")]
+use mirror::Mirror;
+#[cfg(feature = "proxy")]
+pub use negotiate::{HandshakeRewriter, Negotiation};
 use network::Addr;
-
-use mio::{event::Event, Events, Interest, Poll, Token};
+#[cfg(feature = "proxy")]
+pub use rewrite::Rewriter;
+#[cfg(feature = "proxy")]
+pub use throttle::AcceptRate;
+#[cfg(feature = "proxy")]
+use throttle::TokenBucket;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use uring::UringProxy;
+
+#[cfg(feature = "proxy")]
+use mio::{Events, Interest, Poll, Token};
+#[cfg(feature = "proxy")]
 use slab::Slab;
 use thiserror::Error as ThisError;
+#[cfg(feature = "proxy")]
+use tracing::{debug, trace};
+
+#[cfg(feature = "proxy")]
+use crate::Level;
 
+#[cfg(feature = "proxy")]
 use self::{
-    event::{ConnectionId, EventSink, MapiEvent},
-    network::{MioListener, MioStream, MonetAddr},
+    event::{ConnectionId, Direction, EventSink, MapiEvent},
+    network::{
+        is_out_of_fds, ListenOptions, MioListener, MioStream, MonetAddr, TrafficMarking,
+        DEFAULT_BACKLOG,
+    },
+    timer::{Expired, TimerHandle, TimerWheel},
 };
 
 /// Errors that can occur in the [Proxy].
@@ -29,6 +86,12 @@ pub enum Error {
     #[error("Could not create mio poller: {0}")]
     CreatePoll(io::Error),
 
+    #[error("Could not create tokio runtime: {0}")]
+    CreateRuntime(io::Error),
+
+    #[error("Could not set up io_uring: {0}")]
+    CreateRing(io::Error),
+
     #[error("Could not listen on {0}: {1}")]
     StartListening(String, io::Error),
 
@@ -52,11 +115,63 @@ pub enum Error {
     Other(String),
 }
 
+#[cfg(feature = "proxy")]
 type Result<T> = std::result::Result<T, Error>;
 
+/// Bind the addresses `listen_addr` resolves to. Addresses that cannot be bound
+/// are reported with [MapiEvent::BindFailed] and skipped, unless
+/// [ListenOptions::require_all_binds] is set. Fails if none can be bound.
+#[cfg(feature = "proxy")]
+fn bind_listeners<L>(
+    listen_addr: &MonetAddr,
+    options: &ListenOptions,
+    event_sink: &mut EventSink,
+    mut bind: impl FnMut(&Addr) -> io::Result<L>,
+) -> Result<Vec<(Addr, L)>> {
+    let addrs = listen_addr
+        .resolve_with(&options.resolver)
+        .map_err(|e| Error::StartListening(listen_addr.to_string(), e))?;
+    if addrs.is_empty() {
+        let err = io::Error::new(ErrorKind::NotFound, "listen address not found");
+        return Err(Error::StartListening(listen_addr.to_string(), err));
+    }
+
+    let results: Vec<_> = addrs
+        .into_iter()
+        .map(|addr| {
+            let result = bind(&addr);
+            (addr, result)
+        })
+        .collect();
+    let bound = results.iter().filter(|(_, r)| r.is_ok()).count();
+    if bound == 0 || (options.require_all_binds && bound < results.len()) {
+        let (addr, err) = results
+            .into_iter()
+            .find_map(|(addr, r)| Some((addr, r.err()?)))
+            .unwrap();
+        return Err(Error::StartListening(addr.to_string(), err));
+    }
+
+    let mut listeners = Vec::with_capacity(bound);
+    for (addr, result) in results {
+        match result {
+            Ok(listener) => {
+                event_sink.emit_bound(addr.clone());
+                listeners.push((addr, listener));
+            }
+            Err(err) => {
+                debug!(%addr, %err, "could not bind, continuing without it");
+                event_sink.emit_bind_failed(addr, err);
+            }
+        }
+    }
+    Ok(listeners)
+}
+
 /// The Proxy listens on a number of sockets, forwards the connections
 /// to another server and reports on the traffic as a series of
 /// [MapiEvent]s.
+#[cfg(feature = "proxy")]
 pub struct Proxy {
     /// Configured address to listen on. May map to multiple concrete addresses,
     /// the proxy will listen on all of them
@@ -67,24 +182,133 @@ pub struct Proxy {
     /// The mio Poll object used to multiplex all IO on a single thread.
     poll: Poll,
     /// The waker can be used to trigger the proxy externally, we use it
-    /// to stop the proxy on Control-C.
+    /// to stop the proxy on Control-C and to pass on [Control] requests.
     waker: Arc<mio::Waker>,
+    /// The [Control] requests to handle when the waker goes off.
+    controls: mpsc::Receiver<Control>,
+    controls_tx: mpsc::Sender<Control>,
     /// mio Tokens below this number are belong to listeners, the rest belong
     /// to forwarded connections.
     token_base: usize,
     /// Holds ownership of the listeners. `Token(t)` maps to `listeners[t]`.
     listeners: Vec<(Addr, MioListener)>,
+    /// Set for the listeners that have been deregistered because we ran out of
+    /// file descriptors. Indexed like [Proxy::listeners].
+    paused: Vec<bool>,
     /// Holds ownership of the forwarders. `Token(t+self.token_base)` maps to
     /// `forwarders[t/2]`.
     forwarders: Slab<Forwarder>,
+    /// If set, the data the clients send is also sent here, see
+    /// [Proxy::set_mirror].
+    mirror_addr: Option<MonetAddr>,
+    /// See [Proxy::set_mirror_password].
+    mirror_password: Option<String>,
+    /// `Token(Proxy::MIRROR_TOKEN_BASE + m)` maps to `mirrors[m]`.
+    mirrors: Slab<Mirror>,
+    /// The mirrors of the forwarders that are still running, by their index
+    /// in [Proxy::forwarders].
+    mirror_of: HashMap<usize, usize>,
     /// Iterator that yields fresh connection id's.
     ids: RangeFrom<usize>,
     /// This is where events are reported.
     event_sink: EventSink,
+    /// If set, all messages are passed through this before being forwarded.
+    rewriter: Option<Box<dyn Rewriter>>,
+    /// If set, connections are routed to this database through monetdbd.
+    database: Option<String>,
+    /// Changes to the way data is forwarded, see [Proxy::set_fragment] and
+    /// [Proxy::set_step].
+    shaping: Shaping,
+    /// How to find and connect to the servers, see [ListenOptions::resolver]
+    /// and [Proxy::set_traffic_marking].
+    dialer: Dialer,
+    /// If set, [Proxy::forward_addr] is checked periodically and new
+    /// connections go to the standby server while it is down.
+    health: Option<Health>,
+    /// Timers, global or per forwarder. The latter are keyed by their index
+    /// in [Proxy::forwarders] and cancelled when it is removed.
+    timers: TimerWheel<Timer>,
+    /// Set while the timer to retry the paused listeners is running.
+    retry_timer: Option<TimerHandle>,
+    /// Set while a health check is in progress.
+    probe_timer: Option<TimerHandle>,
+    /// If set, limits how fast connections are accepted, see
+    /// [Proxy::set_accept_rate].
+    accept_rate: Option<TokenBucket>,
+    /// Set while the listeners wait for the [Proxy::accept_rate] to allow
+    /// another connection.
+    throttle_timer: Option<TimerHandle>,
+}
+
+/// Requests that can be made to a running [Proxy] from another thread, see
+/// [Proxy::get_controller].
+#[cfg(feature = "proxy")]
+#[derive(Debug)]
+pub enum Control {
+    /// End the main loop of [Proxy::run]
+    Shutdown,
+    /// Send `byte` to the server of connection `id` as TCP urgent data,
+    /// reported as [MapiEvent::OutOfBand]. The outcome is also sent to
+    /// `reply`, if given.
+    OutOfBand {
+        id: ConnectionId,
+        byte: u8,
+        reply: Option<mpsc::Sender<io::Result<()>>>,
+    },
+    /// Forward the message held back on connection `id`, or if `None`, the
+    /// one that has waited longest. See [Proxy::set_step].
+    Step {
+        id: Option<ConnectionId>,
+        reply: Option<mpsc::Sender<io::Result<()>>>,
+    },
+    /// Emit a [MapiEvent::Note] about connection `id`, so the note shows up
+    /// in the output next to the traffic passing at that moment.
+    Note {
+        id: ConnectionId,
+        direction: Option<Direction>,
+        note: String,
+        reply: Option<mpsc::Sender<io::Result<()>>>,
+    },
+    /// Emit a [MapiEvent::Level] about connection `id`, to show its traffic
+    /// at another level from now on.
+    SetLevel {
+        id: ConnectionId,
+        level: Level,
+        reply: Option<mpsc::Sender<io::Result<()>>>,
+    },
+}
+
+/// What to do when a timer of the [Proxy] goes off.
+#[cfg(feature = "proxy")]
+#[derive(Debug)]
+enum Timer {
+    /// Let the paused listeners try to accept again
+    RetryAccept,
+    /// The [AcceptRate] allows another connection
+    AcceptThrottled,
+    /// Start the next health check of the primary server
+    HealthCheck,
+    /// Give up on the health check in progress
+    ProbeTimeout,
+    /// Let a forwarder that pauses between writes continue, see [Fragment]
+    Resume,
 }
 
+#[cfg(feature = "proxy")]
 impl Proxy {
-    const TRIGGER_SHUTDOWN_TOKEN: Token = Token(usize::MAX);
+    const WAKER_TOKEN: Token = Token(usize::MAX);
+    const PROBE_TOKEN: Token = Token(usize::MAX - 1);
+    /// Tokens from here on belong to mirrors, far above those of the
+    /// forwarders.
+    const MIRROR_TOKEN_BASE: usize = usize::MAX / 2;
+
+    /// How long to wait before accepting again after running out of file
+    /// descriptors, if no connection has been closed in the mean time.
+    const RETRY_ACCEPT_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// How long to pause between the writes of a [Fragment]. In practice this
+    /// is rounded up to the resolution of the timers.
+    const FRAGMENT_PAUSE: Duration = Duration::from_millis(1);
 
     /// Create a new Proxy which listens on the TCP/IPv4, TCP/IPv6 and Unix Domain
     /// sockets denoted by `listen_addr`. Returns an error if the listen sockets
@@ -93,39 +317,78 @@ impl Proxy {
         listen_addr: MonetAddr,
         forward_addr: MonetAddr,
         event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<Proxy> {
+        Self::with_backlog(listen_addr, forward_addr, DEFAULT_BACKLOG, event_handler)
+    }
+
+    /// Like [Proxy::new] but with the given listen backlog.
+    pub fn with_backlog(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        backlog: i32,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<Proxy> {
+        let options = ListenOptions {
+            backlog,
+            ..ListenOptions::default()
+        };
+        Self::with_options(listen_addr, forward_addr, options, event_handler)
+    }
+
+    /// Like [Proxy::new] but with the given [ListenOptions].
+    pub fn with_options(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        options: ListenOptions,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
     ) -> Result<Proxy> {
         let poll = Poll::new().map_err(Error::CreatePoll)?;
-        let waker = mio::Waker::new(poll.registry(), Self::TRIGGER_SHUTDOWN_TOKEN)
-            .map_err(Error::CreatePoll)?;
+        let waker =
+            mio::Waker::new(poll.registry(), Self::WAKER_TOKEN).map_err(Error::CreatePoll)?;
         let waker = Arc::new(waker);
+        let (controls_tx, controls) = mpsc::channel();
         let mut proxy = Proxy {
             listen_addr,
             forward_addr,
             poll,
             waker,
+            controls,
+            controls_tx,
             token_base: usize::MAX,
             listeners: Default::default(),
+            paused: Default::default(),
             forwarders: Default::default(),
+            mirror_addr: None,
+            mirror_password: None,
+            mirrors: Default::default(),
+            mirror_of: HashMap::new(),
             ids: 10..,
             event_sink: EventSink::new(event_handler),
+            rewriter: None,
+            database: None,
+            shaping: Shaping::default(),
+            dialer: Dialer {
+                resolver: Arc::clone(&options.resolver),
+                marking: TrafficMarking::default(),
+            },
+            health: None,
+            timers: TimerWheel::new(Instant::now()),
+            retry_timer: None,
+            probe_timer: None,
+            accept_rate: None,
+            throttle_timer: None,
         };
 
-        proxy.add_listeners()?;
+        proxy.add_listeners(&options)?;
         Ok(proxy)
     }
 
-    fn add_listeners(&mut self) -> Result<()> {
-        let addrs = self
-            .listen_addr
-            .resolve()
-            .map_err(|e| Error::StartListening(self.listen_addr.to_string(), e))?;
-
-        if addrs.is_empty() {
-            let err = io::Error::new(ErrorKind::NotFound, "listen address not found");
-            return Err(Error::StartListening(self.listen_addr.to_string(), err));
-        }
-        for addr in addrs {
-            self.add_tcp_listener(addr)?;
+    fn add_listeners(&mut self, options: &ListenOptions) -> Result<()> {
+        let listeners = bind_listeners(&self.listen_addr, options, &mut self.event_sink, |addr| {
+            addr.listen(options)
+        })?;
+        for (addr, listener) in listeners {
+            self.add_tcp_listener(addr, listener)?;
         }
 
         let n = self.listeners.len();
@@ -133,93 +396,449 @@ impl Proxy {
         Ok(())
     }
 
-    fn add_tcp_listener(&mut self, addr: Addr) -> Result<()> {
+    fn add_tcp_listener(&mut self, addr: Addr, mut listener: MioListener) -> Result<()> {
         let n = self.listeners.len();
         let token = Token(n);
 
-        let mut listener = addr
-            .listen()
-            .map_err(|e| Error::StartListening(addr.to_string(), e))?;
-
         self.poll
             .registry()
             .register(&mut listener, token, Interest::READABLE)
             .map_err(|e| Error::StartListening(addr.to_string(), e))?;
+        debug!(%addr, ?token, "registered listener");
 
-        self.event_sink.emit_bound(addr.clone());
         self.listeners.push((addr, listener));
+        self.paused.push(false);
 
         Ok(())
     }
 
+    /// Pass all messages through the given [Rewriter] before forwarding them.
+    pub fn set_rewriter(&mut self, rewriter: Box<dyn Rewriter>) {
+        self.rewriter = Some(rewriter);
+    }
+
+    /// Route every connection to the given database, regardless of the
+    /// database the client asks for. The proxy logs in to the monetdbd at the
+    /// forward address itself and follows its redirects until it reaches the
+    /// mserver5 of the database, reporting them as [MapiEvent::Redirected].
+    /// Only then is the client involved, it logs in to that mserver5 with the
+    /// database name in its login response replaced.
+    pub fn set_database(&mut self, database: Option<String>) {
+        self.database = database;
+    }
+
+    /// Forward the data of new connections in small writes of varying size,
+    /// see [Fragment]. This disables splicing.
+    pub fn set_fragment(&mut self, fragment: Option<Fragment>) {
+        self.shaping.fragment = fragment;
+    }
+
+    /// Hold back each message the client of a new connection sends until it
+    /// is released with [Control::Step]. The proxy emits
+    /// [MapiEvent::Held] when a message starts waiting. This disables
+    /// splicing.
+    pub fn set_step(&mut self, step: bool) {
+        self.shaping.step = step;
+    }
+
+    /// Forward new connections to `standby` while the server at FORWARD_ADDR
+    /// is down. The proxy checks every few seconds whether that server sends
+    /// the start of a login challenge when connected to, and reports
+    /// switching over as [MapiEvent::PrimaryDown] and [MapiEvent::PrimaryUp].
+    /// Connections that are already running are not moved.
+    pub fn set_standby(&mut self, standby: Option<MonetAddr>) {
+        if let Some(timer) = self.probe_timer.take() {
+            self.timers.cancel(timer);
+        }
+        self.health = standby.map(Health::new);
+        if self.health.is_some() {
+            self.timers
+                .add_global(Instant::now(), Duration::ZERO, Timer::HealthCheck);
+        }
+    }
+
+    /// Send a copy of everything the client of a new connection sends to the
+    /// server at `mirror` as well, on a connection of its own. It is
+    /// announced with a [MapiEvent::Incoming] and a [MapiEvent::Mirroring]
+    /// under a new [ConnectionId]. The responses of that server are reported
+    /// and discarded. Problems with the mirror do not affect the connection
+    /// it copies. This disables splicing.
+    pub fn set_mirror(&mut self, mirror: Option<MonetAddr>) {
+        self.shaping.mirror = mirror.is_some();
+        self.mirror_addr = mirror;
+    }
+
+    /// Log in to the [mirror](Proxy::set_mirror) with `password` instead of
+    /// passing on the login of the client, which is only valid for the
+    /// challenge of the real server. The login is otherwise left as it is,
+    /// including the user name and the database.
+    pub fn set_mirror_password(&mut self, password: Option<String>) {
+        self.mirror_password = password;
+    }
+
+    /// Tag the TCP connections to the servers, including those of the health
+    /// checks and the mirror, with `marking`.
+    pub fn set_traffic_marking(&mut self, marking: TrafficMarking) {
+        self.dialer.marking = marking;
+    }
+
+    /// Accept connections no faster than `rate`. Clients connecting faster
+    /// than that are made to wait rather than refused, to protect a fragile
+    /// server from a storm of reconnects.
+    pub fn set_accept_rate(&mut self, rate: Option<AcceptRate>) {
+        self.accept_rate = rate.map(|rate| TokenBucket::new(rate, Instant::now()));
+    }
+
+    /// Whether to emit [MapiEvent::Data] events. Without them, on Linux,
+    /// connections that need no rewriting or Unix socket adjustments are
+    /// forwarded with splice(2) so the data never enters the proxy.
+    pub fn set_report_data(&mut self, report_data: bool) {
+        self.event_sink.set_report_data(report_data);
+    }
+
     /// Run the Proxy's main loop. This will block until the result of a call to [Proxy::get_shutdown_trigger]
     /// is used to trigger a shutdown.
     pub fn run(&mut self) -> Result<()> {
         let mut events = Events::with_capacity(20);
+        let mut expired = vec![];
         loop {
-            match self.poll.poll(&mut events, None) {
+            let timeout = self.timers.timeout(Instant::now());
+            match self.poll.poll(&mut events, timeout) {
                 Ok(_) => {}
                 Err(e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => return Err(Error::Poll(e)),
             }
+            trace!(nevents = events.iter().count(), "poll woke up");
+            let paused = self.paused.contains(&true);
+            // retry paused listeners on timeout or when connections close
+            let mut retry = false;
             for ev in events.iter() {
                 let token = ev.token();
-                if token == Self::TRIGGER_SHUTDOWN_TOKEN {
-                    return Ok(());
+                trace!(
+                    ?token,
+                    readable = ev.is_readable(),
+                    writable = ev.is_writable(),
+                    "event"
+                );
+                if token == Self::WAKER_TOKEN {
+                    while let Ok(control) = self.controls.try_recv() {
+                        debug!(?control, "control request");
+                        match control {
+                            Control::Shutdown => return Ok(()),
+                            Control::OutOfBand { id, byte, reply } => {
+                                let result = self.send_out_of_band(id, byte);
+                                if let Some(reply) = reply {
+                                    let _ = reply.send(result);
+                                }
+                            }
+                            Control::Step { id, reply } => {
+                                let result = self.step(id);
+                                if let Some(reply) = reply {
+                                    let _ = reply.send(result);
+                                }
+                            }
+                            Control::Note {
+                                id,
+                                direction,
+                                note,
+                                reply,
+                            } => {
+                                let result = self.add_note(id, direction, note);
+                                if let Some(reply) = reply {
+                                    let _ = reply.send(result);
+                                }
+                            }
+                            Control::SetLevel { id, level, reply } => {
+                                let result = self.set_level(id, level);
+                                if let Some(reply) = reply {
+                                    let _ = reply.send(result);
+                                }
+                            }
+                        }
+                    }
+                } else if token == Self::PROBE_TOKEN {
+                    self.handle_probe_event();
+                } else if token.0 >= Self::MIRROR_TOKEN_BASE {
+                    self.handle_mirror_event(token.0 - Self::MIRROR_TOKEN_BASE);
                 } else if token.0 < self.token_base {
                     self.handle_listener_event(token.0)?;
                 } else {
-                    self.handle_forward_event(ev, (token.0 - self.token_base) / 2);
+                    retry |= self.handle_forward_event((token.0 - self.token_base) / 2);
                 }
             }
+            self.timers.expire(Instant::now(), &mut expired);
+            for Expired { conn, what } in expired.drain(..) {
+                trace!(?conn, ?what, "timer went off");
+                match what {
+                    Timer::RetryAccept => {
+                        self.retry_timer = None;
+                        retry = true;
+                    }
+                    Timer::AcceptThrottled => {
+                        self.throttle_timer = None;
+                        for n in 0..self.listeners.len() {
+                            if !self.paused[n] {
+                                self.handle_listener_event(n)?;
+                            }
+                        }
+                    }
+                    Timer::HealthCheck => self.start_health_check(),
+                    Timer::Resume => {
+                        let Some(n) = conn else { continue };
+                        if let Some(forwarder) = self.forwarders.get_mut(n) {
+                            forwarder.resume();
+                            retry |= self.handle_forward_event(n);
+                        }
+                    }
+                    Timer::ProbeTimeout => {
+                        self.probe_timer = None;
+                        if let Some(health) = &mut self.health {
+                            let registry = self.poll.registry();
+                            health.timed_out(&self.forward_addr, registry, &mut self.event_sink);
+                        }
+                        self.schedule_health_check();
+                    }
+                }
+            }
+            if paused && retry {
+                self.retry_paused()?;
+            }
         }
     }
 
     /// Obtain a shutdown trigger that when called, will end the main loop of [Proxy::run].
     pub fn get_shutdown_trigger(&mut self) -> Box<dyn Fn() + Send + Sync + 'static> {
+        let controller = self.get_controller();
+        Box::new(move || controller(Control::Shutdown))
+    }
+
+    /// Obtain a function that passes [Control] requests to the main loop of
+    /// [Proxy::run].
+    pub fn get_controller(&mut self) -> Box<dyn Fn(Control) + Send + Sync + 'static> {
         let waker = Arc::clone(&self.waker);
-        Box::new(move || {
+        let sender = self.controls_tx.clone();
+        Box::new(move |control| {
+            // the receiver only goes away when the proxy is gone
+            let _ = sender.send(control);
             if let Err(e) = waker.wake() {
-                eprintln!("Failed to shut down the proxy: {e}");
+                eprintln!("Failed to wake up the proxy: {e}");
             }
         })
     }
 
+    fn step(&mut self, id: Option<ConnectionId>) -> io::Result<()> {
+        let found = match id {
+            Some(id) => self.forwarders.iter().find(|(_, f)| f.id() == id),
+            None => self
+                .forwarders
+                .iter()
+                .filter(|(_, f)| f.held_since().is_some())
+                .min_by_key(|(_, f)| f.held_since()),
+        };
+        let Some((n, _)) = found else {
+            let msg = match id {
+                Some(_) => "no such connection",
+                None => "no message is being held",
+            };
+            return Err(io::Error::new(ErrorKind::NotFound, msg));
+        };
+        if !self.forwarders[n].release() {
+            let msg = "no message is being held on this connection";
+            return Err(io::Error::new(ErrorKind::NotFound, msg));
+        }
+        self.handle_forward_event(n);
+        Ok(())
+    }
+
+    /// Connections that do not exist (anymore) get no [MapiEvent::OutOfBand].
+    fn send_out_of_band(&mut self, id: ConnectionId, byte: u8) -> io::Result<()> {
+        let Some((_, forwarder)) = self.forwarders.iter().find(|(_, f)| f.id() == id) else {
+            return Err(io::Error::new(ErrorKind::NotFound, "no such connection"));
+        };
+        let result = forwarder.send_out_of_band(byte);
+        let error = result
+            .as_ref()
+            .err()
+            .map(|e| io::Error::new(e.kind(), e.to_string()));
+        let mut sink = self.event_sink.connection_sink(id);
+        sink.emit_out_of_band(byte, error);
+        result
+    }
+
+    fn add_note(
+        &mut self,
+        id: ConnectionId,
+        direction: Option<Direction>,
+        note: String,
+    ) -> io::Result<()> {
+        if !self.forwarders.iter().any(|(_, f)| f.id() == id) {
+            return Err(io::Error::new(ErrorKind::NotFound, "no such connection"));
+        }
+        let mut sink = self.event_sink.connection_sink(id);
+        sink.emit_note(direction, note);
+        Ok(())
+    }
+
+    fn set_level(&mut self, id: ConnectionId, level: Level) -> io::Result<()> {
+        if !self.forwarders.iter().any(|(_, f)| f.id() == id) {
+            return Err(io::Error::new(ErrorKind::NotFound, "no such connection"));
+        }
+        let mut sink = self.event_sink.connection_sink(id);
+        sink.emit_level(level);
+        Ok(())
+    }
+
     fn handle_listener_event(&mut self, n: usize) -> Result<()> {
         // When mio notifies us of readiness may only re-enter mio when we
         // have observed an EWOULDBLOCK. Hence the loop.
         loop {
-            let (local, listener) = &self.listeners[n];
+            if let Some(bucket) = &mut self.accept_rate {
+                let now = Instant::now();
+                if let Some(wait) = bucket.wait(now) {
+                    // the connection stays in the backlog until the timer
+                    // goes off, mio will not tell us about it again
+                    if self.throttle_timer.is_none() {
+                        trace!(?wait, "accept rate reached, waiting");
+                        let timer = self.timers.add_global(now, wait, Timer::AcceptThrottled);
+                        self.throttle_timer = Some(timer);
+                    }
+                    return Ok(());
+                }
+            }
+            let (local, listener) = &mut self.listeners[n];
             let (conn, peer) = match listener.accept() {
                 Ok(x) => x,
-                Err(e) if would_block(&e) => return Ok(()),
+                Err(e) if would_block(&e) => {
+                    self.resume(n);
+                    return Ok(());
+                }
+                Err(e) if is_out_of_fds(&e) => {
+                    debug!(%local, "out of file descriptors, pausing listener");
+                    let _ = self.poll.registry().deregister(listener);
+                    if !self.paused[n] {
+                        self.paused[n] = true;
+                        self.event_sink.emit_accept_paused(local.clone(), e);
+                    }
+                    if self.retry_timer.is_none() {
+                        let delay = Self::RETRY_ACCEPT_INTERVAL;
+                        let timer =
+                            self.timers
+                                .add_global(Instant::now(), delay, Timer::RetryAccept);
+                        self.retry_timer = Some(timer);
+                    }
+                    return Ok(());
+                }
                 Err(e) => {
+                    debug!(%local, error = %e, "accept failed");
                     return Err(Error::Accept(local.clone(), e));
                 }
             };
 
+            let local = local.clone();
+            self.resume(n);
+            if let Some(bucket) = &mut self.accept_rate {
+                bucket.take();
+            }
             let id = ConnectionId::new(self.ids.next().unwrap());
             self.event_sink
                 .connection_sink(id)
                 .emit_incoming(local.clone(), peer.clone());
-            self.start_forwarder(id, peer, conn);
+            self.start_forwarder(id, local, peer, conn);
+        }
+    }
+
+    /// Register the paused listeners again and see if they can accept now.
+    fn retry_paused(&mut self) -> Result<()> {
+        for n in 0..self.listeners.len() {
+            if !self.paused[n] {
+                continue;
+            }
+            let (local, listener) = &mut self.listeners[n];
+            self.poll
+                .registry()
+                .register(listener, Token(n), Interest::READABLE)
+                .map_err(|e| Error::Accept(local.clone(), e))?;
+            self.handle_listener_event(n)?;
+        }
+        Ok(())
+    }
+
+    /// The listener no longer runs out of file descriptors.
+    fn resume(&mut self, n: usize) {
+        if self.paused[n] {
+            self.paused[n] = false;
+            let local = self.listeners[n].0.clone();
+            debug!(%local, "resuming listener");
+            self.event_sink.emit_accept_resumed(local);
+        }
+    }
+
+    fn start_health_check(&mut self) {
+        let Some(health) = &mut self.health else {
+            return;
+        };
+        let registry = self.poll.registry();
+        let sink = &mut self.event_sink;
+        let dialer = &self.dialer;
+        if health.start(
+            &self.forward_addr,
+            dialer,
+            registry,
+            Self::PROBE_TOKEN,
+            sink,
+        ) {
+            let now = Instant::now();
+            let timer = self
+                .timers
+                .add_global(now, Health::TIMEOUT, Timer::ProbeTimeout);
+            self.probe_timer = Some(timer);
+        } else {
+            self.schedule_health_check();
+        }
+    }
+
+    fn handle_probe_event(&mut self) {
+        let Some(health) = &mut self.health else {
+            return;
+        };
+        let registry = self.poll.registry();
+        let sink = &mut self.event_sink;
+        if health.ready(&self.forward_addr, registry, Self::PROBE_TOKEN, sink) {
+            if let Some(timer) = self.probe_timer.take() {
+                self.timers.cancel(timer);
+            }
+            self.schedule_health_check();
         }
     }
 
-    fn start_forwarder(&mut self, id: ConnectionId, peer: Addr, conn: MioStream) {
+    fn schedule_health_check(&mut self) {
+        self.timers
+            .add_global(Instant::now(), Health::INTERVAL, Timer::HealthCheck);
+    }
+
+    fn start_forwarder(&mut self, id: ConnectionId, local: Addr, peer: Addr, conn: MioStream) {
         let mut sink = self.event_sink.connection_sink(id);
         let entry = self.forwarders.vacant_entry();
         let n = entry.key();
         let client_token = self.token_base + 2 * n;
         let server_token = self.token_base + 2 * n + 1;
+        debug!(%id, slot = n, client_token, server_token, "starting forwarder");
+        let forward_addr = match &self.health {
+            Some(health) => health.target(&self.forward_addr),
+            None => &self.forward_addr,
+        };
+        let unix_client = peer.is_unix();
         let new = Forwarder::new(
             self.poll.registry(),
             &mut sink,
             conn,
-            peer,
+            peer.clone(),
             Token(client_token),
-            &self.forward_addr,
+            forward_addr,
             Token(server_token),
+            self.database.as_deref(),
+            self.shaping,
+            self.dialer.clone(),
         );
         match new {
             Ok(forwarder) => {
@@ -227,15 +846,96 @@ impl Proxy {
             }
             Err(e) => {
                 sink.emit_aborted(e);
+                return;
             }
         }
+        if self.mirror_addr.is_some() {
+            self.start_mirror(n, id, local, peer, unix_client);
+        }
     }
 
-    fn handle_forward_event(&mut self, ev: &Event, n: usize) {
+    /// Start copying the data of forwarder `n` to the [Proxy::mirror_addr].
+    fn start_mirror(&mut self, n: usize, of: ConnectionId, local: Addr, peer: Addr, unix: bool) {
+        let Some(mirror_addr) = &self.mirror_addr else {
+            return;
+        };
+        let id = ConnectionId::new(self.ids.next().unwrap());
+        let mut sink = self.event_sink.connection_sink(id);
+        sink.emit_incoming(local, peer);
+        sink.emit_mirroring(of);
+        let entry = self.mirrors.vacant_entry();
+        let m = entry.key();
+        let token = Token(Self::MIRROR_TOKEN_BASE + m);
+        debug!(%id, %of, slot = m, ?token, "starting mirror");
+        let password = self.mirror_password.as_deref();
         let registry = self.poll.registry();
-        let Some(forwarder) = self.forwarders.get_mut(n) else {
+        let dialer = &self.dialer;
+        match Mirror::start(
+            &mut sink,
+            mirror_addr,
+            dialer,
+            password,
+            token,
+            registry,
+            unix,
+        ) {
+            Ok(mirror) => {
+                entry.insert(mirror);
+                self.mirror_of.insert(n, m);
+            }
+            Err(e) => sink.emit_aborted(e),
+        }
+    }
+
+    /// Pass the data the client of forwarder `n` has sent to its mirror, if
+    /// it has one. `done` means the client will send no more.
+    fn feed_mirror(&mut self, n: usize, data: &[u8], done: bool) {
+        let Some(&m) = self.mirror_of.get(&n) else {
+            return;
+        };
+        if done {
+            self.mirror_of.remove(&n);
+        }
+        if data.is_empty() && !done {
+            return;
+        }
+        let mirror = &mut self.mirrors[m];
+        let mut sink = self.event_sink.connection_sink(mirror.id());
+        if let Err(e) = mirror.feed(data, done, &mut sink) {
+            sink.emit_aborted(e);
+            self.remove_mirror(m);
+            return;
+        }
+        self.handle_mirror_event(m);
+    }
+
+    fn handle_mirror_event(&mut self, m: usize) {
+        let registry = self.poll.registry();
+        let Some(mirror) = self.mirrors.get_mut(m) else {
             return;
         };
+        let mut sink = self.event_sink.connection_sink(mirror.id());
+        match mirror.process(&mut sink, registry) {
+            Ok(ControlFlow::Continue(_)) => return,
+            Err(e) => sink.emit_aborted(e),
+            Ok(ControlFlow::Break(_)) => sink.emit_end(),
+        }
+        self.remove_mirror(m);
+    }
+
+    fn remove_mirror(&mut self, m: usize) {
+        let mut mirror = self.mirrors.remove(m);
+        debug!(id = %mirror.id(), slot = m, "removing mirror");
+        mirror.deregister(self.poll.registry());
+        self.mirror_of.retain(|_, slot| *slot != m);
+    }
+
+    /// Returns true if the connection has been closed.
+    fn handle_forward_event(&mut self, n: usize) -> bool {
+        let registry = self.poll.registry();
+        let Some(forwarder) = self.forwarders.get_mut(n) else {
+            return false;
+        };
         let id = forwarder.id();
         let mut sink = self.event_sink.connection_sink(id);
 
@@ -244,27 +944,40 @@ impl Proxy {
         // we don't have a loop right here because `Forwarder::handle_event`
         // does the looping. It returns a `ControlFlow` to indicate whether
         // this connection needs to stay around or whether it can be removed.
-        match forwarder.handle_event(&mut sink, registry, ev) {
+        let result = forwarder.handle_event(&mut sink, registry, &mut self.rewriter);
+        let (mirrored, client_done) = forwarder.take_mirrored();
+        let closed = match result {
             Ok(ControlFlow::Continue(_)) => {
-                // return instead of removing it
-                return;
+                if forwarder.take_resume_wanted() {
+                    let (now, pause) = (Instant::now(), Self::FRAGMENT_PAUSE);
+                    self.timers.add_for_conn(now, pause, n, Timer::Resume);
+                }
+                false
             }
             Err(e) => {
                 sink.emit_aborted(e);
-                // fall through to removal
+                true
             }
             Ok(ControlFlow::Break(_)) => {
                 sink.emit_end();
-                // fall through to removal
+                true
             }
+        };
+        self.feed_mirror(n, &mirrored, client_done || closed);
+        if !closed {
+            return false;
         }
 
         // Removal
-        forwarder.deregister(registry);
+        debug!(%id, slot = n, "removing forwarder");
+        self.forwarders[n].deregister(self.poll.registry());
         self.forwarders.remove(n);
+        self.timers.cancel_conn(n);
+        true
     }
 }
 
+#[cfg(feature = "proxy")]
 fn would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
 }
@@ -0,0 +1,184 @@
+//! Changing what client and server agree on in the handshake, to see how they
+//! cope with an unusual outcome, see [Negotiation].
+//!
+//! The challenge and the login are recognized by their shape rather than by
+//! their position in the connection, so the handshakes that follow a redirect
+//! by monetdbd are changed as well.
+
+use std::io;
+
+use super::{
+    event::{ConnectionId, Direction},
+    rewrite::Rewriter,
+};
+
+/// The changes to make to the handshakes passing through the proxy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Negotiation {
+    /// Hash algorithms to remove from the list the server offers in its
+    /// challenge, such as SHA1 or PROT10
+    pub strip_hashes: Vec<String>,
+    /// Reply size to put in the handshake options of the login and in
+    /// `Xreply_size` commands
+    pub reply_size: Option<i64>,
+    /// Language to put in the login, such as sql or mal
+    pub language: Option<String>,
+}
+
+impl Negotiation {
+    /// Whether there is anything to change.
+    pub fn is_empty(&self) -> bool {
+        self == &Negotiation::default()
+    }
+
+    /// The challenge with the hash algorithms removed, or None if it does
+    /// not need to change.
+    fn rewrite_challenge(&self, message: &[u8]) -> Option<Vec<u8>> {
+        if self.strip_hashes.is_empty() {
+            return None;
+        }
+        // salt:mserver:9:hashes:endian:pwhash:...
+        let text = std::str::from_utf8(message).ok()?;
+        let mut fields: Vec<&str> = text.split(':').collect();
+        let is_challenge = fields.len() >= 6
+            && fields[2].parse::<u32>().is_ok()
+            && !text.trim_end().contains('\n');
+        if !is_challenge {
+            return None;
+        }
+        let offered = fields[3];
+        let kept: Vec<&str> = offered
+            .split(',')
+            .filter(|h| !self.strip_hashes.iter().any(|s| s.eq_ignore_ascii_case(h)))
+            .collect();
+        let kept = kept.join(",");
+        if kept == offered {
+            return None;
+        }
+        fields[3] = &kept;
+        Some(fields.join(":").into_bytes())
+    }
+
+    /// The login with the language and reply size replaced, or None if it
+    /// does not need to change.
+    fn rewrite_login(&self, message: &[u8]) -> Option<Vec<u8>> {
+        // byteorder:user:{algo}password:lang:database:...
+        let text = std::str::from_utf8(message).ok()?;
+        let mut fields: Vec<String> = text.split(':').map(str::to_string).collect();
+        let is_login = fields.len() >= 5
+            && matches!(fields[0].as_str(), "BIG" | "LIT")
+            && fields[2].starts_with('{');
+        if !is_login {
+            return None;
+        }
+        if let Some(language) = &self.language {
+            fields[3] = language.clone();
+        }
+        if let Some(n) = self.reply_size {
+            // handshake options such as auto_commit=1,reply_size=100
+            for field in &mut fields[5..] {
+                let options: Vec<String> = field
+                    .split(',')
+                    .map(|opt| match opt.strip_prefix("reply_size=") {
+                        Some(_) => format!("reply_size={n}"),
+                        None => opt.to_string(),
+                    })
+                    .collect();
+                *field = options.join(",");
+            }
+        }
+        let rewritten = fields.join(":");
+        (rewritten != text).then(|| rewritten.into_bytes())
+    }
+
+    /// An `Xreply_size N` command with the reply size replaced, or None if
+    /// the message is something else or does not need to change.
+    fn rewrite_reply_size(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let n = self.reply_size?;
+        let rest = message.strip_prefix(b"Xreply_size ")?;
+        let sign = usize::from(rest.starts_with(b"-"));
+        let digits = sign
+            + rest[sign..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+        let mut rewritten = format!("Xreply_size {n}").into_bytes();
+        rewritten.extend_from_slice(&rest[digits..]);
+        (rewritten != message).then_some(rewritten)
+    }
+}
+
+/// A [Rewriter] that makes the changes of a [Negotiation], and then passes
+/// the messages on to another Rewriter, if any.
+pub struct HandshakeRewriter {
+    negotiation: Negotiation,
+    next: Option<Box<dyn Rewriter>>,
+}
+
+impl HandshakeRewriter {
+    pub fn new(negotiation: Negotiation, next: Option<Box<dyn Rewriter>>) -> Self {
+        HandshakeRewriter { negotiation, next }
+    }
+}
+
+impl Rewriter for HandshakeRewriter {
+    fn rewrite(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        message: &[u8],
+    ) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let negotiation = &self.negotiation;
+        let changed = match direction {
+            Direction::Downstream => negotiation.rewrite_challenge(message),
+            Direction::Upstream => negotiation
+                .rewrite_login(message)
+                .or_else(|| negotiation.rewrite_reply_size(message)),
+        };
+        let Some(next) = &mut self.next else {
+            return Ok(changed.map(|m| vec![m]));
+        };
+        let input = changed.as_deref().unwrap_or(message);
+        let rewritten = next.rewrite(id, direction, input)?;
+        Ok(rewritten.or(changed.map(|m| vec![m])))
+    }
+}
+
+#[test]
+fn test_negotiation() {
+    let negotiation = Negotiation {
+        strip_hashes: vec!["sha512".to_string(), "PROT10".to_string()],
+        reply_size: Some(7),
+        language: Some("mal".to_string()),
+    };
+
+    let challenge = b"salt:mserver:9:RIPEMD160,SHA512,PROT10:LIT:SHA512:";
+    assert_eq!(
+        negotiation.rewrite_challenge(challenge).unwrap(),
+        b"salt:mserver:9:RIPEMD160:LIT:SHA512:"
+    );
+    assert_eq!(
+        negotiation.rewrite_challenge(b"&1 0 1 1 1\n% a:b:c:d:e:f\n"),
+        None
+    );
+
+    let login = b"LIT:monetdb:{SHA512}abc:sql:demo:FILETRANS:auto_commit=1,reply_size=100:\n";
+    assert_eq!(
+        negotiation.rewrite_login(login).unwrap(),
+        b"LIT:monetdb:{SHA512}abc:mal:demo:FILETRANS:auto_commit=1,reply_size=7:\n"
+    );
+    assert_eq!(negotiation.rewrite_login(b"sselect 1;\n"), None);
+
+    assert_eq!(
+        negotiation
+            .rewrite_reply_size(b"Xreply_size 100\n")
+            .unwrap(),
+        b"Xreply_size 7\n"
+    );
+    assert_eq!(negotiation.rewrite_reply_size(b"Xreply_size 7\n"), None);
+    assert_eq!(
+        negotiation.rewrite_reply_size(b"Xreply_size -1").unwrap(),
+        b"Xreply_size 7"
+    );
+    assert_eq!(negotiation.rewrite_reply_size(b"Xauto_commit 1\n"), None);
+}
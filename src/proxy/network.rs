@@ -2,20 +2,132 @@ use std::{
     ffi::{OsStr, OsString},
     fmt::Display,
     io::{self, ErrorKind},
-    net::{self, IpAddr, SocketAddr as TcpSocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr as TcpSocketAddr, SocketAddrV6, ToSocketAddrs},
     path::PathBuf,
+    sync::Arc,
 };
+#[cfg(feature = "proxy")]
+use std::{net, time::Duration};
 
 // These are only used by Unix Domain socket code
-#[cfg(unix)]
-use std::{fs, path::Path};
+#[cfg(all(unix, feature = "proxy"))]
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::Path,
+    sync::Mutex,
+};
 
 use lazy_regex::{regex_captures, regex_is_match};
-#[cfg(unix)]
+#[cfg(all(unix, feature = "proxy"))]
 use mio::net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream};
+#[cfg(feature = "proxy")]
 use mio::net::{TcpListener, TcpStream};
 
-#[cfg(not(unix))]
+/// The listen backlog used unless configured otherwise. This is the value
+/// mio's `TcpListener::bind` used before the backlog became configurable.
+pub const DEFAULT_BACKLOG: i32 = 1024;
+
+/// How the proxy sets up its listen sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenOptions {
+    /// The listen backlog, see [DEFAULT_BACKLOG]
+    pub backlog: i32,
+    /// Fail if any of the addresses LISTEN_ADDR resolves to cannot be bound.
+    /// Otherwise only fail if none of them can.
+    pub require_all_binds: bool,
+    /// Replace Unix Domain sockets that are still in use, see [bind_unix].
+    pub force_bind: bool,
+    /// How to resolve LISTEN_ADDR. The proxy resolves the servers it
+    /// connects to the same way.
+    pub resolver: Arc<Resolver>,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        ListenOptions {
+            backlog: DEFAULT_BACKLOG,
+            require_all_binds: false,
+            force_bind: false,
+            resolver: Arc::default(),
+        }
+    }
+}
+
+/// The directory in which a bare port number puts its Unix Domain socket,
+/// unless configured otherwise, see [Resolver::unix_socket_dir].
+pub const DEFAULT_UNIX_SOCKET_DIR: &str = "/tmp";
+
+/// The name of the Unix Domain socket of a bare port number, with `{port}`
+/// replaced by the port, unless configured otherwise. This is the convention
+/// MonetDB uses.
+pub const DEFAULT_UNIX_SOCKET_TEMPLATE: &str = ".s.monetdb.{port}";
+
+/// Addresses to use for a host name instead of looking it up, as with
+/// `--resolve=NAME:PORT:ADDR`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveOverride {
+    pub host: String,
+    /// None to apply to every port
+    pub port: Option<u16>,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// How [MonetAddr::resolve_with] turns host names and bare port numbers
+/// into addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolver {
+    /// Use these addresses for these host names instead of asking DNS. The
+    /// first override that matches wins.
+    pub overrides: Vec<ResolveOverride>,
+    /// Where to look for the Unix Domain socket of a bare port number, for
+    /// servers with a different `unix_socket_dir` setting
+    pub unix_socket_dir: PathBuf,
+    /// The name of that socket in [Resolver::unix_socket_dir], with `{port}`
+    /// replaced by the port
+    pub unix_socket_template: String,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver {
+            overrides: vec![],
+            unix_socket_dir: DEFAULT_UNIX_SOCKET_DIR.into(),
+            unix_socket_template: DEFAULT_UNIX_SOCKET_TEMPLATE.into(),
+        }
+    }
+}
+
+impl Resolver {
+    fn lookup_override(&self, host: &str, port: u16) -> Option<Vec<IpAddr>> {
+        let found = self
+            .overrides
+            .iter()
+            .find(|o| o.host.eq_ignore_ascii_case(host) && o.port.is_none_or(|p| p == port))?;
+        Some(found.addrs.clone())
+    }
+
+    /// The path of the Unix Domain socket that belongs to the given port.
+    pub fn unix_socket_path(&self, port: u16) -> PathBuf {
+        let name = self
+            .unix_socket_template
+            .replace("{port}", &port.to_string());
+        self.unix_socket_dir.join(name)
+    }
+}
+
+/// How to tag the TCP connections to the server so existing firewall and
+/// traffic control rules can pick them out, as with `--tos` and `--so-mark`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficMarking {
+    /// The TOS byte of IPv4 packets or the traffic class of IPv6 packets
+    pub tos: Option<u8>,
+    /// The SO_MARK of the socket, Linux only
+    pub mark: Option<u32>,
+}
+
+#[cfg(all(not(unix), feature = "proxy"))]
 fn unix_not_supported() -> io::Error {
     io::Error::new(
         ErrorKind::Unsupported,
@@ -25,10 +137,27 @@ fn unix_not_supported() -> io::Error {
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum MonetAddr {
-    Dns { host: String, port: u16 },
-    Ip { ip: IpAddr, port: u16 },
+    Dns {
+        host: String,
+        port: u16,
+    },
+    Ip {
+        ip: IpAddr,
+        port: u16,
+    },
+    /// An IPv6 address with a zone id, such as `[fe80::1%eth0]:50000`. The
+    /// zone is kept as given, `scope_id` is the interface index it names.
+    IpZone {
+        ip: Ipv6Addr,
+        zone: String,
+        scope_id: u32,
+        port: u16,
+    },
     Unix(PathBuf),
     PortOnly(u16),
+    /// `*:PORT`, like [MonetAddr::PortOnly] but listening on all network
+    /// interfaces rather than only on localhost
+    AllInterfaces(u16),
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +166,7 @@ pub enum Addr {
     Unix(PathBuf),
 }
 
+#[cfg(feature = "proxy")]
 #[derive(Debug)]
 pub enum MioListener {
     Tcp(TcpListener),
@@ -44,6 +174,7 @@ pub enum MioListener {
     Unix(UnixListener),
 }
 
+#[cfg(feature = "proxy")]
 #[derive(Debug)]
 pub enum MioStream {
     Tcp(TcpStream),
@@ -64,8 +195,10 @@ impl Display for MonetAddr {
                 ip: IpAddr::V6(ip6),
                 port,
             } => write!(f, "[{ip6}]:{port}"),
+            MonetAddr::IpZone { ip, zone, port, .. } => write!(f, "[{ip}%{zone}]:{port}"),
             MonetAddr::Unix(path) => path.display().fmt(f),
             MonetAddr::PortOnly(n) => n.fmt(f),
+            MonetAddr::AllInterfaces(n) => write!(f, "*:{n}"),
         }
     }
 }
@@ -91,6 +224,10 @@ impl TryFrom<&OsStr> for MonetAddr {
                 return Some(MonetAddr::PortOnly(port));
             }
 
+            if let Some(port) = str_value.strip_prefix("*:") {
+                return Some(MonetAddr::AllInterfaces(port.parse().ok()?));
+            }
+
             // it must end in :PORTNUMBER
             let (_, host_part, port_part) = regex_captures!(r"^(.+):(\d+)$", str_value)?;
             let port: u16 = port_part.parse().ok()?;
@@ -102,12 +239,16 @@ impl TryFrom<&OsStr> for MonetAddr {
                     ip: IpAddr::V4(host_part.parse().ok()?),
                     port,
                 })
-            } else if let Some((_, ip)) = regex_captures!(r"^\[([0-9a-f:]+)\]$"i, host_part) {
+            } else if let Some((_, ip)) = regex_captures!(r"^\[(.+)\]$", host_part) {
                 // IPv6
-                Some(MonetAddr::Ip {
-                    ip: IpAddr::V6(ip.parse().ok()?),
-                    port,
-                })
+                parse_ipv6(ip, port)
+            } else if host_part.contains(':') {
+                // IPv6 without brackets, unless the port could also be the
+                // last group of the address, as in 2001:db8::1:5000
+                if str_value.parse::<Ipv6Addr>().is_ok() {
+                    return None;
+                }
+                parse_ipv6(host_part, port)
             } else if regex_is_match!(r"^[a-z0-9][-a-z0-9.]*$"i, host_part) {
                 // names consisting of letters, digits and hyphens, separated or terminated by periods
                 Some(MonetAddr::Dns {
@@ -119,15 +260,49 @@ impl TryFrom<&OsStr> for MonetAddr {
             }
         }
 
+        /// An IPv6 address with an optional zone id
+        fn parse_ipv6(text: &str, port: u16) -> Option<MonetAddr> {
+            let Some((ip, zone)) = text.split_once('%') else {
+                let ip = IpAddr::V6(text.parse().ok()?);
+                return Some(MonetAddr::Ip { ip, port });
+            };
+            Some(MonetAddr::IpZone {
+                ip: ip.parse().ok()?,
+                zone: zone.to_string(),
+                scope_id: scope_id(zone)?,
+                port,
+            })
+        }
+
         if let Some(monetaddr) = parse(os_value) {
-            Ok(monetaddr)
+            return Ok(monetaddr);
+        }
+        let value = os_value.to_string_lossy();
+        let msg = if value.parse::<Ipv6Addr>().is_ok() {
+            format!("invalid address: {value}: put IPv6 addresses in brackets, as in [::1]:50000")
         } else {
-            Err(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("invalid address: {}", os_value.to_string_lossy()),
-            ))
+            format!("invalid address: {value}")
+        };
+        Err(io::Error::new(ErrorKind::InvalidInput, msg))
+    }
+}
+
+/// The interface index a zone id refers to. Zones can be given as a number
+/// or, on Unix, as the name of a network interface.
+fn scope_id(zone: &str) -> Option<u32> {
+    if let Ok(n) = zone.parse() {
+        return Some(n);
+    }
+    #[cfg(all(unix, feature = "proxy"))]
+    {
+        let name = std::ffi::CString::new(zone).ok()?;
+        // SAFETY: if_nametoindex only reads the string we pass it
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index > 0 {
+            return Some(index);
         }
     }
+    None
 }
 
 impl TryFrom<OsString> for MonetAddr {
@@ -139,32 +314,56 @@ impl TryFrom<OsString> for MonetAddr {
 }
 
 impl MonetAddr {
+    /// Like [MonetAddr::resolve_with] with the default [Resolver].
     pub fn resolve(&self) -> io::Result<Vec<Addr>> {
-        let mut addrs = self.resolve_unix()?;
-        let tcp_addrs = self.resolve_tcp()?;
+        self.resolve_with(&Resolver::default())
+    }
+
+    pub fn resolve_with(&self, resolver: &Resolver) -> io::Result<Vec<Addr>> {
+        let mut addrs = self.resolve_unix(resolver)?;
+        let tcp_addrs = self.resolve_tcp(resolver)?;
         addrs.extend(tcp_addrs);
         Ok(addrs)
     }
 
-    pub fn resolve_tcp(&self) -> io::Result<Vec<Addr>> {
+    pub fn resolve_tcp(&self, resolver: &Resolver) -> io::Result<Vec<Addr>> {
         fn gather<T: ToSocketAddrs>(a: T) -> io::Result<Vec<Addr>> {
             Ok(a.to_socket_addrs()?.map(Addr::Tcp).collect())
         }
 
         match self {
             MonetAddr::Unix(_) => Ok(vec![]),
-            MonetAddr::Dns { host, port } => gather((host.as_str(), *port)),
+            MonetAddr::Dns { host, port } => match resolver.lookup_override(host, *port) {
+                Some(ips) => Ok(ips
+                    .into_iter()
+                    .map(|ip| Addr::Tcp((ip, *port).into()))
+                    .collect()),
+                None => gather((host.as_str(), *port)),
+            },
             MonetAddr::Ip { ip, port } => gather((*ip, *port)),
+            MonetAddr::IpZone {
+                ip, scope_id, port, ..
+            } => Ok(vec![Addr::Tcp(
+                SocketAddrV6::new(*ip, *port, 0, *scope_id).into(),
+            )]),
             MonetAddr::PortOnly(port) => gather(("localhost", *port)),
+            MonetAddr::AllInterfaces(port) => Ok(vec![
+                Addr::Tcp((Ipv4Addr::UNSPECIFIED, *port).into()),
+                Addr::Tcp((Ipv6Addr::UNSPECIFIED, *port).into()),
+            ]),
         }
     }
 
-    pub fn resolve_unix(&self) -> io::Result<Vec<Addr>> {
+    pub fn resolve_unix(&self, resolver: &Resolver) -> io::Result<Vec<Addr>> {
         if cfg!(unix) {
             let path = match self {
-                MonetAddr::Dns { .. } | MonetAddr::Ip { .. } => return Ok(vec![]),
+                MonetAddr::Dns { .. } | MonetAddr::Ip { .. } | MonetAddr::IpZone { .. } => {
+                    return Ok(vec![])
+                }
                 MonetAddr::Unix(p) => p.clone(),
-                MonetAddr::PortOnly(port) => PathBuf::from(format!("/tmp/.s.monetdb.{port}")),
+                MonetAddr::PortOnly(port) | MonetAddr::AllInterfaces(port) => {
+                    resolver.unix_socket_path(*port)
+                }
             };
             Ok(vec![Addr::Unix(path)])
         } else {
@@ -191,20 +390,15 @@ impl Addr {
         !self.is_tcp()
     }
 
-    pub fn listen(&self) -> io::Result<MioListener> {
+    #[cfg(feature = "proxy")]
+    pub fn listen(&self, options: &ListenOptions) -> io::Result<MioListener> {
+        let backlog = options.backlog;
         let listener = match self {
-            Addr::Tcp(a) => MioListener::Tcp(TcpListener::bind(*a)?),
+            Addr::Tcp(a) => MioListener::Tcp(TcpListener::from_std(bind_tcp(a, backlog)?)),
             #[cfg(unix)]
             Addr::Unix(a) => {
-                let listener = match UnixListener::bind(a) {
-                    Ok(lis) => lis,
-                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
-                        fs::remove_file(a)?;
-                        UnixListener::bind(a)?
-                    }
-                    Err(other) => return Err(other),
-                };
-                MioListener::Unix(listener)
+                let lis = bind_unix(a, options.force_bind, |p| bind_unix_std(p, backlog))?;
+                MioListener::Unix(UnixListener::from_std(lis))
             }
             #[cfg(not(unix))]
             Addr::Unix(_) => return Err(unix_not_supported()),
@@ -212,9 +406,12 @@ impl Addr {
         Ok(listener)
     }
 
-    pub fn connect(&self) -> io::Result<MioStream> {
+    /// Start connecting to this address. TCP connections are tagged with
+    /// `marking`.
+    #[cfg(feature = "proxy")]
+    pub fn connect(&self, marking: TrafficMarking) -> io::Result<MioStream> {
         let conn = match self {
-            Addr::Tcp(a) => MioStream::Tcp(TcpStream::connect(*a)?),
+            Addr::Tcp(a) => MioStream::Tcp(TcpStream::from_std(connect_tcp(a, marking)?)),
             #[cfg(unix)]
             Addr::Unix(a) => MioStream::Unix(UnixStream::connect(a)?),
             #[cfg(not(unix))]
@@ -222,6 +419,273 @@ impl Addr {
         };
         Ok(conn)
     }
+
+    /// Check whether we would be able to listen on this address. Unlike
+    /// [Addr::listen], this leaves alone Unix Domain sockets that another
+    /// process is still listening on.
+    #[cfg(feature = "proxy")]
+    pub fn check_listen(&self) -> io::Result<()> {
+        match self {
+            Addr::Tcp(a) => net::TcpListener::bind(a).map(drop),
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                use std::os::unix::net::{UnixListener, UnixStream};
+                let locked = lock_file_path(path).exists()
+                    && lock_socket_file(path).is_err_and(|e| e.kind() == ErrorKind::WouldBlock);
+                if locked {
+                    let msg = "another mapiproxy is listening on this socket";
+                    return Err(io::Error::new(ErrorKind::AddrInUse, msg));
+                }
+                if UnixStream::connect(path).is_ok() {
+                    let msg = "another process is listening on this socket";
+                    return Err(io::Error::new(ErrorKind::AddrInUse, msg));
+                }
+                if path.exists() {
+                    // stale, will be replaced
+                    return Ok(());
+                }
+                UnixListener::bind(path)?;
+                fs::remove_file(path)
+            }
+            #[cfg(not(unix))]
+            Addr::Unix(_) => Err(unix_not_supported()),
+        }
+    }
+
+    /// Check whether a server is accepting connections on this address by
+    /// making a blocking connection attempt and closing it again.
+    #[cfg(feature = "proxy")]
+    pub fn check_connect(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Addr::Tcp(a) => net::TcpStream::connect_timeout(a, timeout).map(drop),
+            #[cfg(unix)]
+            Addr::Unix(path) => std::os::unix::net::UnixStream::connect(path).map(drop),
+            #[cfg(not(unix))]
+            Addr::Unix(_) => Err(unix_not_supported()),
+        }
+    }
+}
+
+/// Bind a nonblocking TCP listener with the given backlog.
+#[cfg(feature = "proxy")]
+pub fn bind_tcp(addr: &TcpSocketAddr, backlog: i32) -> io::Result<net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    let sock = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    // Like std, to be able to restart the proxy right away
+    #[cfg(unix)]
+    sock.set_reuse_address(true)?;
+    // so [::] and 0.0.0.0 can be bound side by side
+    if addr.is_ipv6() {
+        sock.set_only_v6(true)?;
+    }
+    sock.bind(&(*addr).into())?;
+    sock.listen(backlog)?;
+    sock.set_nonblocking(true)?;
+    Ok(sock.into())
+}
+
+/// A nonblocking TCP socket for connecting to the server at `addr`, tagged
+/// with `marking`.
+#[cfg(feature = "proxy")]
+pub fn server_socket(addr: &TcpSocketAddr, marking: TrafficMarking) -> io::Result<socket2::Socket> {
+    use socket2::{Domain, Socket, Type};
+    let sock = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    sock.set_nonblocking(true)?;
+    let context = |what| move |e: io::Error| io::Error::new(e.kind(), format!("{what}: {e}"));
+    if let Some(tos) = marking.tos {
+        match addr {
+            TcpSocketAddr::V4(_) => sock.set_tos(tos.into()),
+            #[cfg(unix)]
+            TcpSocketAddr::V6(_) => sock.set_tclass_v6(tos.into()),
+            #[cfg(not(unix))]
+            TcpSocketAddr::V6(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "not supported for IPv6 on this system",
+            )),
+        }
+        .map_err(context("cannot set the TOS"))?;
+    }
+    // main only accepts --so-mark on Linux
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = marking.mark {
+        sock.set_mark(mark).map_err(context("cannot set SO_MARK"))?;
+    }
+    Ok(sock)
+}
+
+/// Start connecting a nonblocking TCP socket to `addr`, see [server_socket].
+#[cfg(feature = "proxy")]
+pub fn connect_tcp(addr: &TcpSocketAddr, marking: TrafficMarking) -> io::Result<net::TcpStream> {
+    let sock = server_socket(addr, marking)?;
+    match sock.connect(&(*addr).into()) {
+        Ok(()) => {}
+        #[cfg(unix)]
+        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(e) => return Err(e),
+    }
+    Ok(sock.into())
+}
+
+/// Bind a nonblocking Unix Domain socket listener with the given backlog.
+/// Does not remove stale socket files, see [bind_unix] for that.
+#[cfg(all(unix, feature = "proxy"))]
+pub fn bind_unix_std(path: &Path, backlog: i32) -> io::Result<std::os::unix::net::UnixListener> {
+    use socket2::{Domain, SockAddr, Socket, Type};
+    let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    sock.bind(&SockAddr::unix(path)?)?;
+    sock.listen(backlog)?;
+    sock.set_nonblocking(true)?;
+    Ok(sock.into())
+}
+
+/// Whether accept() failed because we ran out of file descriptors. In that
+/// case the proxy stops accepting for a while instead of giving up.
+#[cfg(all(unix, feature = "proxy"))]
+pub fn is_out_of_fds(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(all(not(unix), feature = "proxy"))]
+pub fn is_out_of_fds(_err: &io::Error) -> bool {
+    false
+}
+
+/// The Unix Domain sockets this process listens on, see [bind_unix].
+#[cfg(all(unix, feature = "proxy"))]
+static UNIX_SOCKETS: Mutex<Option<HashMap<PathBuf, BoundSocket>>> = Mutex::new(None);
+
+#[cfg(all(unix, feature = "proxy"))]
+struct BoundSocket {
+    /// Device and inode of the socket file, to recognize it when another
+    /// process has replaced it in the meantime.
+    file_id: Option<(u64, u64)>,
+    /// The lock is held for as long as the file is open.
+    lock: Option<File>,
+}
+
+/// Bind a Unix Domain socket using the given bind function. Shared between
+/// the proxies, the control socket and the mock server.
+///
+/// While listening, a lock is held on a file next to the socket whose name
+/// ends in `.lock`, which tells other instances of mapiproxy that the socket
+/// is in use. If the path is already in use, the socket file is only removed
+/// if it is stale: nobody holds its lock and connecting to it fails, so no
+/// other process, such as an mserver, is accepting connections on it. With
+/// `force`, it is removed anyway.
+#[cfg(all(unix, feature = "proxy"))]
+pub fn bind_unix<T>(
+    path: &Path,
+    force: bool,
+    bind: impl Fn(&Path) -> io::Result<T>,
+) -> io::Result<T> {
+    let lock = match lock_socket_file(path) {
+        Ok(lock) => Some(lock),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+            if !force {
+                let msg = "another mapiproxy is listening on this socket";
+                return Err(io::Error::new(ErrorKind::AddrInUse, msg));
+            }
+            // the other mapiproxy keeps its lock on the removed file
+            let _ = fs::remove_file(lock_file_path(path));
+            lock_socket_file(path).ok()
+        }
+        // without a lock file, rely on the connection attempt
+        Err(_) => None,
+    };
+    let attempt = || match bind(path) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            if !force && std::os::unix::net::UnixStream::connect(path).is_ok() {
+                let msg = "another process is listening on this socket";
+                return Err(io::Error::new(ErrorKind::AddrInUse, msg));
+            }
+            fs::remove_file(path)?;
+            bind(path)
+        }
+        result => result,
+    };
+    let listener = match attempt() {
+        Ok(listener) => listener,
+        Err(e) => {
+            if let Some(lock) = lock {
+                release_lock(path, lock);
+            }
+            return Err(e);
+        }
+    };
+    let bound = BoundSocket {
+        file_id: file_id(path),
+        lock,
+    };
+    let mut sockets = UNIX_SOCKETS.lock().unwrap();
+    sockets
+        .get_or_insert_with(HashMap::new)
+        .insert(path.to_owned(), bound);
+    Ok(listener)
+}
+
+/// Remove the socket file of a Unix Domain socket listener that is done, and
+/// release the lock taken by [bind_unix]. Files that have been replaced by
+/// another process in the meantime, see `--force-bind`, are left alone.
+#[cfg(all(unix, feature = "proxy"))]
+pub fn unbind_unix(path: &Path) {
+    let bound = UNIX_SOCKETS
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|sockets| sockets.remove(path));
+    let Some(bound) = bound else {
+        let _ = fs::remove_file(path);
+        return;
+    };
+    if bound.file_id.is_some() && file_id(path) == bound.file_id {
+        let _ = fs::remove_file(path);
+    }
+    if let Some(lock) = bound.lock {
+        release_lock(path, lock);
+    }
+}
+
+/// Remove the lock file taken by [lock_socket_file] while still holding the
+/// lock, unless another process has replaced it in the meantime.
+#[cfg(all(unix, feature = "proxy"))]
+fn release_lock(path: &Path, lock: File) {
+    let lock_path = lock_file_path(path);
+    let lock_id = lock.metadata().ok().map(|m| (m.dev(), m.ino()));
+    if lock_id.is_some() && file_id(&lock_path) == lock_id {
+        let _ = fs::remove_file(lock_path);
+    }
+}
+
+/// Take the lock on the lock file of a Unix Domain socket. Fails with
+/// [ErrorKind::WouldBlock] if another process holds it.
+#[cfg(all(unix, feature = "proxy"))]
+fn lock_socket_file(path: &Path) -> io::Result<File> {
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_file_path(path))?;
+    // SAFETY: flock only takes the file descriptor, which stays open
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(all(unix, feature = "proxy"))]
+fn lock_file_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lock");
+    name.into()
+}
+
+#[cfg(all(unix, feature = "proxy"))]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    Some((meta.dev(), meta.ino()))
 }
 
 impl From<TcpSocketAddr> for Addr {
@@ -236,7 +700,7 @@ impl From<PathBuf> for Addr {
     }
 }
 
-#[cfg(unix)]
+#[cfg(all(unix, feature = "proxy"))]
 impl From<UnixSocketAddr> for Addr {
     fn from(value: UnixSocketAddr) -> Self {
         value
@@ -247,6 +711,7 @@ impl From<UnixSocketAddr> for Addr {
     }
 }
 
+#[cfg(feature = "proxy")]
 impl mio::event::Source for MioListener {
     fn register(
         &mut self,
@@ -283,6 +748,7 @@ impl mio::event::Source for MioListener {
     }
 }
 
+#[cfg(feature = "proxy")]
 impl MioListener {
     #[allow(dead_code)]
     pub fn is_tcp(&self) -> bool {
@@ -312,6 +778,7 @@ impl MioListener {
     }
 }
 
+#[cfg(feature = "proxy")]
 impl Drop for MioListener {
     fn drop(&mut self) {
         #[cfg(unix)]
@@ -322,11 +789,12 @@ impl Drop for MioListener {
             let Some(path) = unix_sock_addr.as_pathname() else {
                 return;
             };
-            let _ = fs::remove_file(path);
+            unbind_unix(path);
         }
     }
 }
 
+#[cfg(feature = "proxy")]
 impl mio::event::Source for MioStream {
     fn register(
         &mut self,
@@ -363,6 +831,17 @@ impl mio::event::Source for MioStream {
     }
 }
 
+#[cfg(all(unix, feature = "proxy"))]
+impl std::os::fd::AsRawFd for MioStream {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match self {
+            Self::Tcp(s) => s.as_raw_fd(),
+            Self::Unix(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(feature = "proxy")]
 impl MioStream {
     pub fn is_tcp(&self) -> bool {
         matches!(self, Self::Tcp(_))
@@ -392,7 +871,7 @@ impl MioStream {
         }
     }
 
-    pub fn shutdown(&self, shutdown: net::Shutdown) -> io::Result<()> {
+    pub fn shutdown(&self, shutdown: std::net::Shutdown) -> io::Result<()> {
         match self {
             MioStream::Tcp(s) => s.shutdown(shutdown),
             #[cfg(unix)]
@@ -425,8 +904,36 @@ impl MioStream {
             MioStream::Unix(_) => Ok(()),
         }
     }
+
+    /// Send a single byte as TCP urgent data. Unix domain sockets have no
+    /// such thing.
+    #[cfg(unix)]
+    pub fn send_out_of_band(&self, byte: u8) -> io::Result<()> {
+        match self {
+            MioStream::Tcp(s) => {
+                use std::os::fd::{AsRawFd, BorrowedFd};
+                // SAFETY: the fd stays open while `s` is borrowed
+                let fd = unsafe { BorrowedFd::borrow_raw(s.as_raw_fd()) };
+                socket2::SockRef::from(&fd).send_out_of_band(&[byte])?;
+                Ok(())
+            }
+            MioStream::Unix(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "out-of-band data requires TCP",
+            )),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn send_out_of_band(&self, _byte: u8) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "out-of-band data is only supported on Unix",
+        ))
+    }
 }
 
+#[cfg(feature = "proxy")]
 impl io::Write for MioStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
@@ -453,6 +960,7 @@ impl io::Write for MioStream {
     }
 }
 
+#[cfg(feature = "proxy")]
 impl io::Read for MioStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
@@ -470,3 +978,96 @@ impl io::Read for MioStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> io::Result<MonetAddr> {
+        MonetAddr::try_from(OsStr::new(text))
+    }
+
+    #[test]
+    fn test_parse_ipv6() {
+        let ip = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let expected = MonetAddr::Ip { ip, port: 50000 };
+        assert_eq!(parse("[2001:db8::1]:50000").unwrap(), expected);
+        // 50000 cannot be a group, it has too many digits
+        assert_eq!(parse("2001:db8::1:50000").unwrap(), expected);
+        // but 5000 can
+        assert!(parse("2001:db8::1:5000").is_err());
+        assert!(parse("[2001:db8::1]:5000").is_ok());
+    }
+
+    #[test]
+    fn test_parse_ipv6_zone() {
+        let addr = parse("[fe80::1%3]:50000").unwrap();
+        let expected = MonetAddr::IpZone {
+            ip: "fe80::1".parse().unwrap(),
+            zone: "3".to_string(),
+            scope_id: 3,
+            port: 50000,
+        };
+        assert_eq!(addr, expected);
+        assert_eq!(addr.to_string(), "[fe80::1%3]:50000");
+        assert_eq!(parse("fe80::1%3:50000").unwrap(), expected);
+        assert!(parse("[fe80::1%no-such-interface]:50000").is_err());
+
+        let resolved = addr.resolve().unwrap();
+        let [Addr::Tcp(TcpSocketAddr::V6(sock))] = resolved.as_slice() else {
+            panic!("expected one IPv6 address, got {resolved:?}");
+        };
+        assert_eq!(sock.scope_id(), 3);
+    }
+
+    #[test]
+    fn test_resolve_with() {
+        let addr = parse("db.example:50000").unwrap();
+        let local = Resolver {
+            overrides: vec![ResolveOverride {
+                host: "DB.example".to_string(),
+                port: Some(50000),
+                addrs: vec![Ipv4Addr::LOCALHOST.into()],
+            }],
+            ..Resolver::default()
+        };
+        let resolved = addr.resolve_with(&local).unwrap();
+        let [Addr::Tcp(sock)] = resolved.as_slice() else {
+            panic!("expected one TCP address, got {resolved:?}");
+        };
+        assert_eq!(*sock, (Ipv4Addr::LOCALHOST, 50000).into());
+
+        let other = Resolver {
+            unix_socket_dir: "/run/monetdb".into(),
+            unix_socket_template: "mserver-{port}.sock".into(),
+            ..Resolver::default()
+        };
+        let port = parse("50000").unwrap();
+        let unix = |resolver| match port.resolve_unix(resolver).unwrap().as_slice() {
+            [Addr::Unix(path)] => path.clone(),
+            other => panic!("expected one Unix Domain socket, got {other:?}"),
+        };
+        if cfg!(unix) {
+            assert_eq!(unix(&local), PathBuf::from("/tmp/.s.monetdb.50000"));
+            assert_eq!(
+                unix(&other),
+                PathBuf::from("/run/monetdb/mserver-50000.sock")
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_leaves_no_lock_file() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("mapiproxy-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let other = UnixListener::bind(&path).unwrap();
+        let result = bind_unix(&path, false, |p| UnixListener::bind(p));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert!(!lock_file_path(&path).exists());
+        drop(other);
+        let _ = fs::remove_file(&path);
+    }
+}
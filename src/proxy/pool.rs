@@ -0,0 +1,127 @@
+//! Reusable buffers for the payload of [MapiEvent::Data](super::event::MapiEvent::Data).
+//!
+//! Every read on a forwarded connection produces a data event. Allocating a
+//! fresh buffer for each of them adds up on busy connections, so the buffers
+//! are taken from a [BufferPool] and returned to it when the event is dropped,
+//! typically right after it has been rendered.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+/// A shared collection of empty buffers. Cloning it yields a handle to the
+/// same pool.
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl BufferPool {
+    /// Buffers returned while this many are already idle are freed instead.
+    const MAX_IDLE: usize = 128;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy the data into a buffer taken from the pool.
+    pub fn copy_from_slice(&self, data: &[u8]) -> PooledBuf {
+        let mut buf = self.0.lock().unwrap().pop().unwrap_or_default();
+        buf.extend_from_slice(data);
+        PooledBuf {
+            buf,
+            pool: Some(self.clone()),
+        }
+    }
+
+    /// The number of buffers waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn give_back(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut idle = self.0.lock().unwrap();
+        if idle.len() < Self::MAX_IDLE {
+            idle.push(buf);
+        }
+    }
+}
+
+/// Bytes held in a buffer that goes back to its [BufferPool] when dropped.
+/// Buffers created with [From] do not belong to a pool.
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl PooledBuf {
+    /// Take the bytes out of the pool for good.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.buf, f)
+    }
+}
+
+impl From<&[u8]> for PooledBuf {
+    fn from(data: &[u8]) -> Self {
+        Vec::from(data).into()
+    }
+}
+
+impl From<Vec<u8>> for PooledBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        PooledBuf { buf, pool: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new();
+        let a = pool.copy_from_slice(b"hello");
+        let b = pool.copy_from_slice(b"world");
+        assert_eq!(&*a, b"hello");
+        assert_eq!(pool.idle(), 0);
+        drop(a);
+        drop(b);
+        assert_eq!(pool.idle(), 2);
+
+        let c = pool.copy_from_slice(b"again");
+        assert_eq!(&*c, b"again");
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(c.into_vec(), b"again");
+        assert_eq!(pool.idle(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{io, mem};
+
+use crate::mapi::{encode_message, Analyzer};
+
+use super::{
+    event::{ConnectionId, ConnectionSink, Direction},
+    route::replace_database,
+    Error, Result,
+};
+
+/// A Rewriter gets to modify MAPI messages before the proxy forwards them.
+pub trait Rewriter: Send {
+    /// Called for each complete message, without the block headers. Return
+    /// None to forward it unchanged, or the messages to forward instead. An
+    /// empty Vec drops the message altogether. Returning an error aborts the
+    /// connection.
+    fn rewrite(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        message: &[u8],
+    ) -> io::Result<Option<Vec<Vec<u8>>>>;
+}
+
+/// Collects the messages flowing in one direction so they can be passed to a
+/// [Rewriter], and holds the bytes to forward in their place.
+///
+/// If the data turns out not to be valid MAPI, everything from the last message
+/// boundary onward is forwarded unchanged. So is a message that grows larger
+/// than [MessageRewriter::MAX_MESSAGE], such as a huge result set, rather
+/// than holding all of it in memory.
+#[derive(Debug)]
+pub struct MessageRewriter {
+    analyzer: Analyzer,
+    /// Data received since the last message boundary, including block headers
+    raw: Vec<u8>,
+    /// Same, without the block headers
+    body: Vec<u8>,
+    /// Set when we have given up on parsing the MAPI framing
+    passthrough: bool,
+    /// Set while forwarding the rest of a message that is too large to
+    /// rewrite
+    oversized: bool,
+    /// Data to forward
+    output: Vec<u8>,
+    /// How much of [Self::output] has already been forwarded
+    sent: usize,
+    /// Database name to put in the first message, see
+    /// [MessageRewriter::set_login_database]
+    login_database: Option<String>,
+}
+
+impl MessageRewriter {
+    pub const MAX_PENDING: usize = 8192;
+    /// Messages larger than this are forwarded without rewriting them.
+    pub const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+    pub fn new() -> Self {
+        MessageRewriter {
+            analyzer: Analyzer::new(false),
+            raw: vec![],
+            body: vec![],
+            passthrough: false,
+            oversized: false,
+            output: vec![],
+            sent: 0,
+            login_database: None,
+        }
+    }
+
+    /// Replace the database name in the first message, the client's login
+    /// response, before it is passed to the [Rewriter].
+    pub fn set_login_database(&mut self, database: &str) {
+        self.login_database = Some(database.to_string());
+    }
+
+    /// Forward these bytes before everything else. Used for the initial '0'
+    /// byte when forwarding to a Unix Domain socket.
+    pub fn prepend(&mut self, data: &[u8]) {
+        self.output
+            .splice(self.sent..self.sent, data.iter().copied());
+    }
+
+    /// Process data received from the sender. Without a [Rewriter], only the
+    /// login database is replaced.
+    pub fn feed(
+        &mut self,
+        mut data: &[u8],
+        direction: Direction,
+        mut rewriter: Option<&mut dyn Rewriter>,
+        sink: &mut ConnectionSink,
+    ) -> Result<()> {
+        if self.passthrough {
+            self.output.extend_from_slice(data);
+            return Ok(());
+        }
+
+        while let Some(chunk) = self.analyzer.split_chunk(&mut data) {
+            if self.oversized {
+                self.output.extend_from_slice(chunk);
+            } else {
+                self.raw.extend_from_slice(chunk);
+            }
+            if self.analyzer.was_error() {
+                self.passthrough = true;
+                self.output.append(&mut self.raw);
+                self.output.extend_from_slice(data);
+                return Ok(());
+            }
+            if self.oversized {
+                self.oversized = !self.analyzer.was_message_boundary();
+                continue;
+            }
+            if !self.analyzer.was_body() {
+                continue;
+            }
+            self.body.extend_from_slice(chunk);
+            if !self.analyzer.was_message_boundary() {
+                if self.raw.len() > Self::MAX_MESSAGE {
+                    self.oversized = true;
+                    self.output.append(&mut self.raw);
+                    self.body.clear();
+                    let mib = Self::MAX_MESSAGE / (1024 * 1024);
+                    let note = format!("message larger than {mib} MiB forwarded without rewriting");
+                    sink.emit_note(Some(direction), note);
+                }
+                continue;
+            }
+
+            let message = mem::take(&mut self.body);
+            let login = self
+                .login_database
+                .take()
+                .and_then(|db| replace_database(&message, &db));
+            let mut replacement = login.map(|login| vec![login]);
+            if let Some(rewriter) = rewriter.as_deref_mut() {
+                let input = replacement.as_ref().map_or(&message, |msgs| &msgs[0]);
+                let rewritten = rewriter
+                    .rewrite(sink.id(), direction, input)
+                    .map_err(|e| Error::Other(format!("rewriting failed: {e}")))?;
+                replacement = rewritten.or(replacement);
+            }
+            match replacement {
+                None => self.output.append(&mut self.raw),
+                Some(messages) => {
+                    self.raw.clear();
+                    for msg in &messages {
+                        encode_message(msg, &mut self.output);
+                    }
+                    sink.emit_rewritten(direction, message.len(), messages);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The sender will not send any more data, forward any incomplete message
+    /// as is.
+    pub fn finish(&mut self) {
+        self.output.append(&mut self.raw);
+        self.body.clear();
+        self.oversized = false;
+    }
+
+    /// The data that still needs to be forwarded.
+    pub fn pending(&self) -> &[u8] {
+        &self.output[self.sent..]
+    }
+
+    /// Mark `n` bytes of [Self::pending] as forwarded.
+    pub fn consume(&mut self, n: usize) {
+        self.sent += n;
+        if self.sent == self.output.len() {
+            self.output.clear();
+            self.sent = 0;
+        }
+    }
+
+    /// Whether there is room to accept more data from the sender.
+    pub fn has_room(&self) -> bool {
+        self.pending().len() < Self::MAX_PENDING
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mapi::MAX_BLOCK_SIZE,
+        proxy::event::{EventSink, MapiEvent},
+    };
+    use std::sync::mpsc;
+
+    struct Upper;
+
+    impl Rewriter for Upper {
+        fn rewrite(
+            &mut self,
+            _id: ConnectionId,
+            _direction: Direction,
+            message: &[u8],
+        ) -> io::Result<Option<Vec<Vec<u8>>>> {
+            Ok(Some(vec![message.to_ascii_uppercase()]))
+        }
+    }
+
+    #[test]
+    fn test_large_message() {
+        let (send, receive) = mpsc::channel();
+        let mut sink = EventSink::new(move |ev| send.send(ev).unwrap());
+        let mut sink = sink.connection_sink(ConnectionId::new(10));
+        let mut rewriter = MessageRewriter::new();
+        let mut feed = |rewriter: &mut MessageRewriter, data: &[u8]| {
+            rewriter
+                .feed(data, Direction::Downstream, Some(&mut Upper), &mut sink)
+                .unwrap();
+            let out = rewriter.pending().to_vec();
+            rewriter.consume(out.len());
+            out
+        };
+
+        let large = vec![b'x'; MessageRewriter::MAX_MESSAGE + MAX_BLOCK_SIZE];
+        let mut encoded = vec![];
+        encode_message(&large, &mut encoded);
+        let mut forwarded = vec![];
+        for chunk in encoded.chunks(100_000) {
+            forwarded.extend(feed(&mut rewriter, chunk));
+            assert!(rewriter.raw.len() <= MessageRewriter::MAX_MESSAGE + 100_000);
+        }
+        assert_eq!(forwarded, encoded);
+
+        // the next message is rewritten again
+        let mut small = vec![];
+        encode_message(b"abc", &mut small);
+        let mut expected = vec![];
+        encode_message(b"ABC", &mut expected);
+        assert_eq!(feed(&mut rewriter, &small), expected);
+
+        let events: Vec<_> = receive.try_iter().collect();
+        assert!(matches!(&events[0], MapiEvent::Note { note, .. } if note.contains("16 MiB")));
+        assert!(matches!(
+            &events[1],
+            MapiEvent::Rewritten { original: 3, .. }
+        ));
+        assert_eq!(events.len(), 2);
+    }
+}
@@ -0,0 +1,239 @@
+//! Finding the server of a database by going through the login dance with
+//! monetdbd on behalf of the client, see
+//! [Proxy::set_database](super::Proxy::set_database).
+//!
+//! monetdbd does not check the credentials, it only looks at the database name
+//! in the login response. Depending on its configuration it then either
+//! proxies the connection to the mserver5 of that database, which starts a new
+//! login on the same connection, or redirects the client to the address of the
+//! mserver5. The proxy logs in with a dummy user until it receives a challenge
+//! from an mserver5, then hands that challenge to the client, which logs in
+//! for real.
+
+use std::{ffi::OsStr, path::PathBuf, str};
+
+use crate::mapi::{encode_message, read_message};
+
+use super::{event::ConnectionSink, network::MonetAddr, Error, Result};
+
+#[derive(Debug)]
+pub(super) struct Route {
+    database: String,
+    /// Number of redirects followed so far
+    hops: usize,
+    /// Data received from the server that does not form a whole message yet
+    incoming: Vec<u8>,
+    /// Data to send to the server
+    outgoing: Vec<u8>,
+    /// How much of [Self::outgoing] has been sent
+    sent: usize,
+}
+
+/// What to do after receiving data from the server, see [Route::received].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Step {
+    /// Wait for more data
+    Wait,
+    /// Connect to this server and start over
+    Redirect(MonetAddr),
+    /// This is the server, pass the challenge it sent to the client. It
+    /// includes the block headers.
+    Arrived(Vec<u8>),
+}
+
+impl Route {
+    /// Give up after this many redirects, they are probably going in circles.
+    const MAX_HOPS: usize = 10;
+
+    pub fn new(database: &str) -> Self {
+        Route {
+            database: database.to_string(),
+            hops: 0,
+            incoming: vec![],
+            outgoing: vec![],
+            sent: 0,
+        }
+    }
+
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    /// A connection to the next server has been established.
+    pub fn connected(&mut self, unix: bool) {
+        self.incoming.clear();
+        self.outgoing.clear();
+        self.sent = 0;
+        if unix {
+            self.outgoing.push(b'0');
+        }
+    }
+
+    /// The data that still needs to be sent to the server.
+    pub fn pending(&self) -> &[u8] {
+        &self.outgoing[self.sent..]
+    }
+
+    /// Mark `n` bytes of [Route::pending] as sent.
+    pub fn sent(&mut self, n: usize) {
+        self.sent += n;
+    }
+
+    /// Process data received from the server.
+    pub fn received(&mut self, data: &[u8], sink: &mut ConnectionSink) -> Result<Step> {
+        self.incoming.extend_from_slice(data);
+        while let Some(message) = self.take_message() {
+            let text = String::from_utf8_lossy(&message);
+            let line = text.lines().next().unwrap_or_default();
+
+            if line.starts_with("^mapi:merovingian://proxy") {
+                // monetdbd connects us through, the next message comes from
+                // the server
+                sink.emit_redirected(line[1..].to_string());
+                continue;
+            }
+            if let Some(url) = line.strip_prefix('^') {
+                self.hops += 1;
+                if self.hops > Self::MAX_HOPS {
+                    return Err(self.failed("too many redirects"));
+                }
+                let Some(addr) = parse_redirect(url) else {
+                    return Err(self.failed(&format!("cannot follow redirect {url}")));
+                };
+                sink.emit_redirected(url.to_string());
+                return Ok(Step::Redirect(addr));
+            }
+            if let Some(error) = line.strip_prefix('!') {
+                return Err(self.failed(error));
+            }
+
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 6 {
+                return Err(self.failed(&format!("unexpected message {line:?}")));
+            }
+            if fields[1] != "merovingian" {
+                let mut challenge = vec![];
+                encode_message(&message, &mut challenge);
+                challenge.append(&mut self.incoming);
+                return Ok(Step::Arrived(challenge));
+            }
+            let algo = fields[3].split(',').next().unwrap_or_default();
+            let login = format!("LIT:monetdb:{{{algo}}}0:sql:{db}:\n", db = self.database);
+            encode_message(login.as_bytes(), &mut self.outgoing);
+        }
+        Ok(Step::Wait)
+    }
+
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        let mut rd = &self.incoming[..];
+        let message = read_message(&mut rd).ok()??;
+        let consumed = self.incoming.len() - rd.len();
+        self.incoming.drain(..consumed);
+        Some(message)
+    }
+
+    fn failed(&self, why: &str) -> Error {
+        let db = &self.database;
+        Error::Other(format!("could not reach database {db}: {why}"))
+    }
+}
+
+/// Extract the server address from a redirect such as
+/// `mapi:monetdb://host:50001/demo` or
+/// `mapi:monetdb:///tmp/.s.monetdb.50001?database=demo`.
+fn parse_redirect(url: &str) -> Option<MonetAddr> {
+    let rest = url.strip_prefix("mapi:monetdb://")?;
+    let rest = rest.split('?').next()?;
+    if rest.starts_with('/') {
+        return Some(MonetAddr::Unix(PathBuf::from(rest)));
+    }
+    let host_port = rest.split('/').next()?;
+    MonetAddr::try_from(OsStr::new(host_port)).ok()
+}
+
+/// Replace the database name in a login response such as
+/// `LIT:monetdb:{SHA512}...:sql:demo:`. Returns None if it does not need to
+/// change.
+pub(super) fn replace_database(login: &[u8], database: &str) -> Option<Vec<u8>> {
+    let login = str::from_utf8(login).ok()?;
+    let mut fields: Vec<&str> = login.split(':').collect();
+    if fields.len() < 6 || fields[4] == database {
+        return None;
+    }
+    fields[4] = database;
+    Some(fields.join(":").into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::event::{ConnectionId, EventSink, MapiEvent};
+    use std::sync::mpsc;
+
+    fn message(text: &str) -> Vec<u8> {
+        let mut out = vec![];
+        encode_message(text.as_bytes(), &mut out);
+        out
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        let tcp = parse_redirect("mapi:monetdb://localhost:50001/demo");
+        assert_eq!(tcp.unwrap().to_string(), "localhost:50001");
+        let unix = parse_redirect("mapi:monetdb:///tmp/.s.monetdb.50001?database=demo");
+        assert_eq!(unix, Some(MonetAddr::Unix("/tmp/.s.monetdb.50001".into())));
+        assert_eq!(parse_redirect("mapi:merovingian://proxy"), None);
+    }
+
+    #[test]
+    fn test_replace_database() {
+        let login = b"BIG:monetdb:{SHA512}abc:sql:other:FILETRANS:\n";
+        let replaced = replace_database(login, "demo").unwrap();
+        assert_eq!(replaced, b"BIG:monetdb:{SHA512}abc:sql:demo:FILETRANS:\n");
+        assert_eq!(replace_database(&replaced, "demo"), None);
+    }
+
+    #[test]
+    fn test_route() {
+        let (send, receive) = mpsc::channel();
+        let mut sink = EventSink::new(move |ev| send.send(ev).unwrap());
+        let mut sink = sink.connection_sink(ConnectionId::new(10));
+        let mut route = Route::new("demo");
+
+        // monetdbd asks for a login and redirects
+        route.connected(true);
+        let challenge = message("salt:merovingian:9:RIPEMD160,SHA512:LIT:SHA512:");
+        let step = route.received(&challenge[..5], &mut sink).unwrap();
+        assert_eq!(step, Step::Wait);
+        let step = route.received(&challenge[5..], &mut sink).unwrap();
+        assert_eq!(step, Step::Wait);
+        let mut login = b"0".to_vec();
+        login.extend(message("LIT:monetdb:{RIPEMD160}0:sql:demo:\n"));
+        assert_eq!(route.pending(), login);
+        route.sent(login.len());
+        let redirect = message("^mapi:monetdb://db.example.com:50001/demo\n");
+        let Step::Redirect(addr) = route.received(&redirect, &mut sink).unwrap() else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(addr.to_string(), "db.example.com:50001");
+        assert!(matches!(
+            receive.try_recv(),
+            Ok(MapiEvent::Redirected { to, .. }) if to == "mapi:monetdb://db.example.com:50001/demo"
+        ));
+
+        // the next one proxies to the server, which sends its own challenge
+        route.connected(false);
+        route.received(&challenge, &mut sink).unwrap();
+        route.sent(route.pending().len());
+        let proxied = message("^mapi:merovingian://proxy?database=demo\n");
+        let server_challenge = message("salt2:mserver:9:SHA512:LIT:SHA512:");
+        let mut data = proxied;
+        data.extend_from_slice(&server_challenge);
+        let step = route.received(&data, &mut sink).unwrap();
+        assert_eq!(step, Step::Arrived(server_challenge));
+
+        let refused = message("!no such database 'demo'\n");
+        route.connected(false);
+        assert!(route.received(&refused, &mut sink).is_err());
+    }
+}
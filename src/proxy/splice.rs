@@ -0,0 +1,87 @@
+//! Forwarding without copying the data into user space, using splice(2).
+//!
+//! The kernel can only splice between a socket and a pipe, so each direction
+//! of a connection gets its own pipe. Data is spliced from the sending socket
+//! into the pipe and from the pipe into the receiving socket.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+#[derive(Debug)]
+pub struct Pipe {
+    read_end: OwnedFd,
+    write_end: OwnedFd,
+    capacity: usize,
+    /// Number of bytes spliced into the pipe but not yet out of it
+    pending: usize,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as RawFd; 2];
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 has just given us these file descriptors
+        let (read_end, write_end) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let capacity = unsafe { libc::fcntl(write_end.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if capacity < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read_end,
+            write_end,
+            capacity: capacity as usize,
+            pending: 0,
+        })
+    }
+
+    /// Number of bytes in the pipe waiting to be spliced out.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Whether [Pipe::fill] can make progress. If the pipe is not full, an
+    /// [io::ErrorKind::WouldBlock] from [Pipe::fill] means the socket had
+    /// nothing to read.
+    pub fn has_room(&self) -> bool {
+        self.pending < self.capacity
+    }
+
+    /// Move data from the socket into the pipe. Returns 0 on end of file.
+    pub fn fill(&mut self, from: &impl AsRawFd) -> io::Result<usize> {
+        let room = self.capacity - self.pending;
+        let n = splice(from.as_raw_fd(), self.write_end.as_raw_fd(), room)?;
+        self.pending += n;
+        Ok(n)
+    }
+
+    /// Move data from the pipe into the socket.
+    pub fn drain(&mut self, to: &impl AsRawFd) -> io::Result<usize> {
+        let n = splice(self.read_end.as_raw_fd(), to.as_raw_fd(), self.pending)?;
+        self.pending -= n;
+        Ok(n)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let ret = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            flags,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
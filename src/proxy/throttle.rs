@@ -0,0 +1,84 @@
+//! Limiting the rate at which the [Proxy](super::Proxy) accepts connections.
+
+use std::time::{Duration, Instant};
+
+/// Accept at most `count` connections per `per`, see
+/// [Proxy::set_accept_rate](super::Proxy::set_accept_rate). Connections above
+/// the rate are not refused, they wait in the listen backlog until it is
+/// their turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptRate {
+    pub count: u32,
+    pub per: Duration,
+}
+
+/// A token bucket for an [AcceptRate]. It holds up to `count` tokens, so
+/// that many connections can be accepted at once after a quiet period, and
+/// gains them back at the rate.
+#[derive(Debug)]
+pub(super) struct TokenBucket {
+    rate: AcceptRate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: AcceptRate, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.count as f64,
+            last: now,
+        }
+    }
+
+    /// How long until the next connection may be accepted, None if that can
+    /// be done right away.
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        let count = self.rate.count as f64;
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() / self.rate.per.as_secs_f64() * count).min(count);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        let missing = (1.0 - self.tokens) / count;
+        Some(self.rate.per.mul_f64(missing))
+    }
+
+    /// A connection has been accepted.
+    pub fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let start = Instant::now();
+    let rate = AcceptRate {
+        count: 2,
+        per: Duration::from_secs(1),
+    };
+    let mut bucket = TokenBucket::new(rate, start);
+
+    // a burst of two, then wait half a second for each
+    for _ in 0..2 {
+        assert_eq!(bucket.wait(start), None);
+        bucket.take();
+    }
+    assert_eq!(bucket.wait(start), Some(Duration::from_millis(500)));
+    let later = start + Duration::from_millis(250);
+    assert_eq!(bucket.wait(later), Some(Duration::from_millis(250)));
+    let later = start + Duration::from_millis(500);
+    assert_eq!(bucket.wait(later), None);
+    bucket.take();
+    assert!(bucket.wait(later).is_some());
+
+    // after a long pause, still no more than two at once
+    let much_later = start + Duration::from_secs(60);
+    for _ in 0..2 {
+        assert_eq!(bucket.wait(much_later), None);
+        bucket.take();
+    }
+    assert!(bucket.wait(much_later).is_some());
+}
@@ -0,0 +1,281 @@
+//! A timer wheel for the event loop of the [Proxy](super::Proxy).
+//!
+//! Timers are hashed into a fixed number of slots by the tick at which they
+//! expire. Adding and cancelling a timer is cheap and expiring only looks at
+//! the slots of the ticks that have passed. Timers more than one rotation of
+//! the wheel away simply stay in their slot until their turn comes.
+//!
+//! A timer is either global or belongs to a connection. The timers of a
+//! connection can be cancelled all at once when it closes. Timers never go off
+//! early but may go off up to one tick late.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use slab::Slab;
+
+/// A set of timers, each carrying a value of type `T` that tells the owner
+/// what to do when it goes off.
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    /// Length of a tick
+    tick: Duration,
+    /// Start of tick 0
+    origin: Instant,
+    /// Timers up to and including this tick have been expired
+    current: u64,
+    /// Slot `t % slots.len()` holds the keys of the timers that go off at
+    /// tick `t`
+    slots: Vec<Vec<usize>>,
+    timers: Slab<Entry<T>>,
+    /// Keys of the timers belonging to each connection
+    by_conn: HashMap<usize, Vec<usize>>,
+    /// Distinguishes a timer from earlier timers stored under the same key
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    deadline: u64,
+    generation: u64,
+    conn: Option<usize>,
+    what: T,
+}
+
+/// Identifies a timer so it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    key: usize,
+    generation: u64,
+}
+
+/// A timer that has gone off, see [TimerWheel::expire].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Expired<T> {
+    /// The connection it was added for, None for global timers
+    pub conn: Option<usize>,
+    pub what: T,
+}
+
+impl<T> TimerWheel<T> {
+    pub const DEFAULT_TICK: Duration = Duration::from_millis(10);
+
+    pub const DEFAULT_SLOTS: usize = 256;
+
+    pub fn new(now: Instant) -> Self {
+        Self::with_resolution(now, Self::DEFAULT_TICK, Self::DEFAULT_SLOTS)
+    }
+
+    /// Create a wheel with the given tick length and number of slots.
+    pub fn with_resolution(now: Instant, tick: Duration, nslots: usize) -> Self {
+        assert!(!tick.is_zero() && nslots > 0);
+        TimerWheel {
+            tick,
+            origin: now,
+            current: 0,
+            slots: (0..nslots).map(|_| vec![]).collect(),
+            timers: Slab::new(),
+            by_conn: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// The number of timers that have not gone off or been cancelled yet.
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    /// Add a timer that goes off `delay` after `now`.
+    pub fn add_global(&mut self, now: Instant, delay: Duration, what: T) -> TimerHandle {
+        self.add(now + delay, None, what)
+    }
+
+    /// Add a timer on behalf of a connection, identified by a key of the
+    /// caller's choosing. See [TimerWheel::cancel_conn].
+    pub fn add_for_conn(
+        &mut self,
+        now: Instant,
+        delay: Duration,
+        conn: usize,
+        what: T,
+    ) -> TimerHandle {
+        self.add(now + delay, Some(conn), what)
+    }
+
+    fn add(&mut self, at: Instant, conn: Option<usize>, what: T) -> TimerHandle {
+        // round up so it doesn't go off early
+        let elapsed = at.saturating_duration_since(self.origin).as_nanos();
+        let deadline = elapsed.div_ceil(self.tick.as_nanos()) as u64;
+        let deadline = deadline.max(self.current + 1);
+        self.generation += 1;
+        let generation = self.generation;
+        let key = self.timers.insert(Entry {
+            deadline,
+            generation,
+            conn,
+            what,
+        });
+        let slot = self.slot(deadline);
+        self.slots[slot].push(key);
+        if let Some(conn) = conn {
+            self.by_conn.entry(conn).or_default().push(key);
+        }
+        TimerHandle { key, generation }
+    }
+
+    /// Cancel the timer. Returns None if it has already gone off or been
+    /// cancelled.
+    pub fn cancel(&mut self, handle: TimerHandle) -> Option<T> {
+        let entry = self.timers.get(handle.key)?;
+        if entry.generation != handle.generation {
+            return None;
+        }
+        Some(self.remove(handle.key))
+    }
+
+    /// Cancel all timers of the connection, typically because it has closed.
+    /// Returns how many there were.
+    pub fn cancel_conn(&mut self, conn: usize) -> usize {
+        let keys = self.by_conn.remove(&conn).unwrap_or_default();
+        for &key in &keys {
+            self.remove(key);
+        }
+        keys.len()
+    }
+
+    fn remove(&mut self, key: usize) -> T {
+        let entry = self.timers.remove(key);
+        let slot = self.slot(entry.deadline);
+        let slot = &mut self.slots[slot];
+        if let Some(pos) = slot.iter().position(|&k| k == key) {
+            slot.swap_remove(pos);
+        }
+        if let Some(conn) = entry.conn {
+            if let Some(keys) = self.by_conn.get_mut(&conn) {
+                keys.retain(|&k| k != key);
+                if keys.is_empty() {
+                    self.by_conn.remove(&conn);
+                }
+            }
+        }
+        entry.what
+    }
+
+    /// How long from `now` until the next timer goes off, or None if there are
+    /// no timers. Suitable as a poll timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        let deadline = self.next_deadline()?;
+        let nanos = self.tick.as_nanos() * deadline as u128;
+        let at = self.origin + Duration::from_nanos(nanos as u64);
+        Some(at.saturating_duration_since(now))
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        if self.timers.is_empty() {
+            return None;
+        }
+        let rotation = self.slots.len() as u64;
+        for t in self.current + 1..=self.current + rotation {
+            let slot = &self.slots[self.slot(t)];
+            if slot.iter().any(|&k| self.timers[k].deadline == t) {
+                return Some(t);
+            }
+        }
+        // everything is more than a rotation away
+        self.timers.iter().map(|(_, e)| e.deadline).min()
+    }
+
+    /// Remove the timers that have gone off by `now` and append them to
+    /// `expired`, roughly in the order they were due.
+    pub fn expire(&mut self, now: Instant, expired: &mut Vec<Expired<T>>) {
+        let elapsed = now.saturating_duration_since(self.origin).as_nanos();
+        let target = (elapsed / self.tick.as_nanos()) as u64;
+        if target <= self.current {
+            return;
+        }
+        // if more than a rotation has passed, each slot needs to be visited once
+        let rotation = self.slots.len() as u64;
+        let last = target.min(self.current + rotation);
+        for t in self.current + 1..=last {
+            let slot = self.slot(t);
+            let mut i = 0;
+            while i < self.slots[slot].len() {
+                let key = self.slots[slot][i];
+                if self.timers[key].deadline <= target {
+                    let conn = self.timers[key].conn;
+                    let what = self.remove(key);
+                    expired.push(Expired { conn, what });
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = target;
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn expire(wheel: &mut TimerWheel<&'static str>, now: Instant) -> Vec<&'static str> {
+        let mut expired = vec![];
+        wheel.expire(now, &mut expired);
+        expired.into_iter().map(|e| e.what).collect()
+    }
+
+    #[test]
+    fn test_timers_go_off_in_time() {
+        let t0 = Instant::now();
+        let mut wheel = TimerWheel::with_resolution(t0, 10 * MS, 8);
+        wheel.add_global(t0, 25 * MS, "b");
+        wheel.add_global(t0, 5 * MS, "a");
+        // further away than one rotation of 80ms
+        wheel.add_global(t0, 200 * MS, "c");
+        assert_eq!(wheel.timeout(t0), Some(10 * MS));
+
+        assert!(expire(&mut wheel, t0 + 9 * MS).is_empty());
+        assert_eq!(expire(&mut wheel, t0 + 10 * MS), ["a"]);
+        assert_eq!(wheel.timeout(t0 + 12 * MS), Some(18 * MS));
+        assert!(expire(&mut wheel, t0 + 29 * MS).is_empty());
+        assert_eq!(expire(&mut wheel, t0 + 30 * MS), ["b"]);
+        assert_eq!(wheel.timeout(t0 + 30 * MS), Some(170 * MS));
+        assert!(expire(&mut wheel, t0 + 199 * MS).is_empty());
+        assert_eq!(expire(&mut wheel, t0 + 500 * MS), ["c"]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.timeout(t0 + 500 * MS), None);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let t0 = Instant::now();
+        let mut wheel = TimerWheel::with_resolution(t0, 10 * MS, 8);
+        let a = wheel.add_global(t0, 20 * MS, "a");
+        wheel.add_for_conn(t0, 20 * MS, 1, "conn1 x");
+        wheel.add_for_conn(t0, 30 * MS, 1, "conn1 y");
+        let z = wheel.add_for_conn(t0, 20 * MS, 2, "conn2 z");
+        assert_eq!(wheel.len(), 4);
+
+        assert_eq!(wheel.cancel(a), Some("a"));
+        assert_eq!(wheel.cancel(a), None);
+        assert_eq!(wheel.cancel_conn(1), 2);
+        assert_eq!(wheel.cancel_conn(1), 0);
+        assert_eq!(expire(&mut wheel, t0 + 100 * MS), ["conn2 z"]);
+        // expired timers cannot be cancelled, nor can their successors
+        wheel.add_global(t0 + 100 * MS, 20 * MS, "b");
+        assert_eq!(wheel.cancel(z), None);
+        assert_eq!(wheel.len(), 1);
+    }
+}
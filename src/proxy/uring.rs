@@ -0,0 +1,804 @@
+//! Alternative implementation of the [Proxy](super::Proxy) based on io_uring.
+//!
+//! Instead of waiting for the sockets to become ready and then reading and
+//! writing them, the reads and writes themselves are submitted to the kernel,
+//! which completes them in the background. Many of them can be submitted and
+//! completed with a single system call, which pays off with many connections
+//! and large transfers. The bookkeeping of each direction of a connection,
+//! including rewriting and the Unix Domain socket '0' byte adjustment, is done
+//! by the same [Copying] as in the mio based proxy.
+//!
+//! Each direction has at most one receive or send in flight. The buffer it
+//! refers to is owned by the [Copying], which is not touched until the
+//! operation completes.
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Write},
+    mem,
+    net::Shutdown,
+    ops::RangeFrom,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+    vec,
+};
+
+use io_uring::{
+    opcode, squeue,
+    types::{Fd, Timespec},
+    IoUring,
+};
+use slab::Slab;
+use socket2::{Domain, SockAddr, Socket, Type};
+use tracing::{debug, trace};
+
+use super::{
+    bind_listeners,
+    event::{ConnectionId, Direction, EventSink, MapiEvent},
+    forward::{resolve_server, Copying, Dialer},
+    network::{
+        bind_tcp, bind_unix, bind_unix_std, is_out_of_fds, server_socket, unbind_unix, Addr,
+        ListenOptions, MonetAddr, TrafficMarking, DEFAULT_BACKLOG,
+    },
+    rewrite::Rewriter,
+    Error, Result,
+};
+
+/// The UringProxy listens on a number of sockets, forwards the connections to
+/// another server and reports on the traffic as a series of [MapiEvent]s,
+/// just like [Proxy](super::Proxy) but using io_uring.
+pub struct UringProxy {
+    /// Configured address to forward to.
+    forward_addr: MonetAddr,
+    /// How to find and connect to it, see [ListenOptions::resolver] and
+    /// [UringProxy::set_traffic_marking].
+    dialer: Dialer,
+    /// All IO goes through this.
+    ring: Ring,
+    /// The bound listeners. [Op::Accept] refers to them by index.
+    listeners: Vec<Listener>,
+    /// The connections being forwarded. The other [Op]s refer to them by key.
+    conns: Slab<Conn>,
+    /// Iterator that yields fresh connection id's.
+    ids: RangeFrom<usize>,
+    /// This is where events are reported.
+    event_sink: EventSink,
+    /// If set, all messages are passed through this before being forwarded.
+    rewriter: Option<Box<dyn Rewriter>>,
+    /// An eventfd, written to by the shutdown trigger.
+    shutdown: Arc<File>,
+    /// Where [Op::Shutdown] reads the eventfd counter into.
+    shutdown_buf: Box<[u8; 8]>,
+    /// The timeout of [Op::Retry].
+    retry_interval: Box<Timespec>,
+    /// Whether an [Op::Retry] is in flight.
+    retrying: bool,
+}
+
+impl UringProxy {
+    /// Size of the submission queue.
+    const RING_SIZE: u32 = 256;
+
+    /// How long to wait before accepting again after running out of file
+    /// descriptors, if no connection has been closed in the mean time.
+    const RETRY_ACCEPT_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Create a new UringProxy which listens on the sockets denoted by
+    /// `listen_addr`. Returns an error if io_uring is not available or the
+    /// listen sockets could not be bound. Use [UringProxy::run] to start
+    /// forwarding.
+    pub fn new(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<UringProxy> {
+        Self::with_backlog(listen_addr, forward_addr, DEFAULT_BACKLOG, event_handler)
+    }
+
+    /// Like [UringProxy::new] but with the given listen backlog.
+    pub fn with_backlog(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        backlog: i32,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<UringProxy> {
+        let options = ListenOptions {
+            backlog,
+            ..ListenOptions::default()
+        };
+        Self::with_options(listen_addr, forward_addr, options, event_handler)
+    }
+
+    /// Like [UringProxy::new] but with the given [ListenOptions].
+    pub fn with_options(
+        listen_addr: MonetAddr,
+        forward_addr: MonetAddr,
+        options: ListenOptions,
+        event_handler: impl FnMut(MapiEvent) + 'static + Send,
+    ) -> Result<UringProxy> {
+        let ring = IoUring::new(Self::RING_SIZE).map_err(Error::CreateRing)?;
+        let shutdown = eventfd().map_err(Error::CreateRing)?;
+        let retry_interval = Timespec::new().sec(Self::RETRY_ACCEPT_INTERVAL.as_secs());
+        let mut proxy = UringProxy {
+            forward_addr,
+            dialer: Dialer {
+                resolver: Arc::clone(&options.resolver),
+                marking: TrafficMarking::default(),
+            },
+            ring: Ring { ring, in_flight: 0 },
+            listeners: vec![],
+            conns: Slab::new(),
+            ids: 10..,
+            event_sink: EventSink::new(event_handler),
+            rewriter: None,
+            shutdown: Arc::new(shutdown.into()),
+            shutdown_buf: Box::new([0; 8]),
+            retry_interval: Box::new(retry_interval),
+            retrying: false,
+        };
+
+        let listeners = bind_listeners(&listen_addr, &options, &mut proxy.event_sink, |addr| {
+            Listener::bind(addr.clone(), &options)
+        })?;
+        proxy.listeners = listeners.into_iter().map(|(_, l)| l).collect();
+
+        Ok(proxy)
+    }
+
+    /// Pass all messages through the given [Rewriter] before forwarding them.
+    pub fn set_rewriter(&mut self, rewriter: Box<dyn Rewriter>) {
+        self.rewriter = Some(rewriter);
+    }
+
+    /// Tag the TCP connections to the server with `marking`.
+    pub fn set_traffic_marking(&mut self, marking: TrafficMarking) {
+        self.dialer.marking = marking;
+    }
+
+    /// Whether to emit [MapiEvent::Data] events. Unlike the mio based
+    /// [Proxy](super::Proxy), this proxy does not use splice(2) when they are
+    /// not needed.
+    pub fn set_report_data(&mut self, report_data: bool) {
+        self.event_sink.set_report_data(report_data);
+    }
+
+    /// Obtain a shutdown trigger that when called, will end [UringProxy::run].
+    pub fn get_shutdown_trigger(&mut self) -> Box<dyn Fn() + Send + Sync + 'static> {
+        let eventfd = Arc::clone(&self.shutdown);
+        Box::new(move || {
+            if let Err(e) = (&*eventfd).write_all(&1u64.to_ne_bytes()) {
+                eprintln!("Failed to shut down the proxy: {e}");
+            }
+        })
+    }
+
+    /// Run the proxy until the shutdown trigger fires or one of the listeners
+    /// fails.
+    pub fn run(&mut self) -> Result<()> {
+        let result = self.run_until_shutdown();
+        let cancelled = self.cancel_everything();
+        result.and(cancelled)
+    }
+
+    fn run_until_shutdown(&mut self) -> Result<()> {
+        let fd = Fd(self.shutdown.as_raw_fd());
+        let read = opcode::Read::new(fd, self.shutdown_buf.as_mut_ptr(), 8).build();
+        // SAFETY: the buffer lives as long as the proxy
+        unsafe { self.ring.push(read, Op::Shutdown)? };
+        for n in 0..self.listeners.len() {
+            self.submit_accept(n)?;
+        }
+
+        let mut completed = vec![];
+        let mut stopping = false;
+        while !stopping {
+            self.ring.wait()?;
+            completed.extend(
+                self.ring
+                    .ring
+                    .completion()
+                    .map(|c| (c.user_data(), c.result())),
+            );
+            trace!(ncompleted = completed.len(), "ring woke up");
+            self.ring.in_flight -= completed.len();
+            for (user_data, res) in completed.drain(..) {
+                let op = Op::decode(user_data);
+                trace!(?op, res, "completed");
+                match op {
+                    Op::Shutdown => {
+                        debug!("shutdown triggered");
+                        stopping = true;
+                    }
+                    Op::Retry => {
+                        self.retrying = false;
+                        self.retry_paused()?;
+                    }
+                    Op::Cancel => {}
+                    Op::Accept(n) => self.handle_accept(n, res)?,
+                    Op::Connect(key) => self.handle_connect(key, res)?,
+                    Op::Recv(key, direction) => self.handle_recv(key, direction, res)?,
+                    Op::Send(key, direction) => self.handle_send(key, direction, res)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn submit_accept(&mut self, n: usize) -> Result<()> {
+        let listener = &mut self.listeners[n];
+        let (storage, len) = &mut *listener.peer;
+        *len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let fd = Fd(listener.socket.as_raw_fd());
+        let accept = opcode::Accept::new(fd, (storage as *mut libc::sockaddr_storage).cast(), len)
+            .flags(libc::SOCK_CLOEXEC)
+            .build();
+        listener.accepting = true;
+        // SAFETY: the address buffer is boxed and lives as long as the listener
+        unsafe { self.ring.push(accept, Op::Accept(n)) }
+    }
+
+    fn handle_accept(&mut self, n: usize, res: i32) -> Result<()> {
+        let listener = &mut self.listeners[n];
+        listener.accepting = false;
+        let local = listener.addr.clone();
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            if is_out_of_fds(&err) {
+                debug!(%local, "out of file descriptors, pausing listener");
+                if !listener.paused {
+                    listener.paused = true;
+                    self.event_sink.emit_accept_paused(local, err);
+                }
+                return self.start_retry_timer();
+            }
+            debug!(%local, error = %err, "accept failed");
+            return Err(Error::Accept(local, err));
+        }
+
+        // SAFETY: the kernel has just handed us this file descriptor
+        let client = unsafe { Socket::from_raw_fd(res) };
+        let peer = listener.peer_addr();
+        if listener.paused {
+            listener.paused = false;
+            debug!(%local, "resuming listener");
+            self.event_sink.emit_accept_resumed(local.clone());
+        }
+        self.submit_accept(n)?;
+
+        let id = ConnectionId::new(self.ids.next().unwrap());
+        let client_is_unix = local.is_unix();
+        let mut sink = self.event_sink.connection_sink(id);
+        sink.emit_incoming(local, peer);
+        let addrs = match resolve_server(&mut sink, &self.forward_addr, &self.dialer.resolver) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                sink.emit_aborted(e);
+                return Ok(());
+            }
+        };
+
+        let entry = self.conns.vacant_entry();
+        let key = entry.key();
+        debug!(%id, slot = key, "starting connection");
+        entry.insert(Conn {
+            id,
+            client,
+            client_is_unix,
+            server: None,
+            addrs: addrs.into_iter(),
+            connecting: None,
+            lanes: None,
+            busy: [false; 2],
+            in_flight: 0,
+            error: None,
+        });
+        self.connect_next(key)
+    }
+
+    /// Start connecting to the next server address. If there are none left,
+    /// the connection fails.
+    fn connect_next(&mut self, key: usize) -> Result<()> {
+        let marking = self.dialer.marking;
+        let conn = &mut self.conns[key];
+        let mut sink = self.event_sink.connection_sink(conn.id);
+        for addr in conn.addrs.by_ref() {
+            debug!(id = %conn.id, %addr, "connecting");
+            sink.emit_connecting(addr.clone());
+            let (server, sockaddr) = match new_socket(&addr, marking) {
+                Ok(x) => x,
+                Err(e) => {
+                    sink.emit_connect_failed(addr.to_string(), true, e);
+                    continue;
+                }
+            };
+            let sockaddr = Box::new(sockaddr);
+            let fd = Fd(server.as_raw_fd());
+            let connect = opcode::Connect::new(fd, sockaddr.as_ptr(), sockaddr.len()).build();
+            conn.server = Some(server);
+            conn.connecting = Some((addr, sockaddr));
+            conn.in_flight += 1;
+            // SAFETY: the address is kept in conn.connecting until it completes
+            return unsafe { self.ring.push(connect, Op::Connect(key)) };
+        }
+        self.fail(key, Error::Connect)
+    }
+
+    fn handle_connect(&mut self, key: usize, res: i32) -> Result<()> {
+        let conn = &mut self.conns[key];
+        conn.in_flight -= 1;
+        let (addr, _) = conn.connecting.take().unwrap();
+        if conn.error.is_some() {
+            return self.finish_if_done(key);
+        }
+
+        let mut sink = self.event_sink.connection_sink(conn.id);
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            sink.emit_connect_failed(addr.to_string(), false, err);
+            conn.server = None;
+            return self.connect_next(key);
+        }
+
+        debug!(id = %conn.id, "connected");
+        sink.emit_connected(addr.clone());
+        let server = conn.server.as_ref().unwrap();
+        let sockets = [
+            ("client", &conn.client, conn.client_is_unix),
+            ("server", server, addr.is_unix()),
+        ];
+        let nodelay = sockets
+            .into_iter()
+            .filter(|(_, _, is_unix)| !is_unix)
+            .try_for_each(|(side, sock, _)| {
+                sock.set_nodelay(true).map_err(|err| Error::Forward {
+                    doing: "setting nodelay",
+                    side,
+                    err,
+                })
+            });
+        if let Err(e) = nodelay {
+            return self.fail(key, e);
+        }
+
+        let rewriting = self.rewriter.is_some();
+        conn.lanes = Some([
+            Copying::new(conn.client_is_unix, addr.is_unix(), rewriting),
+            Copying::new(false, false, rewriting),
+        ]);
+        self.pump(key, Direction::Upstream)?;
+        self.pump(key, Direction::Downstream)?;
+        self.finish_if_done(key)
+    }
+
+    /// Submit the next receive or send for the given direction of the
+    /// connection, unless one is still in flight.
+    fn pump(&mut self, key: usize, direction: Direction) -> Result<()> {
+        let conn = &mut self.conns[key];
+        let i = lane(direction);
+        if conn.error.is_some() || conn.busy[i] {
+            return Ok(());
+        }
+        let (Some(lanes), Some(server)) = (&mut conn.lanes, &conn.server) else {
+            return Ok(());
+        };
+        let (rd, wr) = match direction {
+            Direction::Upstream => (&conn.client, server),
+            Direction::Downstream => (server, &conn.client),
+        };
+        let copying = &mut lanes[i];
+        let mut sink = self.event_sink.connection_sink(conn.id);
+
+        let pending = copying.pending();
+        if !pending.is_empty() {
+            let len = u32::try_from(pending.len()).unwrap_or(u32::MAX);
+            let send = opcode::Send::new(Fd(wr.as_raw_fd()), pending.as_ptr(), len)
+                .flags(libc::MSG_NOSIGNAL)
+                .build();
+            conn.busy[i] = true;
+            conn.in_flight += 1;
+            // SAFETY: the pending data is left alone until the send completes
+            return unsafe { self.ring.push(send, Op::Send(key, direction)) };
+        }
+
+        match copying.wind_down(direction, &mut sink) {
+            Some(how @ Shutdown::Write) => {
+                let _ = wr.shutdown(how);
+            }
+            Some(how) => {
+                let _ = rd.shutdown(how);
+            }
+            None => {}
+        }
+
+        if let Some(space) = copying.read_space() {
+            let len = u32::try_from(space.len()).unwrap_or(u32::MAX);
+            let recv = opcode::Recv::new(Fd(rd.as_raw_fd()), space.as_mut_ptr(), len).build();
+            conn.busy[i] = true;
+            conn.in_flight += 1;
+            // SAFETY: the buffer is left alone until the receive completes
+            return unsafe { self.ring.push(recv, Op::Recv(key, direction)) };
+        }
+
+        Ok(())
+    }
+
+    fn handle_recv(&mut self, key: usize, direction: Direction, res: i32) -> Result<()> {
+        let conn = &mut self.conns[key];
+        let i = lane(direction);
+        conn.in_flight -= 1;
+        conn.busy[i] = false;
+        if conn.error.is_some() {
+            return self.finish_if_done(key);
+        }
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            let side = direction.sender();
+            return self.fail(
+                key,
+                Error::Forward {
+                    doing: "reading",
+                    side,
+                    err,
+                },
+            );
+        }
+
+        let n = res as usize;
+        let copying = &mut conn.lanes.as_mut().unwrap()[i];
+        let mut sink = self.event_sink.connection_sink(conn.id);
+        let received = copying
+            .received(n, direction, &mut sink, &mut self.rewriter)
+            .and_then(|()| copying.strip_unix_zero());
+        if let Err(e) = received {
+            return self.fail(key, e);
+        }
+        if n == 0 {
+            let _ = conn.sockets(direction).0.shutdown(Shutdown::Read);
+        }
+
+        self.pump(key, direction)?;
+        self.finish_if_done(key)
+    }
+
+    fn handle_send(&mut self, key: usize, direction: Direction, res: i32) -> Result<()> {
+        let conn = &mut self.conns[key];
+        let i = lane(direction);
+        conn.in_flight -= 1;
+        conn.busy[i] = false;
+        if conn.error.is_some() {
+            return self.finish_if_done(key);
+        }
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            let side = direction.receiver();
+            return self.fail(
+                key,
+                Error::Forward {
+                    doing: "writing",
+                    side,
+                    err,
+                },
+            );
+        }
+
+        let n = res as usize;
+        let copying = &mut conn.lanes.as_mut().unwrap()[i];
+        let mut sink = self.event_sink.connection_sink(conn.id);
+        copying.sent(n, direction, &mut sink);
+        if n == 0 {
+            let _ = conn.sockets(direction).1.shutdown(Shutdown::Write);
+        }
+
+        self.pump(key, direction)?;
+        self.finish_if_done(key)
+    }
+
+    /// Give up on the connection. It is removed once the operations in flight
+    /// have completed.
+    fn fail(&mut self, key: usize, error: Error) -> Result<()> {
+        let conn = &mut self.conns[key];
+        if conn.error.is_none() {
+            debug!(id = %conn.id, %error, "connection failed");
+            conn.error = Some(error);
+            conn.close(key, &mut self.ring)?;
+        }
+        self.finish_if_done(key)
+    }
+
+    /// Remove the connection if it has nothing left to do.
+    fn finish_if_done(&mut self, key: usize) -> Result<()> {
+        let conn = &mut self.conns[key];
+        if conn.in_flight > 0 {
+            return Ok(());
+        }
+        let mut sink = self.event_sink.connection_sink(conn.id);
+        if let Some(error) = conn.error.take() {
+            sink.emit_aborted(error);
+        } else if conn
+            .lanes
+            .as_ref()
+            .is_some_and(|lanes| lanes.iter().all(Copying::finished))
+        {
+            sink.emit_end();
+        } else {
+            return Ok(());
+        }
+        debug!(id = %conn.id, slot = key, "removing connection");
+        self.conns.remove(key);
+        self.retry_paused()
+    }
+
+    fn start_retry_timer(&mut self) -> Result<()> {
+        if self.retrying {
+            return Ok(());
+        }
+        self.retrying = true;
+        let timeout = opcode::Timeout::new(&*self.retry_interval).build();
+        // SAFETY: the timespec is boxed and lives as long as the proxy
+        unsafe { self.ring.push(timeout, Op::Retry) }
+    }
+
+    /// Let the paused listeners try to accept again.
+    fn retry_paused(&mut self) -> Result<()> {
+        for n in 0..self.listeners.len() {
+            let listener = &self.listeners[n];
+            if listener.paused && !listener.accepting {
+                self.submit_accept(n)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cancel everything that is in flight and wait for it to complete, so
+    /// the kernel is done with our buffers before they are freed.
+    fn cancel_everything(&mut self) -> Result<()> {
+        for n in 0..self.listeners.len() {
+            if self.listeners[n].accepting {
+                self.ring.cancel(Op::Accept(n))?;
+            }
+        }
+        if self.retrying {
+            self.ring.cancel(Op::Retry)?;
+        }
+        for (key, conn) in &mut self.conns {
+            conn.close(key, &mut self.ring)?;
+        }
+        debug!(in_flight = self.ring.in_flight, "waiting for cancellations");
+        while self.ring.in_flight > 0 {
+            self.ring.wait()?;
+            self.ring.in_flight -= self.ring.ring.completion().count();
+        }
+        Ok(())
+    }
+}
+
+/// The [IoUring] and the number of operations submitted to it that have not
+/// completed yet.
+struct Ring {
+    ring: IoUring,
+    in_flight: usize,
+}
+
+impl Ring {
+    /// Queue an operation for submission. The caller must make sure that the
+    /// memory it refers to stays valid until its completion has come in.
+    unsafe fn push(&mut self, entry: squeue::Entry, op: Op) -> Result<()> {
+        let entry = entry.user_data(op.encode());
+        while self.ring.submission().push(&entry).is_err() {
+            // the submission queue is full, hand it to the kernel first
+            self.ring.submit().map_err(Error::Poll)?;
+        }
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Cancel the operation, if it is still in flight.
+    fn cancel(&mut self, op: Op) -> Result<()> {
+        let cancel = opcode::AsyncCancel::new(op.encode()).build();
+        // SAFETY: refers to no memory
+        unsafe { self.push(cancel, Op::Cancel) }
+    }
+
+    /// Submit the queued operations and wait for at least one completion.
+    fn wait(&mut self) -> Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(()),
+            // the completion queue overflowed, the caller will drain it
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(()),
+            Err(e) => Err(Error::Poll(e)),
+        }
+    }
+}
+
+/// What an operation submitted to the ring is for. Encoded in its user_data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    /// Read from the shutdown eventfd
+    Shutdown,
+    /// Timeout after which paused listeners try again
+    Retry,
+    /// Cancellation of another operation
+    Cancel,
+    /// Accept on the listener with this index
+    Accept(usize),
+    /// Connect to the server on behalf of this connection
+    Connect(usize),
+    /// Receive from the sender in this direction of the connection
+    Recv(usize, Direction),
+    /// Send to the receiver in this direction of the connection
+    Send(usize, Direction),
+}
+
+impl Op {
+    fn encode(self) -> u64 {
+        let (kind, n) = match self {
+            Op::Shutdown => (0, 0),
+            Op::Retry => (1, 0),
+            Op::Cancel => (2, 0),
+            Op::Accept(n) => (3, n),
+            Op::Connect(n) => (4, n),
+            Op::Recv(n, Direction::Upstream) => (5, n),
+            Op::Recv(n, Direction::Downstream) => (6, n),
+            Op::Send(n, Direction::Upstream) => (7, n),
+            Op::Send(n, Direction::Downstream) => (8, n),
+        };
+        (n as u64) << 4 | kind
+    }
+
+    fn decode(user_data: u64) -> Op {
+        let n = (user_data >> 4) as usize;
+        match user_data & 0xF {
+            0 => Op::Shutdown,
+            1 => Op::Retry,
+            2 => Op::Cancel,
+            3 => Op::Accept(n),
+            4 => Op::Connect(n),
+            5 => Op::Recv(n, Direction::Upstream),
+            6 => Op::Recv(n, Direction::Downstream),
+            7 => Op::Send(n, Direction::Upstream),
+            8 => Op::Send(n, Direction::Downstream),
+            other => panic!("invalid operation kind {other} in user_data"),
+        }
+    }
+}
+
+struct Listener {
+    addr: Addr,
+    socket: Socket,
+    /// Where [Op::Accept] stores the address of the client.
+    peer: Box<(libc::sockaddr_storage, libc::socklen_t)>,
+    /// Whether an [Op::Accept] is in flight.
+    accepting: bool,
+    /// Set while accepting fails because we're out of file descriptors.
+    paused: bool,
+}
+
+impl Listener {
+    fn bind(addr: Addr, options: &ListenOptions) -> io::Result<Listener> {
+        let backlog = options.backlog;
+        let socket = match &addr {
+            Addr::Tcp(a) => bind_tcp(a, backlog)?.into(),
+            Addr::Unix(path) => {
+                bind_unix(path, options.force_bind, |p| bind_unix_std(p, backlog))?.into()
+            }
+        };
+        // SAFETY: all zeroes is a valid sockaddr_storage
+        let storage = unsafe { mem::zeroed() };
+        Ok(Listener {
+            addr,
+            socket,
+            peer: Box::new((storage, 0)),
+            accepting: false,
+            paused: false,
+        })
+    }
+
+    /// The client address filled in by the last [Op::Accept].
+    fn peer_addr(&self) -> Addr {
+        let (storage, len) = *self.peer;
+        // SAFETY: the kernel has initialized `len` bytes of it
+        let sockaddr = unsafe { SockAddr::new(storage, len) };
+        match sockaddr.as_socket() {
+            Some(a) => Addr::Tcp(a),
+            None => sockaddr
+                .as_pathname()
+                .unwrap_or(Path::new("<UNNAMED>"))
+                .to_path_buf()
+                .into(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Addr::Unix(path) = &self.addr {
+            unbind_unix(path);
+        }
+    }
+}
+
+struct Conn {
+    id: ConnectionId,
+    client: Socket,
+    client_is_unix: bool,
+    /// Set once we start connecting
+    server: Option<Socket>,
+    /// Server addresses still to try if connecting fails
+    addrs: vec::IntoIter<Addr>,
+    /// The address [Op::Connect] is connecting to. It must stay put until
+    /// the connect completes.
+    connecting: Option<(Addr, Box<SockAddr>)>,
+    /// The state of the upstream and downstream direction, once connected
+    lanes: Option<[Copying; 2]>,
+    /// Whether an [Op::Recv] or [Op::Send] is in flight, per direction
+    busy: [bool; 2],
+    /// Number of operations in flight on behalf of this connection
+    in_flight: usize,
+    /// Set when forwarding failed. The connection is removed as soon as
+    /// nothing is in flight anymore.
+    error: Option<Error>,
+}
+
+impl Conn {
+    /// The sending and the receiving socket for the given direction.
+    fn sockets(&self, direction: Direction) -> (&Socket, &Socket) {
+        let server = self.server.as_ref().unwrap();
+        match direction {
+            Direction::Upstream => (&self.client, server),
+            Direction::Downstream => (server, &self.client),
+        }
+    }
+
+    /// Shut down the sockets and cancel whatever is in flight.
+    fn close(&self, key: usize, ring: &mut Ring) -> Result<()> {
+        let _ = self.client.shutdown(Shutdown::Both);
+        if let Some(server) = &self.server {
+            let _ = server.shutdown(Shutdown::Both);
+        }
+        if self.connecting.is_some() {
+            ring.cancel(Op::Connect(key))?;
+        }
+        for direction in [Direction::Upstream, Direction::Downstream] {
+            if self.busy[lane(direction)] {
+                ring.cancel(Op::Recv(key, direction))?;
+                ring.cancel(Op::Send(key, direction))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lane(direction: Direction) -> usize {
+    match direction {
+        Direction::Upstream => 0,
+        Direction::Downstream => 1,
+    }
+}
+
+/// Create a socket to connect to the given address, see [server_socket].
+fn new_socket(addr: &Addr, marking: TrafficMarking) -> io::Result<(Socket, SockAddr)> {
+    match addr {
+        Addr::Tcp(a) => {
+            let socket = server_socket(a, marking)?;
+            // io_uring does the waiting
+            socket.set_nonblocking(false)?;
+            Ok((socket, SockAddr::from(*a)))
+        }
+        Addr::Unix(path) => {
+            let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+            Ok((socket, SockAddr::unix(path)?))
+        }
+    }
+}
+
+fn eventfd() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: eventfd has just given us this file descriptor
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
@@ -1,19 +1,557 @@
 use core::fmt;
 use std::{
+    collections::{HashMap, VecDeque},
     fmt::Display,
     io::{self, BufWriter, Write},
     mem,
     time::{Duration, Instant},
 };
 
-use crate::proxy::event::{ConnectionId, Direction};
+use crate::{
+    clock::{TimeSource, WallClock},
+    proxy::event::{ConnectionId, Direction},
+};
 
 pub struct Renderer {
     colored: bool,
-    last_time: Option<Instant>,
-    out: BufWriter<Box<dyn io::Write + 'static + Send>>,
+    time: TrackTime,
+    out: Sink,
     current_style: Style,
     at_start: Option<Style>, // if Some(s), we're at line start, style to be reset to s
+    /// Set in deterministic mode, maps connection ids to the ids shown
+    stable_ids: Option<HashMap<ConnectionId, ConnectionId>>,
+    /// Shown after the connection id, see [Renderer::set_label]
+    labels: HashMap<ConnectionId, String>,
+    /// Shown before every connection id, see [Renderer::set_source_name]
+    source: Option<String>,
+    frames: Frames,
+    theme: Theme,
+    depth: ColorDepth,
+    /// Direction of the frame being drawn, for [Frames::Arrows]
+    frame_direction: Option<Direction>,
+    /// Connection of the frame being drawn, picks its hue
+    frame_id: Option<ConnectionId>,
+    /// Number of lines shown at the start and at the end of each frame, see
+    /// [Renderer::set_brief]
+    brief: Brief,
+    /// The setting of [Renderer::brief] for the frame being drawn
+    frame_brief: Option<usize>,
+    /// Number of bytes per line of a hex dump
+    hex_width: usize,
+    glyphs: Glyphs,
+    /// Width of the terminal, if the output goes to one
+    width: Option<usize>,
+    /// The part of the frame being drawn, abbreviated separately in brief
+    /// mode
+    section: Section,
+    /// Number of lines started in the current section
+    section_lines: usize,
+    tail: Tail,
+}
+
+/// In brief mode the header of a result set is always shown in full, while
+/// its rows and any other text are abbreviated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    /// The `&` and `%` lines at the start of a result set
+    ResultHeader,
+    /// The `[` lines of a result set
+    Rows,
+    Other,
+}
+
+impl Section {
+    fn of(line_start: &[u8]) -> Section {
+        match line_start.first() {
+            Some(b'&' | b'%') => Section::ResultHeader,
+            Some(b'[') => Section::Rows,
+            _ => Section::Other,
+        }
+    }
+}
+
+/// Passes the output on, except while a line is being captured for the
+/// [Tail].
+struct Sink {
+    out: BufWriter<Box<dyn io::Write + 'static + Send>>,
+    capture: Option<TailLine>,
+}
+
+impl io::Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.capture {
+            Some(line) => {
+                line.rendered.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            None => self.out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// In brief mode, holds the last lines of the frame being drawn. Lines that
+/// drop out of it are skipped, only their number is kept.
+#[derive(Debug, Default)]
+struct Tail {
+    lines: VecDeque<TailLine>,
+    skipped_lines: usize,
+    skipped_bytes: usize,
+    skipped_rows: usize,
+}
+
+#[derive(Debug, Default)]
+struct TailLine {
+    /// The line as it would have been written, including escape sequences
+    rendered: Vec<u8>,
+    /// Number of bytes of the frame shown on the line
+    bytes: usize,
+    /// Whether the line looks like a row of a result set
+    row: Option<bool>,
+}
+
+impl Tail {
+    fn push(&mut self, line: TailLine, keep: usize) {
+        self.lines.push_back(line);
+        while self.lines.len() > keep {
+            let skipped = self.lines.pop_front().unwrap();
+            self.skipped_lines += 1;
+            self.skipped_bytes += skipped.bytes;
+            self.skipped_rows += usize::from(skipped.row == Some(true));
+        }
+    }
+
+    /// Describes what was skipped, for example "skipped 12 lines, 480 bytes,
+    /// 12 rows".
+    fn summary(&self) -> Option<String> {
+        if self.skipped_lines == 0 {
+            return None;
+        }
+        let mut summary = format!("skipped {} lines", self.skipped_lines);
+        if self.skipped_bytes > 0 {
+            summary += &format!(", {} bytes", self.skipped_bytes);
+        }
+        if self.skipped_rows > 0 {
+            summary += &format!(", {} rows", self.skipped_rows);
+        }
+        Some(summary)
+    }
+}
+
+/// How the times announced in the output are shown, see
+/// [Renderer::set_time_zone].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeZone {
+    #[default]
+    Utc,
+    /// This many seconds east of UTC
+    Offset(i32),
+}
+
+impl TimeZone {
+    /// Accepts 'utc' or an offset such as '+02:00', '-0530' or '+2'. Finding
+    /// out the local offset is left to the caller.
+    pub fn from_setting(value: &str) -> Option<TimeZone> {
+        if value.eq_ignore_ascii_case("utc") || value == "Z" {
+            return Some(TimeZone::Utc);
+        }
+        let (sign, rest) = match value.as_bytes().first()? {
+            b'+' => (1, &value[1..]),
+            b'-' => (-1, &value[1..]),
+            _ => return None,
+        };
+        let (hours, minutes) = match rest.split_once(':') {
+            Some((h, m)) => (h, m),
+            None if rest.len() == 4 => rest.split_at(2),
+            None => (rest, "0"),
+        };
+        let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !all_digits(hours) || !all_digits(minutes) {
+            return None;
+        }
+        let hours: i32 = hours.parse().ok()?;
+        let minutes: i32 = minutes.parse().ok()?;
+        if hours > 14 || minutes >= 60 {
+            return None;
+        }
+        Some(TimeZone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+
+    /// Format a time since the Unix epoch as date and time of day in this
+    /// time zone.
+    pub fn format(&self, t: Duration) -> String {
+        let offset = match self {
+            TimeZone::Utc => 0,
+            TimeZone::Offset(offset) => *offset as i64,
+        };
+        let secs = t.as_secs() as i64 + offset;
+        let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        let zone = match self {
+            TimeZone::Utc => "UTC".to_string(),
+            TimeZone::Offset(_) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                let offset = offset.abs();
+                format!("{sign}{:02}:{:02}", offset / 3600, offset / 60 % 60)
+            }
+        };
+        format!(
+            "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}.{:03} {zone}",
+            rem / 3600,
+            rem / 60 % 60,
+            rem % 60,
+            t.subsec_millis()
+        )
+    }
+}
+
+/// Convert days since the Unix epoch to year, month and day, using Howard
+/// Hinnant's days_from_civil in reverse.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Keeps track of when output was last written, to insert a blank line after
+/// a pause and to announce the time of day now and then.
+struct TrackTime {
+    enabled: bool,
+    /// See [Renderer::set_clock]
+    clock: Box<dyn TimeSource>,
+    zone: TimeZone,
+    /// Pause after which a blank line is inserted, if any
+    separator: Option<Duration>,
+    /// Minimum time between announcements, if any
+    announce: Option<Duration>,
+    last_time: Option<Duration>,
+    /// Also kept when the clock is not steady, see [TimeSource::is_steady]
+    last_instant: Option<Instant>,
+    last_announced: Option<Duration>,
+}
+
+impl TrackTime {
+    const DEFAULT_SEPARATOR: Duration = Duration::from_millis(500);
+    const DEFAULT_ANNOUNCE: Duration = Duration::from_secs(60);
+
+    fn new() -> Self {
+        TrackTime {
+            enabled: true,
+            clock: Box::new(WallClock),
+            zone: TimeZone::Utc,
+            separator: Some(Self::DEFAULT_SEPARATOR),
+            announce: Some(Self::DEFAULT_ANNOUNCE),
+            last_time: None,
+            last_instant: None,
+            last_announced: None,
+        }
+    }
+
+    /// Whether a pause has passed since the last output
+    fn pause(&self) -> bool {
+        let Some(separator) = self.separator else {
+            return false;
+        };
+        if let Some(then) = self.last_instant {
+            return then.elapsed() >= separator;
+        }
+        let Some(then) = self.last_time else {
+            return false;
+        };
+        self.clock
+            .now()
+            .is_some_and(|now| now.saturating_sub(then) >= separator)
+    }
+
+    /// The time to announce before the next output, if any.
+    fn announcement(&mut self) -> Option<String> {
+        let interval = self.announce.filter(|_| self.enabled)?;
+        let now = self.clock.now()?;
+        if let Some(then) = self.last_announced {
+            if now.saturating_sub(then) < interval {
+                return None;
+            }
+        }
+        self.last_announced = Some(now);
+        Some(self.zone.format(now))
+    }
+
+    fn touch(&mut self) {
+        if self.enabled {
+            self.last_time = self.clock.now();
+            // a clock that does not know the time may not have an Instant
+            // either, on wasm32-unknown-unknown
+            self.last_instant = self
+                .last_time
+                .filter(|_| !self.clock.is_steady())
+                .map(|_| Instant::now());
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_time = None;
+        self.last_instant = None;
+        self.last_announced = None;
+    }
+}
+
+/// Bytes that are shown as a substitute, see [Renderer::put_glyph].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Glyph {
+    Newline,
+    Tab,
+    /// Only in hex dumps, text shows spaces as they are
+    Space,
+    Nul,
+    /// Any other byte that cannot be shown
+    Other,
+}
+
+impl Glyph {
+    const ALL: [(Glyph, &'static str); 5] = [
+        (Glyph::Newline, "newline"),
+        (Glyph::Tab, "tab"),
+        (Glyph::Space, "space"),
+        (Glyph::Nul, "nul"),
+        (Glyph::Other, "other"),
+    ];
+}
+
+/// The text shown for each [Glyph], see [Renderer::set_glyphs].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glyphs([String; 5]);
+
+impl Default for Glyphs {
+    fn default() -> Self {
+        Glyphs(["↵", "→", "·", "░", "▒"].map(String::from))
+    }
+}
+
+impl Glyphs {
+    /// Override some of the defaults with a comma separated list of
+    /// `name=TEXT`, for example `newline=$,space=_`. The names are those of
+    /// [Glyph] in lower case. TEXT may be put in single quotes to include
+    /// commas or spaces.
+    pub fn from_setting(value: &str) -> Result<Glyphs, String> {
+        let mut glyphs = Glyphs::default();
+        let mut chars = value.chars().peekable();
+        while chars.peek().is_some() {
+            let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+            let name = name.trim();
+            let Some(&(glyph, _)) = Glyph::ALL.iter().find(|(_, n)| *n == name) else {
+                let names = Glyph::ALL.map(|(_, n)| n).join(", ");
+                return Err(format!("unknown glyph '{name}', expected one of {names}"));
+            };
+            let mut text = String::new();
+            if chars.peek() == Some(&'\'') {
+                chars.next();
+                text.extend(chars.by_ref().take_while(|&c| c != '\''));
+                if chars.peek().is_some_and(|&c| c != ',') {
+                    return Err(format!("expected a comma after the text of '{name}'"));
+                }
+                chars.next();
+            } else {
+                text.extend(chars.by_ref().take_while(|&c| c != ','));
+            }
+            if text.is_empty() {
+                return Err(format!("empty text for '{name}'"));
+            }
+            glyphs.0[glyph as usize] = text;
+        }
+        Ok(glyphs)
+    }
+}
+
+/// How many lines of each frame to show, see [Renderer::set_brief]. Queries
+/// are the frames sent upstream, results those sent downstream. None means
+/// the frames are shown in full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Brief {
+    pub queries: Option<usize>,
+    pub results: Option<usize>,
+}
+
+impl Brief {
+    /// Number of lines kept when no count is given
+    pub const DEFAULT: usize = 5;
+
+    /// The same count for queries and results.
+    pub fn all(n: Option<usize>) -> Brief {
+        Brief {
+            queries: n,
+            results: n,
+        }
+    }
+
+    /// Parse either a single count or `full`, which applies to both kinds,
+    /// or a comma separated list such as `queries=full,results=3`. Kinds
+    /// left out of the list keep [Brief::DEFAULT] lines.
+    pub fn from_setting(value: &str) -> Result<Brief, String> {
+        if !value.contains('=') {
+            return Ok(Brief::all(Self::parse_count(value)?));
+        }
+        let mut brief = Brief::all(Some(Self::DEFAULT));
+        for part in value.split(',') {
+            let Some((kind, count)) = part.split_once('=') else {
+                return Err(format!("expected KIND=COUNT, not '{part}'"));
+            };
+            let n = Self::parse_count(count.trim())?;
+            match kind.trim() {
+                "queries" => brief.queries = n,
+                "results" => brief.results = n,
+                other => {
+                    return Err(format!(
+                        "unknown kind '{other}', expected 'queries' or 'results'"
+                    ))
+                }
+            }
+        }
+        Ok(brief)
+    }
+
+    fn parse_count(value: &str) -> Result<Option<usize>, String> {
+        match value {
+            "full" => Ok(None),
+            _ => match value.parse() {
+                Ok(n @ 1..) => Ok(Some(n)),
+                _ => Err(format!(
+                    "'{value}' must be 'full' or a positive number of lines"
+                )),
+            },
+        }
+    }
+
+    fn for_direction(&self, direction: Direction) -> Option<usize> {
+        match direction {
+            Direction::Upstream => self.queries,
+            Direction::Downstream => self.results,
+        }
+    }
+}
+
+/// How many colors the terminal can show, see [Renderer::set_color_depth].
+/// Beyond the basic 8 colors, every connection gets a hue of its own and
+/// the frame borders are dimmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum ColorDepth {
+    #[default]
+    Basic,
+    /// The xterm 256 color palette
+    Indexed,
+    /// 24 bit RGB
+    TrueColor,
+}
+
+impl ColorDepth {
+    pub fn from_setting(value: &str) -> Option<ColorDepth> {
+        match value {
+            "8" | "basic" => Some(ColorDepth::Basic),
+            "256" => Some(ColorDepth::Indexed),
+            "truecolor" | "24bit" => Some(ColorDepth::TrueColor),
+            _ => None,
+        }
+    }
+
+    /// Guess the capabilities of the terminal from the COLORTERM and TERM
+    /// environment variables, the way most terminal applications do.
+    pub fn detect(colorterm: Option<&str>, term: Option<&str>) -> ColorDepth {
+        if matches!(colorterm, Some("truecolor" | "24bit")) {
+            ColorDepth::TrueColor
+        } else if term.is_some_and(|t| t.contains("256color") || t.ends_with("-direct")) {
+            ColorDepth::Indexed
+        } else {
+            ColorDepth::Basic
+        }
+    }
+}
+
+/// A color as an index in the 256 color palette and as RGB.
+type Color = (u8, [u8; 3]);
+
+/// Colors of the frames of the connections, taken from the Okabe-Ito palette
+/// so they remain distinct with the common forms of color blindness.
+const HUES: [Color; 6] = [
+    (214, [230, 159, 0]),   // orange
+    (74, [86, 180, 233]),   // sky blue
+    (36, [0, 158, 115]),    // bluish green
+    (227, [240, 228, 66]),  // yellow
+    (166, [213, 94, 0]),    // vermillion
+    (175, [204, 121, 167]), // reddish purple
+];
+
+/// Color of the frame borders
+const BORDER: Color = (244, [128, 128, 128]);
+
+/// The colors used for the [Style]s, see [Renderer::set_theme].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Theme {
+    /// Red, green and blue on the terminal's default background
+    #[default]
+    Default,
+    /// Does not rely on telling red from green: errors are shown in reverse
+    /// video, whitespace in yellow and digits in bright blue, letters are not
+    /// colored.
+    Accessible,
+}
+
+impl Theme {
+    pub fn from_setting(value: &str) -> Option<Theme> {
+        match value {
+            "default" => Some(Theme::Default),
+            "accessible" => Some(Theme::Accessible),
+            _ => None,
+        }
+    }
+}
+
+/// How messages and frames are drawn, see [Renderer::set_frames].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frames {
+    /// Use these characters: before one-line messages, at the start of the
+    /// header, at the start of each line in the frame and at the start of the
+    /// footer.
+    Chars([char; 4]),
+    /// Start each line with `>` for upstream and `<` for downstream, or `*`
+    /// if there is no direction. Footers with nothing to say are left out.
+    Arrows,
+}
+
+impl Frames {
+    pub const BOX: Frames = Frames::Chars(['‣', '┌', '│', '└']);
+    pub const ASCII: Frames = Frames::Chars(['*', '+', '|', '+']);
+
+    /// Accepts 'box', 'ascii', 'arrows' or four characters as in
+    /// [Frames::Chars].
+    pub fn from_setting(value: &str) -> Option<Frames> {
+        let frames = match value {
+            "box" => Frames::BOX,
+            "ascii" => Frames::ASCII,
+            "arrows" => Frames::Arrows,
+            _ => {
+                let chars: Vec<char> = value.chars().collect();
+                Frames::Chars(chars.try_into().ok()?)
+            }
+        };
+        Some(frames)
+    }
+
+    fn arrow(direction: Option<Direction>) -> char {
+        match direction {
+            Some(Direction::Upstream) => '>',
+            Some(Direction::Downstream) => '<',
+            None => '*',
+        }
+    }
 }
 
 impl Renderer {
@@ -22,27 +560,173 @@ impl Renderer {
         let buffered = BufWriter::with_capacity(4 * 8192, boxed);
         Renderer {
             colored,
-            out: buffered,
+            out: Sink {
+                out: buffered,
+                capture: None,
+            },
             current_style: Style::Normal,
             at_start: Some(Style::Normal),
-            last_time: None,
+            time: TrackTime::new(),
+            stable_ids: None,
+            labels: HashMap::new(),
+            source: None,
+            frames: Frames::BOX,
+            theme: Theme::Default,
+            depth: ColorDepth::Basic,
+            frame_direction: None,
+            frame_id: None,
+            brief: Brief::default(),
+            frame_brief: None,
+            hex_width: 16,
+            glyphs: Glyphs::default(),
+            width: None,
+            section: Section::Other,
+            section_lines: 0,
+            tail: Tail::default(),
         }
     }
 
-    const THRESHOLD: Duration = Duration::from_millis(500);
+    /// Show this many bytes on each line of a hex dump, 16 or 32.
+    pub fn set_hex_width(&mut self, width: usize) {
+        assert!(width == 16 || width == 32);
+        self.hex_width = width;
+    }
+
+    pub fn hex_width(&self) -> usize {
+        self.hex_width
+    }
+
+    /// Change how newlines, tabs and unprintable bytes are shown.
+    pub fn set_glyphs(&mut self, glyphs: Glyphs) {
+        self.glyphs = glyphs;
+    }
+
+    /// Tell the renderer how wide the terminal is, so rows of result sets
+    /// that do not fit can be folded between columns.
+    pub fn set_width(&mut self, width: Option<usize>) {
+        self.width = width;
+    }
+
+    pub fn width(&self) -> Option<usize> {
+        self.width
+    }
+
+    /// Show only the first and the last few lines of each frame, with
+    /// separate counts for queries and results.
+    pub fn set_brief(&mut self, brief: Brief) {
+        self.brief = brief;
+    }
+
+    /// See [Renderer::set_brief].
+    pub fn brief(&self) -> Brief {
+        self.brief
+    }
+
+    /// Change the colors used when the output is colored.
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// Show the announced times in this time zone, by default UTC.
+    pub fn set_time_zone(&mut self, zone: TimeZone) {
+        self.time.zone = zone;
+    }
+
+    /// Insert a blank line when nothing has been shown for this long, or
+    /// never if None. By default [Renderer::DEFAULT_SEPARATOR].
+    pub fn set_time_separator(&mut self, separator: Option<Duration>) {
+        self.time.separator = separator;
+    }
+
+    /// Announce the time of day at most this often, or never if None. By
+    /// default [Renderer::DEFAULT_ANNOUNCE].
+    pub fn set_time_announce(&mut self, announce: Option<Duration>) {
+        self.time.announce = announce;
+    }
+
+    /// Use more than the basic 8 colors when the output is colored.
+    pub fn set_color_depth(&mut self, depth: ColorDepth) {
+        self.depth = depth;
+    }
+
+    /// Change how messages and frames are drawn, by default [Frames::BOX].
+    pub fn set_frames(&mut self, frames: Frames) {
+        self.frames = frames;
+    }
+
+    pub const DEFAULT_SEPARATOR: Duration = TrackTime::DEFAULT_SEPARATOR;
+    pub const DEFAULT_ANNOUNCE: Duration = TrackTime::DEFAULT_ANNOUNCE;
+    /// Number of the first connection shown in deterministic mode.
+    const FIRST_STABLE_ID: usize = 10;
+
+    /// Make the output depend only on what is rendered, not on when it is
+    /// rendered or on how the connections were numbered. No blank lines are
+    /// inserted after pauses and connections are renumbered in the order in
+    /// which they first appear.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.stable_ids = deterministic.then(HashMap::new);
+        self.time.reset();
+        self.time.enabled = !deterministic;
+    }
+
+    /// Take the time from `clock` instead of the system clock, for example
+    /// the capture time of the packets when reading a pcap file. This
+    /// decides when a blank line is inserted after a pause and which time of
+    /// day is announced.
+    pub fn set_clock(&mut self, clock: impl TimeSource + 'static) {
+        self.time.clock = Box::new(clock);
+        self.time.reset();
+    }
+
+    /// Show the label after the connection id from now on, or stop showing
+    /// a label if None.
+    pub fn set_label(&mut self, id: ConnectionId, label: Option<String>) {
+        match label {
+            Some(label) => self.labels.insert(id, label),
+            None => self.labels.remove(&id),
+        };
+    }
+
+    /// Show the connection ids as for example `fileA#10` rather than `#10`,
+    /// to tell apart the connections of several captures or proxies.
+    pub fn set_source_name(&mut self, name: Option<String>) {
+        self.source = name;
+    }
+
+    fn id_stream(&mut self, id: Option<ConnectionId>, direction: Option<Direction>) -> IdStream {
+        let label = id.and_then(|id| self.labels.get(&id).cloned());
+        let source = id.and(self.source.clone());
+        IdStream(self.shown_id(id), label, direction, source)
+    }
+
+    fn shown_id(&mut self, id: Option<ConnectionId>) -> Option<ConnectionId> {
+        let (Some(id), Some(map)) = (id, &mut self.stable_ids) else {
+            return id;
+        };
+        let next = ConnectionId::new(Self::FIRST_STABLE_ID + map.len());
+        Some(*map.entry(id).or_insert(next))
+    }
 
     fn before(&mut self) -> io::Result<()> {
-        if let Some(then) = self.last_time {
-            let duration = then.elapsed();
-            if duration >= Self::THRESHOLD {
-                writeln!(self.out)?;
-            }
+        if self.time.pause() {
+            writeln!(self.out)?;
+        }
+        if let Some(now) = self.time.announcement() {
+            let mark = match self.frames {
+                Frames::Chars([mark, ..]) => mark,
+                Frames::Arrows => Frames::arrow(None),
+            };
+            self.frame_id = None;
+            self.style(Style::Frame)?;
+            self.write_mark(mark)?;
+            writeln!(self.out, " TIME is {now}")?;
+            self.style(Style::Normal)?;
         }
         Ok(())
     }
 
     fn after(&mut self) {
-        self.last_time = Some(Instant::now());
+        self.time.touch();
     }
 
     pub fn message(
@@ -52,8 +736,15 @@ impl Renderer {
         message: impl Display,
     ) -> io::Result<()> {
         self.before()?;
+        let ids = self.id_stream(id, direction);
+        let mark = match self.frames {
+            Frames::Chars([mark, ..]) => mark,
+            Frames::Arrows => Frames::arrow(direction),
+        };
+        self.frame_id = ids.0;
         self.style(Style::Frame)?;
-        writeln!(self.out, "‣{} {message}", IdStream::from((id, direction)))?;
+        self.write_mark(mark)?;
+        writeln!(self.out, "{ids} {message}")?;
         self.style(Style::Normal)?;
         self.out.flush()?;
         self.after();
@@ -67,8 +758,17 @@ impl Renderer {
         items: &[&dyn fmt::Display],
     ) -> io::Result<()> {
         self.before()?;
+        let ids = self.id_stream(Some(id), Some(direction));
+        self.frame_direction = Some(direction);
+        self.frame_brief = self.brief.for_direction(direction);
+        self.frame_id = ids.0;
+        let mark = match self.frames {
+            Frames::Chars([_, mark, ..]) => mark,
+            Frames::Arrows => Frames::arrow(Some(direction)),
+        };
         let old_style = self.style(Style::Frame)?;
-        write!(self.out, "┌{}", IdStream::from((id, direction)))?;
+        self.write_mark(mark)?;
+        write!(self.out, "{ids}")?;
         let mut sep = " ";
         for item in items {
             write!(self.out, "{sep}{item}")?;
@@ -76,6 +776,9 @@ impl Renderer {
         }
         writeln!(self.out)?;
         self.at_start = Some(old_style);
+        self.section = Section::Other;
+        self.section_lines = 0;
+        self.tail = Tail::default();
         assert_eq!(self.current_style, Style::Frame);
         Ok(())
     }
@@ -83,13 +786,21 @@ impl Renderer {
     pub fn footer(&mut self, items: &[&dyn fmt::Display]) -> io::Result<()> {
         self.clear_line()?;
         assert_eq!(self.current_style, Style::Frame);
-        write!(self.out, "└")?;
-        let mut sep = " ";
-        for item in items {
-            write!(self.out, "{sep}{item}")?;
-            sep = ", ";
+        self.end_section()?;
+        let mark = match self.frames {
+            Frames::Chars([.., mark]) => Some(mark),
+            Frames::Arrows if items.is_empty() => None,
+            Frames::Arrows => Some(Frames::arrow(self.frame_direction)),
+        };
+        if let Some(mark) = mark {
+            self.write_mark(mark)?;
+            let mut sep = " ";
+            for item in items {
+                write!(self.out, "{sep}{item}")?;
+                sep = ", ";
+            }
+            writeln!(self.out)?;
         }
-        writeln!(self.out)?;
         self.style(Style::Normal)?;
         self.out.flush()?;
         self.after();
@@ -99,14 +810,79 @@ impl Renderer {
     pub fn put(&mut self, data: impl AsRef<[u8]>) -> io::Result<()> {
         if let Some(style) = self.at_start {
             assert_eq!(self.current_style, Style::Frame);
-            self.out.write_all("│".as_bytes())?;
+            let section = Section::of(data.as_ref());
+            if section != self.section {
+                self.end_section()?;
+                self.section = section;
+            }
+            self.section_lines += 1;
+            if section != Section::ResultHeader
+                && self.frame_brief.is_some_and(|n| self.section_lines > n)
+            {
+                self.out.capture = Some(TailLine::default());
+            }
+            self.write_mark(self.body_mark())?;
             self.style(style)?;
             self.at_start = None;
         }
-        self.out.write_all(data.as_ref())?;
+        let data = data.as_ref();
+        if let Some(line) = &mut self.out.capture {
+            if line.row.is_none() && !data.is_empty() {
+                line.row = Some(data[0] == b'[');
+            }
+        }
+        self.out.write_all(data)?;
+        Ok(())
+    }
+
+    /// In brief mode, show what was skipped in the current section and the
+    /// lines held back for its tail. Must be called at the start of a line.
+    fn end_section(&mut self) -> io::Result<()> {
+        let tail = mem::take(&mut self.tail);
+        self.section_lines = 0;
+        if let Some(summary) = tail.summary() {
+            self.write_mark(self.body_mark())?;
+            writeln!(self.out, " ({summary})")?;
+        }
+        for line in tail.lines {
+            self.out.write_all(&line.rendered)?;
+        }
         Ok(())
     }
 
+    /// Put the text that stands in for a byte that cannot be shown as is.
+    pub fn put_glyph(&mut self, glyph: Glyph) -> io::Result<()> {
+        let text = mem::take(&mut self.glyphs.0[glyph as usize]);
+        let ret = self.put(&text);
+        self.glyphs.0[glyph as usize] = text;
+        ret
+    }
+
+    /// Number of characters shown for the glyph.
+    pub fn glyph_width(&self, glyph: Glyph) -> usize {
+        self.glyphs.0[glyph as usize].chars().count()
+    }
+
+    /// Continue the current line on the next one, after `indent`. Unlike
+    /// with [Renderer::nl], the continuation counts as part of the same
+    /// line, for example in brief mode.
+    pub fn fold(&mut self, indent: &str) -> io::Result<()> {
+        assert!(self.at_start.is_none());
+        let old_style = self.style(Style::Frame)?;
+        writeln!(self.out)?;
+        self.write_mark(self.body_mark())?;
+        self.style(old_style)?;
+        self.out.write_all(indent.as_bytes())
+    }
+
+    /// Tell the renderer the current line shows another `n` bytes of the
+    /// frame, so it can tell how much was skipped in brief mode.
+    pub fn count_bytes(&mut self, n: usize) {
+        if let Some(line) = &mut self.out.capture {
+            line.bytes += n;
+        }
+    }
+
     pub fn clear_line(&mut self) -> io::Result<()> {
         if self.at_start.is_none() {
             self.nl()?;
@@ -118,6 +894,10 @@ impl Renderer {
         let old_style = self.style(Style::Frame)?;
         writeln!(self.out)?;
         self.at_start = Some(old_style);
+        if let Some(line) = self.out.capture.take() {
+            let keep = self.frame_brief.unwrap_or_default();
+            self.tail.push(line, keep);
+        }
         Ok(())
     }
 
@@ -132,17 +912,57 @@ impl Renderer {
         Ok(style)
     }
 
+    /// The frame character at the start of the lines inside a frame.
+    fn body_mark(&self) -> char {
+        match self.frames {
+            Frames::Chars([_, _, mark, _]) => mark,
+            Frames::Arrows => Frames::arrow(self.frame_direction),
+        }
+    }
+
+    /// Write a frame border character, dimmed if the terminal has the colors
+    /// for it. Must be called in [Style::Frame].
+    fn write_mark(&mut self, mark: char) -> io::Result<()> {
+        if !self.colored || self.depth == ColorDepth::Basic {
+            return write!(self.out, "{mark}");
+        }
+        self.write_color(BORDER)?;
+        write!(self.out, "{mark}")?;
+        self.write_style(Style::Frame)
+    }
+
+    fn write_color(&mut self, (index, [r, g, b]): Color) -> io::Result<()> {
+        match self.depth {
+            ColorDepth::Basic => Ok(()),
+            ColorDepth::Indexed => write!(self.out, "\x1b[38;5;{index}m"),
+            ColorDepth::TrueColor => write!(self.out, "\x1b[38;2;{r};{g};{b}m"),
+        }
+    }
+
     fn write_style(&mut self, style: Style) -> io::Result<()> {
+        if style == Style::Frame && self.depth > ColorDepth::Basic {
+            if let Some(id) = self.frame_id {
+                self.out.write_all(b"\x1b[m")?; // NORMAL
+                return self.write_color(HUES[id.as_usize() % HUES.len()]);
+            }
+        }
+
         // Black=30 Red=31 Green=32 Yellow=33 Blue=34 Magenta=35 Cyan=36 White=37
 
-        let escape_sequence = match style {
-            Style::Normal => "",
-            Style::Header => "\u{1b}[1m",          // bold
-            Style::Frame => "\u{1b}[36m",          // cyan
-            Style::Error => "\u{1b}[1m\u{1b}[31m", // bold red
-            Style::Whitespace => "\u{1b}[31m",     // red
-            Style::Digit => "\u{1b}[32m",          // green
-            Style::Letter => "\u{1b}[34m",         // blue
+        let escape_sequence = match (self.theme, style) {
+            (_, Style::Normal) => "",
+            (_, Style::Header) => "\u{1b}[1m", // bold
+            (_, Style::Frame) => "\u{1b}[36m", // cyan
+            (Theme::Default, Style::Error) => "\u{1b}[1m\u{1b}[31m", // bold red
+            (Theme::Default, Style::Whitespace) => "\u{1b}[31m", // red
+            (Theme::Default, Style::Digit) => "\u{1b}[32m", // green
+            (Theme::Default, Style::Letter) => "\u{1b}[34m", // blue
+            (Theme::Default, Style::Special) => "\u{1b}[1m\u{1b}[35m", // bold magenta
+            (Theme::Accessible, Style::Error) => "\u{1b}[1m\u{1b}[7m", // bold reverse
+            (Theme::Accessible, Style::Whitespace) => "\u{1b}[33m", // yellow
+            (Theme::Accessible, Style::Digit) => "\u{1b}[94m", // bright blue
+            (Theme::Accessible, Style::Letter) => "",
+            (Theme::Accessible, Style::Special) => "\u{1b}[1m\u{1b}[4m", // bold underlined
         };
         self.out.write_all(b"\x1b[m")?; // NORMAL
         self.out.write_all(escape_sequence.as_bytes())?;
@@ -150,14 +970,22 @@ impl Renderer {
     }
 }
 
-pub struct IdStream(Option<ConnectionId>, Option<Direction>);
+pub struct IdStream(
+    Option<ConnectionId>,
+    Option<String>,
+    Option<Direction>,
+    Option<String>,
+);
 
 impl fmt::Display for IdStream {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(id) = self.0 {
-            write!(f, " {id}")?;
+            write!(f, " {}", id.in_source(self.3.as_deref()))?;
+        }
+        if let Some(label) = &self.1 {
+            write!(f, " {label}")?;
         }
-        if let Some(dir) = self.1 {
+        if let Some(dir) = self.2 {
             write!(f, " {dir}")?;
         }
         Ok(())
@@ -167,14 +995,14 @@ impl fmt::Display for IdStream {
 impl From<(ConnectionId, Direction)> for IdStream {
     fn from(value: (ConnectionId, Direction)) -> Self {
         let (id, dir) = value;
-        IdStream(Some(id), Some(dir))
+        IdStream(Some(id), None, Some(dir), None)
     }
 }
 
 impl From<(Option<ConnectionId>, Option<Direction>)> for IdStream {
     fn from(value: (Option<ConnectionId>, Option<Direction>)) -> Self {
         let (id, dir) = value;
-        IdStream(id, dir)
+        IdStream(id, None, dir, None)
     }
 }
 
@@ -187,4 +1015,6 @@ pub enum Style {
     Whitespace,
     Digit,
     Letter,
+    /// NULL, empty strings, NaN and the like in result sets
+    Special,
 }
@@ -0,0 +1,242 @@
+//! Support for user supplied [rhai](https://rhai.rs/) scripts that decide how
+//! connections and frames are rendered, or rewrite the traffic passing through
+//! the proxy. See [Script] for the callbacks.
+
+use std::{io, path::Path};
+
+use anyhow::{anyhow, Result as AResult};
+use rhai::{Array, Blob, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+
+use crate::{
+    mapi::{Hooks, Verdict},
+    proxy::{
+        event::{ConnectionId, Direction},
+        network::Addr,
+        Rewriter,
+    },
+};
+
+/// A loaded script. It may define the following functions:
+///
+/// ```rhai
+/// fn on_connect(conn, local, peer) { }
+/// fn on_message(conn, direction, data) { }
+/// ```
+///
+/// Here `conn` is the numeric connection id, `local` and `peer` are addresses
+/// as strings, `direction` is either "upstream" or "downstream" and `data` is a
+/// blob holding the frame. Use `data.as_string()` to get it as text.
+///
+/// Both functions may return:
+///
+/// - nothing or `true` to render as usual,
+/// - `false` to suppress rendering,
+/// - a string to render as a note,
+/// - a map with optional keys `drop` (bool), `tag` (string, added to the frame
+///   header), `note` (string) and `text` (string) or `data` (blob) to render
+///   instead of the actual data.
+///
+/// For `on_connect`, dropping and tagging apply to all frames of the connection.
+///
+/// When used with `--rewrite`, the proxy calls
+///
+/// ```rhai
+/// fn on_forward(conn, direction, data) { }
+/// ```
+///
+/// for every message before forwarding it. It may return nothing to forward
+/// the message unchanged, a string or blob to forward instead, or an array of
+/// those to forward several messages, for example to inject an extra command.
+/// An empty array drops the message.
+///
+/// Rhai functions cannot see global variables so the functions are called with
+/// `this` bound to a map that is kept between calls, for example
+/// `this.count += 1`.
+pub struct Script {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    state: Dynamic,
+    has_on_connect: bool,
+    has_on_message: bool,
+    has_on_forward: bool,
+}
+
+impl Script {
+    /// Compile the script and run its top level statements.
+    pub fn load(path: &Path) -> AResult<Script> {
+        let name = path.display().to_string();
+
+        let mut engine = Engine::new();
+        // stdout is where the frames go
+        engine.on_print(|s| eprintln!("{s}"));
+        engine.on_debug(|s, _, pos| eprintln!("{pos:?}: {s}"));
+
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| anyhow!("Could not load script {name}: {e}"))?;
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| anyhow!("Could not run script {name}: {e}"))?;
+
+        let has_function = |fname| ast.iter_functions().any(|f| f.name == fname);
+        let has_on_connect = has_function("on_connect");
+        let has_on_message = has_function("on_message");
+        let has_on_forward = has_function("on_forward");
+
+        Ok(Script {
+            name,
+            engine,
+            ast,
+            scope,
+            state: Dynamic::from_map(Map::new()),
+            has_on_connect,
+            has_on_message,
+            has_on_forward,
+        })
+    }
+
+    /// Whether the script defines `on_forward`.
+    pub fn can_rewrite(&self) -> bool {
+        self.has_on_forward
+    }
+
+    fn call<T>(
+        &mut self,
+        fname: &str,
+        args: impl rhai::FuncArgs,
+        convert: impl FnOnce(Dynamic) -> Result<T, String>,
+    ) -> io::Result<T> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        let result = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, fname, args)
+            .map_err(|e| io::Error::other(format!("{}: {fname}: {e}", self.name)))?;
+        convert(result).map_err(|e| io::Error::other(format!("{}: {fname}: {e}", self.name)))
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Upstream => "upstream",
+        Direction::Downstream => "downstream",
+    }
+}
+
+impl Hooks for Script {
+    fn on_connect(&mut self, id: ConnectionId, local: &Addr, peer: &Addr) -> io::Result<Verdict> {
+        if !self.has_on_connect {
+            return Ok(Verdict::default());
+        }
+        let args = (id.as_usize() as i64, local.to_string(), peer.to_string());
+        self.call("on_connect", args, verdict)
+    }
+
+    fn on_message(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<Verdict> {
+        if !self.has_on_message {
+            return Ok(Verdict::default());
+        }
+        let data: Blob = data.to_vec();
+        let args = (
+            id.as_usize() as i64,
+            direction_name(direction).to_string(),
+            data,
+        );
+        self.call("on_message", args, verdict)
+    }
+}
+
+impl Rewriter for Script {
+    fn rewrite(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        message: &[u8],
+    ) -> io::Result<Option<Vec<Vec<u8>>>> {
+        if !self.has_on_forward {
+            return Ok(None);
+        }
+        let data: Blob = message.to_vec();
+        let args = (
+            id.as_usize() as i64,
+            direction_name(direction).to_string(),
+            data,
+        );
+        self.call("on_forward", args, replacement)
+    }
+}
+
+/// Convert the return value of `on_forward` into the messages to forward.
+fn replacement(value: Dynamic) -> Result<Option<Vec<Vec<u8>>>, String> {
+    if value.is_unit() {
+        return Ok(None);
+    }
+    let bytes = |value: Dynamic| {
+        let type_name = value.type_name();
+        if value.is_blob() {
+            Ok(value.into_blob().unwrap())
+        } else if value.is_string() {
+            Ok(value.into_string().unwrap().into_bytes())
+        } else {
+            Err(format!("unexpected return value of type {type_name}"))
+        }
+    };
+    if value.is_array() {
+        let array: Array = value.into_array().unwrap();
+        let messages = array.into_iter().map(bytes).collect::<Result<_, _>>()?;
+        return Ok(Some(messages));
+    }
+    Ok(Some(vec![bytes(value)?]))
+}
+
+/// Convert the return value of a callback into a [Verdict].
+fn verdict(value: Dynamic) -> Result<Verdict, String> {
+    if value.is_unit() {
+        return Ok(Verdict::default());
+    }
+    if let Some(keep) = value.clone().try_cast::<bool>() {
+        return Ok(Verdict {
+            drop: !keep,
+            ..Verdict::default()
+        });
+    }
+    if value.is_string() {
+        return Ok(Verdict {
+            note: Some(value.to_string()),
+            ..Verdict::default()
+        });
+    }
+    let Some(map) = value.clone().try_cast::<Map>() else {
+        return Err(format!(
+            "unexpected return value of type {}",
+            value.type_name()
+        ));
+    };
+
+    let mut verdict = Verdict::default();
+    for (key, value) in map {
+        let type_name = value.type_name();
+        let bad_type = || format!("return value '{key}' has unexpected type {type_name}");
+        match key.as_str() {
+            "drop" => verdict.drop = value.as_bool().map_err(|_| bad_type())?,
+            "tag" => verdict.tag = Some(value.into_string().map_err(|_| bad_type())?),
+            "note" => verdict.note = Some(value.into_string().map_err(|_| bad_type())?),
+            "text" => {
+                let text = value.into_string().map_err(|_| bad_type())?;
+                verdict.replacement = Some(text.into_bytes());
+            }
+            "data" => verdict.replacement = Some(value.into_blob().map_err(|_| bad_type())?),
+            other => return Err(format!("unknown key '{other}' in return value")),
+        }
+    }
+    Ok(verdict)
+}
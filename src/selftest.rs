@@ -0,0 +1,529 @@
+//! Implementation of `mapiproxy selftest`. It runs a fake MAPI server and a
+//! number of clients in-process, sends their traffic through the proxy and
+//! checks both what the clients receive and what gets rendered.
+
+use std::{
+    io::{self, ErrorKind, Write},
+    iter,
+    mem::MaybeUninit,
+    net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use argsplitter::{ArgError, ArgSplitter};
+use mapiproxy::{
+    mapi::{self, encode_message, read_message},
+    proxy::{
+        event::{Direction, MapiEvent},
+        network::{ListenOptions, MonetAddr, TrafficMarking},
+        Control,
+    },
+    render::Renderer,
+    Level,
+};
+
+use crate::{create_pcap_writer, parse_backend, start_proxy, wall_clock, Backend, USAGE, VERSION};
+
+const CHALLENGE: &[u8] =
+    b"sElFtEsT:merovingian:9:RIPEMD160,SHA512,SHA384,SHA256,SHA224,SHA1:LIT:SHA512:";
+const LOGIN: &[u8] = b"LIT:monetdb:{SHA512}0123456789abcdef:sql:demo:FILETRANS:\n";
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The out-of-band byte MonetDB takes as a request to interrupt the query
+const INTERRUPT: u8 = 1;
+
+pub fn selftest(mut args: ArgSplitter) -> AResult<()> {
+    let mut level = Level::Messages;
+    let mut force_binary = false;
+    let mut backend = Backend::Mio;
+    let mut connections = 3;
+    let mut queries = 6;
+    let mut rows = 2000;
+    let mut mix = None;
+    let mut show = false;
+    let mut pcap_file: Option<PathBuf> = None;
+
+    while let Some(flag) = args.flag()? {
+        match flag {
+            "-m" | "--messages" => level = Level::Messages,
+            "-b" | "--blocks" => level = Level::Blocks,
+            "-r" | "--raw" => level = Level::Raw,
+            "-B" | "--binary" => force_binary = true,
+            "--backend" => backend = parse_backend("--backend", &args.param()?)?,
+            "--connections" => connections = parse_count("--connections", &args.param()?)?,
+            "--queries" => queries = parse_count("--queries", &args.param()?)?,
+            "--rows" => rows = parse_count("--rows", &args.param()?)?,
+            "--mix" => mix = Some(Mix::parse("--mix", &args.param()?)?),
+            "--show" => show = true,
+            "--write-pcap" => pcap_file = Some(args.param_os()?.into()),
+            "--help" => {
+                println!("Mapiproxy version {VERSION}");
+                println!();
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => return Err(ArgError::unknown_flag(flag).into()),
+        }
+    }
+    args.no_more_stashed()?;
+    // the proxy can only send out-of-band data over TCP from Unix, and only
+    // the mio backend can be asked to
+    let interrupts = cfg!(unix) && backend == Backend::Mio;
+    let mix = match mix {
+        Some(mix) if mix.0.contains(&Kind::Interrupt) && !interrupts => {
+            bail!("--mix: interrupt needs --backend=mio on Unix")
+        }
+        Some(mix) => mix,
+        None => Mix::default(interrupts),
+    };
+    let mut pcap = pcap_file.as_deref().map(create_pcap_writer).transpose()?;
+
+    let server =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).context("Could not start the fake server")?;
+    let server_port = server.local_addr()?.port();
+    // Let the OS pick a free port, then release it for the proxy to use
+    let proxy_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port();
+    let ip = Ipv4Addr::LOCALHOST.into();
+    let listen_addr = MonetAddr::Ip {
+        ip,
+        port: proxy_port,
+    };
+    let forward_addr = MonetAddr::Ip {
+        ip,
+        port: server_port,
+    };
+
+    let (send_events, receive_events) = mpsc::channel();
+    let failures = send_events.clone();
+    let handler = move |event| {
+        let _ = send_events.send(event);
+    };
+    let on_failure = move |event| {
+        let _ = failures.send(event);
+    };
+    let (trigger, controller) = start_proxy(
+        listen_addr,
+        forward_addr,
+        backend,
+        ListenOptions::default(),
+        TrafficMarking::default(),
+        true,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        false,
+        None,
+        handler,
+        on_failure,
+    )?;
+
+    thread::spawn(move || run_server(server));
+    let clients: Vec<_> = (0..connections)
+        .map(|n| {
+            let mix = mix.clone();
+            thread::spawn(move || run_client(n, proxy_port, &mix, queries, rows))
+        })
+        .collect();
+
+    let output = SharedBuffer::default();
+    let mut renderer = Renderer::new(false, output.clone());
+    let mut mapi_state = mapi::State::new(level, force_binary);
+    let mut ended = 0;
+    let mut nbytes = 0;
+    while ended < connections {
+        let ev = receive_events
+            .recv_timeout(3 * TIMEOUT)
+            .context("Timed out waiting for the proxy")?;
+        match &ev {
+            MapiEvent::End { .. } | MapiEvent::Aborted { .. } => ended += 1,
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                nbytes += data.len();
+                // interrupt the query once it is on its way to the server
+                let sleep = Query::Sleep.text();
+                let is_sleep = data.windows(sleep.len()).any(|w| w == sleep.as_bytes());
+                if let (Direction::Upstream, true, Some(controller)) =
+                    (direction, is_sleep, &controller)
+                {
+                    controller(Control::OutOfBand {
+                        id: *id,
+                        byte: INTERRUPT,
+                        reply: None,
+                    });
+                }
+            }
+            MapiEvent::Failed { error } => bail!("The proxy stopped: {error}"),
+            _ => {}
+        }
+        mapi_state.handle(&ev, &mut renderer)?;
+        if let Some(pcap) = &mut pcap {
+            pcap.handle(&ev, wall_clock())?;
+        }
+    }
+    trigger();
+    drop(renderer);
+    if let Some(pcap) = pcap {
+        pcap.finish()?;
+    }
+
+    let mut failures = vec![];
+    for (n, client) in clients.into_iter().enumerate() {
+        match client.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => failures.push(format!("client {n}: {e:#}")),
+            Err(_) => failures.push(format!("client {n} panicked")),
+        }
+    }
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone())?;
+    if show {
+        print!("{output}");
+    }
+    failures.extend(check_output(
+        &output,
+        level,
+        &mix,
+        connections,
+        queries,
+        rows,
+    ));
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            eprintln!("{failure}");
+        }
+        bail!("selftest failed");
+    }
+    println!(
+        "selftest passed: {connections} connections, {queries} queries each, {nbytes} bytes forwarded"
+    );
+    Ok(())
+}
+
+fn parse_count(setting: &str, value: &str) -> AResult<usize> {
+    match value.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("{setting}={value}: must be a number"),
+    }
+}
+
+/// The kinds of query in a [Mix].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Small,
+    Large,
+    Error,
+    Interrupt,
+}
+
+/// The kinds of query each client sends, in turn. Set with `--mix`, for
+/// example `--mix=small=3,large,interrupt` sends three small queries, one
+/// with a large result and one that gets interrupted, and starts over.
+#[derive(Debug, Clone)]
+struct Mix(Vec<Kind>);
+
+impl Mix {
+    fn default(interrupts: bool) -> Mix {
+        let mut kinds = vec![Kind::Small, Kind::Large, Kind::Error];
+        if interrupts {
+            kinds.push(Kind::Interrupt);
+        }
+        Mix(kinds)
+    }
+
+    fn parse(setting: &str, value: &str) -> AResult<Mix> {
+        let mut kinds = vec![];
+        for item in value.split(',') {
+            let (name, count) = item.split_once('=').unwrap_or((item, "1"));
+            let kind = match name {
+                "small" => Kind::Small,
+                "large" => Kind::Large,
+                "error" => Kind::Error,
+                "interrupt" => Kind::Interrupt,
+                _ => bail!(
+                    "{setting}={value}: unknown kind of query '{name}', \
+                     expected small, large, error or interrupt"
+                ),
+            };
+            let Ok(count) = count.parse() else {
+                bail!("{setting}={value}: '{count}' is not a number");
+            };
+            kinds.extend(iter::repeat_n(kind, count));
+        }
+        if kinds.is_empty() {
+            bail!("{setting}={value}: no queries left to send");
+        }
+        Ok(Mix(kinds))
+    }
+
+    fn kind(&self, i: usize) -> Kind {
+        self.0[i % self.0.len()]
+    }
+
+    /// How many of the first `queries` queries are of this kind.
+    fn count(&self, kind: Kind, queries: usize) -> usize {
+        (0..queries).filter(|&i| self.kind(i) == kind).count()
+    }
+}
+
+/// The queries the clients send. The fake server recognizes them from their
+/// text.
+#[derive(Debug, Clone, Copy)]
+enum Query {
+    /// A single row result
+    Value(usize),
+    /// A result with many rows, spanning several blocks
+    Series(usize),
+    /// Something the server will reject
+    Error,
+    /// A query the server keeps running until it is interrupted
+    Sleep,
+}
+
+impl Query {
+    fn nth(mix: &Mix, client: usize, i: usize, rows: usize) -> Query {
+        match mix.kind(i) {
+            Kind::Small => Query::Value(1000 * client + i),
+            Kind::Large => Query::Series(rows),
+            Kind::Error => Query::Error,
+            Kind::Interrupt => Query::Sleep,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Query::Value(n) => format!("sselect {n};\n"),
+            Query::Series(n) => format!("sselect * from sys.generate_series(0, {n});\n"),
+            Query::Error => "sselect * from nonexistent;\n".to_string(),
+            Query::Sleep => "sselect sys.sleep(60000);\n".to_string(),
+        }
+    }
+
+    fn parse(text: &[u8]) -> Query {
+        let text = String::from_utf8_lossy(text);
+        let Some(rest) = text.strip_prefix("sselect ") else {
+            return Query::Error;
+        };
+        let rest = rest.trim_end_matches(";\n");
+        if rest == "sys.sleep(60000)" {
+            return Query::Sleep;
+        }
+        if let Ok(n) = rest.parse() {
+            return Query::Value(n);
+        }
+        let series = rest
+            .strip_prefix("* from sys.generate_series(0, ")
+            .and_then(|r| r.strip_suffix(')'))
+            .and_then(|n| n.parse().ok());
+        match series {
+            Some(n) => Query::Series(n),
+            None => Query::Error,
+        }
+    }
+
+    fn response(&self) -> Vec<u8> {
+        let (values, name, typ) = match *self {
+            Query::Value(n) => (vec![n], "%2", "int"),
+            Query::Series(n) => ((0..n).collect(), "value", "int"),
+            Query::Error => {
+                return b"!42S02!SELECT: no such table 'nonexistent'\n".to_vec();
+            }
+            Query::Sleep => return b"!HY008!Query aborted\n".to_vec(),
+        };
+        let nrows = values.len();
+        let width = values.last().map(|v| v.to_string().len()).unwrap_or(1);
+        let mut out = String::new();
+        out += &format!("&1 0 {nrows} 1 {nrows}\n");
+        out += "% .%1 # table_name\n";
+        out += &format!("% {name} # name\n");
+        out += &format!("% {typ} # type\n");
+        out += &format!("% {width} # length\n");
+        for v in values {
+            out += &format!("[ {v}\t]\n");
+        }
+        out.into_bytes()
+    }
+}
+
+fn send_message(conn: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let mut buf = vec![];
+    encode_message(message, &mut buf);
+    conn.write_all(&buf)
+}
+
+fn run_server(listener: TcpListener) {
+    for conn in listener.incoming() {
+        let Ok(conn) = conn else { continue };
+        thread::spawn(move || serve_connection(conn));
+    }
+}
+
+fn serve_connection(mut conn: TcpStream) -> io::Result<()> {
+    send_message(&mut conn, CHALLENGE)?;
+    if read_message(&mut conn)?.is_none() {
+        return Ok(());
+    }
+    send_message(&mut conn, b"")?;
+    while let Some(query) = read_message(&mut conn)? {
+        let query = Query::parse(&query);
+        if let Query::Sleep = query {
+            if let Err(e) = wait_for_interrupt(&conn) {
+                send_message(
+                    &mut conn,
+                    format!("!HY000!not interrupted: {e}\n").as_bytes(),
+                )?;
+                continue;
+            }
+        }
+        send_message(&mut conn, &query.response())?;
+    }
+    Ok(())
+}
+
+/// Wait for the out-of-band byte the proxy sends on behalf of the selftest,
+/// see [Query::Sleep].
+fn wait_for_interrupt(conn: &TcpStream) -> io::Result<()> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut byte = [MaybeUninit::uninit()];
+    loop {
+        match socket2::SockRef::from(conn).recv_out_of_band(&mut byte) {
+            Ok(1) => break,
+            Ok(_) => return Err(ErrorKind::UnexpectedEof.into()),
+            // no urgent data has arrived yet
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::WouldBlock) => {
+                if Instant::now() > deadline {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "no out-of-band byte"));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    // SAFETY: recv_out_of_band has filled it in
+    let byte = unsafe { byte[0].assume_init() };
+    if byte != INTERRUPT {
+        let msg = format!("unexpected out-of-band byte 0x{byte:02x}");
+        return Err(io::Error::new(ErrorKind::InvalidData, msg));
+    }
+    Ok(())
+}
+
+fn run_client(n: usize, port: u16, mix: &Mix, queries: usize, rows: usize) -> AResult<()> {
+    let mut conn = TcpStream::connect((Ipv4Addr::LOCALHOST, port))?;
+    conn.set_read_timeout(Some(TIMEOUT))?;
+
+    if receive(&mut conn, "challenge")? != CHALLENGE {
+        bail!("challenge got garbled");
+    }
+    send_message(&mut conn, LOGIN)?;
+    if !receive(&mut conn, "login response")?.is_empty() {
+        bail!("login response got garbled");
+    }
+
+    for i in 0..queries {
+        let query = Query::nth(mix, n, i, rows);
+        send_message(&mut conn, query.text().as_bytes())?;
+        let response = receive(&mut conn, "query response")?;
+        if response != query.response() {
+            bail!("response to query {i} got garbled: {query:?}");
+        }
+    }
+
+    conn.shutdown(Shutdown::Write)?;
+    if read_message(&mut conn)?.is_some() {
+        bail!("unexpected data after the last response");
+    }
+    Ok(())
+}
+
+fn receive(conn: &mut TcpStream, what: &str) -> AResult<Vec<u8>> {
+    match read_message(conn) {
+        Ok(Some(msg)) => Ok(msg),
+        Ok(None) => bail!("connection closed while waiting for {what}"),
+        Err(e) => Err(e).with_context(|| format!("waiting for {what}")),
+    }
+}
+
+/// Look for signs of trouble in the rendered output.
+fn check_output(
+    output: &str,
+    level: Level,
+    mix: &Mix,
+    connections: usize,
+    queries: usize,
+    rows: usize,
+) -> Vec<String> {
+    let mut failures = vec![];
+    let mut complain = |msg: String| failures.push(msg);
+
+    for bad in ["ABORTED", "protocol error", "incomplete"] {
+        if output.contains(bad) {
+            complain(format!("output contains '{bad}'"));
+        }
+    }
+    let ended = output.lines().filter(|l| l.ends_with(" ENDED")).count();
+    if ended != connections {
+        complain(format!(
+            "expected {connections} connections to end, found {ended}"
+        ));
+    }
+
+    let interrupts = connections * mix.count(Kind::Interrupt, queries);
+    let sent = format!("OUT-OF-BAND byte 0x{INTERRUPT:02x} sent to server");
+    let interrupted = output.lines().filter(|l| l.ends_with(&sent)).count();
+    if interrupted != interrupts {
+        complain(format!(
+            "expected {interrupts} out-of-band bytes to be sent, found {interrupted}"
+        ));
+    }
+
+    if level == Level::Messages {
+        // challenge, login, prompt and the queries with their responses
+        let expected = connections * (3 + 2 * queries);
+        let frames = output.lines().filter(|l| l.starts_with('┌')).count();
+        if frames != expected {
+            complain(format!("expected {expected} messages, found {frames}"));
+        }
+    }
+
+    if level != Level::Raw {
+        for n in 0..connections {
+            for i in 0..queries {
+                let text = Query::nth(mix, n, i, rows).text();
+                let rendered = format!("│{}↵", text.trim_end());
+                if !output.contains(&rendered) {
+                    complain(format!("query not found in output: {}", text.trim_end()));
+                }
+            }
+        }
+    }
+
+    failures
+}
+
+/// Collects the rendered output so it can be checked afterward.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
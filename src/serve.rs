@@ -0,0 +1,566 @@
+//! Implementation of `mapiproxy serve`. It reads the MAPI conversations from a
+//! pcap file and plays the server side of them back to the clients that
+//! connect.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use argsplitter::{ArgError, ArgSplitter};
+use mapiproxy::{
+    mapi::{encode_message, read_message, Analyzer},
+    pcap::{self, Clock, PcapWriter, Tracker},
+    proxy::{
+        event::{ConnectionId, Direction, MapiEvent},
+        network::{bind_tcp, Addr, MonetAddr, Resolver, DEFAULT_BACKLOG},
+    },
+    render::Renderer,
+    ProxyError,
+};
+
+use crate::{create_pcap_writer, parse_color, parse_socket_template, wall_clock, USAGE, VERSION};
+
+type SharedRenderer = Arc<Mutex<Renderer>>;
+
+pub fn serve(mut args: ArgSplitter) -> AResult<()> {
+    let mut trace_file: Option<PathBuf> = None;
+    let mut matching = false;
+    let mut speed = Speed::Max;
+    let mut pause_at = None;
+    let mut colored = None;
+    let mut unix_socket_dir: Option<PathBuf> = None;
+    let mut unix_socket_template = None;
+    let mut pcap_file: Option<PathBuf> = None;
+
+    while let Some(flag) = args.flag()? {
+        match flag {
+            "--from" => trace_file = Some(args.param_os()?.into()),
+            "--match" => matching = true,
+            "--speed" => speed = parse_speed("--speed", &args.param()?)?,
+            "--pause-at" => pause_at = Some(parse_pause_at("--pause-at", &args.param()?)?),
+            "--color" => colored = parse_color("--color", &args.param()?)?,
+            "--write-pcap" => pcap_file = Some(args.param_os()?.into()),
+            "--unix-socket-dir" => unix_socket_dir = Some(args.param_os()?.into()),
+            "--unix-socket-template" => {
+                unix_socket_template = Some(parse_socket_template(
+                    "--unix-socket-template",
+                    &args.param()?,
+                )?)
+            }
+            "--help" => {
+                println!("Mapiproxy version {VERSION}");
+                println!();
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => return Err(ArgError::unknown_flag(flag).into()),
+        }
+    }
+    let mut resolver = Resolver::default();
+    if let Some(dir) = unix_socket_dir {
+        resolver.unix_socket_dir = dir;
+    }
+    if let Some(template) = unix_socket_template {
+        resolver.unix_socket_template = template;
+    }
+    let listen_addr: MonetAddr = args.stashed_os("LISTEN_ADDR")?.try_into()?;
+    args.no_more_stashed()?;
+    let Some(trace_file) = trace_file else {
+        return Err(ArgError::message("Please pass the recorded traffic using --from").into());
+    };
+
+    let recording = Recording::load(&trace_file)?;
+    if recording.conversations.is_empty() {
+        bail!("No MAPI connections found in {}", trace_file.display());
+    }
+    let pcap = match &pcap_file {
+        Some(path) => Some(Mutex::new(create_pcap_writer(path)?)),
+        None => None,
+    };
+
+    let out = io::stdout();
+    let colored = colored.unwrap_or_else(|| is_terminal::is_terminal(&out));
+    let renderer = Arc::new(Mutex::new(Renderer::new(colored, out)));
+
+    let server = Arc::new(Server {
+        recording,
+        matching,
+        speed,
+        pause_at,
+        next_id: AtomicUsize::new(10),
+        renderer,
+        pcap,
+    });
+    server.note(
+        None,
+        format!(
+            "REPLAYING {n} connections from {file}",
+            n = server.recording.conversations.len(),
+            file = trace_file.display()
+        ),
+    );
+
+    let addrs = listen_addr
+        .resolve_with(&resolver)
+        .with_context(|| format!("Could not resolve LISTEN_ADDR {listen_addr}"))?;
+    let mut threads = vec![];
+    let mut unix_paths = vec![];
+    for addr in addrs {
+        let listener = Listener::bind(&addr).with_context(|| format!("Could not bind {addr}"))?;
+        server.note(None, format!("LISTEN on port {addr}"));
+        if let Addr::Unix(path) = &addr {
+            unix_paths.push(path.clone());
+        }
+        let server = server.clone();
+        threads.push(thread::spawn(move || server.accept_loop(listener, addr)));
+    }
+    // the accept loops never return by themselves, so the listeners are not
+    // dropped when the user presses Ctrl-C
+    #[cfg(unix)]
+    if !unix_paths.is_empty() {
+        use mapiproxy::proxy::network::unbind_unix;
+        ctrlc::set_handler(move || {
+            for path in &unix_paths {
+                unbind_unix(path);
+            }
+            std::process::exit(130);
+        })
+        .context("cannot set Ctrl-C handler")?;
+    }
+    for t in threads {
+        t.join().unwrap()?;
+    }
+    Ok(())
+}
+
+/// How fast to play back the responses, see `--speed`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Speed {
+    /// Send the responses as soon as the request has been received
+    Max,
+    /// Keep the recorded delays, divided by this factor
+    Factor(f64),
+}
+
+fn parse_speed(setting: &str, value: &str) -> AResult<Speed> {
+    if value == "max" {
+        return Ok(Speed::Max);
+    }
+    match value.strip_suffix('x').unwrap_or(value).parse::<f64>() {
+        Ok(factor) if factor > 0.0 && factor.is_finite() => Ok(Speed::Factor(factor)),
+        _ => bail!("{setting}={value}: must be a factor such as 2x or 0.5x, or 'max'"),
+    }
+}
+
+fn parse_pause_at(setting: &str, value: &str) -> AResult<usize> {
+    match value.trim_start_matches('#').parse() {
+        Ok(n @ 1..) => Ok(n),
+        _ => bail!("{setting}={value}: must be a message number, counting from 1"),
+    }
+}
+
+/// The server side of the MAPI conversations found in a pcap file.
+#[derive(Debug, Default)]
+struct Recording {
+    conversations: Vec<Conversation>,
+    /// For every recorded request, where its responses can be found
+    by_request: HashMap<Vec<u8>, (usize, usize)>,
+}
+
+/// One recorded connection.
+#[derive(Debug, Default)]
+struct Conversation {
+    /// What the server sent before the client sent anything, normally the
+    /// login challenge
+    greeting: Vec<Response>,
+    exchanges: Vec<Exchange>,
+}
+
+/// A message sent by the client and the messages the server sent in response.
+#[derive(Debug)]
+struct Exchange {
+    request: Vec<u8>,
+    responses: Vec<Response>,
+}
+
+/// A message sent by the server.
+#[derive(Debug)]
+struct Response {
+    /// How long after the request, or for the greeting, after the connection
+    /// was set up, the server sent it
+    delay: Duration,
+    message: Vec<u8>,
+}
+
+/// Splits the data of one direction of a connection into messages.
+#[derive(Debug)]
+struct Splitter {
+    analyzer: Analyzer,
+    message: Vec<u8>,
+}
+
+impl Splitter {
+    fn new() -> Self {
+        Splitter {
+            analyzer: Analyzer::new(false),
+            message: vec![],
+        }
+    }
+
+    fn split(&mut self, mut data: &[u8], mut on_message: impl FnMut(Vec<u8>)) {
+        while let Some(chunk) = self.analyzer.split_chunk(&mut data) {
+            if self.analyzer.was_error() {
+                // not MAPI, ignore the rest
+                return;
+            }
+            if !self.analyzer.was_body() {
+                continue;
+            }
+            self.message.extend_from_slice(chunk);
+            if self.analyzer.was_message_boundary() {
+                on_message(mem::take(&mut self.message));
+            }
+        }
+    }
+}
+
+impl Recording {
+    fn load(path: &Path) -> AResult<Recording> {
+        let file = File::open(path)
+            .with_context(|| format!("Could not open pcap file {}", path.display()))?;
+
+        let mut order = vec![];
+        // the splitters, the conversation so far and when the last request
+        // was complete
+        let mut connections: HashMap<ConnectionId, (Splitter, Splitter, Conversation, Duration)> =
+            HashMap::new();
+        let clock = Clock::default();
+        let packet_time = clock.clone();
+        let handler = |ev: MapiEvent| {
+            let now = packet_time.now().unwrap_or_default();
+            match ev {
+                MapiEvent::Incoming { id, .. } => {
+                    order.push(id);
+                    let conv = Conversation::default();
+                    let state = (Splitter::new(), Splitter::new(), conv, now);
+                    connections.insert(id, state);
+                }
+                MapiEvent::Data {
+                    id,
+                    direction,
+                    data,
+                } => {
+                    let Some((upstream, downstream, conv, since)) = connections.get_mut(&id) else {
+                        return Ok(());
+                    };
+                    match direction {
+                        Direction::Upstream => upstream.split(&data, |request| {
+                            conv.exchanges.push(Exchange {
+                                request,
+                                responses: vec![],
+                            });
+                            *since = now;
+                        }),
+                        Direction::Downstream => downstream.split(&data, |message| {
+                            let response = Response {
+                                delay: now.saturating_sub(*since),
+                                message,
+                            };
+                            match conv.exchanges.last_mut() {
+                                Some(exchange) => exchange.responses.push(response),
+                                None => conv.greeting.push(response),
+                            }
+                        }),
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        };
+        let mut tracker = Tracker::new(handler);
+        tracker.set_clock(clock);
+        pcap::parse_pcap_file(file, &mut tracker)?;
+        drop(tracker);
+
+        let mut recording = Recording::default();
+        for id in order {
+            let (_, _, conv, _) = connections.remove(&id).unwrap();
+            if conv.greeting.is_empty() && conv.exchanges.is_empty() {
+                continue;
+            }
+            let n = recording.conversations.len();
+            for (i, exchange) in conv.exchanges.iter().enumerate() {
+                if let Entry::Vacant(e) = recording.by_request.entry(exchange.request.clone()) {
+                    e.insert((n, i));
+                }
+            }
+            recording.conversations.push(conv);
+        }
+        Ok(recording)
+    }
+}
+
+struct Server {
+    recording: Recording,
+    /// Look up incoming requests in the recording rather than just replaying
+    /// the responses in order
+    matching: bool,
+    speed: Speed,
+    /// Wait for the user before answering this message and the ones after it
+    pause_at: Option<usize>,
+    next_id: AtomicUsize,
+    renderer: SharedRenderer,
+    /// Where --write-pcap writes the replayed connections
+    pcap: Option<Mutex<PcapWriter<io::BufWriter<File>>>>,
+}
+
+impl Server {
+    fn note(&self, id: Option<ConnectionId>, message: impl std::fmt::Display) {
+        let mut renderer = self.renderer.lock().unwrap();
+        let _ = renderer.message(id, None, message);
+    }
+
+    /// Write the event to the --write-pcap file, if there is one. The file is
+    /// flushed every time because the server runs until it is killed.
+    fn record(&self, event: MapiEvent) {
+        let Some(pcap) = &self.pcap else {
+            return;
+        };
+        let mut pcap = pcap.lock().unwrap();
+        let result = pcap
+            .handle(&event, wall_clock())
+            .and_then(|()| pcap.flush());
+        if let Err(e) = result {
+            self.note(None, e);
+        }
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: Listener, local: Addr) -> AResult<()> {
+        loop {
+            let (conn, peer) = listener.accept()?;
+            let id = ConnectionId::new(self.next_id.fetch_add(1, Ordering::Relaxed));
+            let server = self.clone();
+            let local = local.clone();
+            thread::spawn(move || {
+                server.note(Some(id), format!("INCOMING from {peer}"));
+                server.record(MapiEvent::Incoming { id, local, peer });
+                match server.serve_connection(id, conn) {
+                    Ok(()) => {
+                        server.note(Some(id), "ENDED");
+                        server.record(MapiEvent::End { id });
+                    }
+                    Err(e) => {
+                        server.note(Some(id), format!("ABORTED: {e}"));
+                        let error = ProxyError::Other(e.to_string());
+                        server.record(MapiEvent::Aborted { id, error });
+                    }
+                }
+            });
+        }
+    }
+
+    /// Record data sent by the client or by us.
+    fn record_data(&self, id: ConnectionId, direction: Direction, data: &[u8]) {
+        if self.pcap.is_some() {
+            let data = data.into();
+            self.record(MapiEvent::Data {
+                id,
+                direction,
+                data,
+            });
+        }
+    }
+
+    fn serve_connection(&self, id: ConnectionId, mut conn: Stream) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Stream::Unix(_) = conn {
+            let mut byte = [0u8];
+            conn.read_exact(&mut byte)?;
+            if byte != [b'0'] {
+                return Err(io::Error::other("client did not send initial '0'"));
+            }
+            self.record_data(id, Direction::Upstream, &byte);
+        }
+
+        let conversations = &self.recording.conversations;
+        let n = (id.as_usize() - 10) % conversations.len();
+        let conv = &conversations[n];
+        self.note(Some(id), format!("replaying recorded connection {}", n + 1));
+
+        self.send(id, &mut conn, &conv.greeting)?;
+        let mut pos = 0;
+        let mut nr = 0;
+        let mut pausing = self.pause_at;
+        while let Some(request) = read_message(&mut conn)? {
+            nr += 1;
+            if self.pcap.is_some() {
+                let mut buf = vec![];
+                encode_message(&request, &mut buf);
+                self.record_data(id, Direction::Upstream, &buf);
+            }
+            if pausing.is_some_and(|at| nr >= at) && !self.pause(id, nr, &request) {
+                pausing = None;
+            }
+            let found = match self.recording.by_request.get(&request) {
+                Some(&(n, i)) if self.matching => Some(&conversations[n].exchanges[i]),
+                _ => None,
+            };
+            let Some(exchange) = found.or_else(|| conv.exchanges.get(pos)) else {
+                self.note(Some(id), "no more recorded responses, closing");
+                break;
+            };
+            pos += 1;
+            self.send(id, &mut conn, &exchange.responses)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, id: ConnectionId, conn: &mut Stream, responses: &[Response]) -> io::Result<()> {
+        let Speed::Factor(factor) = self.speed else {
+            let mut buf = vec![];
+            for response in responses {
+                encode_message(&response.message, &mut buf);
+            }
+            conn.write_all(&buf)?;
+            self.record_data(id, Direction::Downstream, &buf);
+            return Ok(());
+        };
+        let start = Instant::now();
+        for response in responses {
+            let due = start + response.delay.div_f64(factor);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+            let mut buf = vec![];
+            encode_message(&response.message, &mut buf);
+            conn.write_all(&buf)?;
+            self.record_data(id, Direction::Downstream, &buf);
+        }
+        Ok(())
+    }
+
+    /// Wait for the user to press Enter before answering message `nr`.
+    /// Returns false if the user wants to continue without pausing again.
+    fn pause(&self, id: ConnectionId, nr: usize, request: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(request);
+        let first_line = text.lines().next().unwrap_or_default();
+        let preview: String = first_line.chars().take(60).collect();
+        let more = if preview.len() < text.trim_end().len() {
+            "…"
+        } else {
+            ""
+        };
+        self.note(
+            Some(id),
+            format!("PAUSED before answering message {nr}: {preview}{more}"),
+        );
+        self.note(
+            Some(id),
+            "press Enter to step to the next message, or 'c' and Enter to continue",
+        );
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => false,
+            Ok(_) => line.trim() != "c",
+        }
+    }
+}
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    fn bind(addr: &Addr) -> io::Result<Listener> {
+        let listener = match addr {
+            Addr::Tcp(a) => {
+                // like the proxy, so *:PORT can bind both 0.0.0.0 and [::]
+                let listener = bind_tcp(a, DEFAULT_BACKLOG)?;
+                listener.set_nonblocking(false)?;
+                Listener::Tcp(listener)
+            }
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                use mapiproxy::proxy::network::bind_unix;
+                use std::os::unix::net::UnixListener;
+                Listener::Unix(bind_unix(path, false, |p| UnixListener::bind(p))?)
+            }
+            #[cfg(not(unix))]
+            Addr::Unix(_) => return Err(io::Error::other("Unix Domain sockets are not supported")),
+        };
+        Ok(listener)
+    }
+
+    fn accept(&self) -> io::Result<(Stream, Addr)> {
+        let accepted = match self {
+            Listener::Tcp(lis) => {
+                let (conn, peer) = lis.accept()?;
+                (Stream::Tcp(conn), peer.into())
+            }
+            #[cfg(unix)]
+            Listener::Unix(lis) => {
+                let (conn, _) = lis.accept()?;
+                (Stream::Unix(conn), Addr::Unix("<client>".into()))
+            }
+        };
+        Ok(accepted)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(lis) = self {
+            if let Some(path) = lis.local_addr().ok().as_ref().and_then(|a| a.as_pathname()) {
+                mapiproxy::proxy::network::unbind_unix(path);
+            }
+        }
+    }
+}
+
+enum Stream {
+    Tcp(std::net::TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
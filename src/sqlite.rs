@@ -0,0 +1,298 @@
+//! Implementation of --sqlite. Writes the connections, messages and the
+//! requests with their timings to an SQLite database so they can be examined
+//! with SQL. The statements are piped into the sqlite3 command line tool.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use mapiproxy::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+use crate::exchange::{Exchanges, Observed, Outcome, Recorder, RoundTrip};
+
+const SCHEMA: &str = "\
+CREATE TABLE connections (
+    id INTEGER PRIMARY KEY,
+    client TEXT NOT NULL,
+    server TEXT,
+    started REAL NOT NULL,
+    ended REAL,
+    error TEXT
+);
+CREATE TABLE messages (
+    conn INTEGER NOT NULL REFERENCES connections(id),
+    seq INTEGER NOT NULL,
+    direction TEXT NOT NULL,
+    started REAL NOT NULL,
+    completed REAL NOT NULL,
+    size INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    PRIMARY KEY (conn, seq)
+);
+CREATE TABLE queries (
+    conn INTEGER NOT NULL REFERENCES connections(id),
+    seq INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    text TEXT NOT NULL,
+    started REAL NOT NULL,
+    sent REAL NOT NULL,
+    first_byte REAL,
+    completed REAL NOT NULL,
+    duration REAL NOT NULL,
+    network REAL,
+    server REAL,
+    request_size INTEGER NOT NULL,
+    response_size INTEGER,
+    status TEXT NOT NULL,
+    PRIMARY KEY (conn, seq)
+);
+";
+
+pub struct SqliteLog {
+    child: Child,
+    out: BufWriter<ChildStdin>,
+    exchanges: Exchanges,
+    observed: Vec<Observed>,
+    conns: HashMap<ConnectionId, Numbering>,
+    last_commit: Instant,
+}
+
+/// Numbers the messages of a connection.
+#[derive(Default)]
+struct Numbering {
+    next: u64,
+    /// Sequence numbers of the requests that have not been answered yet
+    requests: VecDeque<u64>,
+    rtt: Option<RoundTrip>,
+}
+
+impl SqliteLog {
+    /// Statements are committed in batches, at least this often.
+    const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Start sqlite3 on a new database file. An existing file is never
+    /// overwritten, it may hold the results of an earlier run.
+    pub fn create(path: &Path) -> AResult<SqliteLog> {
+        // sqlite3 treats an empty file as a new database
+        match File::options().write(true).create_new(true).open(path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                bail!("--sqlite: {} already exists", path.display())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not create {}", path.display()))
+            }
+        }
+        let spawned = Command::new("sqlite3")
+            .args(["-batch", "-bail"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = fs::remove_file(path);
+                return Err(e).context("--sqlite needs the sqlite3 command line tool");
+            }
+        };
+        let mut out = BufWriter::new(child.stdin.take().unwrap());
+        writeln!(out, "{SCHEMA}BEGIN;")?;
+        out.flush()?;
+        Ok(SqliteLog {
+            child,
+            out,
+            exchanges: Exchanges::default(),
+            observed: vec![],
+            conns: HashMap::new(),
+            last_commit: Instant::now(),
+        })
+    }
+
+    fn write_observed(&mut self) -> io::Result<()> {
+        let mut observed = std::mem::take(&mut self.observed);
+        let mut sql = String::new();
+        for obs in observed.drain(..) {
+            self.statement(obs, &mut sql);
+        }
+        self.observed = observed;
+        if sql.is_empty() {
+            return Ok(());
+        }
+        if self.last_commit.elapsed() >= Self::COMMIT_INTERVAL {
+            sql.push_str("COMMIT;\nBEGIN;\n");
+            self.last_commit = Instant::now();
+        }
+        self.out.write_all(sql.as_bytes())?;
+        self.out.flush()
+    }
+
+    fn statement(&mut self, obs: Observed, sql: &mut String) {
+        let _ = match obs {
+            Observed::Opened { id, client, time } => {
+                self.conns.insert(id, Numbering::default());
+                let n = id.as_usize();
+                writeln!(
+                    sql,
+                    "INSERT INTO connections (id, client, started) VALUES ({n}, {}, {});",
+                    text(&client),
+                    secs(time),
+                )
+            }
+            Observed::Connected { id, server } => writeln!(
+                sql,
+                "UPDATE connections SET server = {} WHERE id = {n};",
+                text(&server),
+                n = id.as_usize(),
+            ),
+            Observed::RoundTrip { id, rtt } => {
+                if let Some(numbering) = self.conns.get_mut(&id) {
+                    numbering.rtt = Some(rtt);
+                }
+                return;
+            }
+            Observed::Message {
+                id,
+                direction,
+                message,
+            } => {
+                let Some(numbering) = self.conns.get_mut(&id) else {
+                    return;
+                };
+                let n = id.as_usize();
+                let seq = numbering.next;
+                numbering.next += 1;
+                let direction = match direction {
+                    Direction::Upstream => {
+                        numbering.requests.push_back(seq);
+                        "upstream"
+                    }
+                    Direction::Downstream => "downstream",
+                };
+                writeln!(
+                    sql,
+                    "INSERT INTO messages VALUES ({n}, {seq}, '{direction}', {}, {}, {}, {});",
+                    secs(message.started),
+                    secs(message.completed),
+                    message.size,
+                    text(&message.text),
+                )
+            }
+            Observed::Exchange {
+                id,
+                request,
+                response,
+                time,
+                ..
+            } => {
+                let Some(numbering) = self.conns.get_mut(&id) else {
+                    return;
+                };
+                let Some(seq) = numbering.requests.pop_front() else {
+                    return;
+                };
+                let n = id.as_usize();
+                let kind = request.kind();
+                let status = Outcome::of(response.as_ref()).name();
+                let sent = request.message.completed;
+                let duration = time.saturating_sub(sent);
+                let (network, server) = match numbering.rtt.map(|rtt| rtt.split(duration)) {
+                    Some((network, server)) => (secs(network), secs(server)),
+                    None => ("NULL".to_string(), "NULL".to_string()),
+                };
+                let query = match kind {
+                    "QUERY" => &request.message.text[1..],
+                    _ => &request.message.text,
+                };
+                writeln!(
+                    sql,
+                    "INSERT INTO queries VALUES ({n}, {seq}, '{kind}', {}, {}, {}, {}, {}, {}, {network}, {server}, {}, {}, '{status}');",
+                    text(query),
+                    secs(request.message.started),
+                    secs(sent),
+                    request.first_byte.map_or("NULL".to_string(), secs),
+                    secs(time),
+                    secs(duration),
+                    request.message.size,
+                    response.map_or("NULL".to_string(), |r| r.size.to_string()),
+                )
+            }
+            Observed::Closed { id, time, error } => {
+                self.conns.remove(&id);
+                let n = id.as_usize();
+                writeln!(
+                    sql,
+                    "UPDATE connections SET ended = {}, error = {} WHERE id = {n};",
+                    secs(time),
+                    error.as_deref().map_or("NULL".to_string(), text),
+                )
+            }
+        };
+    }
+}
+
+impl Recorder for SqliteLog {
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()> {
+        self.exchanges.process(event, now, &mut self.observed);
+        self.write_observed()
+    }
+
+    /// Record the connections that are still open as ended and wait for
+    /// sqlite3 to write everything to disk.
+    fn finish(mut self: Box<Self>, now: Duration) -> AResult<()> {
+        self.exchanges.finish(now, &mut self.observed);
+        self.write_observed()?;
+        self.out.write_all(b"COMMIT;\n")?;
+        self.out.flush()?;
+        let SqliteLog { mut child, out, .. } = *self;
+        drop(out);
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("sqlite3 exited with {status}");
+        }
+        Ok(())
+    }
+}
+
+/// Time since the Unix epoch as a number of seconds.
+fn secs(t: Duration) -> String {
+    format!("{:.6}", t.as_secs_f64())
+}
+
+/// Format the text as an SQL string literal. The sqlite3 tool cannot handle
+/// NUL characters so they are left out.
+fn text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' => out.push_str("''"),
+            '\0' => {}
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+#[test]
+fn test_create_keeps_existing_file() {
+    let path = std::env::temp_dir().join(format!("mapiproxy-test-{}.db", std::process::id()));
+    std::fs::write(&path, "earlier results").unwrap();
+    let result = SqliteLog::create(&path);
+    let contents = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+
+    let Err(err) = result else {
+        panic!("existing file was accepted");
+    };
+    assert!(err.to_string().ends_with("already exists"), "{err}");
+    assert_eq!(contents.unwrap(), "earlier results");
+}
@@ -0,0 +1,301 @@
+//! Implementation of --stats. Measures how long the server takes to answer each
+//! query and prints histograms of those latencies per kind of statement, for
+//! each connection when it ends and for all connections together at the end.
+//! Averages hide the slow tail, the histograms show it. The sizes of the
+//! messages going each way are counted as well, in powers of two.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, Write},
+    time::Duration,
+};
+
+use anyhow::Result as AResult;
+use mapiproxy::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+use crate::exchange::{Exchanges, Observed, Recorder};
+
+/// The kinds of statement that are counted separately, anything else is
+/// OTHER.
+const KINDS: [&str; 4] = ["SELECT", "INSERT", "COPY", "OTHER"];
+
+/// Upper bounds of the buckets, the last bucket holds everything slower.
+const BOUNDS: [Duration; 15] = [
+    Duration::from_micros(100),
+    Duration::from_micros(200),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+];
+
+/// Number of message size buckets. Bucket 0 holds empty messages, bucket i
+/// the sizes from 2^(i-1) up to 2^i, the last bucket everything larger.
+const SIZE_BUCKETS: usize = 32;
+
+/// Width of the longest bar.
+const BAR_WIDTH: u64 = 40;
+
+#[derive(Default)]
+pub struct Stats {
+    exchanges: Exchanges,
+    observed: Vec<Observed>,
+    conns: HashMap<ConnectionId, Histograms>,
+    total: Histograms,
+    /// Print to stdout instead of stderr, see [Stats::set_stdout]
+    stdout: bool,
+}
+
+#[derive(Default)]
+struct Histograms {
+    /// One latency histogram per entry of [KINDS]
+    latencies: [Histogram; KINDS.len()],
+    /// Message sizes upstream and downstream
+    sizes: [SizeHistogram; 2],
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BOUNDS.len() + 1],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+#[derive(Default)]
+struct SizeHistogram {
+    buckets: [u64; SIZE_BUCKETS],
+    count: u64,
+    sum: u64,
+    max: usize,
+}
+
+impl Stats {
+    /// Print the histograms to stdout instead of stderr, for when the
+    /// traffic itself is not shown.
+    pub fn set_stdout(&mut self, stdout: bool) {
+        self.stdout = stdout;
+    }
+
+    fn process(&mut self) -> io::Result<()> {
+        for obs in self.observed.drain(..) {
+            match obs {
+                Observed::Opened { id, .. } => {
+                    self.conns.insert(id, Histograms::default());
+                }
+                Observed::Message {
+                    id,
+                    direction,
+                    message,
+                } => {
+                    let Some(hists) = self.conns.get_mut(&id) else {
+                        continue;
+                    };
+                    let dir = match direction {
+                        Direction::Upstream => 0,
+                        Direction::Downstream => 1,
+                    };
+                    hists.sizes[dir].add(message.size);
+                    self.total.sizes[dir].add(message.size);
+                }
+                Observed::Exchange {
+                    id,
+                    request,
+                    response: Some(response),
+                    ..
+                } => {
+                    if request.kind() != "QUERY" {
+                        continue;
+                    }
+                    let Some(hists) = self.conns.get_mut(&id) else {
+                        continue;
+                    };
+                    let kind = statement_kind(&request.message.text);
+                    let latency = response.completed.saturating_sub(request.message.completed);
+                    hists.latencies[kind].add(latency);
+                    self.total.latencies[kind].add(latency);
+                }
+                Observed::Closed { id, .. } => {
+                    if let Some(hists) = self.conns.remove(&id) {
+                        hists.print(&id.to_string(), self.stdout)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Recorder for Stats {
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()> {
+        self.exchanges.process(event, now, &mut self.observed);
+        self.process()
+    }
+
+    fn report(&mut self) -> io::Result<bool> {
+        self.total.print("all connections so far", self.stdout)?;
+        Ok(true)
+    }
+
+    fn finish(mut self: Box<Self>, now: Duration) -> AResult<()> {
+        self.exchanges.finish(now, &mut self.observed);
+        self.process()?;
+        self.total.print("all connections", self.stdout)?;
+        Ok(())
+    }
+}
+
+impl Histograms {
+    /// Print the histograms that are not empty, normally to stderr so they
+    /// do not get mixed up with output that is redirected to a file.
+    fn print(&self, title: &str, stdout: bool) -> io::Result<()> {
+        let queries: u64 = self.latencies.iter().map(|h| h.count).sum();
+        let messages: u64 = self.sizes.iter().map(|h| h.count).sum();
+        if messages == 0 {
+            return Ok(());
+        }
+        let mut text = format!("STATS {title}, {queries} queries\n");
+        for (kind, hist) in KINDS.iter().zip(&self.latencies) {
+            if hist.count > 0 {
+                hist.describe(kind, &mut text);
+            }
+        }
+        for (direction, hist) in ["UPSTREAM", "DOWNSTREAM"].iter().zip(&self.sizes) {
+            if hist.count > 0 {
+                hist.describe(direction, &mut text);
+            }
+        }
+        if stdout {
+            io::stdout().write_all(text.as_bytes())
+        } else {
+            io::stderr().write_all(text.as_bytes())
+        }
+    }
+}
+
+impl Histogram {
+    fn add(&mut self, latency: Duration) {
+        let i = BOUNDS.partition_point(|&b| b < latency);
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Append a summary line and one line per bucket, from the fastest to the
+    /// slowest bucket that is not empty.
+    fn describe(&self, kind: &str, text: &mut String) {
+        let average = self.sum / self.count as u32;
+        let _ = writeln!(
+            text,
+            "  {kind:<6} {count:>7} queries, average {average}, max {max}",
+            count = self.count,
+            average = format_latency(average),
+            max = format_latency(self.max),
+        );
+        let first = self.buckets.iter().position(|&n| n > 0).unwrap_or(0);
+        let last = self.buckets.iter().rposition(|&n| n > 0).unwrap_or(0);
+        let most = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for i in first..=last {
+            let n = self.buckets[i];
+            let label = match BOUNDS.get(i) {
+                Some(bound) => format!("<= {bound:?}"),
+                None => format!("> {:?}", BOUNDS[BOUNDS.len() - 1]),
+            };
+            let bar = "#".repeat((n * BAR_WIDTH).div_ceil(most) as usize);
+            let line = format!("    {label:<9} {n:>7} {bar}");
+            let _ = writeln!(text, "{}", line.trim_end());
+        }
+    }
+}
+
+impl SizeHistogram {
+    fn add(&mut self, size: usize) {
+        let i = (usize::BITS - size.leading_zeros()) as usize;
+        self.buckets[i.min(SIZE_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += size as u64;
+        self.max = self.max.max(size);
+    }
+
+    /// Like [Histogram::describe], with message sizes instead of latencies.
+    fn describe(&self, direction: &str, text: &mut String) {
+        let _ = writeln!(
+            text,
+            "  {direction:<10} {count:>7} messages, average {average} bytes, max {max} bytes",
+            count = self.count,
+            average = self.sum / self.count,
+            max = self.max,
+        );
+        let first = self.buckets.iter().position(|&n| n > 0).unwrap_or(0);
+        let last = self.buckets.iter().rposition(|&n| n > 0).unwrap_or(0);
+        let most = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for i in first..=last {
+            let n = self.buckets[i];
+            let label = match i {
+                0 => "0".to_string(),
+                _ if i == SIZE_BUCKETS - 1 => format!(">= {}", format_size(1 << (i - 1))),
+                _ => format!("< {}", format_size(1 << i)),
+            };
+            let bar = "#".repeat((n * BAR_WIDTH).div_ceil(most) as usize);
+            let line = format!("    {label:<9} {n:>7} {bar}");
+            let _ = writeln!(text, "{}", line.trim_end());
+        }
+    }
+}
+
+/// Index into [KINDS] of the statement in a query message, which starts with
+/// the `s` of a query followed by the SQL text. Leading comments are skipped.
+fn statement_kind(text: &str) -> usize {
+    let mut sql = text.strip_prefix('s').unwrap_or(text);
+    loop {
+        sql = sql.trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+        if let Some(rest) = sql.strip_prefix("--") {
+            sql = rest.split_once('\n').map_or("", |(_, after)| after);
+        } else if let Some(rest) = sql.strip_prefix("/*") {
+            sql = rest.split_once("*/").map_or("", |(_, after)| after);
+        } else {
+            break;
+        }
+    }
+    let word = sql
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default();
+    KINDS
+        .iter()
+        .position(|kind| kind.eq_ignore_ascii_case(word))
+        .unwrap_or(KINDS.len() - 1)
+}
+
+fn format_latency(latency: Duration) -> String {
+    let micros = latency.as_micros();
+    if micros < 1000 {
+        format!("{micros}µs")
+    } else if micros < 1_000_000 {
+        format!("{:.1}ms", micros as f64 / 1e3)
+    } else {
+        format!("{:.2}s", micros as f64 / 1e6)
+    }
+}
+
+/// A power of two as a number of bytes, such as 512, 8K or 2M.
+fn format_size(size: u64) -> String {
+    match size {
+        _ if size >= 1 << 30 => format!("{}G", size >> 30),
+        _ if size >= 1 << 20 => format!("{}M", size >> 20),
+        _ if size >= 1 << 10 => format!("{}K", size >> 10),
+        _ => size.to_string(),
+    }
+}
@@ -0,0 +1,161 @@
+//! Implementation of --status-interval. Counts the connections, messages and
+//! bytes passing through the proxy and periodically shows them as a single
+//! line, with a sparkline of the recent throughput.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    time::{Duration, Instant},
+};
+
+use mapiproxy::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+use crate::{exchange::Framing, output::Outputs};
+
+pub struct Status {
+    interval: Duration,
+    next: Instant,
+    conns: HashMap<ConnectionId, Framing>,
+    /// Messages and bytes sent upstream and downstream since the last line
+    messages: [u64; 2],
+    bytes: [u64; 2],
+    /// Bytes per second in the most recent intervals, oldest first
+    history: VecDeque<f64>,
+    /// Whether the last line showed no connections and no traffic
+    was_idle: bool,
+}
+
+impl Status {
+    /// Number of intervals shown in the sparkline.
+    const HISTORY: usize = 20;
+
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    pub fn new(interval: Duration) -> Self {
+        Status {
+            interval,
+            next: Instant::now() + interval,
+            conns: HashMap::new(),
+            messages: [0; 2],
+            bytes: [0; 2],
+            history: VecDeque::with_capacity(Self::HISTORY),
+            was_idle: false,
+        }
+    }
+
+    /// How long until the next line is due.
+    pub fn remaining(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    pub fn record(&mut self, event: &MapiEvent) {
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                self.conns.insert(*id, Framing::new(peer.is_unix()));
+            }
+            MapiEvent::End { id } | MapiEvent::Aborted { id, .. } => {
+                self.conns.remove(id);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let i = index(*direction);
+                self.bytes[i] += data.len() as u64;
+                if let Some(framing) = self.conns.get_mut(id) {
+                    self.messages[i] += framing.data(*direction, data).unwrap_or(0) as u64;
+                }
+            }
+            MapiEvent::Dropped {
+                id,
+                direction,
+                bytes,
+                analyzer,
+                ..
+            } => {
+                self.bytes[index(*direction)] += *bytes as u64;
+                if let Some(framing) = self.conns.get_mut(id) {
+                    framing.skip(*direction, analyzer);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Show the status line if it is due. Lines for an idle proxy are only
+    /// shown once.
+    pub fn render_if_due(&mut self, outputs: &mut Outputs) -> io::Result<()> {
+        if Instant::now() < self.next {
+            return Ok(());
+        }
+        self.render(outputs, false)
+    }
+
+    /// Show the status line now, for example when asked for from the
+    /// keyboard, even if the proxy is idle.
+    pub fn render_now(&mut self, outputs: &mut Outputs) -> io::Result<()> {
+        self.render(outputs, true)
+    }
+
+    fn render(&mut self, outputs: &mut Outputs, always: bool) -> io::Result<()> {
+        let now = Instant::now();
+        // measure from the previous line, not from when this one was due
+        let previous = self.next - self.interval;
+        let secs = now
+            .saturating_duration_since(previous)
+            .as_secs_f64()
+            .max(1e-3);
+        self.next = now + self.interval;
+
+        let [up_messages, down_messages] = self.messages.map(|n| n as f64 / secs);
+        let [up_bytes, down_bytes] = self.bytes.map(|n| n as f64 / secs);
+        self.messages = [0; 2];
+        self.bytes = [0; 2];
+        if self.history.len() == Self::HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(up_bytes + down_bytes);
+
+        let idle = self.conns.is_empty() && up_bytes + down_bytes == 0.0;
+        let was_idle = std::mem::replace(&mut self.was_idle, idle);
+        if idle && was_idle && !always {
+            return Ok(());
+        }
+        outputs.message(
+            None,
+            None,
+            format_args!(
+                "STATUS {active} active, {messages:.1} msgs/s, up {up:.2} MB/s, down {down:.2} MB/s {spark}",
+                active = self.conns.len(),
+                messages = up_messages + down_messages,
+                up = up_bytes / 1e6,
+                down = down_bytes / 1e6,
+                spark = self.sparkline(),
+            ),
+        )
+    }
+
+    fn sparkline(&self) -> String {
+        let max = self.history.iter().copied().fold(0.0, f64::max);
+        self.history
+            .iter()
+            .map(|&v| {
+                let top = Self::BARS.len() - 1;
+                let i = if max > 0.0 {
+                    (v / max * top as f64).round() as usize
+                } else {
+                    0
+                };
+                Self::BARS[i.min(top)]
+            })
+            .collect()
+    }
+}
+
+fn index(direction: Direction) -> usize {
+    match direction {
+        Direction::Upstream => 0,
+        Direction::Downstream => 1,
+    }
+}
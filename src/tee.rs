@@ -0,0 +1,85 @@
+//! Implementation of --tee. Writes the bytes flowing in each direction of each
+//! connection to a file of its own, exactly as they were received, so the
+//! traffic can be replayed or examined with other tools later.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result as AResult};
+use mapiproxy::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+use crate::exchange::Recorder;
+
+pub struct TeeLog {
+    dir: PathBuf,
+    /// The upstream and downstream file of each connection
+    files: HashMap<ConnectionId, (File, File)>,
+}
+
+impl TeeLog {
+    pub fn create(dir: &Path) -> AResult<TeeLog> {
+        let context = || format!("Could not prepare tee directory {}", dir.display());
+        fs::create_dir_all(dir).with_context(context)?;
+        // leftovers from an earlier run would mix with the new files
+        for entry in fs::read_dir(dir).with_context(context)? {
+            let path = entry.with_context(context)?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with("conn")
+                && (name.ends_with(".up.bin") || name.ends_with(".down.bin"))
+            {
+                fs::remove_file(&path).with_context(context)?;
+            }
+        }
+        Ok(TeeLog {
+            dir: dir.to_owned(),
+            files: HashMap::new(),
+        })
+    }
+
+    fn path(&self, id: ConnectionId, direction: Direction) -> PathBuf {
+        let suffix = match direction {
+            Direction::Upstream => "up",
+            Direction::Downstream => "down",
+        };
+        self.dir.join(format!("conn{}.{suffix}.bin", id.as_usize()))
+    }
+}
+
+impl Recorder for TeeLog {
+    fn record(&mut self, event: &MapiEvent, _now: Duration) -> io::Result<()> {
+        match event {
+            MapiEvent::Incoming { id, .. } => {
+                let upstream = File::create(self.path(*id, Direction::Upstream))?;
+                let downstream = File::create(self.path(*id, Direction::Downstream))?;
+                self.files.insert(*id, (upstream, downstream));
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                if let Some((upstream, downstream)) = self.files.get_mut(id) {
+                    let file = match direction {
+                        Direction::Upstream => upstream,
+                        Direction::Downstream => downstream,
+                    };
+                    file.write_all(data)?;
+                }
+            }
+            MapiEvent::End { id } | MapiEvent::StillOpen { id } | MapiEvent::Aborted { id, .. } => {
+                self.files.remove(id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, _now: Duration) -> AResult<()> {
+        Ok(())
+    }
+}
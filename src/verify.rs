@@ -0,0 +1,339 @@
+//! Implementation of --verify. Compares the messages the clients send with the
+//! ones sent in a recorded trace, to check that a new or rewritten client
+//! still sends exactly the same bytes.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Write},
+    mem,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use mapiproxy::{
+    mapi::{Analyzer, Handshake},
+    pcap::{self, Tracker},
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+};
+
+use crate::exchange::Recorder;
+
+pub struct Verifier {
+    /// The messages sent by the clients in the trace, per connection, for
+    /// the live connections still to come
+    recorded: VecDeque<Vec<Vec<u8>>>,
+    conns: HashMap<ConnectionId, Check>,
+    /// Number of live connections compared so far
+    checked: usize,
+    /// Number of those that did not match the trace
+    failed: usize,
+}
+
+/// The comparison of one live connection with its recorded counterpart.
+struct Check {
+    splitter: Splitter,
+    expected: Option<Vec<Vec<u8>>>,
+    /// Number of messages that matched
+    matched: usize,
+    /// Set when a difference has been reported, nothing more is compared
+    done: bool,
+}
+
+/// Splits the data the client sends into messages, following the handshake
+/// in case it switches to the 8 byte headers of protocol 10.
+struct Splitter {
+    upstream: Analyzer,
+    downstream: Analyzer,
+    handshake: Option<Handshake>,
+    message: Vec<u8>,
+    /// Set when the data is not MAPI
+    broken: bool,
+}
+
+impl Verifier {
+    /// Read the trace from a pcap file. Its connections are compared with
+    /// the live connections in the order in which they were made.
+    pub fn load(path: &Path) -> AResult<Verifier> {
+        let file =
+            File::open(path).with_context(|| format!("Could not open trace {}", path.display()))?;
+        let mut order = vec![];
+        let mut conns: HashMap<ConnectionId, (Splitter, Vec<Vec<u8>>)> = HashMap::new();
+        let handler = |ev: MapiEvent| {
+            match ev {
+                MapiEvent::Incoming { id, peer, .. } => {
+                    order.push(id);
+                    conns.insert(id, (Splitter::new(peer.is_unix()), vec![]));
+                }
+                MapiEvent::Data {
+                    id,
+                    direction,
+                    data,
+                } => {
+                    if let Some((splitter, messages)) = conns.get_mut(&id) {
+                        splitter.data(direction, &data, |m| messages.push(m));
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        };
+        let mut tracker = Tracker::new(handler);
+        pcap::parse_pcap_file(file, &mut tracker)
+            .with_context(|| format!("Could not read trace {}", path.display()))?;
+        drop(tracker);
+
+        let recorded: VecDeque<_> = order
+            .into_iter()
+            .filter_map(|id| conns.remove(&id))
+            .map(|(_, messages)| messages)
+            .filter(|messages| !messages.is_empty())
+            .collect();
+        if recorded.is_empty() {
+            bail!("{}: no MAPI messages found", path.display());
+        }
+        Ok(Verifier {
+            recorded,
+            conns: HashMap::new(),
+            checked: 0,
+            failed: 0,
+        })
+    }
+
+    /// Report the outcome of the check of a connection that has ended.
+    fn finish_connection(&mut self, id: ConnectionId, check: Check) -> io::Result<()> {
+        self.checked += 1;
+        if check.done {
+            self.failed += 1;
+            return Ok(());
+        }
+        let Some(expected) = check.expected else {
+            self.failed += 1;
+            return report(id, format_args!("has no counterpart in the trace"));
+        };
+        if check.matched < expected.len() {
+            self.failed += 1;
+            let n = expected.len();
+            let matched = check.matched;
+            return report(
+                id,
+                format_args!("ended after {matched} of the {n} messages in the trace"),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Recorder for Verifier {
+    fn record(&mut self, event: &MapiEvent, _now: Duration) -> io::Result<()> {
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let check = Check {
+                    splitter: Splitter::new(peer.is_unix()),
+                    expected: self.recorded.pop_front(),
+                    matched: 0,
+                    done: false,
+                };
+                self.conns.insert(*id, check);
+            }
+            MapiEvent::Mirroring { id, .. } => {
+                // a copy made by the proxy, not a client of its own
+                if let Some(expected) = self.conns.remove(id).and_then(|c| c.expected) {
+                    self.recorded.push_front(expected);
+                }
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let Some(check) = self.conns.get_mut(id) else {
+                    return Ok(());
+                };
+                let mut messages = vec![];
+                check.splitter.data(*direction, data, |m| messages.push(m));
+                for message in messages {
+                    check.compare(*id, &message)?;
+                }
+                if check.splitter.broken && !check.done {
+                    check.done = true;
+                    report(*id, format_args!("sent data that is not MAPI"))?;
+                }
+            }
+            MapiEvent::Dropped { id, .. } => {
+                if let Some(check) = self.conns.get_mut(id).filter(|c| !c.done) {
+                    check.done = true;
+                    report(*id, format_args!("cannot be compared, data was left out"))?;
+                }
+            }
+            MapiEvent::End { id } | MapiEvent::Aborted { id, .. } => {
+                if let Some(check) = self.conns.remove(id) {
+                    self.finish_connection(*id, check)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, _now: Duration) -> AResult<()> {
+        let mut open: Vec<_> = self.conns.drain().collect();
+        open.sort_by_key(|(id, _)| *id);
+        for (id, check) in open {
+            self.finish_connection(id, check)?;
+        }
+        let left = self.recorded.len();
+        let Verifier {
+            checked, failed, ..
+        } = *self;
+        let mut err = io::stderr();
+        match left {
+            0 => {}
+            1 => writeln!(err, "VERIFY 1 connection in the trace was not made")?,
+            n => writeln!(err, "VERIFY {n} connections in the trace were not made")?,
+        }
+        if failed > 0 {
+            bail!("{failed} of {checked} connections differ from the trace");
+        }
+        if left > 0 {
+            bail!("not all connections in the trace were made");
+        }
+        writeln!(err, "VERIFY all {checked} connections match the trace")?;
+        Ok(())
+    }
+}
+
+impl Check {
+    fn compare(&mut self, id: ConnectionId, message: &[u8]) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        let Some(expected) = &self.expected else {
+            return Ok(());
+        };
+        let nr = self.matched + 1;
+        let Some(want) = expected.get(self.matched) else {
+            self.done = true;
+            let n = expected.len();
+            return report(
+                id,
+                format_args!(
+                    "message {nr} is extra, the trace has {n}: got {}",
+                    snippet(message, 0)
+                ),
+            );
+        };
+        let (want, got) = (comparable(want), comparable(message));
+        let Some(pos) = first_difference(&want, &got) else {
+            self.matched += 1;
+            return Ok(());
+        };
+        self.done = true;
+        report(
+            id,
+            format_args!(
+                "message {nr} differs from the trace at byte {pos}: expected {}, got {}",
+                snippet(&want, pos),
+                snippet(&got, pos)
+            ),
+        )
+    }
+}
+
+impl Splitter {
+    fn new(unix_client: bool) -> Self {
+        Splitter {
+            upstream: Analyzer::new(unix_client),
+            downstream: Analyzer::new(false),
+            handshake: Some(Handshake::new(unix_client)),
+            message: vec![],
+            broken: false,
+        }
+    }
+
+    /// Process data sent in the given direction, calling `on_message` for
+    /// every message the client completes.
+    fn data(&mut self, direction: Direction, data: &[u8], mut on_message: impl FnMut(Vec<u8>)) {
+        if self.broken {
+            return;
+        }
+        let mut rest = data;
+        match direction {
+            Direction::Upstream => {
+                while let Some(chunk) = self.upstream.split_chunk(&mut rest) {
+                    if self.upstream.was_error() {
+                        self.broken = true;
+                        return;
+                    }
+                    if !self.upstream.was_body() {
+                        continue;
+                    }
+                    self.message.extend_from_slice(chunk);
+                    if self.upstream.was_message_boundary() {
+                        on_message(mem::take(&mut self.message));
+                    }
+                }
+            }
+            Direction::Downstream => while self.downstream.split_chunk(&mut rest).is_some() {},
+        }
+        if let Some(handshake) = &mut self.handshake {
+            if let Some(protocol) = handshake.data(direction, data) {
+                if protocol.wide_headers() {
+                    self.upstream.set_wide_headers();
+                    self.downstream.set_wide_headers();
+                }
+            }
+            if handshake.is_done() {
+                self.handshake = None;
+            }
+        }
+    }
+}
+
+fn report(id: ConnectionId, what: impl std::fmt::Display) -> io::Result<()> {
+    writeln!(io::stderr(), "VERIFY {id} {what}")
+}
+
+/// The message with the password hash of a login blanked out, because it
+/// depends on the salt the server picked.
+fn comparable(message: &[u8]) -> Vec<u8> {
+    let mut fields: Vec<&[u8]> = message.split(|b| *b == b':').collect();
+    let is_login =
+        fields.len() >= 5 && matches!(fields[0], b"BIG" | b"LIT") && fields[2].starts_with(b"{");
+    if is_login {
+        let end = fields[2].iter().position(|b| *b == b'}').unwrap_or(0);
+        fields[2] = &fields[2][..end + 1];
+    }
+    fields.join(&b':')
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(pos) => Some(pos),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// A few bytes of `message` around `pos`, quoted and escaped.
+fn snippet(message: &[u8], pos: usize) -> String {
+    const BEFORE: usize = 16;
+    const AFTER: usize = 32;
+    let start = pos.saturating_sub(BEFORE);
+    let end = message.len().min(pos + AFTER);
+    let mut text = String::new();
+    if start > 0 {
+        text.push('…');
+    }
+    text.push('"');
+    text.push_str(&message[start..end].escape_ascii().to_string());
+    text.push('"');
+    if end < message.len() {
+        text.push('…');
+    }
+    if pos >= message.len() {
+        text += " (end)";
+    }
+    text
+}
@@ -0,0 +1,395 @@
+//! Implementation of --webhook. POSTs a JSON document to a URL when a
+//! connection starts or ends and when something goes wrong, so the events end
+//! up in chat or incident tooling. The requests are made by the curl command
+//! line tool, on a thread of their own so a slow receiver does not hold up
+//! the proxy. Documents that pile up meanwhile are posted in one go, and
+//! when too many pile up the new ones are left out.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    process::{Command, Stdio},
+    sync::mpsc::{self, TrySendError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use mapiproxy::proxy::event::{ConnectionId, MapiEvent, SourcedId};
+
+use crate::{
+    exchange::{Exchanges, Framing, Observed, Outcome, Recorder},
+    har::{iso8601, json},
+};
+
+/// How much of a query or error message to include.
+const MAX_TEXT: usize = 500;
+
+/// How many documents may wait to be posted.
+const QUEUE_SIZE: usize = 1000;
+
+/// How many waiting documents are posted with one run of curl.
+const MAX_BATCH: usize = 20;
+
+pub fn parse_webhook(setting: &str, value: &str) -> AResult<String> {
+    if !value.starts_with("http://") && !value.starts_with("https://") {
+        bail!("{setting}={value}: must be an http:// or https:// URL");
+    }
+    Ok(value.to_string())
+}
+
+pub struct Webhook {
+    exchanges: Exchanges,
+    observed: Vec<Observed>,
+    /// Follows the framing of each connection to spot protocol errors
+    conns: HashMap<ConnectionId, Framing>,
+    sender: mpsc::SyncSender<String>,
+    poster: JoinHandle<()>,
+    /// Documents left out since the last report because the queue was full
+    dropped: usize,
+    /// Why documents could not be posted, see [Recorder::take_messages]
+    failures: mpsc::Receiver<String>,
+    /// See [Webhook::set_source_name]
+    source: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> AResult<Webhook> {
+        Command::new("curl")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .status()
+            .context("--webhook needs the curl command line tool")?;
+        Ok(Webhook::with_poster(QUEUE_SIZE, move |documents| {
+            post(&url, documents)
+        }))
+    }
+
+    /// Post the documents with `post`, on a thread of its own.
+    fn with_poster(
+        queue_size: usize,
+        mut post: impl FnMut(&[String]) -> io::Result<()> + Send + 'static,
+    ) -> Webhook {
+        let (sender, receiver) = mpsc::sync_channel::<String>(queue_size);
+        let (report_failure, failures) = mpsc::channel();
+        let poster = thread::spawn(move || {
+            while let Ok(first) = receiver.recv() {
+                let mut batch = vec![first];
+                batch.extend(receiver.try_iter().take(MAX_BATCH - 1));
+                if let Err(e) = post(&batch) {
+                    // an event that cannot be delivered should not stop the proxy
+                    let n = documents(batch.len());
+                    let _ = report_failure.send(format!("WEBHOOK could not deliver {n}: {e}"));
+                }
+            }
+        });
+        Webhook {
+            exchanges: Exchanges::default(),
+            observed: vec![],
+            conns: HashMap::new(),
+            sender,
+            poster,
+            dropped: 0,
+            failures,
+            source: None,
+        }
+    }
+
+    /// Mention the connections as for example `fileA#10` rather than `#10`,
+    /// and include the name as the "source" of every document.
+    pub fn set_source_name(&mut self, name: Option<String>) {
+        self.source = name;
+    }
+
+    fn name(&self, id: ConnectionId) -> SourcedId<'_> {
+        id.in_source(self.source.as_deref())
+    }
+
+    /// Spot protocol errors and failures of the proxy, which [Exchanges]
+    /// does not report.
+    fn check(&mut self, event: &MapiEvent, now: Duration) {
+        match event {
+            MapiEvent::Failed { error } => {
+                let text = format!("proxy failed: {error}");
+                self.send("proxy_failed", None, now, &text, &[]);
+            }
+            MapiEvent::Incoming { id, peer, .. } => {
+                self.conns.insert(*id, Framing::new(peer.is_unix()));
+            }
+            MapiEvent::End { id } | MapiEvent::Aborted { id, .. } => {
+                self.conns.remove(id);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+            } => {
+                let Some(framing) = self.conns.get_mut(id) else {
+                    return;
+                };
+                if framing.data(*direction, data).is_some() {
+                    return;
+                }
+                // the rest of the connection is not MAPI
+                self.conns.remove(id);
+                let text = format!("{} {direction}: mapi protocol error", self.name(*id));
+                let fields = [("direction", json(&direction.to_string()))];
+                self.send("protocol_error", Some(*id), now, &text, &fields);
+            }
+            MapiEvent::Dropped {
+                id,
+                direction,
+                analyzer,
+                ..
+            } => {
+                if let Some(framing) = self.conns.get_mut(id) {
+                    framing.skip(*direction, analyzer);
+                }
+            }
+            MapiEvent::ShutdownRead { id, direction } => {
+                let Some(framing) = self.conns.get_mut(id) else {
+                    return;
+                };
+                let Err(situation) = framing.check_incomplete(*direction) else {
+                    return;
+                };
+                let sender = direction.sender();
+                let text = format!(
+                    "{} {sender} closed the connection {situation}",
+                    self.name(*id)
+                );
+                let fields = [("direction", json(&direction.to_string()))];
+                self.send("protocol_error", Some(*id), now, &text, &fields);
+            }
+            _ => {}
+        }
+    }
+
+    fn send_observed(&mut self) {
+        for obs in std::mem::take(&mut self.observed) {
+            match obs {
+                Observed::Opened { id, client, time } => {
+                    let text = format!("{} new connection from {client}", self.name(id));
+                    let fields = [("client", json(&client))];
+                    self.send("connection_start", Some(id), time, &text, &fields);
+                }
+                Observed::Exchange {
+                    id,
+                    server,
+                    request,
+                    response: Some(response),
+                    time,
+                } if Outcome::of(Some(&response)) == Outcome::Error => {
+                    let error = response.text.trim_start_matches('!').trim_end();
+                    let error = shorten(error);
+                    let text = format!("{} server error: {error}", self.name(id));
+                    let fields = [
+                        ("server", json(&server)),
+                        ("request_kind", json(request.kind())),
+                        ("request", json(shorten(request.message.text.trim_end()))),
+                        ("error", json(error)),
+                    ];
+                    self.send("server_error", Some(id), time, &text, &fields);
+                }
+                Observed::Closed { id, time, error } => {
+                    let (text, fields) = match &error {
+                        Some(error) => (
+                            format!("{} connection aborted: {error}", self.name(id)),
+                            vec![("error", json(error))],
+                        ),
+                        None => (format!("{} connection ended", self.name(id)), vec![]),
+                    };
+                    self.send("connection_end", Some(id), time, &text, &fields);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Queue a document for posting. `text` is a summary for humans, which
+    /// is also what chat services such as Slack show. `fields` are already
+    /// formatted as JSON values.
+    fn send(
+        &mut self,
+        event: &str,
+        id: Option<ConnectionId>,
+        time: Duration,
+        text: &str,
+        fields: &[(&str, String)],
+    ) {
+        let mut doc = format!(
+            r#"{{"event":{},"time":{},"text":{}"#,
+            json(event),
+            json(&iso8601(time)),
+            json(text)
+        );
+        if let Some(id) = id {
+            let _ = write!(doc, r#","connection":{}"#, id.as_usize());
+        }
+        if let Some(source) = &self.source {
+            let _ = write!(doc, r#","source":{}"#, json(source));
+        }
+        for (name, value) in fields {
+            let _ = write!(doc, r#",{}:{value}"#, json(name));
+        }
+        doc.push('}');
+        // the poster only goes away in finish()
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(doc) {
+            self.dropped += 1;
+        }
+    }
+}
+
+impl Recorder for Webhook {
+    fn record(&mut self, event: &MapiEvent, now: Duration) -> io::Result<()> {
+        self.check(event, now);
+        self.exchanges.process(event, now, &mut self.observed);
+        self.send_observed();
+        Ok(())
+    }
+
+    fn take_messages(&mut self) -> Vec<String> {
+        let mut messages: Vec<String> = self.failures.try_iter().collect();
+        if self.dropped > 0 {
+            let n = documents(std::mem::take(&mut self.dropped));
+            messages.push(format!("WEBHOOK left out {n}, too many were waiting"));
+        }
+        messages
+    }
+
+    /// Post the connections that are still open as ended and wait until
+    /// everything has been posted. Fails if something could not be posted
+    /// that has not been reported yet.
+    fn finish(mut self: Box<Self>, now: Duration) -> AResult<()> {
+        self.exchanges.finish(now, &mut self.observed);
+        self.send_observed();
+        let Webhook {
+            sender,
+            poster,
+            dropped,
+            failures,
+            ..
+        } = *self;
+        drop(sender);
+        let _ = poster.join();
+        if let Some(failure) = failures.try_iter().last() {
+            bail!("{failure}");
+        }
+        if dropped > 0 {
+            bail!(
+                "WEBHOOK left out {}, too many were waiting",
+                documents(dropped)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Post the documents one by one, with a single run of curl so the
+/// connection can be reused. Each goes into a request of its own because
+/// receivers such as Slack take one document per request.
+fn post(url: &str, documents: &[String]) -> io::Result<()> {
+    let mut command = Command::new("curl");
+    command.args(["--silent", "--show-error"]);
+    for (i, document) in documents.iter().enumerate() {
+        if i > 0 {
+            command.arg("--next");
+        }
+        command
+            .args(["--fail", "--max-time", "10"])
+            .args(["--header", "Content-Type: application/json"])
+            // a JSON object starts with '{', never with '@'
+            .args(["--data-binary", document])
+            .args(["--url", url]);
+    }
+    let output = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let msg = match stderr.lines().last() {
+            Some(line) => line.trim().to_string(),
+            None => format!("curl {}", output.status),
+        };
+        return Err(io::Error::other(msg));
+    }
+    Ok(())
+}
+
+fn documents(n: usize) -> String {
+    match n {
+        1 => "1 document".to_string(),
+        n => format!("{n} documents"),
+    }
+}
+
+fn shorten(text: &str) -> &str {
+    match text.char_indices().nth(MAX_TEXT) {
+        Some((i, _)) => &text[..i],
+        None => text,
+    }
+}
+
+#[test]
+fn test_queue_and_batches() {
+    use std::sync::{Arc, Mutex};
+
+    let batches = Arc::new(Mutex::new(vec![]));
+    let (started, wait_started) = mpsc::channel();
+    let (release, gate) = mpsc::channel::<()>();
+    let poster = {
+        let batches = Arc::clone(&batches);
+        move |documents: &[String]| {
+            let mut batches = batches.lock().unwrap();
+            batches.push(documents.to_vec());
+            match batches.len() {
+                1 => {
+                    drop(batches);
+                    started.send(()).unwrap();
+                    let _ = gate.recv();
+                    Ok(())
+                }
+                _ => Err(io::Error::other("curl exit status: 22")),
+            }
+        }
+    };
+    let mut webhook = Webhook::with_poster(4, poster);
+    let send = |webhook: &mut Webhook, text| {
+        webhook.send("test", None, Duration::ZERO, text, &[]);
+    };
+
+    // the poster takes the first and is stuck posting it
+    send(&mut webhook, "0");
+    wait_started.recv().unwrap();
+    for text in ["1", "2", "3", "4", "5", "6"] {
+        send(&mut webhook, text);
+    }
+    assert_eq!(
+        webhook.take_messages(),
+        ["WEBHOOK left out 2 documents, too many were waiting"]
+    );
+    assert!(webhook.take_messages().is_empty());
+
+    // the waiting ones go together, and fail
+    release.send(()).unwrap();
+    let err = Box::new(webhook).finish(Duration::ZERO).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "WEBHOOK could not deliver 4 documents: curl exit status: 22"
+    );
+    let mut texts = vec![];
+    for batch in batches.lock().unwrap().iter() {
+        let mut batch_texts = vec![];
+        for doc in batch {
+            let prefix = r#"{"event":"test","time":"1970-01-01T00:00:00.000Z","text":""#;
+            batch_texts.push(doc.strip_prefix(prefix).unwrap().to_string());
+        }
+        texts.push(batch_texts);
+    }
+    assert_eq!(
+        texts,
+        [vec![r#"0"}"#], vec![r#"1"}"#, r#"2"}"#, r#"3"}"#, r#"4"}"#]]
+    );
+}
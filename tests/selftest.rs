@@ -0,0 +1,38 @@
+use std::process::Command;
+
+fn selftest(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_mapiproxy"))
+        .arg("selftest")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "selftest {args:?} failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_selftest_mio() {
+    selftest(&["--backend=mio", "-m"]);
+    selftest(&["--backend=mio", "-r"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_selftest_mix() {
+    selftest(&["--backend=mio", "-m", "--mix=interrupt=2,small,large=0"]);
+}
+
+#[test]
+fn test_selftest_tokio() {
+    selftest(&["--backend=tokio", "-b"]);
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+#[test]
+fn test_selftest_uring() {
+    selftest(&["--backend=uring", "-m"]);
+    selftest(&["--backend=uring", "-r"]);
+}